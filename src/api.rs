@@ -0,0 +1,154 @@
+use anyhow::Result;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// `POST /account/register`
+#[derive(Serialize)]
+pub struct RegisterRequest<'a> {
+    pub username: &'a str,
+    pub bundle: &'a serde_json::Value,
+}
+
+/// One result of `GET /account/search`.
+#[derive(Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    #[serde(rename = "Devices")]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Device {
+    pub id: u64,
+}
+
+/// One device in the response of `GET /account/key-bundle`.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceKeyBundle {
+    pub id: u64,
+    pub key_bundle: KeyBundle,
+}
+
+/// A published X3DH key bundle, with every key base64-encoded.
+#[derive(Serialize, Deserialize)]
+pub struct KeyBundle {
+    #[serde(deserialize_with = "base64_string")]
+    pub identity_key: String,
+    #[serde(deserialize_with = "base64_string")]
+    pub signed_pre_key: String,
+    #[serde(deserialize_with = "base64_string")]
+    pub signed_pre_key_signature: String,
+    #[serde(default)]
+    pub one_time_pre_key: Option<String>,
+}
+
+/// `POST /message/send`
+#[derive(Serialize)]
+pub struct SendRequest {
+    pub messages: Vec<OutgoingMessage>,
+}
+
+/// A message encrypted for one device of the recipient.
+#[derive(Serialize)]
+pub struct OutgoingMessage {
+    pub recipient_device_id: u64,
+    pub ciphertext: String,
+    pub header: String,
+}
+
+/// `POST /message/fetch`. Servers without pagination answer with a plain
+/// array. Messages are decoded one by one, so a single malformed message
+/// does not hold up the rest.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum FetchResponse {
+    Page {
+        messages: Vec<serde_json::Value>,
+        #[serde(default)]
+        next_cursor: Option<String>,
+        /// How many of my one-time prekeys the server still holds.
+        #[serde(default)]
+        prekeys_remaining: Option<u32>,
+    },
+    All(Vec<serde_json::Value>),
+}
+
+/// A message waiting for us on the server, as fetched or streamed.
+#[derive(Deserialize)]
+pub struct IncomingMessage {
+    /// Opaque id used to acknowledge the message.
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    /// The sender.
+    pub username: String,
+    #[serde(deserialize_with = "base64_string")]
+    pub ciphertext: String,
+    #[serde(deserialize_with = "base64_string")]
+    pub header: String,
+    #[serde(default)]
+    pub sender_device_id: Option<u64>,
+    /// When the server accepted the message.
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// Reads a successful response body as `T`. If the server's answer does not
+/// have the expected shape, the error names the endpoint and the offending
+/// field, e.g. `[0].key_bundle.identity_key: invalid type: integer `5`,
+/// expected a base64 string`.
+pub async fn decode<T: DeserializeOwned>(response: reqwest::Response, endpoint: &str) -> Result<T> {
+    let body = response.text().await?;
+    let deserializer = &mut serde_json::Deserializer::from_str(&body);
+
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| invalid_response(endpoint, e.path(), e.inner()))
+}
+
+/// Like `decode`, for a value that was already parsed, such as one message
+/// of a batch.
+pub fn from_value<T: DeserializeOwned>(value: serde_json::Value, what: &str) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|e| invalid_response(what, e.path(), e.inner()))
+}
+
+fn invalid_response(
+    what: &str,
+    path: &serde_path_to_error::Path,
+    error: &serde_json::Error,
+) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Server returned an invalid response from {} at {}: {}",
+        what,
+        path,
+        error
+    )
+}
+
+/// Accepts only strings that are valid standard base64.
+fn base64_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    struct Base64Visitor;
+
+    impl Visitor<'_> for Base64Visitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a base64 string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+            BASE64_STANDARD
+                .decode(value)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))?;
+            Ok(value.to_string())
+        }
+    }
+
+    deserializer.deserialize_str(Base64Visitor)
+}
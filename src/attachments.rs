@@ -0,0 +1,579 @@
+use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use colored::*;
+use dialoguer::Confirm;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::{config, database, ui};
+
+/// Setting holding the key that encrypts and names blobs, base64-encoded.
+/// It lives in the database, so the blobs are only as readable as it is.
+const KEY_SETTING: &str = "attachment_key";
+
+const NONCE_LEN: usize = 24;
+
+/// Settings limiting which received files are ready to open: a size in
+/// bytes, comma-separated MIME types such as `image/*`, and comma-separated
+/// senders. Unset means no limit. Anything else is quarantined until it is
+/// approved with `dood attachments approve`.
+const MAX_SIZE_SETTING: &str = "attachment_max_size";
+const TYPES_SETTING: &str = "attachment_types";
+const SENDERS_SETTING: &str = "attachment_senders";
+
+/// Directory of encrypted attachment blobs, next to the database.
+fn store_dir() -> PathBuf {
+    let path = config::data_dir().join("attachments");
+    fs::create_dir_all(&path).ok();
+    path
+}
+
+fn blob_path(blob: &str) -> PathBuf {
+    store_dir().join(blob)
+}
+
+/// The store key, created on first use.
+fn store_key() -> Result<Zeroizing<[u8; 32]>> {
+    let mut key = Zeroizing::new([0u8; 32]);
+
+    match config::get_setting(KEY_SETTING)? {
+        Some(encoded) => {
+            let bytes = Zeroizing::new(
+                BASE64_STANDARD
+                    .decode(encoded)
+                    .context("The attachment store key is corrupted")?,
+            );
+            if bytes.len() != key.len() {
+                anyhow::bail!("The attachment store key is corrupted");
+            }
+            key.copy_from_slice(&bytes);
+        }
+        None => {
+            rand::thread_rng().fill_bytes(key.as_mut_slice());
+            config::set_setting(KEY_SETTING, &BASE64_STANDARD.encode(key.as_slice()))?;
+        }
+    }
+
+    Ok(key)
+}
+
+/// Names a blob after its content. The hash is keyed, so the file names do
+/// not reveal whether someone else has the same file.
+fn blob_name(key: &[u8; 32], content: &[u8]) -> String {
+    Sha256::new()
+        .chain_update(key)
+        .chain_update(content)
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Encrypts `content` into the store, unless a blob with the same content
+/// is already there, and records it as an attachment of `message_id`.
+pub fn store(message_id: Option<i64>, name: &str, content: &[u8]) -> Result<i64> {
+    let key = store_key()?;
+    let blob = blob_name(&key, content);
+    let path = blob_path(&blob);
+
+    if !path.exists() {
+        let sealed = encrypt(&key, content)?;
+
+        // Written under a temporary name so an interrupted write never
+        // leaves a truncated blob behind under the real one.
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(&sealed)?;
+        file.sync_all()?;
+        fs::rename(&partial, &path)?;
+    }
+
+    database::save_attachment(message_id, name, content.len() as u64, &blob)
+}
+
+/// Encrypts `content` under `key` as the nonce followed by the ciphertext.
+fn encrypt(key: &[u8; 32], content: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), content)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt attachment"))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if data.len() < NONCE_LEN {
+        anyhow::bail!("Encrypted attachment is truncated");
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map(Zeroizing::new)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt attachment"))
+}
+
+/// How much larger a sealed file is than its content.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + 16;
+
+/// A file encrypted with a key of its own for the server's blob storage.
+/// Only the key and the digest travel in the message, inside the ratchet.
+pub struct Sealed {
+    pub blob: Vec<u8>,
+    pub key: Zeroizing<[u8; 32]>,
+    /// SHA-256 of `blob`, hex-encoded.
+    pub digest: String,
+}
+
+pub fn seal(content: &[u8]) -> Result<Sealed> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(key.as_mut_slice());
+
+    let blob = encrypt(&key, content)?;
+    let digest = hex_digest(&blob);
+
+    Ok(Sealed { blob, key, digest })
+}
+
+/// Decrypts a downloaded blob, after checking it against the digest the
+/// sender gave.
+pub fn unseal(blob: &[u8], key: &[u8], digest: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if !hex_digest(blob).eq_ignore_ascii_case(digest) {
+        anyhow::bail!("The downloaded file does not match its digest");
+    }
+
+    let key: &[u8; 32] = key
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid attachment key"))?;
+    decrypt(key, blob)
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Stores a file `sender` sent, quarantining it if it falls outside the
+/// attachment policy. Returns its id and, if quarantined, why.
+pub fn store_received(
+    message_id: Option<i64>,
+    sender: &str,
+    name: &str,
+    content: &[u8],
+) -> Result<(i64, Option<String>)> {
+    let violation = policy_violation(sender, name, content.len() as u64)?;
+    let id = store(message_id, name, content)?;
+
+    if let Some(reason) = &violation {
+        database::set_attachment_quarantine(id, Some(reason))?;
+    }
+
+    Ok((id, violation))
+}
+
+/// Why a file from `sender` falls outside the attachment policy, if it does.
+fn policy_violation(sender: &str, name: &str, size: u64) -> Result<Option<String>> {
+    if let Some(senders) = config::get_setting(SENDERS_SETTING)? {
+        if !split_list(&senders).any(|allowed| allowed == sender) {
+            return Ok(Some(format!("{} is not an allowed sender", sender)));
+        }
+    }
+
+    if let Some(max_size) = config::get_setting(MAX_SIZE_SETTING)? {
+        let max_size: u64 = max_size
+            .parse()
+            .with_context(|| format!("Invalid {} setting '{}'", MAX_SIZE_SETTING, max_size))?;
+        if size > max_size {
+            return Ok(Some(format!("larger than {}", ui::format_size(max_size))));
+        }
+    }
+
+    if let Some(types) = config::get_setting(TYPES_SETTING)? {
+        let mime_type = mime_type(name);
+        if !split_list(&types).any(|allowed| type_matches(allowed, mime_type)) {
+            return Ok(Some(format!("{} is not an allowed type", mime_type)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Whether `mime_type` matches `pattern`, which may end in `/*`.
+fn type_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime_type
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+/// The MIME type suggested by a file name's extension. Only the name is
+/// known before a file is opened, so this is what the policy goes by.
+fn mime_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "txt" | "log" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "exe" | "dll" => "application/vnd.microsoft.portable-executable",
+        "sh" => "application/x-sh",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Changes the attachment policy. Lists are comma-separated; an empty one
+/// removes that limit, as does a maximum size of 0.
+pub fn set_policy(
+    max_size: Option<&str>,
+    types: Option<&str>,
+    senders: Option<&str>,
+) -> Result<()> {
+    if let Some(max_size) = max_size {
+        match parse_size(max_size)? {
+            0 => config::delete_setting(MAX_SIZE_SETTING)?,
+            bytes => config::set_setting(MAX_SIZE_SETTING, &bytes.to_string())?,
+        }
+    }
+
+    for (key, list) in [(TYPES_SETTING, types), (SENDERS_SETTING, senders)] {
+        let Some(list) = list else {
+            continue;
+        };
+        let items: Vec<&str> = split_list(list).collect();
+        if items.is_empty() {
+            config::delete_setting(key)?;
+        } else {
+            config::set_setting(key, &items.join(","))?;
+        }
+    }
+
+    show_policy()
+}
+
+fn show_policy() -> Result<()> {
+    let max_size = config::get_setting(MAX_SIZE_SETTING)?
+        .and_then(|bytes| bytes.parse().ok())
+        .map(ui::format_size);
+    let types = config::get_setting(TYPES_SETTING)?;
+    let senders = config::get_setting(SENDERS_SETTING)?;
+
+    println!("\n{}", "📎 Attachment policy".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    for (label, value) in [
+        ("Max size", max_size),
+        ("Types", types),
+        ("Senders", senders),
+    ] {
+        println!(
+            "  {:<9} {}",
+            label.bright_black(),
+            value.unwrap_or_else(|| "any".to_string())
+        );
+    }
+    println!(
+        "{}",
+        "Files outside the policy are quarantined until 'dood attachments approve <id>'."
+            .bright_black()
+    );
+    println!();
+
+    Ok(())
+}
+
+/// Parses sizes such as `500K`, `2M` or a plain number of bytes.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid size '{}'. Use e.g. 500K or 2M", s))?;
+
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        _ => anyhow::bail!("Invalid size '{}'. Use e.g. 500K or 2M", s),
+    };
+
+    Ok(amount.saturating_mul(multiplier))
+}
+
+/// Releases a quarantined attachment so it can be opened.
+pub fn approve(id: i64) -> Result<()> {
+    let attachment =
+        database::get_attachment(id)?.with_context(|| format!("No attachment #{}", id))?;
+
+    if attachment.quarantined.is_none() {
+        println!(
+            "{}",
+            format!("{} is not quarantined.", attachment.name).yellow()
+        );
+        return Ok(());
+    }
+
+    database::set_attachment_quarantine(id, None)?;
+    println!("{} Approved {}", "✓".green().bold(), attachment.name.bold());
+
+    Ok(())
+}
+
+/// Decrypts a blob, checking that it still matches its name.
+fn read_blob(blob: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let key = store_key()?;
+    let data = fs::read(blob_path(blob))
+        .with_context(|| format!("Attachment content {} is missing from the store", blob))?;
+
+    let content = decrypt(&key, &data)
+        .with_context(|| format!("Attachment content {} is corrupted", blob))?;
+
+    if blob_name(&key, &content) != blob {
+        anyhow::bail!("Attachment content {} is corrupted", blob);
+    }
+
+    Ok(content)
+}
+
+/// Deletes blobs no attachment refers to any more, along with leftovers of
+/// interrupted writes. Returns how many files were removed.
+pub fn collect_garbage() -> Result<usize> {
+    let referenced = database::attachment_blobs()?;
+    let mut removed = 0;
+
+    for entry in fs::read_dir(store_dir())? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if !referenced.contains(name) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+pub fn list() -> Result<()> {
+    let attachments = database::get_attachments()?;
+
+    if attachments.is_empty() {
+        println!("{}", "No attachments.".yellow());
+        return Ok(());
+    }
+
+    println!("\n{}", "📎 Attachments".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for attachment in attachments {
+        let conversation = attachment
+            .conversation_with
+            .map(|username| format!(" with {}", username))
+            .unwrap_or_default();
+
+        let quarantined = attachment
+            .quarantined
+            .map(|reason| format!(" quarantined: {}", reason).red().to_string())
+            .unwrap_or_default();
+
+        println!(
+            "  {} {} {}{}{}",
+            format!("#{}", attachment.id).bright_black(),
+            attachment.name.bold(),
+            format!(
+                "({}, {})",
+                ui::format_size(attachment.size),
+                attachment.created_at.format("%Y-%m-%d %H:%M")
+            )
+            .bright_black(),
+            conversation,
+            quarantined
+        );
+    }
+
+    println!();
+    Ok(())
+}
+
+/// Decrypts an attachment to `output`, or to a private temporary copy that
+/// is handed to the system's default application.
+pub fn open(id: i64, output: Option<&str>) -> Result<()> {
+    let attachment =
+        database::get_attachment(id)?.with_context(|| format!("No attachment #{}", id))?;
+    if let Some(reason) = &attachment.quarantined {
+        anyhow::bail!(
+            "{} is quarantined ({}). Run 'dood attachments approve {}' to open it anyway.",
+            attachment.name,
+            reason,
+            id
+        );
+    }
+    let content = read_blob(&attachment.blob)?;
+
+    if let Some(output) = output {
+        fs::write(output, content.as_slice())?;
+        println!(
+            "{} Saved {} to {}",
+            "✓".green().bold(),
+            attachment.name.bold(),
+            output
+        );
+        return Ok(());
+    }
+
+    let dir = config::runtime_dir().join("attachments");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(safe_file_name(&attachment.name, id));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(&content)?;
+
+    launch(&path)?;
+    println!(
+        "{} Opened {} {}",
+        "✓".green().bold(),
+        attachment.name.bold(),
+        format!("({})", path.display()).bright_black()
+    );
+
+    Ok(())
+}
+
+/// Keeps only the last path component of a received file name, so it cannot
+/// point outside the directory it is written to.
+fn safe_file_name(name: &str, id: i64) -> String {
+    match Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(name) if !name.is_empty() => format!("{}-{}", id, name),
+        _ => format!("{}-attachment", id),
+    }
+}
+
+fn launch(path: &Path) -> Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+
+    command.arg(path).spawn().context(
+        "Could not start an application to open the attachment. Use --output to save it instead.",
+    )?;
+
+    Ok(())
+}
+
+pub fn remove(id: i64, yes: bool) -> Result<()> {
+    let attachment =
+        database::get_attachment(id)?.with_context(|| format!("No attachment #{}", id))?;
+
+    if !yes {
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Delete {}?", attachment.name))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("{}", "Deletion cancelled.".yellow());
+            return Ok(());
+        }
+    }
+
+    database::delete_attachment(id)?;
+    collect_garbage()?;
+
+    println!("{} Deleted {}", "✓".green().bold(), attachment.name.bold());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_patterns_match_by_kind() {
+        assert!(type_matches("image/*", mime_type("photo.JPG")));
+        assert!(type_matches("application/pdf", mime_type("report.pdf")));
+        assert!(!type_matches("image/*", mime_type("setup.exe")));
+        assert!(!type_matches("image/*", mime_type("no-extension")));
+    }
+
+    #[test]
+    fn sealed_files_open_with_their_key_and_digest() {
+        let sealed = seal(b"file content").unwrap();
+
+        let content = unseal(&sealed.blob, sealed.key.as_slice(), &sealed.digest).unwrap();
+
+        assert_eq!(content.as_slice(), b"file content");
+        assert_eq!(sealed.blob.len(), b"file content".len() + SEAL_OVERHEAD);
+    }
+
+    #[test]
+    fn sealed_files_are_checked_against_their_digest() {
+        let sealed = seal(b"file content").unwrap();
+        let mut tampered = sealed.blob.clone();
+        tampered[NONCE_LEN] ^= 1;
+
+        assert!(unseal(&tampered, sealed.key.as_slice(), &sealed.digest).is_err());
+        assert!(unseal(&sealed.blob, &[0u8; 32], &sealed.digest).is_err());
+    }
+
+    #[test]
+    fn sizes_parse_with_units() {
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_size("2mb").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_size("2G").is_err());
+        assert!(parse_size("big").is_err());
+    }
+}
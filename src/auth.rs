@@ -3,50 +3,164 @@ use base64::{prelude::BASE64_STANDARD, Engine};
 use colored::*;
 use dood_encryption::x3dh::X3DH;
 use rusqlite::params;
-use serde_json::json;
 use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
 
+use crate::api;
 use crate::config;
+use crate::crypto;
 use crate::database;
+use crate::lock;
+use crate::messages;
+use crate::server::{self, RetryExt};
+
+pub async fn register(username: &str, reuse_keys: bool) -> Result<()> {
+    if reuse_keys {
+        return reregister(username).await;
+    }
 
-pub async fn register(username: &str) -> Result<()> {
     let server = config::get_server_url()?;
 
+    // Fail before generating keys if the server cannot be reached.
+    let health = server::check_health(&server).await?;
+    if health.status.is_server_error() {
+        anyhow::bail!("Server {} is unavailable: {}", server, health.status);
+    }
+
     println!("{}", "🔐 Generating cryptographic keys...".cyan());
 
     let x3dh = X3DH::new();
     let public_key_bundle = x3dh.export();
-    let private_key_bundle = x3dh.export_private();
+    let private_key_bundle = crypto::SecretJson(x3dh.export_private());
 
     println!("{}", "📡 Registering with server...".cyan());
 
-    let client = reqwest::Client::new();
-    let payload = json!({
-        "bundle": public_key_bundle,
-        "username": username
-    });
+    publish_bundle(&server, username, &public_key_bundle)
+        .await
+        .context("Registration failed")?;
+
+    save_account(
+        username,
+        &x3dh,
+        Zeroizing::new(private_key_bundle.0.to_string()),
+        &server,
+    )?;
+    set_session(username)?;
+
+    println!(
+        "{} Account '{}' created successfully!",
+        "✓".green().bold(),
+        username.bold()
+    );
+    println!("{}", "You are now logged in.".green());
+
+    Ok(())
+}
+
+/// Registers an account that already exists locally again, e.g. after the
+/// server lost its data, so contacts keep the same safety numbers.
+async fn reregister(username: &str) -> Result<()> {
+    let server = config::get_server_url()?;
+
+    if !list_accounts()?.iter().any(|account| account == username) {
+        anyhow::bail!(
+            "Account '{}' not found on this machine. Register without --reuse-keys.",
+            username
+        );
+    }
+
+    let x3dh = load_x3dh(username)?;
+
+    println!(
+        "{}",
+        "📡 Re-registering existing keys with server...".cyan()
+    );
+
+    publish_bundle(&server, username, &republished_bundle(&x3dh))
+        .await
+        .context("Registration failed")?;
+
+    let conn = database::get_connection()?;
+    conn.execute(
+        "UPDATE account SET server_url = ?1 WHERE username = ?2",
+        params![server, username],
+    )?;
+    set_session(username)?;
 
-    let response = client
+    println!(
+        "{} Account '{}' registered again with its existing identity",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    Ok(())
+}
+
+/// Creates `username` on `server` with the given public key bundle.
+async fn publish_bundle(server: &str, username: &str, bundle: &serde_json::Value) -> Result<()> {
+    let response = server::http_client()?
         .post(format!("{}/account/register", server))
-        .json(&payload)
-        .send()
+        .json(&api::RegisterRequest { username, bundle })
+        .send_with_retry()
         .await
         .context("Failed to connect to server")?;
 
     if !response.status().is_success() {
         let error_text = response.text().await?;
-        anyhow::bail!("Registration failed: {}", error_text);
+        anyhow::bail!("{}", error_text);
     }
 
-    save_account(username, &x3dh, private_key_bundle.to_string(), &server)?;
-    set_session(username)?;
+    Ok(())
+}
+
+/// The public key bundle of keys that were published before, without the
+/// one-time prekey: a contact may already have used it, and it must not
+/// start a second session.
+fn republished_bundle(x3dh: &X3DH) -> serde_json::Value {
+    let mut bundle = x3dh.export();
+    if let Some(bundle) = bundle.as_object_mut() {
+        bundle.remove("one_time_pre_key");
+        bundle.remove("one_time_pre_keys");
+    }
+    bundle
+}
+
+/// Publishes the current account's existing key bundle to another server and
+/// moves the account there, keeping the identity key and sessions.
+pub async fn migrate_server(new_url: &str, notify: bool) -> Result<()> {
+    if !new_url.starts_with("http://") && !new_url.starts_with("https://") {
+        anyhow::bail!("Invalid URL format. Must start with http:// or https://");
+    }
+    let new_url = new_url.trim_end_matches('/');
+
+    let username = get_current_username()?;
+    let x3dh = get_current_x3dh()?;
+
+    println!("{}", "📡 Publishing keys to the new server...".cyan());
+
+    publish_bundle(new_url, &username, &republished_bundle(&x3dh))
+        .await
+        .context("Registration on the new server failed")?;
+
+    if notify {
+        println!("{}", "📣 Notifying contacts...".cyan());
+        messages::announce_server_move(new_url).await?;
+    }
+
+    let conn = database::get_connection()?;
+    conn.execute(
+        "UPDATE account SET server_url = ?1 WHERE username = ?2",
+        params![new_url, username],
+    )?;
+    // User and device ids from the old server mean nothing on the new one.
+    database::expire_cached_contact(None)?;
 
     println!(
-        "{} Account '{}' created successfully!",
+        "{} '{}' now uses {}",
         "✓".green().bold(),
-        username.bold()
+        username.bold(),
+        new_url.bold()
     );
-    println!("{}", "You are now logged in.".green());
 
     Ok(())
 }
@@ -78,21 +192,73 @@ pub fn logout() -> Result<()> {
     Ok(())
 }
 
+/// Deregisters the current account from the server, then deletes it and
+/// all its local data.
+pub async fn delete_account(confirm: bool) -> Result<()> {
+    let username = get_current_username()?;
+
+    if !confirm {
+        anyhow::bail!(
+            "This permanently deletes '{}' from the server and this machine. Re-run with --confirm.",
+            username
+        );
+    }
+
+    server::deregister().await?;
+    delete_local_account(&username)?;
+
+    println!(
+        "{} Account '{}' deleted",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    Ok(())
+}
+
+/// Deletes `username` and all its local data, including keys kept in the
+/// OS keychain, without touching the server.
+pub fn delete_local_account(username: &str) -> Result<()> {
+    let in_keychain = stored_key_bundle(username)?.as_str() == KEYCHAIN_MARKER;
+    database::delete_account(username)?;
+
+    // Only once the local data is gone, so a failed deletion keeps the keys.
+    if in_keychain {
+        // Ignore a missing entry: there is nothing left to remove.
+        let _ = keyring::Entry::new(KEYCHAIN_SERVICE, username)?.delete_password();
+    }
+
+    Ok(())
+}
+
 pub fn is_logged_in() -> Result<bool> {
-    let conn = database::get_connection()?;
-    let count: i32 = conn.query_row("SELECT COUNT(*) FROM session WHERE id = 1", [], |row| {
-        row.get(0)
-    })?;
-    Ok(count > 0)
+    Ok(database::current_account().is_ok())
 }
 
 pub fn get_current_username() -> Result<String> {
+    database::current_account()
+}
+
+/// Makes `username` the account used when no `--account` is given.
+pub fn switch_account(username: &str) -> Result<()> {
+    if !list_accounts()?.iter().any(|account| account == username) {
+        anyhow::bail!("Account '{}' not found on this machine.", username);
+    }
+
+    set_session(username)?;
+
+    println!("{} Switched to '{}'", "✓".green().bold(), username.bold());
+
+    Ok(())
+}
+
+pub fn list_accounts() -> Result<Vec<String>> {
     let conn = database::get_connection()?;
-    let username: String =
-        conn.query_row("SELECT username FROM session WHERE id = 1", [], |row| {
-            row.get(0)
-        })?;
-    Ok(username)
+    let mut stmt = conn.prepare("SELECT username FROM account ORDER BY username")?;
+    let accounts = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(accounts)
 }
 
 pub fn get_current_x3dh() -> Result<X3DH> {
@@ -101,18 +267,108 @@ pub fn get_current_x3dh() -> Result<X3DH> {
 }
 
 pub fn load_x3dh(username: &str) -> Result<X3DH> {
+    let key_bundle_str = read_key_bundle(username)?;
+
+    let key_bundle: serde_json::Value = serde_json::from_str(&key_bundle_str)?;
+    let x3dh = X3DH::from_private(key_bundle);
+
+    Ok(x3dh)
+}
+
+/// Service name under which private key bundles are stored in the OS keychain.
+const KEYCHAIN_SERVICE: &str = "dood-cli";
+
+/// Stored in `account.key_bundle` when the real bundle lives in the keychain.
+const KEYCHAIN_MARKER: &str = "@keychain";
+
+/// Reads the private key bundle of `username`, wherever it is stored.
+pub fn read_key_bundle(username: &str) -> Result<Zeroizing<String>> {
+    let stored = stored_key_bundle(username)?;
+
+    if stored.starts_with(lock::PIN_PREFIX) {
+        return lock::unwrap_key_bundle(username, &stored);
+    }
+
+    if stored.as_str() != KEYCHAIN_MARKER {
+        return Ok(stored);
+    }
+
+    let bundle = keyring::Entry::new(KEYCHAIN_SERVICE, username)?
+        .get_password()
+        .context("Failed to read keys from the OS keychain")?;
+
+    Ok(Zeroizing::new(bundle))
+}
+
+/// Raw contents of `account.key_bundle`: a bundle, a keychain marker or a
+/// PIN-wrapped bundle.
+pub fn stored_key_bundle(username: &str) -> Result<Zeroizing<String>> {
     let conn = database::get_connection()?;
 
-    let key_bundle_str: String = conn.query_row(
+    let stored = conn.query_row(
         "SELECT key_bundle FROM account WHERE username = ?1",
         params![username],
-        |row| row.get(0),
+        |row| row.get(0).map(Zeroizing::new),
     )?;
 
-    let key_bundle: serde_json::Value = serde_json::from_str(&key_bundle_str)?;
-    let x3dh = X3DH::from_private(key_bundle);
+    Ok(stored)
+}
 
-    Ok(x3dh)
+pub fn store_key_bundle(username: &str, key_bundle: &str) -> Result<()> {
+    let conn = database::get_connection()?;
+    conn.execute(
+        "UPDATE account SET key_bundle = ?1 WHERE username = ?2",
+        params![key_bundle, username],
+    )?;
+    Ok(())
+}
+
+pub fn migrate_keys(target: &str) -> Result<()> {
+    let username = get_current_username()?;
+
+    if stored_key_bundle(&username)?.starts_with(lock::PIN_PREFIX) {
+        anyhow::bail!("Remove the PIN lock with 'dood lock remove' before migrating keys");
+    }
+
+    let conn = database::get_connection()?;
+    let bundle = read_key_bundle(&username)?;
+    let entry = keyring::Entry::new(KEYCHAIN_SERVICE, &username)?;
+
+    match target {
+        "keychain" => {
+            entry
+                .set_password(&bundle)
+                .context("Failed to store keys in the OS keychain")?;
+            conn.execute(
+                "UPDATE account SET key_bundle = ?1 WHERE username = ?2",
+                params![KEYCHAIN_MARKER, username],
+            )?;
+            println!(
+                "{} Keys for '{}' moved to the OS keychain",
+                "✓".green().bold(),
+                username.bold()
+            );
+        }
+        "database" => {
+            conn.execute(
+                "UPDATE account SET key_bundle = ?1 WHERE username = ?2",
+                params![bundle.as_str(), username],
+            )?;
+            // Ignore a missing entry: the keys were already in the database.
+            let _ = entry.delete_password();
+            println!(
+                "{} Keys for '{}' moved to the local database",
+                "✓".green().bold(),
+                username.bold()
+            );
+        }
+        _ => anyhow::bail!(
+            "Unknown key store '{}'. Use 'keychain' or 'database'.",
+            target
+        ),
+    }
+
+    Ok(())
 }
 
 pub fn get_identity_public_key(x3dh: &X3DH) -> PublicKey {
@@ -126,7 +382,7 @@ pub fn get_identity_public_key(x3dh: &X3DH) -> PublicKey {
 fn save_account(
     username: &str,
     x3dh: &X3DH,
-    private_key_bundle: String,
+    private_key_bundle: Zeroizing<String>,
     server_url: &str,
 ) -> Result<()> {
     let conn = database::get_connection()?;
@@ -147,7 +403,7 @@ fn save_account(
             &[] as &[u8],
             &[] as &[u8],
             &[] as &[u8],
-            private_key_bundle,
+            private_key_bundle.as_str(),
             server_url,
             now,
         ],
@@ -1,9 +1,72 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::Duration;
 use colored::*;
 use rusqlite::params;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use crate::database;
 
+/// Data directory chosen with `--data-dir` for this process.
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+const DATA_DIR_ENV: &str = "DOOD_DATA_DIR";
+
+pub fn set_data_dir(path: &Path) {
+    let _ = DATA_DIR.set(path.to_path_buf());
+}
+
+/// A data directory given with `--data-dir` or `DOOD_DATA_DIR`, which keeps
+/// a separate profile apart from the default one.
+pub fn custom_data_dir() -> Option<PathBuf> {
+    DATA_DIR.get().cloned().or_else(|| {
+        std::env::var_os(DATA_DIR_ENV)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Where the database is kept: a custom data directory if one was given,
+/// otherwise `$XDG_DATA_HOME/dood` on Linux and the platform's equivalent
+/// elsewhere.
+pub fn data_dir() -> PathBuf {
+    let path = custom_data_dir().unwrap_or_else(|| {
+        dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("dood")
+    });
+    std::fs::create_dir_all(&path).ok();
+    path
+}
+
+/// Where files that only matter while running go, such as the unlock cache
+/// and daemon status. A custom data directory keeps its own, so profiles
+/// with the same account name do not share them.
+pub fn runtime_dir() -> PathBuf {
+    match custom_data_dir() {
+        Some(dir) => {
+            let path = dir.join("run");
+            std::fs::create_dir_all(&path).ok();
+            path
+        }
+        None => dirs::runtime_dir().unwrap_or_else(cache_dir),
+    }
+}
+
+/// Where short-lived files go when the platform has no runtime directory:
+/// `$XDG_CACHE_HOME/dood` on Linux.
+fn cache_dir() -> PathBuf {
+    let mut path = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+    path.push("dood");
+    std::fs::create_dir_all(&path).ok();
+    path
+}
+
+pub fn get_db_path() -> PathBuf {
+    data_dir().join("dood.db")
+}
+
 pub fn set_server_url(new_url: &str) -> Result<()> {
     if !new_url.starts_with("http://") && !new_url.starts_with("https://") {
         anyhow::bail!("Invalid URL format. Must start with http:// or https://");
@@ -27,6 +90,15 @@ pub fn set_server_url(new_url: &str) -> Result<()> {
     )?;
 
     println!("{} Server URL set to: {}", "✓".green().bold(), url.bold());
+
+    if is_onion(url) && !get_proxy()?.is_some_and(|proxy| proxy.starts_with("socks5h://")) {
+        println!(
+            "{}",
+            "⚠️  .onion servers need Tor: run 'dood set-server --proxy socks5h://127.0.0.1:9050'"
+                .yellow()
+        );
+    }
+
     println!("{}", "You can now register or login.".bright_black());
 
     Ok(())
@@ -51,6 +123,131 @@ pub fn get_server_url() -> Result<String> {
     }
 }
 
+/// Sets or, with `None`, clears the proxy used for all server connections.
+pub fn set_proxy(proxy: Option<&str>) -> Result<()> {
+    let conn = database::get_connection()?;
+
+    let Some(proxy) = proxy else {
+        conn.execute("DELETE FROM config WHERE key = 'proxy'", [])?;
+        println!("{} Proxy removed", "✓".green().bold());
+        return Ok(());
+    };
+
+    let proxy = proxy.trim_end_matches('/');
+    if !PROXY_SCHEMES
+        .iter()
+        .any(|scheme| proxy.starts_with(&format!("{}://", scheme)))
+    {
+        anyhow::bail!(
+            "Invalid proxy URL. Must start with one of: {}",
+            PROXY_SCHEMES.join("://, ") + "://"
+        );
+    }
+
+    set_setting("proxy", proxy)?;
+    println!("{} Proxy set to: {}", "✓".green().bold(), proxy.bold());
+
+    Ok(())
+}
+
+const PROXY_SCHEMES: [&str; 4] = ["socks5h", "socks5", "http", "https"];
+
+/// The proxy for server connections: `DOOD_PROXY` if set, otherwise the
+/// `proxy` setting.
+pub fn get_proxy() -> Result<Option<String>> {
+    if let Ok(proxy) = std::env::var("DOOD_PROXY") {
+        return Ok(Some(proxy).filter(|proxy| !proxy.is_empty()));
+    }
+
+    get_setting("proxy")
+}
+
+/// Adds headers sent with every server request, e.g. an access token for a
+/// reverse proxy in front of the server. Each is given as `Name: value`.
+pub fn add_headers(headers: &[String]) -> Result<()> {
+    let mut configured = get_extra_headers()?;
+
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("Invalid header '{}'. Use 'Name: value'", header))?;
+        let (name, value) = (name.trim().to_lowercase(), value.trim().to_string());
+
+        reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        reqwest::header::HeaderValue::from_str(&value)
+            .with_context(|| format!("Invalid value for header '{}'", name))?;
+
+        println!("{} Sending header {}", "✓".green().bold(), name.bold());
+        configured.insert(name, value);
+    }
+
+    set_setting("extra_headers", &serde_json::to_string(&configured)?)
+}
+
+pub fn clear_headers() -> Result<()> {
+    let conn = database::get_connection()?;
+    conn.execute("DELETE FROM config WHERE key = 'extra_headers'", [])?;
+    println!("{} Extra headers removed", "✓".green().bold());
+    Ok(())
+}
+
+/// Headers configured with `add_headers`, by lowercase name.
+pub fn get_extra_headers() -> Result<BTreeMap<String, String>> {
+    match get_setting("extra_headers")? {
+        Some(headers) => Ok(serde_json::from_str(&headers)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Sets or, with `None`, clears how many days messages are kept by default.
+pub fn set_retention_days(days: Option<u32>) -> Result<()> {
+    match days.filter(|days| *days > 0) {
+        Some(days) => set_setting("retention_days", &days.to_string()),
+        None => {
+            let conn = database::get_connection()?;
+            conn.execute("DELETE FROM config WHERE key = 'retention_days'", [])?;
+            Ok(())
+        }
+    }
+}
+
+/// How many days messages are kept unless a conversation says otherwise;
+/// `None` keeps them forever.
+pub fn get_retention_days() -> Result<Option<u32>> {
+    get_setting("retention_days")?
+        .map(|days| {
+            days.parse()
+                .with_context(|| format!("Invalid retention_days setting '{}'", days))
+        })
+        .transpose()
+}
+
+/// Default for how long messages stay in the trash.
+const DEFAULT_TRASH_PERIOD: &str = "30d";
+
+/// Sets how long messages stay in the trash before being deleted for good,
+/// e.g. `30d`; `0` keeps them until the trash is emptied.
+pub fn set_trash_period(duration: &str) -> Result<()> {
+    parse_duration(duration)?;
+    set_setting("trash_period", duration.trim())
+}
+
+/// How long messages stay in the trash; `None` keeps them until it is
+/// emptied.
+pub fn get_trash_period() -> Result<Option<Duration>> {
+    let setting = get_setting("trash_period")?;
+    let period = parse_duration(setting.as_deref().unwrap_or(DEFAULT_TRASH_PERIOD))?;
+    Ok((period > Duration::zero()).then_some(period))
+}
+
+fn is_onion(url: &str) -> bool {
+    url.split("://")
+        .nth(1)
+        .and_then(|rest| rest.split(['/', ':']).next())
+        .is_some_and(|host| host.ends_with(".onion"))
+}
+
 pub fn is_server_configured() -> Result<bool> {
     let conn = database::get_connection()?;
 
@@ -72,3 +269,54 @@ pub fn is_server_configured() -> Result<bool> {
 
     Ok(url_exists)
 }
+
+pub fn get_setting(key: &str) -> Result<Option<String>> {
+    let conn = database::get_connection()?;
+
+    let value = conn
+        .query_row(
+            "SELECT value FROM config WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .ok();
+
+    Ok(value)
+}
+
+pub fn set_setting(key: &str, value: &str) -> Result<()> {
+    let conn = database::get_connection()?;
+
+    conn.execute(
+        "INSERT OR REPLACE INTO config (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_setting(key: &str) -> Result<()> {
+    let conn = database::get_connection()?;
+    conn.execute("DELETE FROM config WHERE key = ?1", params![key])?;
+    Ok(())
+}
+
+/// Parses durations such as `30m`, `8h`, `2d` or `1w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration '{}'. Use e.g. 30m, 8h, 2d", s))?;
+
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" | "" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        "w" => Ok(Duration::weeks(amount)),
+        _ => anyhow::bail!("Invalid duration '{}'. Use e.g. 30m, 8h, 2d", s),
+    }
+}
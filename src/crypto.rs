@@ -1,27 +1,48 @@
 use anyhow::{Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
 use colored::*;
-use dood_encryption::x3dh::X3DH;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use dialoguer::{Confirm, Input, Password};
+use dood_encryption::x3dh::{X3DHKeyBundle, X3DH};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
 use std::fs;
+use std::ops::RangeInclusive;
 use std::path::Path;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::{auth, config, database};
+use crate::{auth, config, database, messages};
 
 pub fn export_keys(output_path: &str) -> Result<()> {
     let username = auth::get_current_username()?;
-    let conn = database::get_connection()?;
+    let key_bundle = auth::read_key_bundle(&username)?;
 
-    let key_bundle: String = conn.query_row(
-        "SELECT key_bundle FROM account WHERE username = ?1",
-        rusqlite::params![username],
-        |row| row.get(0),
-    )?;
+    let passphrase: Zeroizing<String> = Password::new()
+        .with_prompt("Export passphrase")
+        .with_confirmation("Confirm passphrase", "Passphrases do not match")
+        .interact()?
+        .into();
 
-    let export_data = serde_json::json!({
-        "username": username,
-        "key_bundle": key_bundle,
-        "version": "1.0",
-        "exported_at": chrono::Utc::now().to_rfc3339(),
-    });
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase must not be empty");
+    }
+
+    let secret = Zeroizing::new(serde_json::to_vec(
+        &SecretJson(serde_json::json!({
+            "username": username,
+            "key_bundle": key_bundle.as_str(),
+        }))
+        .0,
+    )?);
+
+    let export_data = encrypt_export(&secret, &passphrase)?;
 
     let json_str = serde_json::to_string_pretty(&export_data)?;
     fs::write(output_path, json_str)?;
@@ -33,20 +54,351 @@ pub fn export_keys(output_path: &str) -> Result<()> {
     );
     println!(
         "{}",
-        "⚠️  Keep this file secure! Anyone with access can read your messages.".yellow()
+        "⚠️  Keep this file and its passphrase safe! Together they give access to your account."
+            .yellow()
     );
 
     Ok(())
 }
 
-pub fn import_keys(input_path: &str) -> Result<()> {
+const EXPORT_VERSION: &str = "2.0";
+
+/// Argon2id parameters for export files: 64 MiB, 3 passes, 1 lane.
+const ARGON2_M_COST: u32 = 64 * 1024;
+const ARGON2_T_COST: u32 = 3;
+const ARGON2_P_COST: u32 = 1;
+
+/// Costs accepted when reading a file back. The parameters are not
+/// authenticated before the key is derived, so anything weaker than what
+/// `encrypt_export` writes is refused, and so is anything costly enough to
+/// make opening the file hang: at most 1 GiB, 10 passes and 8 lanes.
+const ARGON2_M_COST_RANGE: RangeInclusive<u32> = ARGON2_M_COST..=1024 * 1024;
+const ARGON2_T_COST_RANGE: RangeInclusive<u32> = ARGON2_T_COST..=10;
+const ARGON2_P_COST_RANGE: RangeInclusive<u32> = ARGON2_P_COST..=8;
+
+fn derive_export_key(
+    passphrase: &str,
+    salt: &[u8],
+    m: u32,
+    t: u32,
+    p: u32,
+) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(m, t, p, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {}", e))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+pub fn encrypt_export(plaintext: &[u8], passphrase: &str) -> Result<serde_json::Value> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let key = derive_export_key(
+        passphrase,
+        &salt,
+        ARGON2_M_COST,
+        ARGON2_T_COST,
+        ARGON2_P_COST,
+    )?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_slice()));
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt export"))?;
+
+    Ok(serde_json::json!({
+        "version": EXPORT_VERSION,
+        "encrypted": true,
+        "kdf": {
+            "algorithm": "argon2id",
+            "salt": BASE64_STANDARD.encode(salt),
+            "m_cost": ARGON2_M_COST,
+            "t_cost": ARGON2_T_COST,
+            "p_cost": ARGON2_P_COST,
+        },
+        "cipher": "xchacha20poly1305",
+        "nonce": BASE64_STANDARD.encode(nonce),
+        "ciphertext": BASE64_STANDARD.encode(ciphertext),
+        "exported_at": chrono::Utc::now().to_rfc3339(),
+    }))
+}
+
+const BACKUP_CONTENTS: &str = "full-backup";
+
+/// Backups hold a SQLCipher database keyed with the passphrase key itself.
+const SNAPSHOT_CIPHER: &str = "sqlcipher";
+
+/// Writes a passphrase-encrypted archive of the whole local state: accounts
+/// and their keys, ratchet sessions, contacts and message history.
+pub fn backup(output_path: &str) -> Result<()> {
+    let passphrase: Zeroizing<String> = Password::new()
+        .with_prompt("Backup passphrase")
+        .with_confirmation("Confirm passphrase", "Passphrases do not match")
+        .interact()?
+        .into();
+
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase must not be empty");
+    }
+
+    println!("{}", "📦 Creating backup...".cyan());
+
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let key = derive_export_key(
+        &passphrase,
+        &salt,
+        ARGON2_M_COST,
+        ARGON2_T_COST,
+        ARGON2_P_COST,
+    )?;
+
+    // The snapshot is encrypted with the passphrase key as it is written, so
+    // the database never lands on disk in plaintext.
+    let snapshot_path = config::get_db_path().with_extension("db.backup");
+    database::encrypted_snapshot_to(&snapshot_path, &key)?;
+
+    let result = (|| -> Result<Vec<u8>> {
+        // Keys kept in the OS keychain would not survive a move to another
+        // machine, so the snapshot always carries them inline.
+        let conn = database::open_snapshot(&snapshot_path, &key)?;
+        let mut stmt = conn.prepare("SELECT username FROM account")?;
+        let usernames = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for username in usernames {
+            let key_bundle = auth::read_key_bundle(&username)?;
+            conn.execute(
+                "UPDATE account SET key_bundle = ?1 WHERE username = ?2",
+                rusqlite::params![key_bundle.as_str(), username],
+            )?;
+        }
+        drop(stmt);
+        drop(conn);
+
+        Ok(fs::read(&snapshot_path)?)
+    })();
+
+    fs::remove_file(&snapshot_path).ok();
+    let snapshot = result?;
+
+    let backup_data = serde_json::json!({
+        "version": EXPORT_VERSION,
+        "encrypted": true,
+        "contents": BACKUP_CONTENTS,
+        "kdf": {
+            "algorithm": "argon2id",
+            "salt": BASE64_STANDARD.encode(salt),
+            "m_cost": ARGON2_M_COST,
+            "t_cost": ARGON2_T_COST,
+            "p_cost": ARGON2_P_COST,
+        },
+        "cipher": SNAPSHOT_CIPHER,
+        "database": BASE64_STANDARD.encode(snapshot),
+        "exported_at": chrono::Utc::now().to_rfc3339(),
+    });
+
+    fs::write(output_path, serde_json::to_string(&backup_data)?)?;
+
+    println!(
+        "{} Backup written to {}",
+        "✓".green().bold(),
+        output_path.bold()
+    );
+    println!(
+        "{}",
+        "⚠️  Keep this file and its passphrase safe! Together they give access to your account."
+            .yellow()
+    );
+
+    Ok(())
+}
+
+pub fn restore(input_path: &str, force: bool) -> Result<()> {
     if !Path::new(input_path).exists() {
         anyhow::bail!("File not found: {}", input_path);
     }
 
-    let json_str = fs::read_to_string(input_path)?;
-    let import_data: serde_json::Value = serde_json::from_str(&json_str)?;
+    let backup_data: serde_json::Value = serde_json::from_str(&fs::read_to_string(input_path)?)?;
+    if backup_data["contents"].as_str() != Some(BACKUP_CONTENTS) {
+        anyhow::bail!("'{}' is not a DooD backup file", input_path);
+    }
+
+    if database::has_accounts()? && !force {
+        anyhow::bail!(
+            "This machine already has DooD accounts. Re-run with --force to replace all local data."
+        );
+    }
+
+    let passphrase: Zeroizing<String> = Password::new()
+        .with_prompt("Backup passphrase")
+        .interact()?
+        .into();
+
+    if backup_data["cipher"].as_str() == Some(SNAPSHOT_CIPHER) {
+        let key = derive_envelope_key(&backup_data, &passphrase)?;
+        let snapshot = BASE64_STANDARD.decode(
+            backup_data["database"]
+                .as_str()
+                .context("Invalid backup file: missing database")?,
+        )?;
+        database::restore_snapshot(&snapshot, &key)?;
+    } else {
+        // Backups written before snapshots were encrypted by SQLCipher.
+        let contents = decrypt_payload(&backup_data, &passphrase)?;
+        database::replace_with(&contents)?;
+    }
+    database::init()?;
+
+    println!("{} Backup restored", "✓".green().bold());
+    println!(
+        "{}",
+        "Log in with 'dood login --username <name>' to continue.".bright_black()
+    );
+
+    Ok(())
+}
+
+fn decrypt_export(export: &serde_json::Value, passphrase: &str) -> Result<SecretJson> {
+    let plaintext = decrypt_payload(export, passphrase)?;
+    Ok(SecretJson(serde_json::from_slice(&plaintext)?))
+}
+
+/// A JSON document holding key material. serde_json values cannot be kept in
+/// `Zeroizing`, so every string in the document is wiped when it is dropped.
+pub struct SecretJson(pub serde_json::Value);
+
+impl Drop for SecretJson {
+    fn drop(&mut self) {
+        wipe_json(&mut self.0);
+    }
+}
+
+fn wipe_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => text.zeroize(),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(wipe_json),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(wipe_json),
+        _ => {}
+    }
+}
+
+fn decrypt_payload(export: &serde_json::Value, passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let key = derive_envelope_key(export, passphrase)?;
+    open_envelope(export, &key)
+}
+
+/// Derives the key of a passphrase-encrypted envelope, as written by
+/// `encrypt_export` or `backup`, from its stored KDF parameters.
+pub fn derive_envelope_key(
+    envelope: &serde_json::Value,
+    passphrase: &str,
+) -> Result<Zeroizing<[u8; 32]>> {
+    let kdf = &envelope["kdf"];
+    if kdf["algorithm"].as_str() != Some("argon2id")
+        || !matches!(
+            envelope["cipher"].as_str(),
+            Some("xchacha20poly1305" | SNAPSHOT_CIPHER)
+        )
+    {
+        anyhow::bail!("Unsupported export file encryption");
+    }
+
+    let cost = |name: &str, allowed: RangeInclusive<u32>| -> Result<u32> {
+        let value = kdf[name]
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .with_context(|| format!("Invalid export file: missing {}", name))?;
+        if !allowed.contains(&value) {
+            anyhow::bail!(
+                "Invalid export file: {} is {}, outside {}..={}",
+                name,
+                value,
+                allowed.start(),
+                allowed.end()
+            );
+        }
+        Ok(value)
+    };
+
+    let salt = BASE64_STANDARD.decode(
+        kdf["salt"]
+            .as_str()
+            .context("Invalid export file: missing salt")?,
+    )?;
+
+    derive_export_key(
+        passphrase,
+        &salt,
+        cost("m_cost", ARGON2_M_COST_RANGE)?,
+        cost("t_cost", ARGON2_T_COST_RANGE)?,
+        cost("p_cost", ARGON2_P_COST_RANGE)?,
+    )
+}
+
+/// Decrypts an envelope with an already derived key.
+pub fn open_envelope(envelope: &serde_json::Value, key: &[u8; 32]) -> Result<Zeroizing<Vec<u8>>> {
+    let nonce = BASE64_STANDARD.decode(
+        envelope["nonce"]
+            .as_str()
+            .context("Invalid export file: missing nonce")?,
+    )?;
+    let ciphertext = BASE64_STANDARD.decode(
+        envelope["ciphertext"]
+            .as_str()
+            .context("Invalid export file: missing ciphertext")?,
+    )?;
+
+    if nonce.len() != 24 {
+        anyhow::bail!("Invalid export file: bad nonce length");
+    }
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map(Zeroizing::new)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt file. Is the passphrase correct?"))?;
+
+    Ok(plaintext)
+}
+
+pub fn import_keys(input_path: &str, insecure_plaintext: bool) -> Result<()> {
+    if !Path::new(input_path).exists() {
+        anyhow::bail!("File not found: {}", input_path);
+    }
+
+    let json_str = Zeroizing::new(fs::read_to_string(input_path)?);
+    let file_data = SecretJson(serde_json::from_str(&json_str)?);
+
+    let import_data = if file_data.0["encrypted"].as_bool() == Some(true) {
+        let passphrase: Zeroizing<String> = Password::new()
+            .with_prompt("Export passphrase")
+            .interact()?
+            .into();
+        decrypt_export(&file_data.0, &passphrase)?
+    } else if insecure_plaintext {
+        println!("{}", "⚠️  Importing an unencrypted legacy export.".yellow());
+        file_data
+    } else {
+        anyhow::bail!(
+            "'{}' is an unencrypted legacy export. Re-run with --insecure-plaintext to import it.",
+            input_path
+        );
+    };
 
+    install_account(&import_data.0)
+}
+
+/// Creates a local account from decrypted export data: `username` and the
+/// private `key_bundle`.
+fn install_account(import_data: &serde_json::Value) -> Result<()> {
     let username = import_data["username"]
         .as_str()
         .context("Invalid export file: missing username")?;
@@ -104,3 +456,369 @@ pub fn import_keys(input_path: &str) -> Result<()> {
 
     Ok(())
 }
+
+const TRANSFER_CODE_PREFIX: &str = "dood-transfer:v1:";
+const TRANSFER_BLOB_PREFIX: &str = "dood-keys:v1:";
+
+/// Runs on the new machine: shows an ephemeral public key for the old
+/// machine to encrypt to, then installs the keys pasted back from it.
+pub fn receive_keys() -> Result<()> {
+    let import_data = receive_key_data("dood transfer-keys --to-device")?;
+    install_account(&import_data.0)
+}
+
+/// Shows a transfer code, waits for the blob produced by `transfer_keys` on
+/// the other machine (which runs `sender_command`) and decrypts it.
+pub fn receive_key_data(sender_command: &str) -> Result<SecretJson> {
+    let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let public = PublicKey::from(&secret);
+    let code = format!(
+        "{}{}",
+        TRANSFER_CODE_PREFIX,
+        BASE64_STANDARD.encode(public.as_bytes())
+    );
+
+    println!("\n{}", "📲 Receive keys from another device".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!(
+        "On your old machine run '{}' and paste or scan:\n",
+        sender_command
+    );
+    println!("{}", render_qr(&code)?);
+    println!("  {}\n", code.bold());
+
+    let blob: String = Input::new()
+        .with_prompt("Transfer blob from the old machine")
+        .interact_text()?;
+    let blob = blob
+        .trim()
+        .strip_prefix(TRANSFER_BLOB_PREFIX)
+        .context("Not a DooD key transfer blob")?;
+    let blob = BASE64_STANDARD.decode(blob)?;
+
+    if blob.len() < 32 + 24 {
+        anyhow::bail!("Transfer blob is too short");
+    }
+    let (sender_public, rest) = blob.split_at(32);
+    let (nonce, ciphertext) = rest.split_at(24);
+    let sender_public: [u8; 32] = sender_public.try_into()?;
+    let sender_public = PublicKey::from(sender_public);
+
+    let key = transfer_key(secret, &sender_public, &sender_public, &public);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_slice()));
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map(Zeroizing::new)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt the transfer blob"))?;
+
+    let matches = Confirm::new()
+        .with_prompt(format!(
+            "Does the old machine show the code {}?",
+            short_auth_code(&sender_public, &public)
+        ))
+        .default(false)
+        .interact()?;
+    if !matches {
+        anyhow::bail!("Codes do not match. The transfer may have been tampered with.");
+    }
+
+    Ok(SecretJson(serde_json::from_slice(&plaintext)?))
+}
+
+/// Runs on the old machine: encrypts the current account's keys to the code
+/// shown by `receive_keys`.
+pub fn transfer_keys(code: &str) -> Result<()> {
+    let code = if code.is_empty() {
+        Input::new()
+            .with_prompt("Code shown on the new device")
+            .interact_text()?
+    } else {
+        code.to_string()
+    };
+
+    let receiver_public: [u8; 32] = BASE64_STANDARD
+        .decode(
+            code.trim()
+                .strip_prefix(TRANSFER_CODE_PREFIX)
+                .context("Not a DooD transfer code")?,
+        )?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid transfer code"))?;
+    let receiver_public = PublicKey::from(receiver_public);
+
+    let username = auth::get_current_username()?;
+    let key_bundle = auth::read_key_bundle(&username)?;
+    let secret = Zeroizing::new(serde_json::to_vec(
+        &SecretJson(serde_json::json!({
+            "username": username,
+            "key_bundle": key_bundle.as_str(),
+        }))
+        .0,
+    )?);
+
+    let ephemeral = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let key = transfer_key(
+        ephemeral,
+        &receiver_public,
+        &ephemeral_public,
+        &receiver_public,
+    );
+
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_slice()));
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), secret.as_slice())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt keys"))?;
+
+    let mut blob = ephemeral_public.as_bytes().to_vec();
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+
+    println!("\nPaste this on the new machine:\n");
+    println!("{}{}\n", TRANSFER_BLOB_PREFIX, BASE64_STANDARD.encode(blob));
+    println!(
+        "The new machine must show the code {}",
+        short_auth_code(&ephemeral_public, &receiver_public).bold()
+    );
+    println!(
+        "{}",
+        "Only the device that showed the code can decrypt it.".bright_black()
+    );
+
+    Ok(())
+}
+
+/// Six digits both machines show so a swapped code or blob is noticed.
+fn short_auth_code(sender: &PublicKey, receiver: &PublicKey) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"dood-key-transfer-sas");
+    hasher.update(sender.as_bytes());
+    hasher.update(receiver.as_bytes());
+    let digest = hasher.finalize();
+
+    let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", value / 1000, value % 1000)
+}
+
+/// Key for a transfer, bound to both ephemeral public keys.
+fn transfer_key(
+    secret: EphemeralSecret,
+    peer: &PublicKey,
+    sender: &PublicKey,
+    receiver: &PublicKey,
+) -> Zeroizing<[u8; 32]> {
+    let shared = secret.diffie_hellman(peer);
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"dood-key-transfer");
+    hasher.update(shared.as_bytes());
+    hasher.update(sender.as_bytes());
+    hasher.update(receiver.as_bytes());
+
+    Zeroizing::new(hasher.finalize().into())
+}
+
+/// Number of SHA-512 iterations used to stretch each fingerprint half.
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Derives a 30-digit fingerprint from a user's identity key.
+fn fingerprint_digits(username: &str, identity_key: &[u8]) -> String {
+    let mut hash = Sha512::new()
+        .chain_update(0u16.to_be_bytes())
+        .chain_update(identity_key)
+        .chain_update(username.as_bytes())
+        .finalize();
+
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(identity_key)
+            .finalize();
+    }
+
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// The safety number is the same on both sides: both halves are sorted before
+/// being joined.
+pub fn safety_number(
+    local_username: &str,
+    local_key: &[u8],
+    remote_username: &str,
+    remote_key: &[u8],
+) -> String {
+    let mut halves = [
+        fingerprint_digits(local_username, local_key),
+        fingerprint_digits(remote_username, remote_key),
+    ];
+    halves.sort();
+    halves.concat()
+}
+
+/// Checks that a bundle's signed prekey was signed by its identity key.
+///
+/// Identity keys are X25519 keys, so the signature is verified XEdDSA-style:
+/// the key is mapped to its Edwards form with a positive sign bit and the
+/// signature checked as Ed25519 over the raw signed prekey bytes.
+pub fn verify_signed_pre_key(bundle: &X3DHKeyBundle) -> Result<()> {
+    let edwards = MontgomeryPoint(bundle.identity_key)
+        .to_edwards(0)
+        .context("Identity key is not a valid curve point")?;
+    let verifying_key = VerifyingKey::from_bytes(&edwards.compress().to_bytes())
+        .map_err(|_| anyhow::anyhow!("Identity key is not a valid signing key"))?;
+    let signature = Signature::from_bytes(&bundle.signed_pre_key_signature);
+
+    verifying_key
+        .verify(bundle.signed_pre_key.as_bytes(), &signature)
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Signed prekey signature is invalid. The server may have tampered with this key bundle."
+            )
+        })
+}
+
+/// Formats key bytes as lines of space-separated hex groups for display.
+pub fn format_fingerprint(key: &[u8]) -> Vec<String> {
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    let groups: Vec<&str> = hex
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap_or_default())
+        .collect();
+
+    groups.chunks(8).map(|line| line.join(" ")).collect()
+}
+
+const FINGERPRINT_PAYLOAD_PREFIX: &str = "dood-verify:v1";
+
+/// Text encoded in fingerprint QR codes: the owner's username and identity key.
+pub fn fingerprint_payload(username: &str, identity_key: &[u8]) -> String {
+    format!(
+        "{}:{}:{}",
+        FINGERPRINT_PAYLOAD_PREFIX,
+        username,
+        BASE64_STANDARD.encode(identity_key)
+    )
+}
+
+fn parse_fingerprint_payload(payload: &str) -> Result<(String, Vec<u8>)> {
+    let rest = payload
+        .trim()
+        .strip_prefix(FINGERPRINT_PAYLOAD_PREFIX)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .context("Not a DooD fingerprint code")?;
+
+    let (username, key_b64) = rest
+        .rsplit_once(':')
+        .context("Malformed fingerprint code")?;
+
+    Ok((username.to_string(), BASE64_STANDARD.decode(key_b64)?))
+}
+
+pub fn render_qr(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Failed to build QR code: {}", e))?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+pub async fn verify_contact(username: &str, show_qr: bool, scanned: Option<&str>) -> Result<()> {
+    let local_username = auth::get_current_username()?;
+    let local_key = auth::get_identity_public_key(&auth::get_current_x3dh()?).to_bytes();
+    let remote_key = messages::fetch_identity_key(username).await?;
+
+    if let Some(scanned) = scanned {
+        let (scanned_username, scanned_key) = parse_fingerprint_payload(scanned)?;
+
+        if scanned_username != username || scanned_key != remote_key {
+            database::clear_verified(username)?;
+            anyhow::bail!(
+                "Scanned code does not match the identity key the server has for '{}'",
+                username
+            );
+        }
+
+        database::set_verified(username, &remote_key)?;
+        println!(
+            "{} Code matches. Marked {} as verified",
+            "✔".green().bold(),
+            username.bold()
+        );
+        return Ok(());
+    }
+
+    let number = safety_number(&local_username, &local_key, username, &remote_key);
+
+    println!(
+        "\n{} {}",
+        "🔏 Safety number with".bold().cyan(),
+        username.bold()
+    );
+    println!("{}", "─".repeat(60).bright_black());
+
+    for row in number.as_bytes().chunks(20) {
+        let groups: Vec<&str> = row
+            .chunks(5)
+            .map(|group| std::str::from_utf8(group).unwrap_or_default())
+            .collect();
+        println!("   {}", groups.join("  ").bold());
+    }
+
+    if show_qr {
+        println!();
+        println!(
+            "{}",
+            format!("Expected code for {}:", username).bright_black()
+        );
+        println!(
+            "{}",
+            render_qr(&fingerprint_payload(username, &remote_key))?
+        );
+    }
+
+    println!();
+    println!(
+        "{}",
+        format!(
+            "Compare this number with {} in person or over a trusted channel.",
+            username
+        )
+        .bright_black()
+    );
+
+    if database::is_verified(username)? {
+        println!("{} {} is already verified", "✔".green().bold(), username);
+        return Ok(());
+    }
+
+    let confirmed = Confirm::new()
+        .with_prompt("Does the number match?")
+        .default(false)
+        .interact()?;
+
+    if confirmed {
+        database::set_verified(username, &remote_key)?;
+        println!(
+            "{} Marked {} as verified",
+            "✔".green().bold(),
+            username.bold()
+        );
+    } else {
+        database::clear_verified(username)?;
+        println!("{}", "Contact not verified.".yellow());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use colored::*;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use crate::{auth, config, database, messages, output, server, ui};
+
+/// How often a running daemon refreshes its status file. A status older than
+/// two heartbeats means the daemon is gone.
+const HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Longest wait before reconnecting after the stream or a poll fails.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// Most received messages waiting for a handler. Once this many are queued,
+/// the daemon stops receiving until handlers catch up.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+/// What a running daemon publishes for other `dood` invocations.
+#[derive(Serialize, Deserialize)]
+struct Status {
+    pid: u32,
+    started_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    /// `stream` while connected over WebSocket, otherwise `polling`.
+    mode: String,
+    messages_received: usize,
+    last_message_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+/// Stays connected and stores incoming messages until interrupted, so
+/// `chats` and `history` are current without fetching by hand. Uses the
+/// WebSocket stream and falls back to long-polling when it is unavailable.
+/// With a `handler` command, every received message is also handed to it,
+/// running at most `workers` of them at once.
+pub async fn run(handler: Option<String>, workers: usize) -> Result<()> {
+    let username = auth::get_current_username()?;
+    let mut handlers = handler.map(|command| Handlers::new(command, workers));
+
+    if is_running(&username) {
+        anyhow::bail!("A daemon is already running for '{}'", username);
+    }
+
+    let mut status = Status {
+        pid: std::process::id(),
+        started_at: Utc::now(),
+        updated_at: Utc::now(),
+        mode: "polling".to_string(),
+        messages_received: 0,
+        last_message_at: None,
+        last_error: None,
+    };
+    write_status(&username, &mut status)?;
+
+    println!(
+        "{} Daemon running for '{}'. Press Ctrl+C to stop.",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    let result = tokio::select! {
+        result = receive_loop(&username, &mut status, &mut handlers) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    let _ = fs::remove_file(status_path(&username));
+    println!("{}", "Daemon stopped.".bright_black());
+
+    result
+}
+
+async fn receive_loop(
+    username: &str,
+    status: &mut Status,
+    handlers: &mut Option<Handlers>,
+) -> Result<()> {
+    let (interval, jitter) = messages::poll_timing(None, None)?;
+    let mut reconnect_delay = Duration::from_secs(1);
+
+    loop {
+        enforce_retention(username, status)?;
+
+        // Catch up on anything that arrived while disconnected.
+        match messages::fetch_pending(None).await {
+            Ok(count) => received(username, status, handlers, count).await?,
+            Err(e) => record_error(username, status, &e)?,
+        }
+
+        match server::open_message_stream().await {
+            Ok(mut stream) => {
+                status.mode = "stream".to_string();
+                status.last_error = None;
+                write_status(username, status)?;
+                reconnect_delay = Duration::from_secs(1);
+
+                let mut heartbeat = tokio::time::interval(HEARTBEAT);
+                loop {
+                    tokio::select! {
+                        frame = stream.next() => match frame {
+                            Some(Ok(frame)) => {
+                                match messages::handle_stream_frame(username, frame).await {
+                                    Ok(count) => received(username, status, handlers, count).await?,
+                                    Err(e) => record_error(username, status, &e)?,
+                                }
+                            }
+                            Some(Err(e)) => {
+                                record_error(username, status, &anyhow::Error::from(e))?;
+                                break;
+                            }
+                            None => break,
+                        },
+                        _ = heartbeat.tick() => {
+                            enforce_retention(username, status)?;
+                            write_status(username, status)?;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                status.mode = "polling".to_string();
+                record_error(username, status, &e)?;
+
+                // Long-poll until the next attempt to open the stream.
+                let until = tokio::time::Instant::now() + MAX_RECONNECT_DELAY;
+                while tokio::time::Instant::now() < until {
+                    match messages::fetch_pending(Some(interval)).await {
+                        Ok(count) => received(username, status, handlers, count).await?,
+                        Err(e) => {
+                            record_error(username, status, &e)?;
+                            break;
+                        }
+                    }
+                    tokio::time::sleep(messages::random_jitter(jitter)).await;
+                }
+            }
+        }
+
+        tokio::time::sleep(reconnect_delay + messages::random_jitter(jitter)).await;
+        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Records `count` newly stored messages and hands them to the handlers.
+async fn received(
+    username: &str,
+    status: &mut Status,
+    handlers: &mut Option<Handlers>,
+    count: usize,
+) -> Result<()> {
+    record_messages(username, status, count)?;
+
+    match handlers {
+        Some(handlers) if count > 0 => handlers.dispatch().await,
+        _ => Ok(()),
+    }
+}
+
+/// One sender's messages waiting for the handler, each holding its place in
+/// the overall queue.
+type Queue = mpsc::UnboundedSender<(output::MessageEntry, OwnedSemaphorePermit)>;
+
+/// Runs the handler command for received messages. Messages from the same
+/// sender are handled one at a time and in order; different senders are
+/// handled concurrently, up to the worker limit.
+struct Handlers {
+    command: Arc<String>,
+    workers: Arc<Semaphore>,
+    queued: Arc<Semaphore>,
+    senders: HashMap<String, Queue>,
+    /// The last message handed out, so each is handled once.
+    since: DateTime<Utc>,
+    last_id: i64,
+}
+
+impl Handlers {
+    fn new(command: String, workers: usize) -> Self {
+        Handlers {
+            command: Arc::new(command),
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            queued: Arc::new(Semaphore::new(MAX_QUEUED_MESSAGES)),
+            senders: HashMap::new(),
+            since: Utc::now(),
+            last_id: 0,
+        }
+    }
+
+    /// Queues messages stored since the last call. Waits while the queue is
+    /// full, which holds up receiving.
+    async fn dispatch(&mut self) -> Result<()> {
+        for message in database::get_received_since(self.since)? {
+            if message.id <= self.last_id {
+                continue;
+            }
+            self.last_id = message.id;
+            self.since = message.received_at.unwrap_or(message.timestamp);
+
+            let permit = Arc::clone(&self.queued).acquire_owned().await?;
+            let queue = self
+                .senders
+                .entry(message.sender.clone())
+                .or_insert_with(|| spawn_queue(&self.command, &self.workers));
+            queue
+                .send(((&message).into(), permit))
+                .map_err(|_| anyhow::anyhow!("A message handler queue stopped"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Starts the task that handles one sender's messages in order.
+fn spawn_queue(command: &Arc<String>, workers: &Arc<Semaphore>) -> Queue {
+    let (queue, mut messages) = mpsc::unbounded_channel();
+    let command = Arc::clone(command);
+    let workers = Arc::clone(workers);
+
+    tokio::spawn(async move {
+        while let Some((message, _queued)) = messages.recv().await {
+            let Ok(_worker) = workers.acquire().await else {
+                return;
+            };
+            if let Err(e) = run_handler(&command, &message).await {
+                eprintln!(
+                    "{} Handler failed for message {} from {}: {}",
+                    "✗".red(),
+                    message.id,
+                    message.sender,
+                    e
+                );
+            }
+        }
+    });
+
+    queue
+}
+
+/// Runs `command` through the shell with the message as JSON on stdin and
+/// its sender and id in `DOOD_SENDER` and `DOOD_MESSAGE_ID`.
+async fn run_handler(command: &str, message: &output::MessageEntry) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let mut child = shell
+        .arg(command)
+        .env("DOOD_SENDER", &message.sender)
+        .env("DOOD_MESSAGE_ID", message.id.to_string())
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Could not start the handler")?;
+
+    // Handlers that do not read the message may close stdin early.
+    if let Some(mut stdin) = child.stdin.take() {
+        let json = serde_json::to_string(message)?;
+        stdin.write_all(json.as_bytes()).await.ok();
+    }
+
+    let exit = child.wait().await?;
+    if !exit.success() {
+        anyhow::bail!("the handler exited with {}", exit);
+    }
+
+    Ok(())
+}
+
+fn record_messages(username: &str, status: &mut Status, count: usize) -> Result<()> {
+    if count > 0 {
+        status.messages_received += count;
+        status.last_message_at = Some(Utc::now());
+    }
+    write_status(username, status)
+}
+
+/// Long-running daemons delete expired messages as they go, not only when
+/// started.
+fn enforce_retention(username: &str, status: &mut Status) -> Result<()> {
+    match ui::enforce_retention(false) {
+        Ok(()) => Ok(()),
+        Err(e) => record_error(username, status, &e),
+    }
+}
+
+fn record_error(username: &str, status: &mut Status, error: &anyhow::Error) -> Result<()> {
+    eprintln!("{} {}", "Error:".red(), error);
+    status.last_error = Some(error.to_string());
+    write_status(username, status)
+}
+
+pub fn display_status() -> Result<()> {
+    let username = auth::get_current_username()?;
+
+    let Some(status) = read_status(&username).filter(is_fresh) else {
+        println!(
+            "{}",
+            format!("No daemon is running for '{}'.", username).yellow()
+        );
+        return Ok(());
+    };
+
+    println!("\n{}", "🛰️  Daemon".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!("{} {}", "Account:".bold(), username);
+    println!("{} {}", "PID:".bold(), status.pid);
+    println!(
+        "{} {}",
+        "Running since:".bold(),
+        status
+            .started_at
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+    );
+    println!("{} {}", "Mode:".bold(), status.mode);
+    println!(
+        "{} {}",
+        "Messages received:".bold(),
+        status.messages_received
+    );
+    if let Some(last_message_at) = status.last_message_at {
+        println!(
+            "{} {}",
+            "Last message:".bold(),
+            last_message_at
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    if let Some(error) = &status.last_error {
+        println!("{} {}", "Last error:".bold(), error.red());
+    }
+    println!();
+
+    Ok(())
+}
+
+/// Whether a daemon is receiving messages for `username`. Other commands that
+/// receive messages must not run alongside it, or both would advance the same
+/// ratchets.
+pub fn is_running(username: &str) -> bool {
+    read_status(username).filter(is_fresh).is_some()
+}
+
+/// Fails if a daemon already receives messages for the current account.
+pub fn ensure_not_running() -> Result<()> {
+    let username = auth::get_current_username()?;
+    if is_running(&username) {
+        anyhow::bail!(
+            "The daemon is already receiving messages for '{}'. Run 'dood chats' to see them.",
+            username
+        );
+    }
+    Ok(())
+}
+
+fn is_fresh(status: &Status) -> bool {
+    let age = Utc::now().signed_duration_since(status.updated_at);
+    age.to_std().map_or(true, |age| age < HEARTBEAT * 2)
+}
+
+fn status_path(username: &str) -> PathBuf {
+    config::runtime_dir().join(format!("dood-daemon-{}.json", username))
+}
+
+fn read_status(username: &str) -> Option<Status> {
+    let contents = fs::read_to_string(status_path(username)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn write_status(username: &str, status: &mut Status) -> Result<()> {
+    status.updated_at = Utc::now();
+    fs::write(status_path(username), serde_json::to_string(status)?)
+        .context("Failed to write the daemon status file")
+}
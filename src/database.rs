@@ -1,24 +1,685 @@
-use anyhow::Result;
-use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use colored::*;
+use dialoguer::Password;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use zeroize::Zeroizing;
 
-pub fn get_db_path() -> PathBuf {
-    let mut path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
-    path.push(".dood");
-    std::fs::create_dir_all(&path).ok();
-    path.push("dood.db");
-    path
+use crate::{config, output};
+
+/// Journal files SQLite keeps next to the database.
+const JOURNAL_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Older versions kept the database in `~/.dood`. Moves it to the data
+/// directory unless a database is already there or a custom data directory
+/// is used.
+fn migrate_legacy_location() -> Result<()> {
+    if config::custom_data_dir().is_some() {
+        return Ok(());
+    }
+
+    let Some(legacy_dir) = dirs::home_dir().map(|home| home.join(".dood")) else {
+        return Ok(());
+    };
+    let legacy_path = legacy_dir.join("dood.db");
+    let path = config::get_db_path();
+
+    if !legacy_path.exists() || path.exists() {
+        return Ok(());
+    }
+
+    for suffix in std::iter::once("").chain(JOURNAL_SUFFIXES) {
+        let from = with_suffix(&legacy_path, suffix);
+        if !from.exists() {
+            continue;
+        }
+        let to = with_suffix(&path, suffix);
+        // A rename cannot cross file systems; fall back to copying.
+        if std::fs::rename(&from, &to).is_err() {
+            std::fs::copy(&from, &to).with_context(|| {
+                format!("Failed to move {} to {}", from.display(), to.display())
+            })?;
+            std::fs::remove_file(&from)?;
+        }
+    }
+
+    // Only succeeds if nothing else was kept there.
+    std::fs::remove_dir(&legacy_dir).ok();
+
+    output::status(
+        format!(
+            "Moved the database from {} to {}",
+            legacy_dir.display(),
+            path.display()
+        )
+        .bright_black(),
+    );
+
+    Ok(())
+}
+
+/// Passphrase of an encrypted database, asked for at most once per process.
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+const PASSPHRASE_ENV: &str = "DOOD_DB_PASSPHRASE";
+
+/// How long to wait for another process to finish writing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections to the database at `config::get_db_path`, kept open for reuse
+/// so a command does not reopen (and possibly unlock) the file on every query.
+static POOL: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// Idle connections kept beyond this are closed.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// A connection borrowed from the pool, returned to it when dropped. Nested
+/// calls simply borrow another connection, except inside `with_transaction`,
+/// where they all share the transaction's.
+pub struct PooledConnection(Lease);
+
+enum Lease {
+    Pooled(Option<Connection>),
+    Transaction(Rc<Connection>),
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match &self.0 {
+            Lease::Pooled(conn) => conn.as_ref().expect("connection is only taken on drop"),
+            Lease::Transaction(conn) => conn.as_ref(),
+        }
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match &mut self.0 {
+            Lease::Pooled(conn) => conn.as_mut().expect("connection is only taken on drop"),
+            Lease::Transaction(_) => panic!("cannot start a transaction inside with_transaction"),
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Lease::Pooled(conn) = &mut self.0 {
+            if let Some(conn) = conn.take() {
+                release(conn);
+            }
+        }
+    }
+}
+
+fn pool() -> MutexGuard<'static, Vec<Connection>> {
+    POOL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn release(conn: Connection) {
+    let mut pool = pool();
+    if pool.len() < MAX_IDLE_CONNECTIONS {
+        pool.push(conn);
+    }
+}
+
+thread_local! {
+    /// Connection of the `with_transaction` call running on this thread.
+    static TRANSACTION: RefCell<Option<Rc<Connection>>> = RefCell::new(None);
 }
 
-pub fn get_connection() -> Result<Connection> {
-    let conn = Connection::open(get_db_path())?;
+pub fn get_connection() -> Result<PooledConnection> {
+    if let Some(conn) = TRANSACTION.with(|tx| tx.borrow().clone()) {
+        return Ok(PooledConnection(Lease::Transaction(conn)));
+    }
+
+    let idle = pool().pop();
+    let conn = match idle {
+        Some(conn) => conn,
+        None => open_connection(&config::get_db_path())?,
+    };
+    Ok(PooledConnection(Lease::Pooled(Some(conn))))
+}
+
+/// Runs `f` as one transaction: everything it writes through
+/// `get_connection` is committed if it succeeds and rolled back if it fails.
+/// Calls nested inside another `with_transaction` join the outer one.
+pub fn with_transaction<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if TRANSACTION.with(|tx| tx.borrow().is_some()) {
+        return f();
+    }
+
+    let idle = pool().pop();
+    let conn = match idle {
+        Some(conn) => conn,
+        None => open_connection(&config::get_db_path())?,
+    };
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+
+    let conn = Rc::new(conn);
+    TRANSACTION.with(|tx| *tx.borrow_mut() = Some(Rc::clone(&conn)));
+    let result = f();
+    TRANSACTION.with(|tx| tx.borrow_mut().take());
+
+    let conn = Rc::try_unwrap(conn)
+        .map_err(|_| anyhow::anyhow!("A database connection outlived its transaction"))?;
+
+    let finished = match &result {
+        Ok(_) => conn.execute_batch("COMMIT"),
+        Err(_) => conn.execute_batch("ROLLBACK"),
+    };
+    if let Err(e) = finished {
+        conn.execute_batch("ROLLBACK").ok();
+        return Err(e.into());
+    }
+
+    release(conn);
+    result
+}
+
+/// Closes idle connections. Must be called before the database file is
+/// replaced, or they would keep using the old one.
+fn close_connections() {
+    pool().clear();
+}
+
+/// Opens a database file, unlocking it first if it is encrypted.
+pub fn open_connection(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+
+    if is_encrypted(path) {
+        conn.pragma_update(None, "key", database_passphrase()?)?;
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            .context("Failed to unlock database. Is the passphrase correct?")?;
+    }
+
+    // The daemon, interactive chat and one-off commands may all use the
+    // database at once: let readers proceed alongside a writer, and have
+    // writers wait for each other instead of failing with "database is locked".
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+
     Ok(conn)
 }
 
-pub fn init() -> Result<()> {
+/// A plaintext SQLite file always starts with this header; a SQLCipher file
+/// is indistinguishable from random bytes.
+fn is_encrypted(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    match File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(()) => &header != b"SQLite format 3\0",
+        Err(_) => false,
+    }
+}
+
+fn database_passphrase() -> Result<&'static str> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => Password::new()
+            .with_prompt("Database passphrase")
+            .interact()?,
+    };
+
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
+}
+
+/// Copies the live database to `path` with SQLite's online backup API, so
+/// other processes may keep using it meanwhile. An encrypted database gives
+/// an encrypted copy under the same passphrase.
+pub fn backup_to(path: &Path) -> Result<()> {
+    if path.exists() {
+        anyhow::bail!("{} already exists", path.display());
+    }
+
+    let conn = get_connection()?;
+    let mut copy = Connection::open(path)?;
+    if is_encrypted(&config::get_db_path()) {
+        copy.pragma_update(None, "key", database_passphrase()?)?;
+    }
+
+    let backup = rusqlite::backup::Backup::new(&conn, &mut copy)?;
+    backup.run_to_completion(BACKUP_PAGES_PER_STEP, Duration::from_millis(10), None)?;
+
+    Ok(())
+}
+
+/// Pages copied per backup step; other processes may write between steps.
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 256;
+
+/// Checks that `path` holds a DooD database this version can use, returning
+/// the accounts in it.
+pub fn inspect_backup(path: &Path) -> Result<Vec<String>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    if is_encrypted(path) {
+        conn.pragma_update(None, "key", database_passphrase()?)?;
+    }
+
+    let problems: Vec<String> = {
+        let mut stmt = conn
+            .prepare("PRAGMA integrity_check")
+            .with_context(|| format!("'{}' is not a readable database", path.display()))?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+    if problems.iter().any(|problem| problem != "ok") {
+        anyhow::bail!("'{}' is damaged: {}", path.display(), problems.join("; "));
+    }
+
+    let has_accounts: bool = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'account'",
+        [],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+    if !has_accounts {
+        anyhow::bail!("'{}' is not a DooD database", path.display());
+    }
+
+    let has_versions: bool = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        [],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+    if has_versions && schema_version(&conn)? > MIGRATIONS.len() {
+        anyhow::bail!(
+            "'{}' was written by a newer version of dood-cli. Please upgrade dood-cli.",
+            path.display()
+        );
+    }
+
+    let mut stmt = conn.prepare("SELECT username FROM account ORDER BY username")?;
+    let accounts = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(accounts)
+}
+
+/// Replaces the database with the given file contents. Stale journal files
+/// from the old database are removed so they are not replayed onto it.
+pub fn replace_with(contents: &[u8]) -> Result<()> {
+    close_connections();
+    let path = config::get_db_path();
+    remove_journals(&path)?;
+
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+fn remove_journals(path: &Path) -> Result<()> {
+    for suffix in JOURNAL_SUFFIXES {
+        let journal = with_suffix(path, suffix);
+        if journal.exists() {
+            std::fs::remove_file(journal)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a copy of the live database to `path` with SQLCipher's export,
+/// encrypted with the raw `key`, so no plaintext copy touches the disk even
+/// when the database itself is not encrypted.
+pub fn encrypted_snapshot_to(path: &Path, key: &[u8; 32]) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let conn = get_connection()?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS snapshot KEY ?2",
+        params![path.to_string_lossy(), raw_key(key).as_str()],
+    )?;
+    let exported = conn.query_row("SELECT sqlcipher_export('snapshot')", [], |_| Ok(()));
+    conn.execute("DETACH DATABASE snapshot", [])?;
+    exported?;
+
+    Ok(())
+}
+
+/// Opens a copy written by `encrypted_snapshot_to`.
+pub fn open_snapshot(path: &Path, key: &[u8; 32]) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "key", raw_key(key).as_str())?;
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+        .context("Failed to decrypt the backup. Is the passphrase correct?")?;
+    Ok(conn)
+}
+
+/// Replaces the database with a copy written by `encrypted_snapshot_to`. The
+/// copy is decrypted straight into the database's place, encrypted again
+/// under the database passphrase if the current database is encrypted.
+pub fn restore_snapshot(contents: &[u8], key: &[u8; 32]) -> Result<()> {
+    let path = config::get_db_path();
+    let snapshot_path = path.with_extension("db.restoring");
+    let restored_path = path.with_extension("db.restored");
+    for stale in [&snapshot_path, &restored_path] {
+        if stale.exists() {
+            std::fs::remove_file(stale)?;
+        }
+    }
+
+    let restored_key = match is_encrypted(&path) {
+        true => database_passphrase()?,
+        false => "",
+    };
+
+    std::fs::write(&snapshot_path, contents)?;
+    let result = (|| -> Result<()> {
+        let conn = open_snapshot(&snapshot_path, key)?;
+        conn.execute(
+            "ATTACH DATABASE ?1 AS restored KEY ?2",
+            params![restored_path.to_string_lossy(), restored_key],
+        )?;
+        conn.query_row("SELECT sqlcipher_export('restored')", [], |_| Ok(()))?;
+        conn.execute("DETACH DATABASE restored", [])?;
+        Ok(())
+    })();
+    std::fs::remove_file(&snapshot_path).ok();
+    if let Err(e) = result {
+        std::fs::remove_file(&restored_path).ok();
+        return Err(e);
+    }
+
+    close_connections();
+    remove_journals(&path)?;
+    std::fs::rename(&restored_path, &path)?;
+    Ok(())
+}
+
+/// A raw 256-bit SQLCipher key, used as is instead of being derived from a
+/// passphrase.
+fn raw_key(key: &[u8; 32]) -> Zeroizing<String> {
+    let hex: Zeroizing<String> = Zeroizing::new(key.iter().map(|b| format!("{:02x}", b)).collect());
+    Zeroizing::new(format!("x'{}'", hex.as_str()))
+}
+
+/// The server-assigned device id (if known) and creation time of an account.
+pub fn get_account_details(username: &str) -> Result<(Option<i64>, DateTime<Utc>)> {
+    let conn = get_connection()?;
+    let (device_id, created_at): (Option<i64>, String) = conn.query_row(
+        "SELECT device_id, created_at FROM account WHERE username = ?1",
+        params![username],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let created_at = DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc);
+    Ok((device_id, created_at))
+}
+
+/// The public identity key of an account, which its devices share.
+pub fn get_account_identity_key(username: &str) -> Result<Vec<u8>> {
+    let conn = get_connection()?;
+    let identity_key = conn.query_row(
+        "SELECT identity_public_key FROM account WHERE username = ?1",
+        params![username],
+        |row| row.get(0),
+    )?;
+    Ok(identity_key)
+}
+
+pub fn has_accounts() -> Result<bool> {
+    let conn = get_connection()?;
+    let count: i32 = conn.query_row("SELECT COUNT(*) FROM account", [], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+/// Re-encrypts a plaintext database in place with a new passphrase.
+pub fn encrypt_database() -> Result<()> {
+    let path = config::get_db_path();
+
+    if is_encrypted(&path) {
+        anyhow::bail!("Database is already encrypted");
+    }
+
+    let passphrase = Password::new()
+        .with_prompt("New database passphrase")
+        .with_confirmation("Confirm passphrase", "Passphrases do not match")
+        .interact()?;
+
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase must not be empty");
+    }
+
+    close_connections();
+
+    let encrypted_path = path.with_extension("db.encrypting");
+    if encrypted_path.exists() {
+        std::fs::remove_file(&encrypted_path)?;
+    }
+
+    {
+        let conn = Connection::open(&path)?;
+        conn.execute(
+            "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+            params![encrypted_path.to_string_lossy(), passphrase],
+        )?;
+        conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+        conn.execute("DETACH DATABASE encrypted", [])?;
+    }
+
+    std::fs::rename(&encrypted_path, &path)?;
+
+    println!("{} Database encrypted", "✓".green().bold());
+    println!(
+        "{}",
+        format!(
+            "You will be asked for the passphrase on every command, or set {}.",
+            PASSPHRASE_ENV
+        )
+        .bright_black()
+    );
+
+    Ok(())
+}
+
+/// Size on disk of the database, including its write-ahead log.
+pub fn database_size() -> u64 {
+    let path = config::get_db_path();
+
+    [with_suffix(&path, "-wal"), path]
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Deletes rows left behind by removed accounts and messages, then rebuilds
+/// the indexes and the file itself. Returns how many rows were pruned.
+pub fn compact() -> Result<usize> {
+    let mut conn = get_connection()?;
+    conn.pragma_update(None, "secure_delete", true)?;
+
+    let tx = conn.transaction()?;
+    let mut pruned = tx.execute(
+        "DELETE FROM ratchet_states
+         WHERE username LIKE '%:%'
+           AND substr(username, 1, instr(username, ':') - 1) NOT IN (SELECT username FROM account)",
+        [],
+    )?;
+    pruned += tx.execute(
+        "DELETE FROM quarantine
+         WHERE owner IS NOT NULL AND owner NOT IN (SELECT username FROM account)",
+        [],
+    )?;
+    for table in [
+        "attachments",
+        "attachment_downloads",
+        "outbox",
+        "contacts",
+        "verified_contacts",
+        "key_history",
+        "security_events",
+        "user_devices",
+        "contact_devices",
+        "profiles",
+        "message_parts",
+        "received_counters",
+        "polls",
+        "poll_votes",
+    ] {
+        pruned += tx.execute(
+            &format!(
+                "DELETE FROM {} WHERE owner NOT IN (SELECT username FROM account)",
+                table
+            ),
+            [],
+        )?;
+    }
+    for table in [
+        "starred_messages",
+        "mentions",
+        "failed_messages",
+        "polls",
+        "attachments",
+        "attachment_downloads",
+    ] {
+        pruned += tx.execute(
+            &format!(
+                "DELETE FROM {} WHERE message_id NOT IN (SELECT id FROM messages)",
+                table
+            ),
+            [],
+        )?;
+    }
+    pruned += tx.execute(
+        "DELETE FROM poll_votes
+         WHERE (owner, creator, poll_id) NOT IN (SELECT owner, creator, poll_id FROM polls)",
+        [],
+    )?;
+    tx.commit()?;
+
+    conn.execute_batch("REINDEX; VACUUM;")?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+    Ok(pruned)
+}
+
+/// Problems found by SQLite's integrity check; empty if there are none.
+pub fn check_integrity() -> Result<Vec<String>> {
     let conn = get_connection()?;
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(problems
+        .into_iter()
+        .filter(|problem| problem != "ok")
+        .collect())
+}
+
+/// Schema changes, applied in order by `init`. Each runs once, inside a
+/// transaction, and brings the database to the schema version given by its
+/// position in this list. Append new migrations; never edit released ones.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    create_initial_schema,
+    add_message_indexes,
+    add_conversation_retention,
+    add_message_received_at,
+    add_ratchet_state_version,
+    add_attachments,
+    add_conversation_metadata,
+    add_message_trash,
+    add_outbox,
+    add_contact_ownership,
+    add_outbox_message_id,
+    add_pending_session_init,
+    scope_polls_to_creator,
+    add_attachment_quarantine,
+    add_attachment_downloads,
+];
+
+pub fn init() -> Result<()> {
+    migrate_legacy_location()?;
+
+    let mut conn = get_connection()?;
+
+    // Only takes effect on a new database; `purge_messages` converts old ones.
+    conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    let current = schema_version(&conn)?;
+    if current > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {} is newer than this version of dood-cli supports ({}). Please upgrade dood-cli.",
+            current,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = index + 1;
+        let tx = conn.transaction()?;
+        migration(&tx)
+            .with_context(|| format!("Failed to migrate database to schema version {}", version))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+            params![version as i64, Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+    }
+
+    // Legacy data may only become attributable later (e.g. after logging in
+    // or learning a peer's devices), so these fixups run on every start.
+    let unresolved = migrate_legacy_ratchet_states(&conn)?;
+    if unresolved > 0 {
+        output::notice(
+            format!(
+                "⚠️  {} encrypted session(s) from an old version could not be matched to a contact and were not migrated. Looking the contact up again, e.g. with 'dood refresh-keys <username>', lets the next run migrate them.",
+                unresolved
+            )
+            .yellow(),
+        );
+    }
+    migrate_single_device_sessions(&conn)?;
+    backfill_account_ownership(&conn)?;
+
+    Ok(())
+}
+
+/// The highest migration applied to the database; 0 for a database created
+/// before versioned migrations.
+fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })?;
+    Ok(version.unwrap_or(0) as usize)
+}
 
+/// The schema as of the introduction of versioned migrations. Databases
+/// created before then already have some of these tables, so this is written
+/// to be safe to apply on top of any earlier schema.
+fn create_initial_schema(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS account (
             id INTEGER PRIMARY KEY,
@@ -47,7 +708,8 @@ pub fn init() -> Result<()> {
             timestamp TEXT NOT NULL,
             is_outgoing INTEGER NOT NULL,
             is_read INTEGER NOT NULL DEFAULT 0,
-            message_id TEXT
+            message_id TEXT,
+            owner TEXT
         )",
         [],
     )?;
@@ -82,103 +744,3005 @@ pub fn init() -> Result<()> {
         [],
     )?;
 
-    Ok(())
-}
-
-pub struct Message {
-    pub id: i64,
-    pub conversation_with: String,
-    pub sender: String,
-    pub recipient: String,
-    pub content: String,
-    pub timestamp: DateTime<Utc>,
-    pub is_outgoing: bool,
-    pub is_read: bool,
-}
-
-pub fn save_message(
-    conversation_with: &str,
-    sender: &str,
-    recipient: &str,
-    content: &str,
-    is_outgoing: bool,
-) -> Result<()> {
-    let conn = get_connection()?;
-    let timestamp = Utc::now().to_rfc3339();
-
     conn.execute(
-        "INSERT INTO messages (conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-        params![conversation_with, sender, recipient, content, timestamp, is_outgoing as i32, 0],
+        "CREATE TABLE IF NOT EXISTS config (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
     )?;
 
-    Ok(())
-}
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS conversations (
+            owner TEXT NOT NULL,
+            username TEXT NOT NULL,
+            archived INTEGER NOT NULL DEFAULT 0,
+            muted INTEGER NOT NULL DEFAULT 0,
+            muted_until TEXT,
+            PRIMARY KEY (owner, username)
+        )",
+        [],
+    )?;
 
-pub fn get_messages(username: &str, limit: usize) -> Result<Vec<Message>> {
-    let conn = get_connection()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read
-         FROM messages
-         WHERE conversation_with = ?1
-         ORDER BY timestamp DESC
-         LIMIT ?2",
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS starred_messages (
+            message_id INTEGER PRIMARY KEY,
+            starred_at TEXT NOT NULL
+        )",
+        [],
     )?;
 
-    let messages = stmt
-        .query_map(params![username, limit], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                conversation_with: row.get(1)?,
-                sender: row.get(2)?,
-                recipient: row.get(3)?,
-                content: row.get(4)?,
-                timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
-                    .unwrap()
-                    .with_timezone(&Utc),
-                is_outgoing: row.get::<_, i32>(6)? != 0,
-                is_read: row.get::<_, i32>(7)? != 0,
-            })
-        })?
-        .collect::<Result<Vec<_>, _>>()?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mentions (
+            message_id INTEGER PRIMARY KEY
+        )",
+        [],
+    )?;
 
-    Ok(messages)
-}
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS polls (
+            poll_id TEXT PRIMARY KEY,
+            message_id INTEGER NOT NULL,
+            creator TEXT NOT NULL,
+            question TEXT NOT NULL,
+            options TEXT NOT NULL
+        )",
+        [],
+    )?;
 
-pub fn get_conversations() -> Result<Vec<(String, DateTime<Utc>, String, i32)>> {
-    let conn = get_connection()?;
-    let mut stmt = conn.prepare(
-        "SELECT conversation_with, MAX(timestamp) as last_message_time, 
-                (SELECT content FROM messages m2 
-                 WHERE m2.conversation_with = m1.conversation_with 
-                 ORDER BY timestamp DESC LIMIT 1) as last_message,
-                SUM(CASE WHEN is_read = 0 AND is_outgoing = 0 THEN 1 ELSE 0 END) as unread_count
-         FROM messages m1
-         GROUP BY conversation_with
-         ORDER BY last_message_time DESC",
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS poll_votes (
+            poll_id TEXT NOT NULL,
+            voter TEXT NOT NULL,
+            option_index INTEGER NOT NULL,
+            voted_at TEXT NOT NULL,
+            PRIMARY KEY (poll_id, voter)
+        )",
+        [],
     )?;
 
-    let conversations = stmt
-        .query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
-                    .unwrap()
-                    .with_timezone(&Utc),
-                row.get::<_, String>(2)?,
-                row.get::<_, i32>(3)?,
-            ))
-        })?
-        .collect::<Result<Vec<_>, _>>()?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_parts (
+            sender TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            part_index INTEGER NOT NULL,
+            total_parts INTEGER NOT NULL,
+            content TEXT NOT NULL,
+            received_at TEXT NOT NULL,
+            PRIMARY KEY (sender, message_id, part_index)
+        )",
+        [],
+    )?;
 
-    Ok(conversations)
-}
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS failed_messages (
+            message_id INTEGER PRIMARY KEY,
+            error TEXT NOT NULL,
+            failed_at TEXT NOT NULL
+        )",
+        [],
+    )?;
 
-pub fn mark_messages_as_read(username: &str) -> Result<()> {
-    let conn = get_connection()?;
     conn.execute(
-        "UPDATE messages SET is_read = 1 WHERE conversation_with = ?1 AND is_outgoing = 0",
-        params![username],
+        "CREATE TABLE IF NOT EXISTS verified_contacts (
+            username TEXT PRIMARY KEY,
+            identity_key BLOB NOT NULL,
+            verified_at TEXT NOT NULL
+        )",
+        [],
     )?;
-    Ok(())
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS quarantine (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sender TEXT NOT NULL,
+            ciphertext TEXT NOT NULL,
+            header TEXT NOT NULL,
+            error TEXT NOT NULL,
+            received_at TEXT NOT NULL,
+            owner TEXT
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS key_history (
+            username TEXT NOT NULL,
+            identity_key BLOB NOT NULL,
+            first_seen TEXT NOT NULL,
+            last_seen TEXT NOT NULL,
+            PRIMARY KEY (username, identity_key)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS received_counters (
+            sender TEXT NOT NULL,
+            ratchet_key BLOB NOT NULL,
+            n INTEGER NOT NULL,
+            received_at TEXT NOT NULL,
+            PRIMARY KEY (sender, ratchet_key, n)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS security_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            event TEXT NOT NULL,
+            details TEXT NOT NULL,
+            occurred_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_devices (
+            username TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            device_id INTEGER NOT NULL,
+            last_updated TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS contact_devices (
+            username TEXT NOT NULL,
+            device_id INTEGER NOT NULL,
+            PRIMARY KEY (username, device_id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profiles (
+            username TEXT PRIMARY KEY,
+            display_name TEXT,
+            status TEXT,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    add_account_ownership(conn)?;
+
+    Ok(())
+}
+
+/// Indexes for listing conversations and their history, counting unread
+/// messages, and looking up a message by its sender-assigned id. Listing is
+/// always scoped to an account, so `owner` leads those indexes.
+fn add_message_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS messages_conversation
+             ON messages (owner, conversation_with, timestamp);
+         CREATE INDEX IF NOT EXISTS messages_unread
+             ON messages (owner, is_read, is_outgoing);
+         CREATE INDEX IF NOT EXISTS messages_message_id
+             ON messages (message_id);
+         CREATE INDEX IF NOT EXISTS polls_message
+             ON polls (message_id);",
+    )?;
+
+    Ok(())
+}
+
+/// Lets a conversation keep messages for longer or shorter than the default
+/// retention period; 0 keeps them forever.
+fn add_conversation_retention(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "ALTER TABLE conversations ADD COLUMN retention_days INTEGER",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// `timestamp` holds when a message was sent, by the server's clock where it
+/// says; `received_at` when this machine stored an incoming message.
+fn add_message_received_at(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE messages ADD COLUMN received_at TEXT", [])?;
+
+    Ok(())
+}
+
+/// Records which serialization of the ratchet each session was saved in.
+/// Sessions saved before then use the first.
+fn add_ratchet_state_version(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "ALTER TABLE ratchet_states ADD COLUMN format_version INTEGER NOT NULL DEFAULT 1",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Files attached to messages. Their content is kept encrypted in the
+/// attachment store under `blob`; attachments with the same content share it.
+fn add_attachments(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE attachments (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             owner TEXT NOT NULL,
+             message_id INTEGER,
+             name TEXT NOT NULL,
+             size INTEGER NOT NULL,
+             blob TEXT NOT NULL,
+             created_at TEXT NOT NULL
+         );
+         CREATE INDEX attachments_owner ON attachments (owner, created_at);
+         CREATE INDEX attachments_blob ON attachments (blob);",
+    )?;
+
+    Ok(())
+}
+
+/// Makes `conversations` the record of every conversation, with a row per
+/// peer that messages were exchanged with, rather than only those with
+/// settings. Adds a nickname, a disappearing-messages timer in seconds, and
+/// when the conversation was last read.
+fn add_conversation_metadata(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE conversations ADD COLUMN nickname TEXT;
+         ALTER TABLE conversations ADD COLUMN expire_timer INTEGER;
+         ALTER TABLE conversations ADD COLUMN last_read_at TEXT;",
+    )?;
+    add_missing_conversations(conn)?;
+    conn.execute(
+        "UPDATE conversations SET last_read_at =
+             (SELECT MAX(timestamp) FROM messages m
+              WHERE m.owner = conversations.owner
+                AND m.conversation_with = conversations.username
+                AND m.is_read = 1 AND m.is_outgoing = 0)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Adds a conversation row for peers that only appear in messages.
+fn add_missing_conversations(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO conversations (owner, username)
+         SELECT DISTINCT owner, conversation_with FROM messages WHERE owner IS NOT NULL",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Messages removed with `dood rm` keep their row, hidden from everything but
+/// the trash, until the trash is emptied.
+fn add_message_trash(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE messages ADD COLUMN deleted_at TEXT;
+         CREATE INDEX messages_trash ON messages (owner, deleted_at)
+             WHERE deleted_at IS NOT NULL;",
+    )?;
+
+    Ok(())
+}
+
+/// Every envelope of encrypted messages, stored before it is uploaded along
+/// with how far sending it got.
+fn add_outbox(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE outbox (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             owner TEXT NOT NULL,
+             recipient TEXT NOT NULL,
+             envelope TEXT NOT NULL,
+             state TEXT NOT NULL,
+             error TEXT,
+             attempts INTEGER NOT NULL DEFAULT 0,
+             created_at TEXT NOT NULL,
+             updated_at TEXT NOT NULL
+         );
+         CREATE INDEX outbox_owner ON outbox (owner, created_at);",
+    )?;
+
+    Ok(())
+}
+
+/// Contacts and what is known about them (keys, devices, profiles), polls,
+/// partly received messages and replay counters were shared by every account
+/// on a machine, so one server's "bob" stood in for another's. Give each row
+/// an owner. Rows from before then were visible to every account, so every
+/// account keeps a copy; polls belong to the owner of their message.
+fn add_contact_ownership(conn: &Connection) -> Result<()> {
+    add_owner(
+        conn,
+        "contacts",
+        "id INTEGER PRIMARY KEY,
+         owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         identity_key BLOB NOT NULL,
+         key_bundle TEXT,
+         last_fetched TEXT NOT NULL,
+         UNIQUE (owner, username)",
+        &["username", "identity_key", "key_bundle", "last_fetched"],
+    )?;
+    add_owner(
+        conn,
+        "verified_contacts",
+        "owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         identity_key BLOB NOT NULL,
+         verified_at TEXT NOT NULL,
+         PRIMARY KEY (owner, username)",
+        &["username", "identity_key", "verified_at"],
+    )?;
+    add_owner(
+        conn,
+        "key_history",
+        "owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         identity_key BLOB NOT NULL,
+         first_seen TEXT NOT NULL,
+         last_seen TEXT NOT NULL,
+         PRIMARY KEY (owner, username, identity_key)",
+        &["username", "identity_key", "first_seen", "last_seen"],
+    )?;
+    add_owner(
+        conn,
+        "security_events",
+        "id INTEGER PRIMARY KEY AUTOINCREMENT,
+         owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         event TEXT NOT NULL,
+         details TEXT NOT NULL,
+         occurred_at TEXT NOT NULL",
+        &["username", "event", "details", "occurred_at"],
+    )?;
+    add_owner(
+        conn,
+        "user_devices",
+        "owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         user_id INTEGER NOT NULL,
+         device_id INTEGER NOT NULL,
+         last_updated TEXT NOT NULL,
+         PRIMARY KEY (owner, username)",
+        &["username", "user_id", "device_id", "last_updated"],
+    )?;
+    add_owner(
+        conn,
+        "contact_devices",
+        "owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         device_id INTEGER NOT NULL,
+         PRIMARY KEY (owner, username, device_id)",
+        &["username", "device_id"],
+    )?;
+    add_owner(
+        conn,
+        "profiles",
+        "owner TEXT NOT NULL,
+         username TEXT NOT NULL,
+         display_name TEXT,
+         status TEXT,
+         updated_at TEXT NOT NULL,
+         PRIMARY KEY (owner, username)",
+        &["username", "display_name", "status", "updated_at"],
+    )?;
+    add_owner(
+        conn,
+        "message_parts",
+        "owner TEXT NOT NULL,
+         sender TEXT NOT NULL,
+         message_id TEXT NOT NULL,
+         part_index INTEGER NOT NULL,
+         total_parts INTEGER NOT NULL,
+         content TEXT NOT NULL,
+         received_at TEXT NOT NULL,
+         PRIMARY KEY (owner, sender, message_id, part_index)",
+        &[
+            "sender",
+            "message_id",
+            "part_index",
+            "total_parts",
+            "content",
+            "received_at",
+        ],
+    )?;
+    add_owner(
+        conn,
+        "received_counters",
+        "owner TEXT NOT NULL,
+         sender TEXT NOT NULL,
+         ratchet_key BLOB NOT NULL,
+         n INTEGER NOT NULL,
+         received_at TEXT NOT NULL,
+         PRIMARY KEY (owner, sender, ratchet_key, n)",
+        &["sender", "ratchet_key", "n", "received_at"],
+    )?;
+
+    conn.execute_batch(
+        "ALTER TABLE polls RENAME TO polls_legacy;
+         ALTER TABLE poll_votes RENAME TO poll_votes_legacy;
+         DROP INDEX IF EXISTS polls_message;
+         CREATE TABLE polls (
+             owner TEXT NOT NULL,
+             poll_id TEXT NOT NULL,
+             message_id INTEGER NOT NULL,
+             creator TEXT NOT NULL,
+             question TEXT NOT NULL,
+             options TEXT NOT NULL,
+             PRIMARY KEY (owner, poll_id)
+         );
+         CREATE INDEX polls_message ON polls (message_id);
+         CREATE TABLE poll_votes (
+             owner TEXT NOT NULL,
+             poll_id TEXT NOT NULL,
+             voter TEXT NOT NULL,
+             option_index INTEGER NOT NULL,
+             voted_at TEXT NOT NULL,
+             PRIMARY KEY (owner, poll_id, voter)
+         );
+         INSERT INTO polls (owner, poll_id, message_id, creator, question, options)
+             SELECT m.owner, l.poll_id, l.message_id, l.creator, l.question, l.options
+             FROM polls_legacy l JOIN messages m ON m.id = l.message_id
+             WHERE m.owner IS NOT NULL;
+         INSERT INTO poll_votes (owner, poll_id, voter, option_index, voted_at)
+             SELECT p.owner, l.poll_id, l.voter, l.option_index, l.voted_at
+             FROM poll_votes_legacy l JOIN polls p ON p.poll_id = l.poll_id;
+         DROP TABLE polls_legacy;
+         DROP TABLE poll_votes_legacy;",
+    )?;
+
+    Ok(())
+}
+
+/// Links outbox envelopes to the sender-assigned id of the message they carry,
+/// so a message sent again does not also leave its old envelopes to retry.
+fn add_outbox_message_id(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE outbox ADD COLUMN message_id TEXT;
+         CREATE INDEX outbox_message ON outbox (owner, message_id);",
+    )?;
+
+    Ok(())
+}
+
+/// Keeps the first-message header of sessions I started until the peer
+/// answers, so later messages can repeat it.
+fn add_pending_session_init(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE ratchet_states ADD COLUMN pending_init TEXT", [])?;
+
+    Ok(())
+}
+
+/// Poll ids are chosen by whoever creates the poll, so a contact could reuse
+/// the id of someone else's poll. Key polls and their votes by creator too.
+fn scope_polls_to_creator(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE polls RENAME TO polls_legacy;
+         ALTER TABLE poll_votes RENAME TO poll_votes_legacy;
+         DROP INDEX IF EXISTS polls_message;
+         CREATE TABLE polls (
+             owner TEXT NOT NULL,
+             creator TEXT NOT NULL,
+             poll_id TEXT NOT NULL,
+             message_id INTEGER NOT NULL,
+             question TEXT NOT NULL,
+             options TEXT NOT NULL,
+             PRIMARY KEY (owner, creator, poll_id)
+         );
+         CREATE INDEX polls_message ON polls (message_id);
+         CREATE TABLE poll_votes (
+             owner TEXT NOT NULL,
+             creator TEXT NOT NULL,
+             poll_id TEXT NOT NULL,
+             voter TEXT NOT NULL,
+             option_index INTEGER NOT NULL,
+             voted_at TEXT NOT NULL,
+             PRIMARY KEY (owner, creator, poll_id, voter)
+         );
+         INSERT INTO polls (owner, creator, poll_id, message_id, question, options)
+             SELECT owner, creator, poll_id, message_id, question, options FROM polls_legacy;
+         INSERT INTO poll_votes (owner, creator, poll_id, voter, option_index, voted_at)
+             SELECT p.owner, p.creator, l.poll_id, l.voter, l.option_index, l.voted_at
+             FROM poll_votes_legacy l
+             JOIN polls p ON p.owner = l.owner AND p.poll_id = l.poll_id;
+         DROP TABLE polls_legacy;
+         DROP TABLE poll_votes_legacy;",
+    )?;
+
+    Ok(())
+}
+
+/// Why a received attachment is held back until approved, for files that
+/// fall outside the attachment policy; NULL for everything else.
+fn add_attachment_quarantine(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE attachments ADD COLUMN quarantined TEXT", [])?;
+
+    Ok(())
+}
+
+/// Files announced by a pointer message but not downloaded yet, with the
+/// key and digest of their encrypted blob on the server.
+fn add_attachment_downloads(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE attachment_downloads (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             owner TEXT NOT NULL,
+             message_id INTEGER NOT NULL,
+             sender TEXT NOT NULL,
+             name TEXT NOT NULL,
+             remote_id TEXT NOT NULL,
+             key TEXT NOT NULL,
+             digest TEXT NOT NULL,
+             created_at TEXT NOT NULL
+         );
+         CREATE INDEX attachment_downloads_owner ON attachment_downloads (owner);",
+    )?;
+
+    Ok(())
+}
+
+/// Rebuilds `table` with `definition`, which adds an `owner` column, and
+/// copies each old row to every account.
+fn add_owner(conn: &Connection, table: &str, definition: &str, columns: &[&str]) -> Result<()> {
+    let legacy_columns: Vec<String> = columns.iter().map(|column| format!("l.{}", column)).collect();
+
+    conn.execute_batch(&format!(
+        "ALTER TABLE {table} RENAME TO {table}_legacy;
+         CREATE TABLE {table} ({definition});
+         INSERT INTO {table} (owner, {columns})
+             SELECT a.username, {legacy_columns}
+             FROM {table}_legacy l, account a
+             ORDER BY a.username, l.rowid;
+         DROP TABLE {table}_legacy;",
+        columns = columns.join(", "),
+        legacy_columns = legacy_columns.join(", "),
+    ))?;
+
+    Ok(())
+}
+
+/// Account chosen with `--account` for this process, overriding the session.
+static SELECTED_ACCOUNT: OnceLock<String> = OnceLock::new();
+
+pub fn select_account(username: &str) {
+    let _ = SELECTED_ACCOUNT.set(username.to_string());
+}
+
+/// The account commands act on: the one selected with `--account`, otherwise
+/// the logged-in one.
+pub fn current_account() -> Result<String> {
+    if let Some(account) = SELECTED_ACCOUNT.get() {
+        return Ok(account.clone());
+    }
+
+    let conn = get_connection()?;
+    conn.query_row("SELECT username FROM session WHERE id = 1", [], |row| {
+        row.get(0)
+    })
+    .context("Not logged in. Please run 'dood login' first.")
+}
+
+/// Key under which the ratchet state between `owner` and `peer` is stored.
+pub fn conversation_key(owner: &str, peer: &str) -> String {
+    format!("{}:{}", owner, peer)
+}
+
+/// Ratchet peer of one device of `username`; a bare username is a session
+/// from before multi-device support.
+pub fn device_address(username: &str, device_id: u64) -> String {
+    format!("{}#{}", username, device_id)
+}
+
+/// Refuses usernames that would make ratchet peers ambiguous, since `:` and
+/// `#` separate the parts of `conversation_key` and `device_address`.
+pub fn check_peer_username(username: &str) -> Result<()> {
+    if username.is_empty() || username.contains([':', '#']) {
+        anyhow::bail!("Invalid username '{}'", username);
+    }
+    Ok(())
+}
+
+/// Username and device of a ratchet peer written by `device_address`; a
+/// session from before multi-device support has no device.
+pub fn split_address(address: &str) -> Result<(&str, Option<u64>)> {
+    let (username, device_id) = match address.split_once('#') {
+        Some((username, device_id)) => {
+            let device_id = device_id
+                .parse()
+                .with_context(|| format!("Invalid session peer '{}'", address))?;
+            (username, Some(device_id))
+        }
+        None => (address, None),
+    };
+
+    check_peer_username(username)?;
+    Ok((username, device_id))
+}
+
+/// Username part of a ratchet peer written by `device_address`.
+pub fn address_username(address: &str) -> Result<&str> {
+    split_address(address).map(|(username, _)| username)
+}
+
+/// Deletes every ratchet session `owner` has with any device of `peer`.
+pub fn delete_peer_sessions(owner: &str, peer: &str) -> Result<()> {
+    let conn = get_connection()?;
+    let key = conversation_key(owner, peer);
+    let device_prefix = format!("{}#", key);
+
+    conn.execute(
+        "DELETE FROM ratchet_states
+         WHERE username = ?1 OR substr(username, 1, length(?2)) = ?2",
+        params![key, device_prefix],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_contact_devices(username: &str) -> Result<Vec<u64>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn
+        .prepare("SELECT device_id FROM contact_devices WHERE owner = ?1 AND username = ?2")?;
+    let devices = stmt
+        .query_map(params![owner, username], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(devices)
+}
+
+/// Deletes the ratchet session `owner` has with one device of `peer`.
+pub fn delete_device_session(owner: &str, peer: &str, device_id: u64) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM ratchet_states WHERE username = ?1",
+        params![conversation_key(owner, &device_address(peer, device_id))],
+    )?;
+    Ok(())
+}
+
+/// Replaces the known devices of `username`. The first one is also recorded
+/// as the primary device in `user_devices`.
+pub fn set_contact_devices(username: &str, user_id: u64, device_ids: &[u64]) -> Result<()> {
+    let owner = current_account()?;
+    let mut conn = get_connection()?;
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "DELETE FROM contact_devices WHERE owner = ?1 AND username = ?2",
+        params![owner, username],
+    )?;
+    for device_id in device_ids {
+        tx.execute(
+            "INSERT OR IGNORE INTO contact_devices (owner, username, device_id)
+             VALUES (?1, ?2, ?3)",
+            params![owner, username, device_id],
+        )?;
+    }
+
+    if let Some(primary) = device_ids.first() {
+        tx.execute(
+            "INSERT OR REPLACE INTO user_devices (owner, username, user_id, device_id, last_updated)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![owner, username, user_id, primary, Utc::now().to_rfc3339()],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// The user id and devices of `username` as last looked up, if that was
+/// after `fresh_since`.
+pub fn get_cached_contact(
+    username: &str,
+    fresh_since: DateTime<Utc>,
+) -> Result<Option<(u64, Vec<u64>)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let cached: Option<(u64, String)> = conn
+        .query_row(
+            "SELECT user_id, last_updated FROM user_devices WHERE owner = ?1 AND username = ?2",
+            params![owner, username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .ok();
+
+    let Some((user_id, last_updated)) = cached else {
+        return Ok(None);
+    };
+    if DateTime::parse_from_rfc3339(&last_updated)?.with_timezone(&Utc) < fresh_since {
+        return Ok(None);
+    }
+
+    let devices = get_contact_devices(username)?;
+    if devices.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some((user_id, devices)))
+}
+
+/// Makes the next `get_cached_contact` and `get_cached_key_bundles` for
+/// `username` miss, e.g. after the server rejected a message sent to the
+/// cached devices. Without `username`, every contact of the current account
+/// is looked up again, e.g. after it moved to another server.
+pub fn expire_cached_contact(username: Option<&str>) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let epoch = DateTime::<Utc>::UNIX_EPOCH.to_rfc3339();
+    conn.execute(
+        "UPDATE user_devices SET last_updated = ?3
+         WHERE owner = ?1 AND (?2 IS NULL OR username = ?2)",
+        params![owner, username, epoch],
+    )?;
+    conn.execute(
+        "UPDATE contacts SET last_fetched = ?3
+         WHERE owner = ?1 AND (?2 IS NULL OR username = ?2)",
+        params![owner, username, epoch],
+    )?;
+    Ok(())
+}
+
+pub fn get_primary_device(username: &str) -> Result<Option<u64>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let device_id = conn
+        .query_row(
+            "SELECT device_id FROM user_devices WHERE owner = ?1 AND username = ?2",
+            params![owner, username],
+            |row| row.get(0),
+        )
+        .ok();
+    Ok(device_id)
+}
+
+/// Peers `owner` has a ratchet session with, along with the raw state and
+/// when it was last updated.
+pub fn get_ratchet_sessions(owner: &str) -> Result<Vec<(String, String, DateTime<Utc>)>> {
+    let conn = get_connection()?;
+    let prefix = conversation_key(owner, "");
+
+    let mut stmt = conn.prepare(
+        "SELECT username, state_data, last_updated FROM ratchet_states
+         WHERE substr(username, 1, length(?1)) = ?1",
+    )?;
+
+    let sessions = stmt
+        .query_map(params![prefix], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|(key, state, last_updated)| {
+            let peer = key[prefix.len()..].to_string();
+            let last_updated = DateTime::parse_from_rfc3339(&last_updated)
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_default();
+            (peer, state, last_updated)
+        })
+        .collect();
+
+    Ok(sessions)
+}
+
+/// Rewrites `ratchet_states` rows written by older versions, which were keyed
+/// either by `user_<id>` or by the bare peer username, under the unified
+/// `owner:peer` conversation key. Returns how many `user_<id>` rows belong to
+/// peers not looked up yet, which are left for a later run.
+fn migrate_legacy_ratchet_states(conn: &Connection) -> Result<usize> {
+    let legacy_rows: Vec<(i64, String)> = {
+        let mut stmt =
+            conn.prepare("SELECT id, username FROM ratchet_states WHERE username NOT LIKE '%:%'")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    if legacy_rows.is_empty() {
+        return Ok(0);
+    }
+
+    let owner = match legacy_owner(conn)? {
+        Some(owner) => owner,
+        None => return Ok(0),
+    };
+
+    let mut unresolved = 0;
+    for (id, legacy_key) in legacy_rows {
+        let peer = match resolve_legacy_peer(conn, &owner, &legacy_key)? {
+            Some(peer) => peer,
+            None => {
+                unresolved += 1;
+                continue;
+            }
+        };
+
+        let key = conversation_key(&owner, &peer);
+
+        let already_migrated: bool = conn.query_row(
+            "SELECT COUNT(*) FROM ratchet_states WHERE username = ?1",
+            params![key],
+            |row| row.get::<_, i32>(0).map(|count| count > 0),
+        )?;
+
+        if already_migrated {
+            conn.execute("DELETE FROM ratchet_states WHERE id = ?1", params![id])?;
+        } else {
+            conn.execute(
+                "UPDATE ratchet_states SET username = ?1 WHERE id = ?2",
+                params![key, id],
+            )?;
+        }
+    }
+
+    Ok(unresolved)
+}
+
+/// Sessions from before multi-device support were keyed by the peer alone
+/// and always talked to the peer's first device; key them by that device.
+fn migrate_single_device_sessions(conn: &Connection) -> Result<()> {
+    let rows: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, username FROM ratchet_states
+             WHERE username LIKE '%:%' AND username NOT LIKE '%#%'",
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    for (id, key) in rows {
+        let Some((owner, peer)) = key.split_once(':') else {
+            continue;
+        };
+
+        let device_id: Option<i64> = conn
+            .query_row(
+                "SELECT device_id FROM user_devices WHERE owner = ?1 AND username = ?2",
+                params![owner, peer],
+                |row| row.get(0),
+            )
+            .ok();
+
+        if let Some(device_id) = device_id {
+            conn.execute(
+                "UPDATE ratchet_states SET username = ?1 WHERE id = ?2",
+                params![format!("{}#{}", key, device_id), id],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let count: i32 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Older versions shared messages, conversation settings and quarantined
+/// messages between all accounts on a machine. Give those tables an owner so
+/// each account only sees its own.
+fn add_account_ownership(conn: &Connection) -> Result<()> {
+    for table in ["messages", "quarantine"] {
+        if !has_column(conn, table, "owner")? {
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN owner TEXT", table), [])?;
+        }
+    }
+
+    if !has_column(conn, "conversations", "owner")? {
+        conn.execute_batch(
+            "ALTER TABLE conversations RENAME TO conversations_legacy;
+             CREATE TABLE conversations (
+                 owner TEXT NOT NULL,
+                 username TEXT NOT NULL,
+                 archived INTEGER NOT NULL DEFAULT 0,
+                 muted INTEGER NOT NULL DEFAULT 0,
+                 muted_until TEXT,
+                 PRIMARY KEY (owner, username)
+             );",
+        )?;
+        // Settings that cannot be attributed to one account are kept for
+        // every account rather than dropped.
+        conn.execute(
+            "INSERT INTO conversations (owner, username, archived, muted, muted_until)
+             SELECT a.username, l.username, l.archived, l.muted, l.muted_until
+             FROM conversations_legacy l, account a
+             WHERE ?1 IS NULL OR a.username = ?1",
+            params![legacy_owner(conn)?],
+        )?;
+        conn.execute("DROP TABLE conversations_legacy", [])?;
+    }
+
+    Ok(())
+}
+
+/// Attributes messages written by older versions to the legacy owner.
+fn backfill_account_ownership(conn: &Connection) -> Result<()> {
+    if let Some(owner) = legacy_owner(conn)? {
+        for table in ["messages", "quarantine"] {
+            conn.execute(
+                &format!("UPDATE {} SET owner = ?1 WHERE owner IS NULL", table),
+                params![owner],
+            )?;
+        }
+        add_missing_conversations(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Legacy rows carry no owner, so they can only be attributed when it is
+/// unambiguous: the logged-in user, or the only account on this machine.
+fn legacy_owner(conn: &Connection) -> Result<Option<String>> {
+    let session_user: Option<String> = conn
+        .query_row("SELECT username FROM session WHERE id = 1", [], |row| {
+            row.get(0)
+        })
+        .ok();
+
+    if session_user.is_some() {
+        return Ok(session_user);
+    }
+
+    let mut stmt = conn.prepare("SELECT username FROM account")?;
+    let accounts = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if accounts.len() == 1 {
+        Ok(accounts.into_iter().next())
+    } else {
+        Ok(None)
+    }
+}
+
+/// The peer a legacy ratchet key stands for. `user_<id>` keys are matched
+/// through the user ids older versions recorded in `user_devices` whenever
+/// they looked a user up; `None` if the user was never looked up.
+fn resolve_legacy_peer(conn: &Connection, owner: &str, legacy_key: &str) -> Result<Option<String>> {
+    let user_id = match legacy_key
+        .strip_prefix("user_")
+        .and_then(|id| id.parse::<i64>().ok())
+    {
+        Some(user_id) => user_id,
+        None => return Ok(Some(legacy_key.to_string())),
+    };
+
+    Ok(conn
+        .query_row(
+            "SELECT username FROM user_devices WHERE owner = ?1 AND user_id = ?2",
+            params![owner, user_id],
+            |row| row.get(0),
+        )
+        .ok())
+}
+
+pub struct Message {
+    pub id: i64,
+    pub conversation_with: String,
+    pub sender: String,
+    pub recipient: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    pub is_outgoing: bool,
+    pub is_read: bool,
+    /// When an incoming message was stored, if different from `timestamp`.
+    pub received_at: Option<DateTime<Utc>>,
+}
+
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        id: row.get(0)?,
+        conversation_with: row.get(1)?,
+        sender: row.get(2)?,
+        recipient: row.get(3)?,
+        content: row.get(4)?,
+        timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
+            .unwrap()
+            .with_timezone(&Utc),
+        is_outgoing: row.get::<_, i32>(6)? != 0,
+        is_read: row.get::<_, i32>(7)? != 0,
+        received_at: row
+            .get::<_, Option<String>>(8)?
+            .and_then(|received_at| DateTime::parse_from_rfc3339(&received_at).ok())
+            .map(|received_at| received_at.with_timezone(&Utc)),
+    })
+}
+
+/// Stores a message. `message_id` is the id its sender gave it, shared by
+/// every copy of the message.
+pub fn save_message(
+    conversation_with: &str,
+    sender: &str,
+    recipient: &str,
+    content: &str,
+    is_outgoing: bool,
+    sent_at: Option<DateTime<Utc>>,
+    message_id: Option<&str>,
+) -> Result<i64> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let now = Utc::now();
+    let timestamp = sent_at.unwrap_or(now).to_rfc3339();
+    let received_at = (!is_outgoing).then(|| now.to_rfc3339());
+
+    conn.execute(
+        "INSERT INTO messages (conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read, owner, received_at, message_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![conversation_with, sender, recipient, content, timestamp, is_outgoing as i32, 0, owner, received_at, message_id],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    conn.execute(
+        "INSERT OR IGNORE INTO conversations (owner, username) VALUES (?1, ?2)",
+        params![owner, conversation_with],
+    )?;
+
+    Ok(id)
+}
+
+/// The id the sender gave message #`id`, if it has one.
+pub fn get_sender_message_id(id: i64) -> Result<Option<String>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    Ok(conn
+        .query_row(
+            "SELECT message_id FROM messages WHERE id = ?1 AND owner = ?2",
+            params![id, owner],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten())
+}
+
+/// Whether a copy of the message `sender` sent as `message_id` is already
+/// stored.
+pub fn has_message(sender: &str, message_id: &str) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM messages WHERE owner = ?1 AND sender = ?2 AND message_id = ?3",
+        params![owner, sender, message_id],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Stores one part of a split message. Once every part has arrived, the parts
+/// are removed and the reassembled text is returned. A message whose parts
+/// add up to more than `max_bytes` is dropped along with its stored parts.
+pub fn save_message_part(
+    sender: &str,
+    message_id: &str,
+    index: usize,
+    total: usize,
+    content: &str,
+    max_bytes: usize,
+) -> Result<Option<String>> {
+    if index >= total {
+        anyhow::bail!("Received part {} of a message with {} parts", index + 1, total);
+    }
+
+    let owner = current_account()?;
+    let conn = get_connection()?;
+
+    let stored: usize = conn.query_row(
+        "SELECT COALESCE(SUM(LENGTH(CAST(content AS BLOB))), 0) FROM message_parts
+         WHERE owner = ?1 AND sender = ?2 AND message_id = ?3 AND part_index != ?4",
+        params![owner, sender, message_id, index],
+        |row| row.get(0),
+    )?;
+    if stored + content.len() > max_bytes {
+        conn.execute(
+            "DELETE FROM message_parts WHERE owner = ?1 AND sender = ?2 AND message_id = ?3",
+            params![owner, sender, message_id],
+        )?;
+        anyhow::bail!("Received a split message larger than {} bytes", max_bytes);
+    }
+
+    conn.execute(
+        "INSERT OR REPLACE INTO message_parts
+             (owner, sender, message_id, part_index, total_parts, content, received_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            owner,
+            sender,
+            message_id,
+            index,
+            total,
+            content,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+
+    let received: usize = conn.query_row(
+        "SELECT COUNT(*) FROM message_parts WHERE owner = ?1 AND sender = ?2 AND message_id = ?3",
+        params![owner, sender, message_id],
+        |row| row.get(0),
+    )?;
+
+    if received < total {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT content FROM message_parts
+         WHERE owner = ?1 AND sender = ?2 AND message_id = ?3
+         ORDER BY part_index",
+    )?;
+    let parts = stmt
+        .query_map(params![owner, sender, message_id], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    conn.execute(
+        "DELETE FROM message_parts WHERE owner = ?1 AND sender = ?2 AND message_id = ?3",
+        params![owner, sender, message_id],
+    )?;
+
+    Ok(Some(parts.concat()))
+}
+
+pub fn get_message(message_id: i64) -> Result<Option<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let message = conn
+        .query_row(
+            "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read,
+                    received_at
+             FROM messages
+             WHERE id = ?1 AND owner = ?2 AND deleted_at IS NULL",
+            params![message_id, owner],
+            message_from_row,
+        )
+        .ok();
+    Ok(message)
+}
+
+pub fn mark_failed(message_id: i64, error: &str) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO failed_messages (message_id, error, failed_at) VALUES (?1, ?2, ?3)",
+        params![message_id, error, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+pub fn clear_failed(message_id: i64) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM failed_messages WHERE message_id = ?1",
+        params![message_id],
+    )?;
+    Ok(())
+}
+
+pub fn is_failed(message_id: i64) -> Result<bool> {
+    let conn = get_connection()?;
+    let failed: bool = conn.query_row(
+        "SELECT COUNT(*) FROM failed_messages WHERE message_id = ?1",
+        params![message_id],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+    Ok(failed)
+}
+
+/// Failed outgoing messages with the error of their last attempt, oldest first.
+pub fn get_failed_messages() -> Result<Vec<(Message, String)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.conversation_with, m.sender, m.recipient, m.content, m.timestamp,
+                m.is_outgoing, m.is_read, m.received_at, f.error
+         FROM messages m
+         JOIN failed_messages f ON f.message_id = m.id
+         WHERE m.owner = ?1 AND m.deleted_at IS NULL
+         ORDER BY m.timestamp",
+    )?;
+
+    let messages = stmt
+        .query_map(params![owner], |row| {
+            Ok((message_from_row(row)?, row.get::<_, String>(9)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub struct Attachment {
+    pub id: i64,
+    /// The conversation of the message it is attached to, if any.
+    pub conversation_with: Option<String>,
+    pub name: String,
+    pub size: u64,
+    pub blob: String,
+    pub created_at: DateTime<Utc>,
+    /// Why the file is held back until approved, if it is.
+    pub quarantined: Option<String>,
+}
+
+fn attachment_from_row(row: &rusqlite::Row) -> rusqlite::Result<Attachment> {
+    Ok(Attachment {
+        id: row.get(0)?,
+        conversation_with: row.get(1)?,
+        name: row.get(2)?,
+        size: row.get::<_, i64>(3)? as u64,
+        blob: row.get(4)?,
+        created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
+            .unwrap()
+            .with_timezone(&Utc),
+        quarantined: row.get(6)?,
+    })
+}
+
+pub fn save_attachment(message_id: Option<i64>, name: &str, size: u64, blob: &str) -> Result<i64> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO attachments (owner, message_id, name, size, blob, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            owner,
+            message_id,
+            name,
+            size as i64,
+            blob,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// The current account's attachments, newest first.
+pub fn get_attachments() -> Result<Vec<Attachment>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT a.id, m.conversation_with, a.name, a.size, a.blob, a.created_at,
+                a.quarantined
+         FROM attachments a
+         LEFT JOIN messages m ON m.id = a.message_id
+         WHERE a.owner = ?1
+         ORDER BY a.created_at DESC",
+    )?;
+
+    let attachments = stmt
+        .query_map(params![owner], attachment_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(attachments)
+}
+
+pub fn get_attachment(id: i64) -> Result<Option<Attachment>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let attachment = conn
+        .query_row(
+            "SELECT a.id, m.conversation_with, a.name, a.size, a.blob, a.created_at,
+                    a.quarantined
+             FROM attachments a
+             LEFT JOIN messages m ON m.id = a.message_id
+             WHERE a.id = ?1 AND a.owner = ?2",
+            params![id, owner],
+            attachment_from_row,
+        )
+        .ok();
+
+    Ok(attachment)
+}
+
+/// Holds an attachment back for `reason`, or with `None` releases it.
+/// Returns whether the attachment existed.
+pub fn set_attachment_quarantine(id: i64, reason: Option<&str>) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let updated = conn.execute(
+        "UPDATE attachments SET quarantined = ?1 WHERE id = ?2 AND owner = ?3",
+        params![reason, id, owner],
+    )?;
+
+    Ok(updated > 0)
+}
+
+/// Returns whether the attachment existed.
+pub fn delete_attachment(id: i64) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let deleted = conn.execute(
+        "DELETE FROM attachments WHERE id = ?1 AND owner = ?2",
+        params![id, owner],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+/// A file to fetch from the server's blob storage.
+pub struct AttachmentDownload {
+    pub id: i64,
+    pub message_id: i64,
+    pub sender: String,
+    pub name: String,
+    pub remote_id: String,
+    /// Key of the encrypted blob, base64-encoded.
+    pub key: Zeroizing<String>,
+    /// SHA-256 of the encrypted blob, hex-encoded.
+    pub digest: String,
+}
+
+pub fn save_attachment_download(
+    message_id: i64,
+    sender: &str,
+    name: &str,
+    remote_id: &str,
+    key: &str,
+    digest: &str,
+) -> Result<i64> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO attachment_downloads
+             (owner, message_id, sender, name, remote_id, key, digest, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            owner,
+            message_id,
+            sender,
+            name,
+            remote_id,
+            key,
+            digest,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// The current account's files still to download, oldest first.
+pub fn get_attachment_downloads() -> Result<Vec<AttachmentDownload>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, message_id, sender, name, remote_id, key, digest
+         FROM attachment_downloads
+         WHERE owner = ?1
+         ORDER BY id",
+    )?;
+
+    let downloads = stmt
+        .query_map(params![owner], |row| {
+            Ok(AttachmentDownload {
+                id: row.get(0)?,
+                message_id: row.get(1)?,
+                sender: row.get(2)?,
+                name: row.get(3)?,
+                remote_id: row.get(4)?,
+                key: Zeroizing::new(row.get(5)?),
+                digest: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(downloads)
+}
+
+pub fn delete_attachment_download(id: i64) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM attachment_downloads WHERE id = ?1 AND owner = ?2",
+        params![id, owner],
+    )?;
+    Ok(())
+}
+
+/// Blobs referenced by any account's attachments.
+pub fn attachment_blobs() -> Result<HashSet<String>> {
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare("SELECT DISTINCT blob FROM attachments")?;
+    let blobs = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    Ok(blobs)
+}
+
+/// How far sending an outbox envelope got.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutboxState {
+    /// Stored, not uploaded yet.
+    Queued,
+    /// Being uploaded; still set after a crash mid-upload.
+    Sending,
+    Sent,
+    Failed,
+}
+
+impl OutboxState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutboxState::Queued => "queued",
+            OutboxState::Sending => "sending",
+            OutboxState::Sent => "sent",
+            OutboxState::Failed => "failed",
+        }
+    }
+
+    fn parse(state: &str) -> Self {
+        match state {
+            "queued" => OutboxState::Queued,
+            "sending" => OutboxState::Sending,
+            "sent" => OutboxState::Sent,
+            _ => OutboxState::Failed,
+        }
+    }
+}
+
+pub struct OutboxEntry {
+    pub id: i64,
+    pub recipient: String,
+    /// The JSON body uploaded to `/message/send`.
+    pub envelope: String,
+    pub state: OutboxState,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// The stored message the envelope carries (a part of), if any.
+    pub message: Option<i64>,
+}
+
+/// Outbox columns read by `outbox_entry_from_row`, for `outbox o`.
+const OUTBOX_COLUMNS: &str = "o.id, o.recipient, o.envelope, o.state, o.error, o.attempts,
+     o.created_at, o.updated_at,
+     (SELECT m.id FROM messages m
+      WHERE m.owner = o.owner AND m.message_id = o.message_id AND m.is_outgoing = 1)";
+
+fn outbox_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<OutboxEntry> {
+    Ok(OutboxEntry {
+        id: row.get(0)?,
+        recipient: row.get(1)?,
+        envelope: row.get(2)?,
+        state: OutboxState::parse(&row.get::<_, String>(3)?),
+        error: row.get(4)?,
+        attempts: row.get(5)?,
+        created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
+            .unwrap()
+            .with_timezone(&Utc),
+        updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(7)?)
+            .unwrap()
+            .with_timezone(&Utc),
+        message: row.get(8)?,
+    })
+}
+
+/// Sent envelopes are kept this long for inspection.
+const OUTBOX_SENT_KEEP_DAYS: i64 = 7;
+
+/// Stores an envelope for `recipient` as queued, and forgets envelopes sent
+/// long enough ago. `message_id` is the sender-assigned id of the message
+/// the envelope carries, if any. Returns its outbox id.
+pub fn queue_outgoing(recipient: &str, message_id: Option<&str>, envelope: &str) -> Result<i64> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let now = Utc::now();
+
+    conn.execute(
+        "DELETE FROM outbox
+         WHERE owner = ?1 AND state = ?2 AND datetime(updated_at) < datetime(?3)",
+        params![
+            owner,
+            OutboxState::Sent.as_str(),
+            (now - chrono::Duration::days(OUTBOX_SENT_KEEP_DAYS)).to_rfc3339()
+        ],
+    )?;
+    conn.execute(
+        "INSERT INTO outbox (owner, recipient, envelope, state, created_at, updated_at, message_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6)",
+        params![
+            owner,
+            recipient,
+            envelope,
+            OutboxState::Queued.as_str(),
+            now.to_rfc3339(),
+            message_id
+        ],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// Moves an envelope to `state`, counting an attempt when it starts sending.
+pub fn set_outbox_state(id: i64, state: OutboxState, error: Option<&str>) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "UPDATE outbox
+         SET state = ?2, error = ?3, updated_at = ?4,
+             attempts = attempts + (CASE WHEN ?2 = 'sending' THEN 1 ELSE 0 END)
+         WHERE id = ?1",
+        params![id, state.as_str(), error, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// The current account's outbox, newest first.
+pub fn get_outbox() -> Result<Vec<OutboxEntry>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}
+         FROM outbox o
+         WHERE o.owner = ?1
+         ORDER BY o.created_at DESC, o.id DESC",
+        OUTBOX_COLUMNS
+    ))?;
+
+    let entries = stmt
+        .query_map(params![owner], outbox_entry_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(entries)
+}
+
+pub fn get_outbox_entry(id: i64) -> Result<Option<OutboxEntry>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let entry = conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM outbox o WHERE o.id = ?1 AND o.owner = ?2",
+                OUTBOX_COLUMNS
+            ),
+            params![id, owner],
+            outbox_entry_from_row,
+        )
+        .optional()?;
+
+    Ok(entry)
+}
+
+/// Drops the envelopes of the message sent as `message_id` that were never
+/// confirmed uploaded, before it is encrypted and sent again.
+pub fn discard_unsent_envelopes(message_id: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM outbox WHERE owner = ?1 AND message_id = ?2 AND state != ?3",
+        params![owner, message_id, OutboxState::Sent.as_str()],
+    )?;
+    Ok(())
+}
+
+pub fn quarantine_message(sender: &str, ciphertext: &str, header: &str, error: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO quarantine (sender, ciphertext, header, error, received_at, owner)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            sender,
+            ciphertext,
+            header,
+            error,
+            Utc::now().to_rfc3339(),
+            owner
+        ],
+    )?;
+    Ok(())
+}
+
+/// Quarantined messages as `(id, sender, ciphertext, header)`, oldest first.
+pub fn get_quarantined_messages() -> Result<Vec<(i64, String, String, String)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, sender, ciphertext, header FROM quarantine WHERE owner = ?1 ORDER BY id",
+    )?;
+
+    let messages = stmt
+        .query_map(params![owner], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub fn update_quarantine_error(id: i64, error: &str) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "UPDATE quarantine SET error = ?1 WHERE id = ?2",
+        params![error, id],
+    )?;
+    Ok(())
+}
+
+pub fn remove_quarantined(id: i64) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute("DELETE FROM quarantine WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+pub fn mark_mentioned(message_id: i64) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR IGNORE INTO mentions (message_id) VALUES (?1)",
+        params![message_id],
+    )?;
+    Ok(())
+}
+
+pub fn get_mentions(limit: usize) -> Result<Vec<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.conversation_with, m.sender, m.recipient, m.content, m.timestamp,
+                m.is_outgoing, m.is_read, m.received_at
+         FROM messages m
+         JOIN mentions mn ON mn.message_id = m.id
+         WHERE m.owner = ?2 AND m.deleted_at IS NULL
+         ORDER BY m.timestamp DESC
+         LIMIT ?1",
+    )?;
+
+    let messages = stmt
+        .query_map(params![limit, owner], message_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub fn get_messages(username: &str, limit: usize) -> Result<Vec<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read,
+                    received_at
+         FROM messages
+         WHERE conversation_with = ?1 AND owner = ?3 AND deleted_at IS NULL
+         ORDER BY timestamp DESC
+         LIMIT ?2",
+    )?;
+
+    let messages = stmt
+        .query_map(params![username, limit, owner], message_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub fn get_recent_messages(limit: usize) -> Result<Vec<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read,
+                    received_at
+         FROM messages
+         WHERE owner = ?2 AND deleted_at IS NULL
+         ORDER BY timestamp DESC
+         LIMIT ?1",
+    )?;
+
+    let messages = stmt
+        .query_map(params![limit, owner], message_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+/// Incoming messages this machine stored at or after `since`, oldest first.
+pub fn get_received_since(since: DateTime<Utc>) -> Result<Vec<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read,
+                    received_at
+         FROM messages
+         WHERE owner = ?2 AND is_outgoing = 0 AND deleted_at IS NULL
+           AND julianday(received_at) >= julianday(?1)
+         ORDER BY received_at, id",
+    )?;
+
+    let messages = stmt
+        .query_map(params![since.to_rfc3339(), owner], message_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub fn star_message(message_id: i64) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) FROM messages WHERE id = ?1 AND owner = ?2 AND deleted_at IS NULL",
+        params![message_id, owner],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+
+    if !exists {
+        anyhow::bail!("Message #{} not found", message_id);
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO starred_messages (message_id, starred_at) VALUES (?1, ?2)",
+        params![message_id, Utc::now().to_rfc3339()],
+    )?;
+
+    Ok(())
+}
+
+pub fn unstar_message(message_id: i64) -> Result<()> {
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM starred_messages WHERE message_id = ?1",
+        params![message_id],
+    )?;
+    Ok(())
+}
+
+pub fn get_starred_messages() -> Result<Vec<Message>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.conversation_with, m.sender, m.recipient, m.content, m.timestamp,
+                m.is_outgoing, m.is_read, m.received_at
+         FROM messages m
+         JOIN starred_messages s ON s.message_id = m.id
+         WHERE m.owner = ?1 AND m.deleted_at IS NULL
+         ORDER BY m.timestamp DESC",
+    )?;
+
+    let messages = stmt
+        .query_map(params![owner], message_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+pub struct Conversation {
+    pub username: String,
+    pub archived: bool,
+    pub muted: bool,
+    /// Messages are deleted this many seconds after they were sent.
+    pub expire_timer: Option<u64>,
+    pub verified: bool,
+    /// Time and content of the newest message, if any are left.
+    pub last_message: Option<(DateTime<Utc>, String)>,
+    pub unread: i32,
+}
+
+/// The current account's conversations, most recently active first.
+pub fn get_conversations(include_archived: bool) -> Result<Vec<Conversation>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT c.username, c.archived, c.muted, c.muted_until, c.expire_timer,
+                MAX(m.timestamp) as last_message_time,
+                (SELECT content FROM messages m2
+                 WHERE m2.conversation_with = c.username AND m2.owner = c.owner
+                   AND m2.deleted_at IS NULL
+                 ORDER BY timestamp DESC LIMIT 1) as last_message,
+                SUM(CASE WHEN m.is_read = 0 AND m.is_outgoing = 0 THEN 1 ELSE 0 END) as unread_count,
+                EXISTS (SELECT 1 FROM verified_contacts v
+                        JOIN contacts k ON k.owner = v.owner AND k.username = v.username
+                        WHERE v.owner = c.owner AND v.username = c.username
+                          AND v.identity_key = k.identity_key)
+         FROM conversations c
+         LEFT JOIN messages m ON m.owner = c.owner AND m.conversation_with = c.username
+             AND m.deleted_at IS NULL
+         WHERE c.owner = ?2 AND (?1 OR c.archived = 0)
+         GROUP BY c.username
+         ORDER BY COALESCE(last_message_time, c.last_read_at) DESC",
+    )?;
+
+    let conversations = stmt
+        .query_map(params![include_archived, owner], |row| {
+            let last_message_time = row.get::<_, Option<String>>(5)?;
+            let last_message = row.get::<_, Option<String>>(6)?;
+
+            Ok(Conversation {
+                username: row.get(0)?,
+                archived: row.get::<_, i32>(1)? != 0,
+                muted: is_muted_now(row.get(2)?, row.get(3)?),
+                expire_timer: row.get::<_, Option<i64>>(4)?.map(|secs| secs as u64),
+                last_message: last_message_time.zip(last_message).map(|(time, content)| {
+                    (
+                        DateTime::parse_from_rfc3339(&time)
+                            .unwrap()
+                            .with_timezone(&Utc),
+                        content,
+                    )
+                }),
+                unread: row.get::<_, Option<i32>>(7)?.unwrap_or(0),
+                verified: row.get(8)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(conversations)
+}
+
+pub fn set_archived(username: &str, archived: bool) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO conversations (owner, username, archived) VALUES (?3, ?1, ?2)
+         ON CONFLICT(owner, username) DO UPDATE SET archived = excluded.archived",
+        params![username, archived as i32, owner],
+    )?;
+    Ok(())
+}
+
+/// Mutes a conversation until `until`, or indefinitely when `None`.
+pub fn set_muted(username: &str, until: Option<DateTime<Utc>>) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO conversations (owner, username, muted, muted_until) VALUES (?3, ?1, 1, ?2)
+         ON CONFLICT(owner, username) DO UPDATE SET muted = 1, muted_until = excluded.muted_until",
+        params![username, until.map(|t| t.to_rfc3339()), owner],
+    )?;
+    Ok(())
+}
+
+pub fn clear_muted(username: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "UPDATE conversations SET muted = 0, muted_until = NULL WHERE owner = ?2 AND username = ?1",
+        params![username, owner],
+    )?;
+    Ok(())
+}
+
+pub fn is_muted(username: &str) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let row: Option<(i32, Option<String>)> = conn
+        .query_row(
+            "SELECT muted, muted_until FROM conversations WHERE owner = ?2 AND username = ?1",
+            params![username, owner],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .ok();
+
+    Ok(row.is_some_and(|(muted, until)| is_muted_now(muted, until)))
+}
+
+fn is_muted_now(muted: i32, until: Option<String>) -> bool {
+    match (muted, until) {
+        (0, _) => false,
+        (_, None) => true,
+        (_, Some(until)) => DateTime::parse_from_rfc3339(&until)
+            .is_ok_and(|until| until.with_timezone(&Utc) > Utc::now()),
+    }
+}
+
+/// Sets the name shown for `username` on this account; `None` removes it.
+pub fn set_nickname(username: &str, nickname: Option<&str>) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO conversations (owner, username, nickname) VALUES (?3, ?1, ?2)
+         ON CONFLICT(owner, username) DO UPDATE SET nickname = excluded.nickname",
+        params![username, nickname, owner],
+    )?;
+    Ok(())
+}
+
+pub fn get_nickname(username: &str) -> Result<Option<String>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let nickname = conn
+        .query_row(
+            "SELECT nickname FROM conversations WHERE owner = ?2 AND username = ?1",
+            params![username, owner],
+            |row| row.get(0),
+        )
+        .ok()
+        .flatten();
+    Ok(nickname)
+}
+
+/// Makes messages with `username` disappear `seconds` after they were sent;
+/// `None` keeps them.
+pub fn set_expire_timer(username: &str, seconds: Option<u64>) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO conversations (owner, username, expire_timer) VALUES (?3, ?1, ?2)
+         ON CONFLICT(owner, username) DO UPDATE SET expire_timer = excluded.expire_timer",
+        params![username, seconds.map(|seconds| seconds as i64), owner],
+    )?;
+    Ok(())
+}
+
+/// Deletes messages of every account whose conversation timer has run out.
+/// Returns how many were deleted.
+pub fn expire_messages() -> Result<usize> {
+    let mut conn = get_connection()?;
+
+    let conversations: Vec<(String, String, i64)> = {
+        let mut stmt = conn.prepare(
+            "SELECT owner, username, expire_timer FROM conversations
+             WHERE expire_timer IS NOT NULL AND expire_timer > 0",
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    if conversations.is_empty() {
+        return Ok(0);
+    }
+
+    conn.pragma_update(None, "secure_delete", true)?;
+    let tx = conn.transaction()?;
+
+    let mut deleted = 0;
+    for (owner, username, seconds) in conversations {
+        let before = Utc::now() - chrono::Duration::seconds(seconds);
+        deleted += delete_messages(&tx, &owner, &username, Some(before))?;
+    }
+
+    tx.commit()?;
+
+    if deleted > 0 {
+        reclaim_space(&conn)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Overrides the retention period for a conversation; `None` returns it to
+/// the default.
+pub fn set_retention(username: &str, days: Option<u32>) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO conversations (owner, username, retention_days) VALUES (?3, ?1, ?2)
+         ON CONFLICT(owner, username) DO UPDATE SET retention_days = excluded.retention_days",
+        params![username, days, owner],
+    )?;
+    Ok(())
+}
+
+/// Conversations of the current account with their own retention period.
+pub fn get_retention_overrides() -> Result<Vec<(String, u32)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT username, retention_days FROM conversations
+         WHERE owner = ?1 AND retention_days IS NOT NULL
+         ORDER BY username",
+    )?;
+
+    let overrides = stmt
+        .query_map(params![owner], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(overrides)
+}
+
+/// Deletes messages of every account that are older than their
+/// conversation's retention period, or `default_days` where it has none.
+/// Returns how many were deleted.
+pub fn apply_retention(default_days: Option<u32>) -> Result<usize> {
+    let mut conn = get_connection()?;
+
+    let conversations: Vec<(String, String, Option<u32>)> = {
+        let mut stmt = conn.prepare("SELECT owner, username, retention_days FROM conversations")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+
+    conn.pragma_update(None, "secure_delete", true)?;
+    let tx = conn.transaction()?;
+
+    let mut deleted = 0;
+    for (owner, username, days) in conversations {
+        let days = match days.or(default_days) {
+            Some(days) if days > 0 => days,
+            _ => continue,
+        };
+        let before = Utc::now() - chrono::Duration::days(days.into());
+        deleted += delete_messages(&tx, &owner, &username, Some(before))?;
+    }
+
+    tx.commit()?;
+
+    if deleted > 0 {
+        reclaim_space(&conn)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Removes everything stored for the local account `username`: the account
+/// itself, its ratchet sessions, messages and conversation settings.
+pub fn delete_account(username: &str) -> Result<()> {
+    let mut conn = get_connection()?;
+    conn.pragma_update(None, "secure_delete", true)?;
+    let tx = conn.transaction()?;
+
+    let owned = "SELECT id FROM messages WHERE owner = ?1";
+    for table in ["starred_messages", "mentions", "failed_messages"] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE message_id IN ({})", table, owned),
+            params![username],
+        )?;
+    }
+
+    for table in [
+        "messages",
+        "quarantine",
+        "conversations",
+        "outbox",
+        "attachments",
+        "attachment_downloads",
+        "polls",
+        "poll_votes",
+        "contacts",
+        "verified_contacts",
+        "key_history",
+        "security_events",
+        "user_devices",
+        "contact_devices",
+        "profiles",
+        "message_parts",
+        "received_counters",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE owner = ?1", table),
+            params![username],
+        )?;
+    }
+
+    tx.execute(
+        "DELETE FROM ratchet_states WHERE substr(username, 1, length(?1)) = ?1",
+        params![conversation_key(username, "")],
+    )?;
+    tx.execute("DELETE FROM session WHERE username = ?1", params![username])?;
+    tx.execute("DELETE FROM account WHERE username = ?1", params![username])?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Deletes messages exchanged with `username` (optionally only those older
+/// than `before`) along with everything derived from them, then overwrites
+/// the freed pages so the plaintext does not linger on disk.
+pub fn purge_messages(username: &str, before: Option<DateTime<Utc>>) -> Result<usize> {
+    let owner = current_account()?;
+    let mut conn = get_connection()?;
+    conn.pragma_update(None, "secure_delete", true)?;
+
+    let tx = conn.transaction()?;
+    let deleted = delete_messages(&tx, &owner, username, before)?;
+    tx.commit()?;
+
+    reclaim_space(&conn)?;
+
+    Ok(deleted)
+}
+
+/// Deletes `owner`'s messages exchanged with `username` (optionally only
+/// those older than `before`) along with everything derived from them.
+fn delete_messages(
+    conn: &Connection,
+    owner: &str,
+    username: &str,
+    before: Option<DateTime<Utc>>,
+) -> Result<usize> {
+    let before = before.map(|dt| dt.to_rfc3339());
+
+    let selected = "SELECT id FROM messages
+                    WHERE conversation_with = ?1 AND owner = ?3
+                      AND (?2 IS NULL OR datetime(timestamp) < datetime(?2))";
+
+    for table in [
+        "starred_messages",
+        "mentions",
+        "failed_messages",
+        "attachments",
+        "attachment_downloads",
+    ] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE message_id IN ({})", table, selected),
+            params![username, before, owner],
+        )?;
+    }
+    conn.execute(
+        &format!(
+            "DELETE FROM poll_votes WHERE (owner, creator, poll_id) IN
+             (SELECT owner, creator, poll_id FROM polls WHERE message_id IN ({}))",
+            selected
+        ),
+        params![username, before, owner],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM polls WHERE message_id IN ({})", selected),
+        params![username, before, owner],
+    )?;
+    conn.execute(
+        "DELETE FROM message_parts
+         WHERE sender = ?1 AND owner = ?3
+           AND (?2 IS NULL OR datetime(received_at) < datetime(?2))",
+        params![username, before, owner],
+    )?;
+    let deleted = conn.execute(
+        "DELETE FROM messages
+         WHERE conversation_with = ?1 AND owner = ?3
+           AND (?2 IS NULL OR datetime(timestamp) < datetime(?2))",
+        params![username, before, owner],
+    )?;
+
+    Ok(deleted)
+}
+
+/// Moves one of the current account's messages to the trash. Returns whether
+/// there was such a message outside the trash.
+pub fn trash_message(message_id: i64) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let trashed = conn.execute(
+        "UPDATE messages SET deleted_at = ?3
+         WHERE id = ?1 AND owner = ?2 AND deleted_at IS NULL",
+        params![message_id, owner, Utc::now().to_rfc3339()],
+    )?;
+    Ok(trashed > 0)
+}
+
+/// Takes a message out of the trash. Returns whether it was in there.
+pub fn restore_message(message_id: i64) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let restored = conn.execute(
+        "UPDATE messages SET deleted_at = NULL
+         WHERE id = ?1 AND owner = ?2 AND deleted_at IS NOT NULL",
+        params![message_id, owner],
+    )?;
+    Ok(restored > 0)
+}
+
+/// Messages in the current account's trash with when they were moved there,
+/// most recently deleted first.
+pub fn get_trashed_messages() -> Result<Vec<(Message, DateTime<Utc>)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT id, conversation_with, sender, recipient, content, timestamp, is_outgoing, is_read,
+                received_at, deleted_at
+         FROM messages
+         WHERE owner = ?1 AND deleted_at IS NOT NULL
+         ORDER BY deleted_at DESC",
+    )?;
+
+    let messages = stmt
+        .query_map(params![owner], |row| {
+            Ok((
+                message_from_row(row)?,
+                DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
+                    .unwrap()
+                    .with_timezone(&Utc),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+/// Permanently deletes everything in the current account's trash. Returns
+/// how many messages were deleted.
+pub fn empty_trash() -> Result<usize> {
+    let owner = current_account()?;
+    delete_trashed(Some(&owner), None)
+}
+
+/// Permanently deletes messages of every account that were moved to the
+/// trash before `before`. Returns how many were deleted.
+pub fn expire_trash(before: DateTime<Utc>) -> Result<usize> {
+    delete_trashed(None, Some(before))
+}
+
+fn delete_trashed(owner: Option<&str>, before: Option<DateTime<Utc>>) -> Result<usize> {
+    let before = before.map(|dt| dt.to_rfc3339());
+    let mut conn = get_connection()?;
+    conn.pragma_update(None, "secure_delete", true)?;
+
+    let selected = "SELECT id FROM messages
+                    WHERE deleted_at IS NOT NULL AND (?1 IS NULL OR owner = ?1)
+                      AND (?2 IS NULL OR datetime(deleted_at) < datetime(?2))";
+
+    let tx = conn.transaction()?;
+    for table in [
+        "starred_messages",
+        "mentions",
+        "failed_messages",
+        "attachments",
+        "attachment_downloads",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE message_id IN ({})", table, selected),
+            params![owner, before],
+        )?;
+    }
+    tx.execute(
+        &format!(
+            "DELETE FROM poll_votes WHERE (owner, creator, poll_id) IN
+             (SELECT owner, creator, poll_id FROM polls WHERE message_id IN ({}))",
+            selected
+        ),
+        params![owner, before],
+    )?;
+    tx.execute(
+        &format!("DELETE FROM polls WHERE message_id IN ({})", selected),
+        params![owner, before],
+    )?;
+    let deleted = tx.execute(
+        &format!("DELETE FROM messages WHERE id IN ({})", selected),
+        params![owner, before],
+    )?;
+    tx.commit()?;
+
+    if deleted > 0 {
+        reclaim_space(&conn)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Returns the pages freed by deleted messages to the file system, and
+/// empties the write-ahead log, which still holds copies of them.
+fn reclaim_space(conn: &Connection) -> Result<()> {
+    let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+    if auto_vacuum == 2 {
+        conn.execute_batch("PRAGMA incremental_vacuum")?;
+    } else {
+        // Switching vacuum mode needs a full VACUUM, which also rewrites the file.
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+        conn.execute_batch("VACUUM")?;
+    }
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+    Ok(())
+}
+
+/// Message counts per local calendar day for a conversation, oldest first.
+pub fn get_daily_message_counts(username: &str, days: i64) -> Result<Vec<(NaiveDate, i64)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT date(timestamp, 'localtime') as day, COUNT(*)
+         FROM messages
+         WHERE conversation_with = ?1 AND owner = ?3 AND deleted_at IS NULL
+           AND date(timestamp, 'localtime') >= date('now', 'localtime', ?2)
+         GROUP BY day
+         ORDER BY day",
+    )?;
+
+    let counts = stmt
+        .query_map(params![username, format!("-{} days", days), owner], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter_map(|(day, count)| {
+            NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+                .ok()
+                .map(|day| (day, count))
+        })
+        .collect();
+
+    Ok(counts)
+}
+
+/// Message counts per local hour of day (0-23) for a conversation.
+pub fn get_hourly_message_counts(username: &str) -> Result<[i64; 24]> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT CAST(strftime('%H', timestamp, 'localtime') AS INTEGER) as hour, COUNT(*)
+         FROM messages
+         WHERE conversation_with = ?1 AND owner = ?2 AND deleted_at IS NULL
+         GROUP BY hour",
+    )?;
+
+    let mut counts = [0; 24];
+    let rows = stmt.query_map(params![username, owner], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+    })?;
+
+    for row in rows {
+        let (hour, count) = row?;
+        if (0..24).contains(&hour) {
+            counts[hour as usize] = count;
+        }
+    }
+
+    Ok(counts)
+}
+
+pub struct Poll {
+    pub poll_id: String,
+    pub creator: String,
+    /// The contact the poll was shared with or received from.
+    pub conversation: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Number of votes per option, in the same order as `options`.
+    pub tally: Vec<i64>,
+}
+
+pub fn save_poll(
+    poll_id: &str,
+    message_id: i64,
+    creator: &str,
+    question: &str,
+    options: &[String],
+) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR IGNORE INTO polls (owner, poll_id, message_id, creator, question, options)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            owner,
+            poll_id,
+            message_id,
+            creator,
+            question,
+            serde_json::to_string(options)?
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn record_vote(creator: &str, poll_id: &str, voter: &str, option_index: usize) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO poll_votes (owner, creator, poll_id, voter, option_index, voted_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![owner, creator, poll_id, voter, option_index, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Poll columns read by `load_poll`, for `polls p JOIN messages m`.
+const POLL_COLUMNS: &str = "p.poll_id, p.creator, m.conversation_with, p.question, p.options
+     FROM polls p JOIN messages m ON m.id = p.message_id";
+
+pub fn get_poll(creator: &str, poll_id: &str) -> Result<Option<Poll>> {
+    let owner = current_account()?;
+    load_poll(
+        &owner,
+        &format!(
+            "SELECT {} WHERE p.owner = ?1 AND p.creator = ?2 AND p.poll_id = ?3",
+            POLL_COLUMNS
+        ),
+        params![owner, creator, poll_id],
+    )
+}
+
+/// The poll `poll_id` in the conversation with `username`, created by
+/// either of us; the newest if both happened to pick the same id.
+pub fn find_poll(username: &str, poll_id: &str) -> Result<Option<Poll>> {
+    let owner = current_account()?;
+    load_poll(
+        &owner,
+        &format!(
+            "SELECT {} WHERE p.owner = ?1 AND m.conversation_with = ?2 AND p.poll_id = ?3
+             ORDER BY m.timestamp DESC LIMIT 1",
+            POLL_COLUMNS
+        ),
+        params![owner, username, poll_id],
+    )
+}
+
+pub fn get_poll_for_message(message_id: i64) -> Result<Option<Poll>> {
+    let owner = current_account()?;
+    load_poll(
+        &owner,
+        &format!(
+            "SELECT {} WHERE p.owner = ?1 AND p.message_id = ?2",
+            POLL_COLUMNS
+        ),
+        params![owner, message_id],
+    )
+}
+
+fn load_poll(owner: &str, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Option<Poll>> {
+    let conn = get_connection()?;
+
+    let row: Option<(String, String, String, String, String)> = conn
+        .query_row(sql, params, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })
+        .optional()?;
+
+    let Some((poll_id, creator, conversation, question, options)) = row else {
+        return Ok(None);
+    };
+
+    let options: Vec<String> = serde_json::from_str(&options)?;
+    let mut tally = vec![0; options.len()];
+
+    let mut stmt = conn.prepare(
+        "SELECT option_index, COUNT(*) FROM poll_votes
+         WHERE owner = ?1 AND creator = ?2 AND poll_id = ?3
+         GROUP BY option_index",
+    )?;
+    let counts = stmt.query_map(params![owner, creator, poll_id], |row| {
+        Ok((row.get::<_, usize>(0)?, row.get::<_, i64>(1)?))
+    })?;
+
+    for count in counts {
+        let (index, votes) = count?;
+        if let Some(slot) = tally.get_mut(index) {
+            *slot = votes;
+        }
+    }
+
+    Ok(Some(Poll {
+        poll_id,
+        creator,
+        conversation,
+        question,
+        options,
+        tally,
+    }))
+}
+
+pub fn save_contact_identity(username: &str, identity_key: &[u8], key_bundle: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO contacts (owner, username, identity_key, key_bundle, last_fetched)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(owner, username) DO UPDATE SET
+             identity_key = excluded.identity_key,
+             key_bundle = excluded.key_bundle,
+             last_fetched = excluded.last_fetched",
+        params![owner, username, identity_key, key_bundle, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// The key bundles of `username`'s devices as saved by
+/// `save_contact_identity`, if they were fetched after `fresh_since`.
+pub fn get_cached_key_bundles(
+    username: &str,
+    fresh_since: DateTime<Utc>,
+) -> Result<Option<String>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let cached: Option<(Option<String>, String)> = conn
+        .query_row(
+            "SELECT key_bundle, last_fetched FROM contacts WHERE owner = ?1 AND username = ?2",
+            params![owner, username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    let Some((Some(key_bundle), last_fetched)) = cached else {
+        return Ok(None);
+    };
+    if DateTime::parse_from_rfc3339(&last_fetched)?.with_timezone(&Utc) < fresh_since {
+        return Ok(None);
+    }
+
+    Ok(Some(key_bundle))
+}
+
+/// Pins `identity_key` for `username` unless a key is pinned already.
+pub fn pin_contact_identity(username: &str, identity_key: &[u8]) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO contacts (owner, username, identity_key, last_fetched)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(owner, username) DO NOTHING",
+        params![owner, username, identity_key, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+pub fn get_contact_identity(username: &str) -> Result<Option<Vec<u8>>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let identity_key = conn
+        .query_row(
+            "SELECT identity_key FROM contacts WHERE owner = ?1 AND username = ?2",
+            params![owner, username],
+            |row| row.get(0),
+        )
+        .ok();
+    Ok(identity_key)
+}
+
+/// Records that `identity_key` was observed for `username`.
+pub fn record_identity_key(username: &str, identity_key: &[u8]) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO key_history (owner, username, identity_key, first_seen, last_seen)
+         VALUES (?1, ?2, ?3, ?4, ?4)
+         ON CONFLICT(owner, username, identity_key) DO UPDATE SET last_seen = excluded.last_seen",
+        params![owner, username, identity_key, now],
+    )?;
+    Ok(())
+}
+
+/// Records that message `n` of the sending chain `ratchet_key` from
+/// `sender` was decrypted. Returns false if it had been seen before.
+pub fn record_received_counter(sender: &str, ratchet_key: &[u8], n: i64) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO received_counters (owner, sender, ratchet_key, n, received_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![owner, sender, ratchet_key, n, Utc::now().to_rfc3339()],
+    )?;
+    Ok(inserted > 0)
+}
+
+pub fn is_counter_seen(sender: &str, ratchet_key: &[u8], n: i64) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let seen: bool = conn.query_row(
+        "SELECT COUNT(*) FROM received_counters
+         WHERE owner = ?1 AND sender = ?2 AND ratchet_key = ?3 AND n = ?4",
+        params![owner, sender, ratchet_key, n],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+    Ok(seen)
+}
+
+pub fn record_security_event(username: &str, event: &str, details: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO security_events (owner, username, event, details, occurred_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![owner, username, event, details, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Security events for `username` as (event, details, when), oldest first.
+pub fn get_security_events(username: &str) -> Result<Vec<(String, String, DateTime<Utc>)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT event, details, occurred_at FROM security_events
+         WHERE owner = ?1 AND username = ?2
+         ORDER BY id",
+    )?;
+
+    let events = stmt
+        .query_map(params![owner, username], |row| {
+            let occurred_at: String = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                DateTime::parse_from_rfc3339(&occurred_at)
+                    .unwrap()
+                    .with_timezone(&Utc),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(events)
+}
+
+/// Every identity key seen for `username` as (key, first seen, last seen),
+/// oldest first.
+pub fn get_key_history(username: &str) -> Result<Vec<(Vec<u8>, DateTime<Utc>, DateTime<Utc>)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT identity_key, first_seen, last_seen FROM key_history
+         WHERE owner = ?1 AND username = ?2
+         ORDER BY first_seen",
+    )?;
+
+    let history = stmt
+        .query_map(params![owner, username], |row| {
+            let first_seen: String = row.get(1)?;
+            let last_seen: String = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                DateTime::parse_from_rfc3339(&first_seen)
+                    .unwrap()
+                    .with_timezone(&Utc),
+                DateTime::parse_from_rfc3339(&last_seen)
+                    .unwrap()
+                    .with_timezone(&Utc),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(history)
+}
+
+pub fn set_verified(username: &str, identity_key: &[u8]) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO verified_contacts (owner, username, identity_key, verified_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![owner, username, identity_key, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+pub fn clear_verified(username: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM verified_contacts WHERE owner = ?1 AND username = ?2",
+        params![owner, username],
+    )?;
+    Ok(())
+}
+
+/// A contact is verified only while the identity key that was verified is
+/// still the one on record for them.
+pub fn is_verified(username: &str) -> Result<bool> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let verified: bool = conn.query_row(
+        "SELECT COUNT(*) FROM verified_contacts v
+         JOIN contacts c ON c.owner = v.owner AND c.username = v.username
+         WHERE v.owner = ?1 AND v.username = ?2 AND v.identity_key = c.identity_key",
+        params![owner, username],
+        |row| row.get::<_, i32>(0).map(|count| count > 0),
+    )?;
+    Ok(verified)
+}
+
+pub fn save_profile(
+    username: &str,
+    display_name: Option<&str>,
+    status: Option<&str>,
+) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO profiles (owner, username, display_name, status, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![owner, username, display_name, status, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Display name and status last seen for `username`.
+pub fn get_profile(username: &str) -> Result<(Option<String>, Option<String>)> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let profile = conn
+        .query_row(
+            "SELECT display_name, status FROM profiles WHERE owner = ?1 AND username = ?2",
+            params![owner, username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap_or((None, None));
+    Ok(profile)
+}
+
+pub fn get_unread_counts() -> Result<Vec<(String, i32)>> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT conversation_with, COUNT(*) as unread_count
+         FROM messages
+         WHERE is_read = 0 AND is_outgoing = 0 AND owner = ?1 AND deleted_at IS NULL
+         GROUP BY conversation_with
+         ORDER BY MAX(timestamp) DESC",
+    )?;
+
+    let counts = stmt
+        .query_map(params![owner], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(counts)
+}
+
+/// Marks the received messages among `message_ids` read, e.g. the ones a
+/// listing showed, leaving the rest of their conversations unread.
+pub fn mark_listed_messages_as_read(message_ids: &[i64]) -> Result<()> {
+    let owner = current_account()?;
+
+    with_transaction(|| {
+        let conn = get_connection()?;
+        for message_id in message_ids {
+            conn.execute(
+                "UPDATE messages SET is_read = 1 WHERE id = ?1 AND is_outgoing = 0 AND owner = ?2",
+                params![message_id, owner],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+pub fn mark_messages_as_read(username: &str) -> Result<()> {
+    let owner = current_account()?;
+    let conn = get_connection()?;
+    conn.execute(
+        "UPDATE messages SET is_read = 1
+         WHERE conversation_with = ?1 AND is_outgoing = 0 AND owner = ?2",
+        params![username, owner],
+    )?;
+    conn.execute(
+        "UPDATE conversations SET last_read_at = ?3 WHERE owner = ?2 AND username = ?1",
+        params![username, owner, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Identity key of the `alice` account `test_database` logs in as.
+#[cfg(test)]
+pub const TEST_IDENTITY_KEY: [u8; 32] = [1; 32];
+
+/// Points every test in this process at one fresh database, logged in as
+/// `alice`. Tests keep apart by using their own peers and message ids.
+#[cfg(test)]
+pub fn test_database() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("dood-test-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        config::set_data_dir(&dir);
+        select_account("alice");
+        init().unwrap();
+        add_test_account("alice", &TEST_IDENTITY_KEY);
+    });
+}
+
+/// Adds an account with `identity_key` and no usable private keys.
+#[cfg(test)]
+pub fn add_test_account(username: &str, identity_key: &[u8]) {
+    get_connection()
+        .unwrap()
+        .execute(
+            "INSERT OR IGNORE INTO account
+                 (username, identity_private_key, identity_public_key, signed_pre_key_private,
+                  signed_pre_key_public, signed_pre_key_signature, key_bundle, server_url,
+                  created_at)
+             VALUES (?1, x'', ?2, x'', x'', x'', '{}', 'https://dood.example', ?3)",
+            params![username, identity_key, Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_apply_in_order_to_an_empty_database() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        for migration in MIGRATIONS {
+            migration(&conn).unwrap();
+        }
+
+        // Columns added by later migrations.
+        for query in [
+            "SELECT owner, message_id FROM outbox",
+            "SELECT quarantined FROM attachments",
+            "SELECT key, digest FROM attachment_downloads",
+        ] {
+            conn.prepare(query).unwrap();
+        }
+    }
+
+    #[test]
+    fn migrations_build_on_earlier_ones() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        assert!(add_outbox_message_id(&conn).is_err());
+        assert!(add_attachment_quarantine(&conn).is_err());
+    }
+
+    #[test]
+    fn legacy_sessions_are_keyed_by_conversation() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Rows as an old version left them, before any migration.
+        create_initial_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO account (username, identity_private_key, identity_public_key,
+                                  signed_pre_key_private, signed_pre_key_public,
+                                  signed_pre_key_signature, key_bundle, server_url, created_at)
+                 VALUES ('alice', x'', x'', x'', x'', x'', '{}', 'https://dood.example',
+                         '2024-01-01T00:00:00Z');
+             INSERT INTO user_devices (username, user_id, device_id, last_updated)
+                 VALUES ('bob', 7, 1, '2024-01-01T00:00:00Z');
+             INSERT INTO ratchet_states (username, state_data, last_updated) VALUES
+                 ('user_7', '{}', '2024-01-01T00:00:00Z'),
+                 ('user_8', '{}', '2024-01-01T00:00:00Z'),
+                 ('carol', '{}', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+        for migration in &MIGRATIONS[1..] {
+            migration(&conn).unwrap();
+        }
+
+        assert_eq!(migrate_legacy_ratchet_states(&conn).unwrap(), 1);
+
+        let keys: Vec<String> = conn
+            .prepare("SELECT username FROM ratchet_states ORDER BY username")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(keys, ["alice:bob", "alice:carol", "user_8"]);
+    }
+
+    #[test]
+    fn init_records_every_migration_once() {
+        test_database();
+
+        init().unwrap();
+
+        let conn = get_connection().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        let applied: usize = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len());
+    }
+
+    fn stored_parts(message_id: &str) -> usize {
+        get_connection()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM message_parts WHERE message_id = ?1",
+                params![message_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn message_parts_reassemble_in_order_once_all_arrive() {
+        test_database();
+
+        let part = |index, text| save_message_part("bob", "parts-1", index, 3, text, 100).unwrap();
+
+        assert_eq!(part(2, "!"), None);
+        assert_eq!(part(0, "hello"), None);
+        // A part delivered twice is only stored once.
+        assert_eq!(part(0, "hello"), None);
+        assert_eq!(part(1, " world").as_deref(), Some("hello world!"));
+        assert_eq!(stored_parts("parts-1"), 0);
+    }
+
+    #[test]
+    fn message_parts_past_the_limit_are_dropped() {
+        test_database();
+
+        save_message_part("bob", "parts-2", 0, 2, "12345", 8).unwrap();
+        let error = save_message_part("bob", "parts-2", 1, 2, "6789", 8).unwrap_err();
+
+        assert!(error.to_string().contains("larger than 8 bytes"));
+        assert_eq!(stored_parts("parts-2"), 0);
+    }
+
+    #[test]
+    fn message_parts_out_of_range_are_refused() {
+        test_database();
+
+        assert!(save_message_part("bob", "parts-3", 3, 3, "x", 100).is_err());
+        assert_eq!(stored_parts("parts-3"), 0);
+    }
+
+    #[test]
+    fn resending_discards_only_unsent_envelopes_of_that_message() {
+        test_database();
+        let sent = queue_outgoing("carol", Some("resend-1"), "{}").unwrap();
+        set_outbox_state(sent, OutboxState::Sent, None).unwrap();
+        let failed = queue_outgoing("carol", Some("resend-1"), "{}").unwrap();
+        set_outbox_state(failed, OutboxState::Failed, Some("offline")).unwrap();
+        let queued = queue_outgoing("carol", Some("resend-1"), "{}").unwrap();
+        let other = queue_outgoing("carol", Some("resend-2"), "{}").unwrap();
+
+        discard_unsent_envelopes("resend-1").unwrap();
+
+        assert!(get_outbox_entry(sent).unwrap().is_some());
+        assert!(get_outbox_entry(failed).unwrap().is_none());
+        assert!(get_outbox_entry(queued).unwrap().is_none());
+        assert!(get_outbox_entry(other).unwrap().is_some());
+    }
+
+    #[test]
+    fn outbox_entries_link_to_their_failed_message() {
+        test_database();
+        let message =
+            save_message("dave", "alice", "dave", "hi", true, None, Some("resend-3")).unwrap();
+        let envelope = queue_outgoing("dave", Some("resend-3"), "{}").unwrap();
+        set_outbox_state(envelope, OutboxState::Sending, None).unwrap();
+        set_outbox_state(envelope, OutboxState::Failed, Some("offline")).unwrap();
+        mark_failed(message, "offline").unwrap();
+
+        let entry = get_outbox_entry(envelope).unwrap().unwrap();
+
+        // `dood outbox retry` sends such entries to `dood resend` instead.
+        assert_eq!(entry.message, Some(message));
+        assert!(is_failed(message).unwrap());
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.state == OutboxState::Failed);
+    }
+
+    #[test]
+    fn purging_messages_deletes_their_attachments() {
+        test_database();
+        let message =
+            save_message("heidi", "heidi", "alice", "attached", false, None, None).unwrap();
+        save_attachment(Some(message), "photo.jpg", 3, "purged-blob").unwrap();
+        save_attachment_download(message, "heidi", "photo.jpg", "remote", "key", "digest").unwrap();
+
+        assert_eq!(purge_messages("heidi", None).unwrap(), 1);
+
+        let conn = get_connection().unwrap();
+        for table in ["attachments", "attachment_downloads"] {
+            let left: i64 = conn
+                .query_row(
+                    &format!("SELECT COUNT(*) FROM {} WHERE message_id = ?1", table),
+                    params![message],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(left, 0, "{} rows left behind", table);
+        }
+    }
+
+    #[test]
+    fn reclaiming_space_empties_the_log() {
+        const SECRET: &[u8] = b"purged plaintext";
+        let dir = std::env::temp_dir().join(format!("dood-wal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dood.db");
+        let conn = open_connection(&path).unwrap();
+        conn.pragma_update(None, "secure_delete", true).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notes (text TEXT);
+             INSERT INTO notes VALUES ('purged plaintext');
+             DELETE FROM notes;",
+        )
+        .unwrap();
+
+        reclaim_space(&conn).unwrap();
+
+        let wal = std::fs::metadata(with_suffix(&path, "-wal")).unwrap();
+        assert_eq!(wal.len(), 0);
+        let file = std::fs::read(&path).unwrap();
+        assert!(!file.windows(SECRET.len()).any(|bytes| bytes == SECRET));
+        drop(conn);
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use std::sync::mpsc;
+use tokio::sync::mpsc as async_mpsc;
+
+use crate::output;
+
+/// A line editor for chat with history, Ctrl-R search and tab-completion.
+/// Reading a line blocks, so the editor lives on its own thread and hands
+/// finished lines to the chat loop, which can keep handling incoming
+/// messages meanwhile. Everything printed through `output` while the editor
+/// runs goes through rustyline, which moves the prompt below it.
+pub struct LineEditor {
+    prompts: mpsc::Sender<()>,
+    lines: async_mpsc::UnboundedReceiver<Option<String>>,
+    prompting: bool,
+    _capture: output::Capture,
+}
+
+impl LineEditor {
+    /// Starts an editor that completes `commands` at the start of a line and
+    /// `contacts` elsewhere, as `@name` mentions or command arguments.
+    pub fn start(prompt: String, commands: Vec<String>, contacts: Vec<String>) -> Result<Self> {
+        let mut editor = Editor::<ChatHelper, DefaultHistory>::new()?;
+        editor.set_helper(Some(ChatHelper { commands, contacts }));
+        let mut printer = editor.create_external_printer()?;
+
+        let (prompts, prompt_requests) = mpsc::channel::<()>();
+        let (line_sender, lines) = async_mpsc::unbounded_channel();
+
+        // History stays in memory: it holds message text, which a history
+        // file would keep on disk where purging, retention and the database
+        // passphrase cannot reach it.
+        std::thread::spawn(move || {
+            while prompt_requests.recv().is_ok() {
+                let line = match editor.readline(&prompt) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            editor.add_history_entry(line.as_str()).ok();
+                        }
+                        Some(line)
+                    }
+                    // Ctrl-C and Ctrl-D leave the chat.
+                    Err(ReadlineError::Interrupted | ReadlineError::Eof) => None,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        None
+                    }
+                };
+
+                if line_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(LineEditor {
+            prompts,
+            lines,
+            prompting: false,
+            _capture: output::capture(move |event| {
+                let (output::Event::Status(line) | output::Event::Notice(line)) = event;
+                printer.print(line).ok();
+            }),
+        })
+    }
+
+    /// Shows the prompt, unless it is already showing, and waits for a line.
+    /// Returns `None` when the user leaves with Ctrl-C or Ctrl-D.
+    pub async fn next_line(&mut self) -> Option<String> {
+        if !self.prompting {
+            self.prompts.send(()).ok()?;
+            self.prompting = true;
+        }
+
+        let line = self.lines.recv().await.flatten();
+        self.prompting = false;
+        line
+    }
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ChatHelper {
+    commands: Vec<String>,
+    contacts: Vec<String>,
+}
+
+impl Completer for ChatHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+
+        let matching = |prefix: &str, names: &[String], sigil: &str| -> Vec<String> {
+            names
+                .iter()
+                .filter(|name| name.starts_with(prefix))
+                .map(|name| format!("{}{} ", sigil, name))
+                .collect()
+        };
+
+        let candidates = if start == 0 && word.starts_with('/') {
+            matching(word, &self.commands, "")
+        } else if let Some(prefix) = word.strip_prefix('@') {
+            matching(prefix, &self.contacts, "@")
+        } else if line.starts_with('/') {
+            matching(word, &self.contacts, "")
+        } else {
+            Vec::new()
+        };
+
+        Ok((start, candidates))
+    }
+}
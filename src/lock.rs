@@ -0,0 +1,591 @@
+use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use colored::*;
+use dialoguer::Password;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+use crate::messages::RemoteCommand;
+use crate::{auth, config, crypto, database, output};
+
+/// Prefix of `account.key_bundle` when the bundle is wrapped with a PIN.
+pub const PIN_PREFIX: &str = "@pin:";
+
+/// Setting holding how long an unlock is remembered, e.g. `15m`.
+const CACHE_SETTING: &str = "unlock_cache";
+const DEFAULT_CACHE: &str = "15m";
+
+/// Per-account settings for what happens after wrong PINs: how many are
+/// allowed before each further attempt has to wait, and after how many the
+/// account's local data is wiped (unset to never wipe).
+const DELAY_AFTER_SETTING: &str = "unlock_delay_after";
+const WIPE_AFTER_SETTING: &str = "unlock_wipe_after";
+const DEFAULT_DELAY_AFTER: u32 = 3;
+
+/// Longest wait between attempts, however many have failed.
+const MAX_DELAY_SECONDS: i64 = 60 * 60;
+
+pub fn set_pin(cache: Option<&str>) -> Result<()> {
+    let username = auth::get_current_username()?;
+    let stored = auth::stored_key_bundle(&username)?;
+
+    if stored.starts_with(PIN_PREFIX) {
+        anyhow::bail!("A PIN is already set. Run 'dood lock remove' first.");
+    }
+    if stored.starts_with('@') {
+        anyhow::bail!(
+            "Keys stored in the OS keychain cannot be PIN-locked. Run 'dood migrate-keys --to database' first."
+        );
+    }
+
+    if let Some(cache) = cache {
+        set_cache_duration(cache)?;
+    }
+
+    let pin: Zeroizing<String> = Password::new()
+        .with_prompt("New PIN")
+        .with_confirmation("Confirm PIN", "PINs do not match")
+        .interact()?
+        .into();
+
+    if pin.is_empty() {
+        anyhow::bail!("PIN must not be empty");
+    }
+
+    let envelope = crypto::encrypt_export(stored.as_bytes(), &pin)?;
+    let key = crypto::derive_envelope_key(&envelope, &pin)?;
+
+    auth::store_key_bundle(&username, &format!("{}{}", PIN_PREFIX, envelope))?;
+    cache_key(&username, &key)?;
+
+    println!(
+        "{} PIN lock enabled for '{}'",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    Ok(())
+}
+
+pub fn remove_pin() -> Result<()> {
+    let username = auth::get_current_username()?;
+    let stored = auth::stored_key_bundle(&username)?;
+
+    if !stored.starts_with(PIN_PREFIX) {
+        anyhow::bail!("No PIN is set for '{}'", username);
+    }
+
+    let bundle = unwrap_key_bundle(&username, &stored)?;
+    auth::store_key_bundle(&username, &bundle)?;
+    clear_cache(&username)?;
+
+    println!(
+        "{} PIN lock removed for '{}'",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    Ok(())
+}
+
+/// Forgets a cached unlock so the next command asks for the PIN again.
+pub fn lock_now() -> Result<()> {
+    let username = auth::get_current_username()?;
+    clear_cache(&username)?;
+    println!("{} Locked", "✓".green().bold());
+    Ok(())
+}
+
+pub fn set_cache_duration(duration: &str) -> Result<()> {
+    config::parse_duration(duration)?;
+    config::set_setting(CACHE_SETTING, duration.trim())?;
+    println!(
+        "{} Unlock is remembered for {}",
+        "✓".green().bold(),
+        duration.trim().bold()
+    );
+    Ok(())
+}
+
+/// Sets how many wrong PINs are allowed before attempts are delayed, and
+/// after how many the account's local data is wiped; a wipe limit of 0
+/// turns wiping off. Asks for the PIN even if an unlock is cached, so these
+/// cannot be loosened on a device left unlocked.
+pub fn set_lockout(delay_after: Option<u32>, wipe_after: Option<u32>) -> Result<()> {
+    let username = auth::get_current_username()?;
+    let stored = auth::stored_key_bundle(&username)?;
+
+    if !stored.starts_with(PIN_PREFIX) {
+        anyhow::bail!("No PIN is set for '{}'", username);
+    }
+
+    check_lockout(&username)?;
+
+    let pin: Zeroizing<String> = Password::new()
+        .with_prompt(format!("PIN for '{}'", username))
+        .interact()?
+        .into();
+
+    change_lockout(&username, &stored, &pin, delay_after, wipe_after)?;
+
+    let (delay_after, wipe_after) = lockout_settings(&username)?;
+    println!(
+        "{} Wrong PINs are delayed after {} attempt(s)",
+        "✓".green().bold(),
+        delay_after.to_string().bold()
+    );
+    match wipe_after {
+        Some(wipe_after) => println!(
+            "{}",
+            format!(
+                "⚠️  Local account data is wiped after {} wrong PINs in a row",
+                wipe_after
+            )
+            .yellow()
+        ),
+        None => println!("{}", "Local account data is never wiped.".bright_black()),
+    }
+
+    Ok(())
+}
+
+/// Writes the lockout settings of `username` once `pin` opens the stored key
+/// bundle. A wrong PIN counts like any other.
+fn change_lockout(
+    username: &str,
+    stored: &str,
+    pin: &str,
+    delay_after: Option<u32>,
+    wipe_after: Option<u32>,
+) -> Result<()> {
+    let envelope: serde_json::Value = serde_json::from_str(&stored[PIN_PREFIX.len()..])
+        .context("Corrupted PIN-locked key bundle")?;
+    let key = crypto::derive_envelope_key(&envelope, pin)?;
+    if crypto::open_envelope(&envelope, &key).is_err() {
+        return Err(record_failure(username)?);
+    }
+
+    if let Some(delay_after) = delay_after {
+        config::set_setting(
+            &lockout_setting(DELAY_AFTER_SETTING, username),
+            &delay_after.to_string(),
+        )?;
+    }
+
+    let wipe_setting = lockout_setting(WIPE_AFTER_SETTING, username);
+    match wipe_after {
+        Some(0) => config::delete_setting(&wipe_setting)?,
+        Some(wipe_after) => config::set_setting(&wipe_setting, &wipe_after.to_string())?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn lockout_setting(setting: &str, username: &str) -> String {
+    format!("{}:{}", setting, username)
+}
+
+fn lockout_settings(username: &str) -> Result<(u32, Option<u32>)> {
+    let parse = |setting: &str| -> Result<Option<u32>> {
+        let key = lockout_setting(setting, username);
+        config::get_setting(&key)?
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {} setting '{}'", key, value))
+            })
+            .transpose()
+    };
+
+    Ok((
+        parse(DELAY_AFTER_SETTING)?.unwrap_or(DEFAULT_DELAY_AFTER),
+        parse(WIPE_AFTER_SETTING)?,
+    ))
+}
+
+/// Wrong PINs entered for `username` since the last unlock, and when the
+/// last one was.
+fn failed_attempts(username: &str) -> Result<(u32, Option<DateTime<Utc>>)> {
+    let Some(value) = config::get_setting(&failures_setting(username))? else {
+        return Ok((0, None));
+    };
+
+    let (count, last) = value.split_once(' ').unwrap_or((&value, ""));
+    let last = DateTime::parse_from_rfc3339(last)
+        .ok()
+        .map(|last| last.with_timezone(&Utc));
+
+    Ok((count.parse().unwrap_or(0), last))
+}
+
+fn failures_setting(username: &str) -> String {
+    format!("unlock_failures:{}", username)
+}
+
+/// How long to wait after the last of `failures` wrong PINs: nothing for the
+/// first `delay_after`, then doubling from one second.
+fn lockout_delay(failures: u32, delay_after: u32) -> chrono::Duration {
+    let Some(excess) = failures.checked_sub(delay_after) else {
+        return chrono::Duration::zero();
+    };
+
+    let seconds = 1i64
+        .checked_shl(excess)
+        .filter(|seconds| *seconds > 0)
+        .map_or(MAX_DELAY_SECONDS, |seconds| seconds.min(MAX_DELAY_SECONDS));
+    chrono::Duration::seconds(seconds)
+}
+
+/// Refuses to ask for a PIN while the delay after earlier wrong ones runs.
+fn check_lockout(username: &str) -> Result<()> {
+    let (failures, last) = failed_attempts(username)?;
+    let (delay_after, _) = lockout_settings(username)?;
+
+    if let Some(last) = last {
+        let remaining = last + lockout_delay(failures, delay_after) - Utc::now();
+        if remaining > chrono::Duration::zero() {
+            anyhow::bail!(
+                "{} wrong PINs. Try again in {} second(s).",
+                failures,
+                remaining.num_seconds() + 1
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts a wrong PIN in the security log and, once the configured limit is
+/// reached, wipes the account's local data. Returns the error to report.
+fn record_failure(username: &str) -> Result<anyhow::Error> {
+    let (failures, _) = failed_attempts(username)?;
+    let failures = failures + 1;
+    let (delay_after, wipe_after) = lockout_settings(username)?;
+
+    database::record_security_event(
+        username,
+        "unlock_failed",
+        &format!("wrong PIN, attempt {}", failures),
+    )?;
+
+    if wipe_after.is_some_and(|wipe_after| failures >= wipe_after) {
+        auth::delete_local_account(username)?;
+        clear_cache(username)?;
+        config::delete_setting(&failures_setting(username))?;
+        return Ok(anyhow::anyhow!(
+            "Incorrect PIN. {} wrong PINs in a row: the local data of '{}' has been wiped.",
+            failures,
+            username
+        ));
+    }
+
+    config::set_setting(
+        &failures_setting(username),
+        &format!("{} {}", failures, Utc::now().to_rfc3339()),
+    )?;
+
+    let delay = lockout_delay(failures, delay_after);
+    if delay > chrono::Duration::zero() {
+        return Ok(anyhow::anyhow!(
+            "Incorrect PIN. Wait {} second(s) before trying again.",
+            delay.num_seconds()
+        ));
+    }
+    Ok(anyhow::anyhow!("Incorrect PIN"))
+}
+
+fn remote_setting(username: &str) -> String {
+    format!("remote_commands:{}", username)
+}
+
+fn pending_remote_setting(username: &str) -> String {
+    format!("remote_pending:{}", username)
+}
+
+/// Sets which commands from my other devices this device obeys: `none`,
+/// `lock`, or `wipe`, which includes locking.
+pub fn set_remote_commands(accept: &str) -> Result<()> {
+    let username = auth::get_current_username()?;
+
+    match accept {
+        "none" => config::delete_setting(&remote_setting(&username))?,
+        "lock" | "wipe" => config::set_setting(&remote_setting(&username), accept)?,
+        _ => anyhow::bail!(
+            "Unknown remote command '{}'. Use none, lock or wipe",
+            accept
+        ),
+    }
+
+    println!(
+        "{} This device obeys remote commands up to: {}",
+        "✓".green().bold(),
+        accept.bold()
+    );
+
+    Ok(())
+}
+
+/// A command stored under `key`: the most drastic one this device obeys,
+/// or one waiting to be carried out.
+fn remote_command_setting(key: &str) -> Result<Option<RemoteCommand>> {
+    Ok(match config::get_setting(key)?.as_deref() {
+        Some("lock") => Some(RemoteCommand::Lock),
+        Some("wipe") => Some(RemoteCommand::Wipe),
+        _ => None,
+    })
+}
+
+/// Remembers a command another of my devices sent, to be carried out by
+/// `obey_remote_command` once the messages around it are stored. Keeps the
+/// most drastic one if several arrive.
+pub fn queue_remote_command(username: &str, command: RemoteCommand) -> Result<()> {
+    let name = remote_command_name(command);
+    database::record_security_event(username, "remote_command", name)?;
+
+    let pending = remote_command_setting(&pending_remote_setting(username))?;
+    if pending.map_or(true, |pending| command > pending) {
+        config::set_setting(&pending_remote_setting(username), name)?;
+    }
+
+    Ok(())
+}
+
+fn remote_command_name(command: RemoteCommand) -> &'static str {
+    match command {
+        RemoteCommand::Lock => "lock",
+        RemoteCommand::Wipe => "wipe",
+    }
+}
+
+/// Carries out a command received from another of my devices, if this
+/// device accepts it. A wipe ends with an error, so nothing else runs on the
+/// deleted account.
+pub fn obey_remote_command(username: &str) -> Result<()> {
+    let Some(command) = remote_command_setting(&pending_remote_setting(username))? else {
+        return Ok(());
+    };
+    config::delete_setting(&pending_remote_setting(username))?;
+
+    let name = remote_command_name(command);
+    let accepted = remote_command_setting(&remote_setting(username))?;
+    if accepted.map_or(true, |accepted| command > accepted) {
+        output::notice(
+            format!(
+                "⚠️  Ignored a '{}' command from another of your devices. Run 'dood devices accept {}' here to obey it.",
+                name, name
+            )
+            .yellow(),
+        );
+        return Ok(());
+    }
+
+    match command {
+        RemoteCommand::Lock => {
+            clear_cache(username)?;
+            output::notice(format!("{} Locked by another of your devices", "🔒".bold()));
+            Ok(())
+        }
+        RemoteCommand::Wipe => {
+            clear_cache(username)?;
+            auth::delete_local_account(username)?;
+            config::delete_setting(&remote_setting(username))?;
+            anyhow::bail!(
+                "Another of your devices wiped the local data of '{}' from this machine",
+                username
+            )
+        }
+    }
+}
+
+/// Asks for the PIN of the current account if it is locked, so commands fail
+/// early instead of halfway through.
+pub fn ensure_unlocked() -> Result<()> {
+    if !auth::is_logged_in()? {
+        return Ok(());
+    }
+
+    let username = auth::get_current_username()?;
+    let stored = auth::stored_key_bundle(&username)?;
+
+    if stored.starts_with(PIN_PREFIX) {
+        unwrap_key_bundle(&username, &stored)?;
+    }
+
+    Ok(())
+}
+
+/// Decrypts a PIN-wrapped key bundle, using the cached key while it is valid.
+pub fn unwrap_key_bundle(username: &str, stored: &str) -> Result<Zeroizing<String>> {
+    let envelope: serde_json::Value = serde_json::from_str(&stored[PIN_PREFIX.len()..])
+        .context("Corrupted PIN-locked key bundle")?;
+
+    if let Some(key) = cached_key(username) {
+        if let Ok(bundle) = crypto::open_envelope(&envelope, &key) {
+            return Ok(Zeroizing::new(String::from_utf8(bundle.to_vec())?));
+        }
+    }
+
+    check_lockout(username)?;
+
+    let pin: Zeroizing<String> = Password::new()
+        .with_prompt(format!("PIN for '{}'", username))
+        .interact()?
+        .into();
+
+    let key = crypto::derive_envelope_key(&envelope, &pin)?;
+    let Ok(bundle) = crypto::open_envelope(&envelope, &key) else {
+        return Err(record_failure(username)?);
+    };
+
+    let (failures, _) = failed_attempts(username)?;
+    if failures > 0 {
+        output::notice(
+            format!(
+                "⚠️  {} wrong PIN(s) were entered since the last unlock. See 'dood key-history {}'.",
+                failures, username
+            )
+            .yellow(),
+        );
+        config::delete_setting(&failures_setting(username))?;
+    }
+
+    cache_key(username, &key)?;
+
+    Ok(Zeroizing::new(String::from_utf8(bundle.to_vec())?))
+}
+
+/// The unlock cache lives in a directory only the current user can enter,
+/// so other users can neither read it nor plant a file or link in its place.
+fn cache_path(username: &str) -> Result<PathBuf> {
+    let dir = config::runtime_dir().join("dood-unlock");
+
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    if let Err(e) = builder.create(&dir) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(e.into());
+        }
+    }
+
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            anyhow::bail!(
+                "{} can be accessed by other users; remove it and try again",
+                dir.display()
+            );
+        }
+    }
+
+    Ok(dir.join(username))
+}
+
+fn cache_key(username: &str, key: &[u8; 32]) -> Result<()> {
+    let setting = config::get_setting(CACHE_SETTING)?;
+    let duration = config::parse_duration(setting.as_deref().unwrap_or(DEFAULT_CACHE))?;
+
+    if duration.num_seconds() <= 0 {
+        return Ok(());
+    }
+
+    let encoded = Zeroizing::new(BASE64_STANDARD.encode(key));
+    let contents = Zeroizing::new(serde_json::to_string(
+        &crypto::SecretJson(serde_json::json!({
+            "key": encoded.as_str(),
+            "expires_at": (Utc::now() + duration).to_rfc3339(),
+        }))
+        .0,
+    )?);
+
+    // A new file every time, so whatever was at the path is never written
+    // through, and the mode is the one it was created with.
+    clear_cache(username)?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(cache_path(username)?)?
+        .write_all(contents.as_bytes())?;
+
+    Ok(())
+}
+
+fn cached_key(username: &str) -> Option<Zeroizing<[u8; 32]>> {
+    let contents = Zeroizing::new(fs::read_to_string(cache_path(username).ok()?).ok()?);
+    let cache = crypto::SecretJson(serde_json::from_str(&contents).ok()?);
+
+    let expires_at = DateTime::parse_from_rfc3339(cache.0["expires_at"].as_str()?).ok()?;
+    if expires_at.with_timezone(&Utc) <= Utc::now() {
+        return None;
+    }
+
+    let bytes = Zeroizing::new(BASE64_STANDARD.decode(cache.0["key"].as_str()?).ok()?);
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(bytes.get(..32)?);
+    Some(key)
+}
+
+fn clear_cache(username: &str) -> Result<()> {
+    match fs::remove_file(cache_path(username)?) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_delay_doubles_after_free_attempts() {
+        assert_eq!(lockout_delay(0, 3), chrono::Duration::zero());
+        assert_eq!(lockout_delay(2, 3), chrono::Duration::zero());
+        assert_eq!(lockout_delay(3, 3), chrono::Duration::seconds(1));
+        assert_eq!(lockout_delay(4, 3), chrono::Duration::seconds(2));
+        assert_eq!(lockout_delay(8, 3), chrono::Duration::seconds(32));
+    }
+
+    #[test]
+    fn lockout_delay_is_capped() {
+        assert_eq!(
+            lockout_delay(40, 3),
+            chrono::Duration::seconds(MAX_DELAY_SECONDS)
+        );
+        assert_eq!(
+            lockout_delay(u32::MAX, 0),
+            chrono::Duration::seconds(MAX_DELAY_SECONDS)
+        );
+    }
+
+    #[test]
+    fn lockout_settings_are_kept_after_a_wrong_pin() {
+        database::test_database();
+        let envelope = crypto::encrypt_export(b"{}", "1234").unwrap();
+        let stored = format!("{}{}", PIN_PREFIX, envelope);
+        change_lockout("grace", &stored, "1234", Some(5), Some(10)).unwrap();
+
+        assert!(change_lockout("grace", &stored, "0000", Some(u32::MAX), Some(0)).is_err());
+
+        assert_eq!(lockout_settings("grace").unwrap(), (5, Some(10)));
+        assert_eq!(failed_attempts("grace").unwrap().0, 1);
+    }
+}
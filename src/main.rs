@@ -1,18 +1,58 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
+mod api;
+mod attachments;
 mod auth;
 mod config;
 mod crypto;
+mod daemon;
 mod database;
+mod line_editor;
+mod lock;
 mod messages;
+mod output;
 mod server;
+mod text;
+mod theme;
+mod tls;
+mod tui;
 mod ui;
 
 #[derive(Parser)]
 #[command(name = "dood")]
 #[command(about = "DooD - End-to-End Encrypted Messenger CLI", long_about = None)]
 struct Cli {
+    /// Run the command as this account instead of the logged-in one
+    #[arg(long, global = true)]
+    account: Option<String>,
+
+    /// Keep all data in this directory instead of the default one (also DOOD_DATA_DIR)
+    #[arg(long, global = true, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// How often to retry server requests that fail temporarily
+    #[arg(long, global = true, default_value_t = server::DEFAULT_RETRIES)]
+    retries: u32,
+
+    /// Give up on server requests that take longer than this (e.g. 30s, 2m)
+    #[arg(long, global = true)]
+    timeout: Option<String>,
+
+    /// Print a JSON document instead of text (see 'dood schema dump')
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Print text without colors (also NO_COLOR, and whenever output is not a terminal)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Print stable tab-separated lines instead of text
+    #[arg(long, global = true, visible_alias = "quiet", conflicts_with = "json")]
+    porcelain: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,8 +62,59 @@ enum Commands {
     /// Set the server URL (required before registration)
     SetServer {
         /// Server URL to use
+        #[arg(short, long, required_unless_present_any = ["proxy", "no_proxy", "pin", "no_pin", "ca_cert", "no_ca_cert", "header", "clear_headers", "client_cert", "no_client_cert"])]
+        url: Option<String>,
+
+        /// Connect through this proxy (e.g. socks5h://127.0.0.1:9050 for Tor)
+        #[arg(long, conflicts_with = "no_proxy")]
+        proxy: Option<String>,
+
+        /// Stop using a proxy
+        #[arg(long)]
+        no_proxy: bool,
+
+        /// Only accept the server certificate, or its public key, with this SHA-256 hash (hex)
+        #[arg(long, conflicts_with = "no_pin")]
+        pin: Option<String>,
+
+        /// Remove the certificate pin
+        #[arg(long)]
+        no_pin: bool,
+
+        /// Also trust the CA certificate(s) in this PEM file
+        #[arg(long, conflicts_with = "no_ca_cert")]
+        ca_cert: Option<String>,
+
+        /// Stop trusting the custom CA certificate
+        #[arg(long)]
+        no_ca_cert: bool,
+
+        /// Send this header with every request, as 'Name: value' (repeatable)
+        #[arg(long = "header", value_name = "HEADER")]
+        header: Vec<String>,
+
+        /// Stop sending the extra headers
+        #[arg(long, conflicts_with = "header")]
+        clear_headers: bool,
+
+        /// Present this client certificate (PEM) for mutual TLS
+        #[arg(long, requires = "client_key", conflicts_with = "no_client_cert")]
+        client_cert: Option<String>,
+
+        /// Private key (PEM) of the client certificate
+        #[arg(long, requires = "client_cert")]
+        client_key: Option<String>,
+
+        /// Stop presenting a client certificate
+        #[arg(long)]
+        no_client_cert: bool,
+    },
+
+    /// Check that the server is reachable and measure the round trip
+    Ping {
+        /// Server to check instead of the configured one
         #[arg(short, long)]
-        url: String,
+        url: Option<String>,
     },
 
     /// Register a new account
@@ -31,6 +122,11 @@ enum Commands {
         /// Username to register
         #[arg(short, long)]
         username: String,
+
+        /// Upload the keys of an existing local account instead of new ones,
+        /// e.g. after the server was reset
+        #[arg(long)]
+        reuse_keys: bool,
     },
 
     /// Login to existing account
@@ -38,6 +134,10 @@ enum Commands {
         /// Username to login
         #[arg(short, long)]
         username: String,
+
+        /// Check all sessions and refresh recipient devices after login
+        #[arg(long)]
+        warm_up: bool,
     },
 
     /// Send a message to a user
@@ -51,11 +151,157 @@ enum Commands {
         message: String,
     },
 
+    /// Retry sending a failed message
+    Resend {
+        /// Message id, as shown in 'failed'
+        message_id: i64,
+    },
+
+    /// List messages that failed to send
+    Failed,
+
+    /// Inspect encrypted envelopes waiting to be or already uploaded
+    Outbox {
+        #[command(subcommand)]
+        command: OutboxCommands,
+    },
+
+    /// Send a poll to a user
+    Poll {
+        /// Recipient username
+        username: String,
+
+        /// Poll question
+        #[arg(short, long)]
+        question: String,
+
+        /// Answer option (repeat for each option)
+        #[arg(short, long = "option", required = true)]
+        options: Vec<String>,
+    },
+
     /// Fetch and display new messages
-    Fetch,
+    Fetch {
+        /// Keep polling for new messages until interrupted
+        #[arg(long)]
+        follow: bool,
+
+        /// Time between polls with --follow (e.g. 30s, 5m)
+        #[arg(long, requires = "follow")]
+        interval: Option<String>,
+
+        /// Random extra delay of up to this long between polls (e.g. 5s)
+        #[arg(long, requires = "follow")]
+        jitter: Option<String>,
+    },
+
+    /// Stay connected and display messages as they arrive
+    Listen,
 
     /// List all conversations
-    Chats,
+    Chats {
+        /// Include archived conversations
+        #[arg(short, long)]
+        all: bool,
+    },
+
+    /// Archive a conversation (hidden from 'chats' by default)
+    Archive {
+        /// Username of the conversation to archive
+        username: String,
+    },
+
+    /// Restore an archived conversation
+    Unarchive {
+        /// Username of the conversation to unarchive
+        username: String,
+    },
+
+    /// Mute alerts for a conversation (messages are still stored)
+    Mute {
+        /// Username of the conversation to mute
+        username: String,
+
+        /// Mute for a limited time, e.g. 30m, 8h, 2d (default: indefinitely)
+        #[arg(long = "for")]
+        duration: Option<String>,
+    },
+
+    /// Unmute a conversation
+    Unmute {
+        /// Username of the conversation to unmute
+        username: String,
+    },
+
+    /// Give a contact a name that only you see
+    Nickname {
+        /// Username of the contact
+        username: String,
+
+        /// Name to show instead of the username
+        nickname: Option<String>,
+
+        /// Remove the nickname
+        #[arg(long, conflicts_with = "nickname")]
+        clear: bool,
+    },
+
+    /// Make messages in a conversation disappear after a while
+    Timer {
+        /// Username of the conversation
+        username: String,
+
+        /// How long messages are kept after they are sent, e.g. 30m, 1d
+        duration: Option<String>,
+
+        /// Stop messages from disappearing
+        #[arg(long, conflicts_with = "duration")]
+        off: bool,
+    },
+
+    /// Show recent messages from all conversations in one stream
+    Inbox {
+        /// Number of messages to show (default: 30)
+        #[arg(short, long, default_value = "30")]
+        limit: usize,
+    },
+
+    /// Show unread message counts (exits with status 2 if any are unread)
+    Unread,
+
+    /// Move a message to the trash by its id
+    Rm {
+        /// Message id as shown in history
+        message_id: i64,
+    },
+
+    /// Show, restore or empty deleted messages
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
+    },
+
+    /// Star (bookmark) a message by its id
+    Star {
+        /// Message id, as shown in 'history'
+        message_id: i64,
+    },
+
+    /// Remove a star from a message
+    Unstar {
+        /// Message id, as shown in 'history'
+        message_id: i64,
+    },
+
+    /// List starred messages from all conversations
+    Starred,
+
+    /// List messages in which you were @mentioned
+    Mentions {
+        /// Number of messages to show (default: 50)
+        #[arg(short, long, default_value = "50")]
+        limit: usize,
+    },
 
     /// View conversation history with a user
     History {
@@ -65,6 +311,20 @@ enum Commands {
         /// Number of messages to show (default: 50)
         #[arg(short, long, default_value = "50")]
         limit: usize,
+
+        /// Order and time messages by when they arrived here instead of when they were sent
+        #[arg(long)]
+        received: bool,
+    },
+
+    /// Show an activity heatmap and hourly histogram for a conversation
+    Stats {
+        /// Username of the conversation
+        username: String,
+
+        /// Number of weeks to cover in the heatmap (default: 26)
+        #[arg(short, long, default_value = "26")]
+        weeks: i64,
     },
 
     /// Start interactive chat mode
@@ -73,6 +333,85 @@ enum Commands {
         username: String,
     },
 
+    /// Full-screen chat with all conversations
+    Tui,
+
+    /// Try again to decrypt messages that previously failed
+    RetryDecrypt,
+
+    /// Show the state of the encrypted session with a user
+    SessionInfo {
+        /// Username of the session
+        username: String,
+    },
+
+    /// Look up a user's devices and key bundles again instead of using the cache
+    RefreshKeys {
+        /// Username to refresh
+        username: String,
+    },
+
+    /// Discard the encrypted session with a user and start a new one
+    ResetSession {
+        /// Username to reset the session with
+        username: String,
+    },
+
+    /// Compare safety numbers with a contact and mark them verified
+    Verify {
+        /// Username to verify
+        username: String,
+
+        /// Also show the contact's expected fingerprint as a QR code
+        #[arg(long)]
+        qr: bool,
+
+        /// Verify using the text of a scanned fingerprint QR code
+        #[arg(long, value_name = "CODE")]
+        scan: Option<String>,
+    },
+
+    /// Permanently delete messages with a contact and overwrite them on disk
+    Purge {
+        /// Username of the contact
+        username: String,
+
+        /// Only delete messages from before this date (YYYY-MM-DD)
+        #[arg(long)]
+        before: Option<String>,
+
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Show or set how long messages are kept before being deleted
+    Retention {
+        /// Number of days to keep messages (0 keeps them forever)
+        days: Option<u32>,
+
+        /// Apply to the conversation with this user instead of all conversations
+        #[arg(long = "with", value_name = "USERNAME")]
+        username: Option<String>,
+
+        /// Remove the retention period (or the conversation's own period)
+        #[arg(long, conflicts_with = "days")]
+        clear: bool,
+    },
+
+    /// Show every identity key seen for a contact and any security events
+    KeyHistory {
+        /// Username of the contact
+        username: String,
+    },
+
+    /// Move your private keys between the local database and the OS keychain
+    MigrateKeys {
+        /// Where to store the keys: 'keychain' or 'database'
+        #[arg(long, value_parser = ["keychain", "database"])]
+        to: String,
+    },
+
     /// Export account keys (backup)
     Export {
         /// Output file path
@@ -85,33 +424,469 @@ enum Commands {
         /// Input file path
         #[arg(short, long)]
         input: String,
+
+        /// Accept a legacy export file that is not passphrase-protected
+        #[arg(long)]
+        insecure_plaintext: bool,
+    },
+
+    /// Move your account to another server, keeping your keys
+    MigrateServer {
+        /// URL of the new server
+        #[arg(short, long)]
+        url: String,
+
+        /// Tell existing contacts about the new server
+        #[arg(long)]
+        notify: bool,
+    },
+
+    /// Show or change your display name and status
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+
+    /// Delete your account from the server and this machine
+    DeleteAccount {
+        /// Confirm that the account and its messages should be deleted
+        #[arg(long)]
+        confirm: bool,
+    },
+
+    /// List the accounts on this machine or switch between them
+    Account {
+        #[command(subcommand)]
+        command: AccountCommands,
+    },
+
+    /// List or revoke the devices of your account, and lock or wipe those
+    /// registered by other DooD clients
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommands,
+    },
+
+    /// Move your keys to another computer without writing them to disk
+    TransferKeys {
+        /// Encrypt your keys to the code shown by the new device
+        #[arg(long, value_name = "CODE", num_args = 0..=1, default_missing_value = "")]
+        to_device: Option<String>,
+
+        /// Run on the new device to show a code and receive the keys
+        #[arg(long, conflicts_with = "to_device")]
+        receive: bool,
+    },
+
+    /// Write a passphrase-encrypted backup of all local data
+    Backup {
+        /// Output file path
+        #[arg(short, long)]
+        output: String,
+    },
+
+    /// Restore all local data from a backup
+    Restore {
+        /// Backup file path
+        #[arg(short, long)]
+        input: String,
+
+        /// Replace existing local accounts and messages
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Encrypt the local database with a passphrase
+    EncryptDb,
+
+    /// Maintain the local database
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+
+    /// Manage files stored with messages
+    Attachments {
+        #[command(subcommand)]
+        command: AttachmentsCommands,
     },
 
     /// Show account information
-    Info,
+    Info {
+        /// Show your identity fingerprint as a QR code
+        #[arg(long)]
+        qr: bool,
+    },
+
+    /// Stay running in the background and store messages as they arrive
+    Daemon {
+        #[command(subcommand)]
+        command: DaemonCommands,
+    },
+
+    /// Protect your keys with a PIN
+    Lock {
+        #[command(subcommand)]
+        command: LockCommands,
+    },
+
+    /// Choose the colors of messages
+    Theme {
+        #[command(subcommand)]
+        command: ThemeCommands,
+    },
+
+    /// Inspect the JSON output schema used by '--json'
+    Schema {
+        #[command(subcommand)]
+        command: SchemaCommands,
+    },
 
     /// Logout and clear session
     Logout,
 }
 
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// Publish your display name and status
+    Set {
+        /// Display name shown next to your username
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Short status message
+        #[arg(long)]
+        status: Option<String>,
+    },
+    /// Show your profile, or a contact's
+    Show {
+        /// Contact username
+        username: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AccountCommands {
+    /// List the accounts on this machine
+    List,
+    /// Use another account by default
+    Switch {
+        /// Account username
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DevicesCommands {
+    /// List the devices registered for your account
+    List,
+    /// Remove a device from your account
+    Revoke {
+        /// Device ID as shown by 'dood devices list'
+        id: u64,
+    },
+    /// Lock your other devices (registered by other DooD clients), so they ask for their PIN again
+    Lock,
+    /// Delete your account's local data on your other devices
+    Wipe {
+        /// Confirm the wipe
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Choose which commands from your other devices this device obeys
+    Accept {
+        #[arg(value_parser = ["none", "lock", "wipe"])]
+        command: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum OutboxCommands {
+    /// List recent envelopes and whether they were sent
+    List,
+    /// Upload an unsent envelope again, exactly as it was encrypted
+    Retry {
+        /// Outbox id as shown by 'dood outbox list'
+        id: i64,
+    },
+}
+
+#[derive(Subcommand)]
+enum TrashCommands {
+    /// List messages in the trash
+    List,
+    /// Put a message back in its conversation
+    Restore {
+        /// Message id as shown by 'dood trash list'
+        message_id: i64,
+    },
+    /// Permanently delete everything in the trash
+    Empty {
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Show or change how long messages stay in the trash
+    Period {
+        /// Duration such as 30d or 1w; 0 to keep them until emptied
+        duration: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum LockCommands {
+    /// Set a PIN that is required before keys or history are used
+    Set {
+        /// How long an unlock is remembered (e.g. 15m, 1h; 0 to always ask)
+        #[arg(long)]
+        cache: Option<String>,
+    },
+    /// Remove the PIN
+    Remove,
+    /// Forget the cached unlock right away
+    Now,
+    /// Change how long an unlock is remembered
+    Cache {
+        /// Duration such as 15m or 1h; 0 to always ask
+        duration: String,
+    },
+    /// Change what happens after wrong PINs; asks for the PIN
+    Lockout {
+        /// Wrong PINs allowed before each further attempt has to wait longer
+        #[arg(long)]
+        delay_after: Option<u32>,
+
+        /// Wipe the account's local data after this many wrong PINs in a row (0 to never wipe)
+        #[arg(long)]
+        wipe_after: Option<u32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DaemonCommands {
+    /// Run the daemon until interrupted
+    Run {
+        /// Command run for every received message, which gets it as JSON on stdin
+        #[arg(long)]
+        handler: Option<String>,
+
+        /// Most handler commands running at once
+        #[arg(long, default_value_t = 4, requires = "handler")]
+        workers: usize,
+    },
+    /// Show whether the daemon is running and what it has received
+    Status,
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Copy the database while it is in use
+    Backup {
+        /// Output file path
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Replace the database with a copy made by 'db backup'
+    Restore {
+        /// Copy to restore
+        #[arg(short, long)]
+        input: String,
+
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Remove leftover rows, rebuild indexes and shrink the file
+    Compact,
+    /// Check the database for corruption
+    Check,
+}
+
+#[derive(Subcommand)]
+enum AttachmentsCommands {
+    /// List stored attachments
+    List,
+    /// Open an attachment with the default application
+    Open {
+        /// Attachment ID as shown by 'dood attachments list'
+        id: i64,
+
+        /// Save the attachment to this file instead
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Delete an attachment
+    Rm {
+        /// Attachment ID as shown by 'dood attachments list'
+        id: i64,
+
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Release a quarantined attachment so it can be opened
+    Approve {
+        /// Attachment ID as shown by 'dood attachments list'
+        id: i64,
+    },
+    /// Show or change which received files are quarantined
+    Policy {
+        /// Largest file that is not quarantined, e.g. 500K (0 for no limit)
+        #[arg(long)]
+        max_size: Option<String>,
+
+        /// MIME types not quarantined, comma-separated, e.g. 'image/*' (empty for any)
+        #[arg(long)]
+        types: Option<String>,
+
+        /// Senders whose files are not quarantined, comma-separated (empty for anyone)
+        #[arg(long)]
+        senders: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ThemeCommands {
+    /// Show the current colors
+    Show,
+
+    /// Change the color of one part of a message
+    Set {
+        /// incoming, outgoing, sender, you or timestamp
+        element: String,
+
+        /// A color name such as 'red' or 'bright black', a hex color such as '#ff8800', or 'default'
+        color: String,
+    },
+
+    /// Restore the default colors
+    Reset,
+}
+
+#[derive(Subcommand)]
+enum SchemaCommands {
+    /// Print the versioned schema of all JSON output
+    Dump,
+}
+
+/// Exit status of `unread` when messages are unread, so scripts can tell it
+/// apart from the 1 of a failed command.
+const UNREAD_EXIT_CODE: u8 = 2;
+
+/// Exit status when the server cannot be reached, so scripts can tell being
+/// offline apart from other failures.
+const OFFLINE_EXIT_CODE: u8 = 3;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
 
+    match run(cli).await {
+        Err(e) if e.downcast_ref::<server::Offline>().is_some() => {
+            eprintln!("Error: {:?}", e);
+            Ok(ExitCode::from(OFFLINE_EXIT_CODE))
+        }
+        result => result,
+    }
+}
+
+async fn run(cli: Cli) -> Result<ExitCode> {
+    if let Some(data_dir) = &cli.data_dir {
+        config::set_data_dir(data_dir);
+    }
+
+    theme::init_colors(cli.no_color);
+
+    if cli.json || cli.porcelain {
+        if !supports_machine_output(&cli.command) {
+            anyhow::bail!("--json and --porcelain are not supported by this command");
+        }
+        output::set_mode(if cli.json {
+            output::Mode::Json
+        } else {
+            output::Mode::Porcelain
+        });
+    }
+
     database::init()?;
 
+    if let Some(account) = &cli.account {
+        if !auth::list_accounts()?.contains(account) {
+            anyhow::bail!("Account '{}' not found on this machine.", account);
+        }
+        database::select_account(account);
+    }
+
+    if let Err(e) = ui::enforce_retention(false) {
+        eprintln!("⚠️  Could not apply the retention policy: {}", e);
+    }
+
+    server::set_retries(cli.retries);
+    if let Some(timeout) = &cli.timeout {
+        server::set_timeout(timeout)?;
+    }
+
     match cli.command {
-        Commands::SetServer { url } => {
-            config::set_server_url(&url)?;
+        Commands::SetServer {
+            url,
+            proxy,
+            no_proxy,
+            pin,
+            no_pin,
+            ca_cert,
+            no_ca_cert,
+            header,
+            clear_headers,
+            client_cert,
+            client_key,
+            no_client_cert,
+        } => {
+            if proxy.is_some() || no_proxy {
+                config::set_proxy(proxy.as_deref())?;
+            }
+            if pin.is_some() || no_pin {
+                tls::set_pin(pin.as_deref())?;
+            }
+            if ca_cert.is_some() || no_ca_cert {
+                tls::set_ca_cert(ca_cert.as_deref())?;
+            }
+            if clear_headers {
+                config::clear_headers()?;
+            }
+            if !header.is_empty() {
+                config::add_headers(&header)?;
+            }
+            if no_client_cert {
+                tls::set_client_cert(None)?;
+            }
+            if let (Some(cert), Some(key)) = (&client_cert, &client_key) {
+                tls::set_client_cert(Some((cert, key)))?;
+            }
+            if let Some(url) = url {
+                config::set_server_url(&url)?;
+            }
+        }
+
+        Commands::Ping { url } => {
+            if url.is_none() {
+                ensure_server_configured()?;
+            }
+            ui::ping(url.as_deref()).await?;
         }
 
-        Commands::Register { username } => {
+        Commands::Register {
+            username,
+            reuse_keys,
+        } => {
             ensure_server_configured()?;
-            auth::register(&username).await?;
+            auth::register(&username, reuse_keys).await?;
         }
 
-        Commands::Login { username } => {
+        Commands::Login { username, warm_up } => {
             auth::login(&username)?;
+            if warm_up {
+                messages::warm_up_sessions().await?;
+            }
         }
 
         Commands::Send { to, message } => {
@@ -119,19 +894,161 @@ async fn main() -> Result<()> {
             messages::send_message(&to, &message).await?;
         }
 
-        Commands::Fetch => {
+        Commands::Resend { message_id } => {
+            ensure_logged_in()?;
+            messages::resend_message(message_id).await?;
+        }
+
+        Commands::Outbox { command } => {
+            ensure_logged_in()?;
+            match command {
+                OutboxCommands::List => ui::display_outbox()?,
+                OutboxCommands::Retry { id } => messages::retry_outgoing(id).await?,
+            }
+        }
+
+        Commands::Failed => {
+            ensure_logged_in()?;
+            ui::display_failed()?;
+        }
+
+        Commands::Poll {
+            username,
+            question,
+            options,
+        } => {
+            ensure_logged_in()?;
+            messages::send_poll(&username, &question, &options).await?;
+        }
+
+        Commands::Fetch {
+            follow,
+            interval,
+            jitter,
+        } => {
+            ensure_logged_in()?;
+            daemon::ensure_not_running()?;
+            if follow {
+                messages::follow_messages(interval.as_deref(), jitter.as_deref()).await?;
+            } else {
+                messages::fetch_messages().await?;
+            }
+        }
+
+        Commands::Listen => {
+            ensure_logged_in()?;
+            daemon::ensure_not_running()?;
+            messages::listen().await?;
+        }
+
+        Commands::Chats { all } => {
+            ensure_logged_in()?;
+            ui::display_chats(all)?;
+        }
+
+        Commands::Archive { username } => {
+            ensure_logged_in()?;
+            ui::set_archived(&username, true)?;
+        }
+
+        Commands::Unarchive { username } => {
+            ensure_logged_in()?;
+            ui::set_archived(&username, false)?;
+        }
+
+        Commands::Mute { username, duration } => {
+            ensure_logged_in()?;
+            ui::mute(&username, duration.as_deref())?;
+        }
+
+        Commands::Unmute { username } => {
+            ensure_logged_in()?;
+            ui::unmute(&username)?;
+        }
+
+        Commands::Nickname {
+            username,
+            nickname,
+            clear,
+        } => {
+            ensure_logged_in()?;
+            if nickname.is_none() && !clear {
+                anyhow::bail!("Give a nickname, or pass --clear to remove it.");
+            }
+            ui::set_nickname(&username, nickname.as_deref())?;
+        }
+
+        Commands::Timer {
+            username,
+            duration,
+            off,
+        } => {
+            ensure_logged_in()?;
+            if duration.is_none() && !off {
+                anyhow::bail!("Give a duration such as 1d, or pass --off to turn the timer off.");
+            }
+            ui::set_expire_timer(&username, duration.as_deref())?;
+        }
+
+        Commands::Inbox { limit } => {
+            ensure_logged_in()?;
+            ui::display_inbox(limit)?;
+        }
+
+        Commands::Unread => {
+            ensure_logged_in()?;
+            if ui::display_unread()? > 0 {
+                return Ok(ExitCode::from(UNREAD_EXIT_CODE));
+            }
+        }
+
+        Commands::Rm { message_id } => {
+            ensure_logged_in()?;
+            ui::remove_message(message_id)?;
+        }
+
+        Commands::Trash { command } => {
+            ensure_logged_in()?;
+            match command {
+                TrashCommands::List => ui::display_trash()?,
+                TrashCommands::Restore { message_id } => ui::restore_message(message_id)?,
+                TrashCommands::Empty { yes } => ui::empty_trash(yes)?,
+                TrashCommands::Period { duration } => ui::trash_period(duration.as_deref())?,
+            }
+        }
+
+        Commands::Star { message_id } => {
+            ensure_logged_in()?;
+            ui::star(message_id)?;
+        }
+
+        Commands::Unstar { message_id } => {
+            ensure_logged_in()?;
+            ui::unstar(message_id)?;
+        }
+
+        Commands::Starred => {
+            ensure_logged_in()?;
+            ui::display_starred()?;
+        }
+
+        Commands::Mentions { limit } => {
             ensure_logged_in()?;
-            messages::fetch_messages().await?;
+            ui::display_mentions(limit)?;
         }
 
-        Commands::Chats => {
+        Commands::History {
+            username,
+            limit,
+            received,
+        } => {
             ensure_logged_in()?;
-            ui::display_chats()?;
+            ui::display_history(&username, limit, received)?;
         }
 
-        Commands::History { username, limit } => {
+        Commands::Stats { username, weeks } => {
             ensure_logged_in()?;
-            ui::display_history(&username, limit)?;
+            ui::display_stats(&username, weeks)?;
         }
 
         Commands::Chat { username } => {
@@ -139,33 +1056,243 @@ async fn main() -> Result<()> {
             ui::interactive_chat(&username).await?;
         }
 
+        Commands::Tui => {
+            ensure_logged_in()?;
+            tui::run().await?;
+        }
+
+        Commands::RetryDecrypt => {
+            ensure_logged_in()?;
+            messages::retry_quarantined().await?;
+        }
+
+        Commands::SessionInfo { username } => {
+            ensure_logged_in()?;
+            messages::display_session_info(&username)?;
+        }
+
+        Commands::RefreshKeys { username } => {
+            ensure_logged_in()?;
+            ensure_server_configured()?;
+            messages::refresh_keys(&username).await?;
+        }
+
+        Commands::ResetSession { username } => {
+            ensure_logged_in()?;
+            messages::reset_session(&username).await?;
+        }
+
+        Commands::Verify { username, qr, scan } => {
+            ensure_logged_in()?;
+            crypto::verify_contact(&username, qr, scan.as_deref()).await?;
+        }
+
+        Commands::Retention {
+            days,
+            username,
+            clear,
+        } => {
+            ensure_logged_in()?;
+            ui::retention(username.as_deref(), days, clear)?;
+        }
+
+        Commands::Purge {
+            username,
+            before,
+            yes,
+        } => {
+            ensure_logged_in()?;
+            ui::purge(&username, before.as_deref(), yes)?;
+        }
+
+        Commands::KeyHistory { username } => {
+            ensure_logged_in()?;
+            ui::display_key_history(&username)?;
+        }
+
+        Commands::MigrateKeys { to } => {
+            ensure_logged_in()?;
+            auth::migrate_keys(&to)?;
+        }
+
         Commands::Export { output } => {
             ensure_logged_in()?;
             crypto::export_keys(&output)?;
         }
 
-        Commands::Import { input } => {
-            crypto::import_keys(&input)?;
+        Commands::Import {
+            input,
+            insecure_plaintext,
+        } => {
+            crypto::import_keys(&input, insecure_plaintext)?;
         }
 
-        Commands::Info => {
+        Commands::MigrateServer { url, notify } => {
             ensure_logged_in()?;
-            ui::display_account_info()?;
+            auth::migrate_server(&url, notify).await?;
         }
 
+        Commands::Profile { command } => {
+            ensure_logged_in()?;
+            match command {
+                ProfileCommands::Set { name, status } => {
+                    ui::set_profile(name.as_deref(), status.as_deref()).await?
+                }
+                ProfileCommands::Show { username } => {
+                    ui::display_profile(username.as_deref()).await?
+                }
+            }
+        }
+
+        Commands::DeleteAccount { confirm } => {
+            ensure_logged_in()?;
+            auth::delete_account(confirm).await?;
+        }
+
+        Commands::Account { command } => match command {
+            AccountCommands::List => ui::display_accounts()?,
+            AccountCommands::Switch { name } => auth::switch_account(&name)?,
+        },
+
+        Commands::Devices { command } => {
+            ensure_logged_in()?;
+            match command {
+                DevicesCommands::List => ui::display_devices().await?,
+                DevicesCommands::Revoke { id } => ui::revoke_device(id).await?,
+                DevicesCommands::Lock => {
+                    messages::send_remote_command(messages::RemoteCommand::Lock).await?
+                }
+                DevicesCommands::Wipe { confirm } => {
+                    if !confirm {
+                        anyhow::bail!(
+                            "This deletes your account's local data on all your other devices that accept it. Re-run with --confirm."
+                        );
+                    }
+                    messages::send_remote_command(messages::RemoteCommand::Wipe).await?
+                }
+                DevicesCommands::Accept { command } => lock::set_remote_commands(&command)?,
+            }
+        }
+
+        Commands::TransferKeys { to_device, receive } => match to_device {
+            Some(code) => {
+                ensure_logged_in()?;
+                crypto::transfer_keys(&code)?;
+            }
+            None if receive => crypto::receive_keys()?,
+            None => anyhow::bail!("Use --to-device on the old machine or --receive on the new one"),
+        },
+
+        Commands::Backup { output } => {
+            lock::ensure_unlocked()?;
+            crypto::backup(&output)?;
+        }
+
+        Commands::Restore { input, force } => {
+            crypto::restore(&input, force)?;
+        }
+
+        Commands::EncryptDb => {
+            database::encrypt_database()?;
+        }
+
+        Commands::Db { command } => match command {
+            DbCommands::Backup { output } => ui::backup_database(&output)?,
+            DbCommands::Restore { input, yes } => ui::restore_database(&input, yes)?,
+            DbCommands::Compact => ui::compact_database()?,
+            DbCommands::Check => ui::check_database()?,
+        },
+
+        Commands::Attachments { command } => {
+            ensure_logged_in()?;
+            match command {
+                AttachmentsCommands::List => attachments::list()?,
+                AttachmentsCommands::Open { id, output } => {
+                    attachments::open(id, output.as_deref())?
+                }
+                AttachmentsCommands::Rm { id, yes } => attachments::remove(id, yes)?,
+                AttachmentsCommands::Approve { id } => attachments::approve(id)?,
+                AttachmentsCommands::Policy {
+                    max_size,
+                    types,
+                    senders,
+                } => attachments::set_policy(
+                    max_size.as_deref(),
+                    types.as_deref(),
+                    senders.as_deref(),
+                )?,
+            }
+        }
+
+        Commands::Info { qr } => {
+            ensure_logged_in()?;
+            ui::display_account_info(qr)?;
+        }
+
+        Commands::Daemon { command } => {
+            ensure_logged_in()?;
+            match command {
+                DaemonCommands::Run { handler, workers } => {
+                    daemon::run(handler, workers).await?
+                }
+                DaemonCommands::Status => daemon::display_status()?,
+            }
+        }
+
+        Commands::Lock { command } => {
+            // Not `ensure_logged_in`: locking must not ask for the PIN first.
+            if !auth::is_logged_in()? {
+                anyhow::bail!("Not logged in. Please run 'dood login' first.");
+            }
+            match command {
+                LockCommands::Set { cache } => lock::set_pin(cache.as_deref())?,
+                LockCommands::Remove => lock::remove_pin()?,
+                LockCommands::Now => lock::lock_now()?,
+                LockCommands::Cache { duration } => lock::set_cache_duration(&duration)?,
+                LockCommands::Lockout {
+                    delay_after,
+                    wipe_after,
+                } => lock::set_lockout(delay_after, wipe_after)?,
+            }
+        }
+
+        Commands::Theme { command } => match command {
+            ThemeCommands::Show => theme::display()?,
+            ThemeCommands::Set { element, color } => theme::set(&element, &color)?,
+            ThemeCommands::Reset => theme::reset()?,
+        },
+
+        Commands::Schema { command } => match command {
+            SchemaCommands::Dump => output::dump_schema()?,
+        },
+
         Commands::Logout => {
             auth::logout()?;
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Commands that can print their result with `--json` or `--porcelain`.
+fn supports_machine_output(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Chats { .. }
+            | Commands::History { .. }
+            | Commands::Fetch { follow: false, .. }
+            | Commands::Info { qr: false }
+            | Commands::Send { .. }
+            | Commands::Unread
+            | Commands::Schema { .. }
+    )
 }
 
 fn ensure_logged_in() -> Result<()> {
     if !auth::is_logged_in()? {
         anyhow::bail!("Not logged in. Please run 'dood login' first.");
     }
-    Ok(())
+    lock::ensure_unlocked()
 }
 
 fn ensure_server_configured() -> Result<()> {
@@ -1,128 +1,676 @@
 use anyhow::{Context, Result};
 use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
 use colored::*;
-use dood_encryption::{double_ratchet::DoubleRatchet, x3dh::X3DHKeyBundle};
-use reqwest;
+use dialoguer::Confirm;
+use dood_encryption::{
+    double_ratchet::DoubleRatchet,
+    x3dh::{X3DHKeyBundle, X3DH},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use rusqlite::OptionalExtension;
 use serde_json::json;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use tokio_tungstenite::tungstenite::Message;
 use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
 
-use crate::{auth, database, server};
+use crate::server::RetryExt;
+use crate::{api, attachments, auth, config, crypto, database, lock, output, server, theme, ui};
 
 pub async fn send_message(recipient_username: &str, message: &str) -> Result<()> {
-    println!("{}", "🔐 Encrypting message...".cyan());
-
-    let mut sender_x3dh = auth::get_current_x3dh()?;
     let sender_username = auth::get_current_username()?;
-    let server_url = auth::get_server_url()?;
+    let uid = new_message_id();
+
+    if let Err(mut e) = deliver_text(recipient_username, &uid, message).await {
+        let message_id = database::save_message(
+            recipient_username,
+            &sender_username,
+            recipient_username,
+            message,
+            true,
+            None,
+            Some(&uid),
+        )?;
+        database::mark_failed(message_id, &e.to_string())?;
+
+        // The view that hit it asks whether to trust the new key, then
+        // resends this message.
+        if let Some(change) = e.downcast_mut::<IdentityChanged>() {
+            change.message = Some(message_id);
+            return Err(e);
+        }
+
+        if let Some(limit) = e.downcast_ref::<server::RateLimited>() {
+            if output::is_machine() {
+                return output::print(&output::SendResult {
+                    recipient: recipient_username.to_string(),
+                    message_id,
+                    status: output::SendStatus::Queued,
+                });
+            }
+            output::status(
+                format!(
+                    "{}. Message saved as #{}; run 'dood resend {}' once the limit has passed.",
+                    limit, message_id, message_id
+                )
+                .yellow(),
+            );
+            return Ok(());
+        }
+
+        output::notice(
+            format!(
+                "Message saved as #{}. Run 'dood resend {}' to try again.",
+                message_id, message_id
+            )
+            .yellow(),
+        );
+        return Err(e);
+    }
+
+    let message_id = database::save_message(
+        recipient_username,
+        &sender_username,
+        recipient_username,
+        message,
+        true,
+        None,
+        Some(&uid),
+    )?;
+
+    if output::is_machine() {
+        output::print(&output::SendResult {
+            recipient: recipient_username.to_string(),
+            message_id,
+            status: output::SendStatus::Sent,
+        })?;
+    } else {
+        output::status(format!(
+            "{} Message sent to {}",
+            "✓".green().bold(),
+            recipient_username.bold()
+        ));
+    }
 
-    let (recipient_user_id, recipient_device_id) = search_user(recipient_username).await?;
+    sync_sent(recipient_username, &uid, message).await;
 
-    let is_first_message = load_ratchet_state(recipient_username).is_err();
+    Ok(())
+}
 
-    let (mut ratchet_state, x3dh_metadata) = if is_first_message {
-        println!("{}", "🔑 Initiating new encrypted session...".cyan());
+/// Marks the conversation with `username` read here, and remembers to tell
+/// my other devices the next time messages are fetched, so reading stays a
+/// local operation.
+pub fn mark_read(username: &str) -> Result<()> {
+    database::mark_messages_as_read(username)?;
+
+    let key = read_sync_key()?;
+    let mut pending = pending_read_syncs(&key)?;
+    if !pending.iter().any(|conversation| conversation == username) {
+        pending.push(username.to_string());
+        config::set_setting(&key, &serde_json::to_string(&pending)?)?;
+    }
 
-        let recipient_bundle_json = server::fetch_key_bundle_by_id(recipient_user_id).await?;
-        let recipient_bundle = parse_key_bundle(&recipient_bundle_json)?;
+    Ok(())
+}
 
-        let x3dh_result = sender_x3dh.initiate_key_agreement(recipient_bundle);
+fn read_sync_key() -> Result<String> {
+    Ok(format!("read_sync:{}", auth::get_current_username()?))
+}
 
-        let metadata = json!({
-            "sender_identity": BASE64_STANDARD.encode(x3dh_result.alice_identity_pub.as_bytes()),
-            "one_time_pre_key": x3dh_result.bob_one_time_pre_key.map(|k| BASE64_STANDARD.encode(k.as_bytes()))
-        });
+fn pending_read_syncs(key: &str) -> Result<Vec<String>> {
+    Ok(config::get_setting(key)?
+        .and_then(|pending| serde_json::from_str(&pending).ok())
+        .unwrap_or_default())
+}
 
-        let ratchet = DoubleRatchet::new_sender(
-            x3dh_result.rk,
-            x3dh_result.alice_dhs,
-            x3dh_result.bob_public_key,
-        );
+/// Tells my other devices which conversations I read since the last time.
+async fn flush_read_syncs() -> Result<()> {
+    let key = read_sync_key()?;
+    let pending = pending_read_syncs(&key)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
 
-        (ratchet, Some(metadata))
-    } else {
-        (load_ratchet_state(recipient_username)?, None)
+    config::delete_setting(&key)?;
+    for conversation in pending {
+        sync_to_own_devices(&Payload::SyncRead { conversation }, None).await;
+    }
+
+    Ok(())
+}
+
+/// Sends my other devices a copy of `payload` (a text or a structured
+/// payload), which I sent to `recipient` as `message_id`.
+async fn sync_sent(recipient: &str, message_id: &str, payload: &str) {
+    let sync = Payload::SyncSent {
+        recipient: recipient.to_string(),
+        message_id: message_id.to_string(),
+        text: payload.to_string(),
     };
+    sync_to_own_devices(&sync, Some(message_id)).await;
+}
 
-    let encrypt_result = ratchet_state.ratchet_encrypt(message.as_bytes());
+/// Sends a sync payload to every other device of the current account, in
+/// parts if it is large and has a `message_id`. Sync is best effort, so
+/// failures are only reported.
+async fn sync_to_own_devices(payload: &Payload, message_id: Option<&str>) {
+    let result = async {
+        let current_user = auth::get_current_username()?;
+        let (own_device, _) = database::get_account_details(&current_user)?;
 
-    save_ratchet_state(recipient_username, &ratchet_state)?;
+        // Without knowing which device this is, it could sync to itself.
+        let Some(own_device) = own_device else {
+            return Ok(());
+        };
+        let own_device = Some(own_device as u64);
+        let payload = serde_json::to_string(payload)?;
 
-    let header_with_x3dh = if let Some(metadata) = x3dh_metadata {
-        let header_json: serde_json::Value = serde_json::from_slice(&encrypt_result.header[32..])
-            .context("Failed to parse header JSON")?;
+        match message_id {
+            Some(message_id) => {
+                deliver_text_to(&current_user, message_id, &payload, own_device).await
+            }
+            None => deliver_to(&current_user, &payload, own_device, None).await,
+        }
+    }
+    .await;
+
+    match result {
+        // Sending already reported being offline; stay quiet.
+        Err(e) if e.downcast_ref::<server::Offline>().is_some() => {}
+        Err(e) => output::notice(
+            format!("Could not sync to your other devices: {}", e).bright_black(),
+        ),
+        Ok(()) => {}
+    }
+}
 
-        let mut modified_header = header_json.as_object().unwrap().clone();
-        modified_header.insert("x3dh_init".to_string(), metadata);
+/// Sends `command` to every other device of the current account. Unlike
+/// syncing, this fails if it cannot be delivered.
+pub async fn send_remote_command(command: RemoteCommand) -> Result<()> {
+    let current_user = auth::get_current_username()?;
+    let (own_device, _) = database::get_account_details(&current_user)?;
+    let own_device = own_device.context(
+        "This device does not know its own device id, so it cannot tell the others apart",
+    )? as u64;
+
+    let (_, devices) = lookup_user(&current_user).await?;
+    if !devices.iter().any(|device_id| *device_id != own_device) {
+        anyhow::bail!("'{}' has no other devices", current_user);
+    }
 
-        let header_bytes = serde_json::to_vec(&modified_header)?;
+    let payload = serde_json::to_string(&Payload::Remote { command })?;
+    deliver_to(&current_user, &payload, Some(own_device), None).await?;
 
-        let mut full_header = Vec::new();
-        full_header.extend_from_slice(&encrypt_result.header[0..32]);
-        full_header.extend_from_slice(&header_bytes);
+    output::status(format!(
+        "{} Sent to your other devices. Those that accept it obey once they receive messages.",
+        "✓".green().bold()
+    ));
 
-        full_header
-    } else {
-        encrypt_result.header.clone()
+    Ok(())
+}
+
+/// Re-encrypts a previously failed outgoing message with the current ratchet
+/// state and sends it again, every part of it and under its original id, so
+/// the recipient stores it once. Its envelopes left in the outbox are dropped
+/// first, so it cannot also be delivered by `dood outbox retry`.
+pub async fn resend_message(message_id: i64) -> Result<()> {
+    let message = database::get_message(message_id)?
+        .with_context(|| format!("Message #{} not found", message_id))?;
+
+    if !database::is_failed(message_id)? {
+        anyhow::bail!("Message #{} has not failed to send", message_id);
+    }
+
+    // Messages stored before they had an id get a new one.
+    let uid = match database::get_sender_message_id(message_id)? {
+        Some(uid) => {
+            database::discard_unsent_envelopes(&uid)?;
+            uid
+        }
+        None => new_message_id(),
     };
 
-    let ciphertext_b64 = BASE64_STANDARD.encode(&encrypt_result.cipher_text);
-    let header_b64 = BASE64_STANDARD.encode(&header_with_x3dh);
+    if let Err(e) = deliver_text(&message.conversation_with, &uid, &message.content).await {
+        database::mark_failed(message_id, &e.to_string())?;
+        return Err(e);
+    }
 
-    println!("{}", "📡 Sending to server...".cyan());
+    database::clear_failed(message_id)?;
 
-    let message_obj = json!({
-        "recipient_device_id": recipient_device_id,
-        "ciphertext": ciphertext_b64,
-        "header": header_b64
-    });
+    output::status(format!(
+        "{} Message #{} sent to {}",
+        "✓".green().bold(),
+        message_id,
+        message.conversation_with.bold()
+    ));
 
-    let body = json!({
-        "messages": [message_obj]
-    });
+    sync_sent(&message.conversation_with, &uid, &message.content).await;
+
+    Ok(())
+}
+
+/// A new id for a message I send. Every copy of the message carries it, so
+/// recipients store the message once however many copies reach them.
+fn new_message_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Sends a text message under `message_id`, splitting it into parts if it is
+/// too large for a single payload.
+async fn deliver_text(recipient_username: &str, message_id: &str, message: &str) -> Result<()> {
+    deliver_text_to(recipient_username, message_id, message, None).await
+}
 
+/// Like `deliver_text`, but leaves out `skip_device` as `deliver_to` does.
+async fn deliver_text_to(
+    recipient_username: &str,
+    message_id: &str,
+    message: &str,
+    skip_device: Option<u64>,
+) -> Result<()> {
+    learn_server_limit().await?;
+    let max_bytes = max_message_bytes()?;
+
+    let payload = serde_json::to_string(&Payload::Text {
+        message_id: message_id.to_string(),
+        text: message.to_string(),
+    })?;
+    if payload.len() <= max_bytes {
+        return deliver_to(recipient_username, &payload, skip_device, Some(message_id)).await;
+    }
+
+    // The wrapper of the largest part number possible; JSON escaping of the
+    // text is measured by `split_message`.
+    let wrapper = serde_json::to_string(&Payload::Part {
+        message_id: message_id.to_string(),
+        index: message.len(),
+        total: message.len(),
+        text: String::new(),
+    })?;
+    let parts = split_message(message, max_bytes - wrapper.len());
+
+    output::status(format!("✂️  Message is large, sending in {} parts...", parts.len()).cyan());
+
+    for (index, text) in parts.iter().enumerate() {
+        let payload = Payload::Part {
+            message_id: message_id.to_string(),
+            index,
+            total: parts.len(),
+            text: text.to_string(),
+        };
+        deliver_to(
+            recipient_username,
+            &serde_json::to_string(&payload)?,
+            skip_device,
+            Some(message_id),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Encrypts `plaintext` separately for every device of `recipient_username`,
+/// each with its own ratchet, and uploads all copies in one request.
+async fn deliver(recipient_username: &str, plaintext: &str) -> Result<()> {
+    deliver_to(recipient_username, plaintext, None, None).await
+}
+
+/// Like `deliver`, but leaves out `skip_device`, e.g. this device when
+/// syncing to my own devices. The outbox links the envelope to `message_id`,
+/// the sender-assigned id of the message it carries.
+async fn deliver_to(
+    recipient_username: &str,
+    plaintext: &str,
+    skip_device: Option<u64>,
+    message_id: Option<&str>,
+) -> Result<()> {
+    database::check_peer_username(recipient_username)?;
+    let (recipient_user_id, mut recipient_device_ids) = lookup_user(recipient_username).await?;
+    recipient_device_ids.retain(|device_id| Some(*device_id) != skip_device);
+
+    if recipient_device_ids.is_empty() && skip_device.is_some() {
+        return Ok(());
+    }
+
+    output::status("🔐 Encrypting message...".cyan());
+
+    let mut sender_x3dh = auth::get_current_x3dh()?;
+
+    let plaintext = compress_payload(plaintext)?;
+
+    // Only fetched when some device has no session yet.
+    let mut bundles: Option<Vec<(u64, X3DHKeyBundle)>> = None;
+    let mut outgoing = Vec::new();
+    let mut sessions = Vec::new();
+
+    let devices = recipient_device_ids.clone();
+    for device_id in recipient_device_ids {
+        let address = database::device_address(recipient_username, device_id);
+
+        let (mut ratchet_state, x3dh_metadata) = match load_ratchet_state(&address)? {
+            Some(state) => (state, load_pending_init(&address)?),
+            None => {
+                if bundles.is_none() {
+                    bundles =
+                        Some(device_bundles(recipient_username, recipient_user_id, &devices).await?);
+                }
+
+                let available = bundles.as_mut().unwrap();
+                let Some(position) = available.iter().position(|(id, _)| *id == device_id) else {
+                    output::notice(format!(
+                        "{} No key bundle for device {} of {}, skipping it",
+                        "⚠️".yellow(),
+                        device_id,
+                        recipient_username
+                    ));
+                    continue;
+                };
+                let (_, bundle) = available.swap_remove(position);
+
+                output::status(
+                    format!(
+                        "🔑 Initiating new encrypted session with device {}...",
+                        device_id
+                    )
+                    .cyan(),
+                );
+
+                let x3dh_result = sender_x3dh.initiate_key_agreement(bundle);
+
+                let metadata = json!({
+                    "sender_identity": BASE64_STANDARD.encode(x3dh_result.alice_identity_pub.as_bytes()),
+                    "one_time_pre_key": x3dh_result.bob_one_time_pre_key.map(|k| BASE64_STANDARD.encode(k.as_bytes()))
+                });
+
+                let ratchet = DoubleRatchet::new_sender(
+                    x3dh_result.rk,
+                    x3dh_result.alice_dhs,
+                    x3dh_result.bob_public_key,
+                );
+
+                (ratchet, Some(metadata))
+            }
+        };
+
+        let encrypt_result = ratchet_state.ratchet_encrypt(plaintext.as_bytes());
+
+        let header_with_x3dh = if let Some(metadata) = &x3dh_metadata {
+            let header_json: serde_json::Value =
+                serde_json::from_slice(&encrypt_result.header[32..])
+                    .context("Failed to parse header JSON")?;
+
+            let mut modified_header = header_json.as_object().unwrap().clone();
+            modified_header.insert("x3dh_init".to_string(), metadata.clone());
+
+            let header_bytes = serde_json::to_vec(&modified_header)?;
+
+            let mut full_header = Vec::new();
+            full_header.extend_from_slice(&encrypt_result.header[0..32]);
+            full_header.extend_from_slice(&header_bytes);
+
+            full_header
+        } else {
+            encrypt_result.header.clone()
+        };
+
+        outgoing.push(api::OutgoingMessage {
+            recipient_device_id: device_id,
+            ciphertext: BASE64_STANDARD.encode(&encrypt_result.cipher_text),
+            header: BASE64_STANDARD.encode(&header_with_x3dh),
+        });
+        sessions.push((address, ratchet_state, x3dh_metadata));
+    }
+
+    if outgoing.is_empty() {
+        anyhow::bail!("No reachable devices for '{}'", recipient_username);
+    }
+
+    let body = api::SendRequest { messages: outgoing };
+
+    // The envelope is stored together with the ratchet states that produced
+    // it, so a crash during the upload leaves something to retry.
+    let outbox_id = database::with_transaction(|| {
+        for (address, ratchet_state, x3dh_metadata) in &sessions {
+            save_ratchet_state(address, ratchet_state)?;
+            set_pending_init(address, x3dh_metadata.as_ref())?;
+        }
+        database::queue_outgoing(recipient_username, message_id, &serde_json::to_string(&body)?)
+    })?;
+
+    post_envelope(&mut sender_x3dh, outbox_id, recipient_username, &body).await
+}
+
+/// Uploads an envelope from the outbox and records how it went.
+async fn post_envelope<T: Serialize>(
+    sender_x3dh: &mut X3DH,
+    outbox_id: i64,
+    recipient_username: &str,
+    body: &T,
+) -> Result<()> {
+    database::set_outbox_state(outbox_id, database::OutboxState::Sending, None)?;
+
+    let result = upload_envelope(sender_x3dh, recipient_username, body).await;
+
+    match &result {
+        Ok(()) => database::set_outbox_state(outbox_id, database::OutboxState::Sent, None)?,
+        Err(e) => database::set_outbox_state(
+            outbox_id,
+            database::OutboxState::Failed,
+            Some(&e.to_string()),
+        )?,
+    }
+
+    result
+}
+
+async fn upload_envelope<T: Serialize>(
+    sender_x3dh: &mut X3DH,
+    recipient_username: &str,
+    body: &T,
+) -> Result<()> {
+    output::status("📡 Sending to server...".cyan());
+
+    let server_url = auth::get_server_url()?;
     let challenge = sender_x3dh.generate_challenge();
     let token = BASE64_STANDARD.encode(&challenge);
-    let identity_pub = auth::get_identity_public_key(&sender_x3dh);
+    let identity_pub = auth::get_identity_public_key(sender_x3dh);
 
-    let response = reqwest::Client::new()
+    let response = server::http_client()?
         .post(format!("{}/message/send", server_url))
-        .json(&body)
+        .json(body)
         .bearer_auth(&token)
         .header("identity", BASE64_STANDARD.encode(identity_pub.to_bytes()))
-        .send()
+        .header(server::IDEMPOTENCY_KEY_HEADER, server::idempotency_key())
+        .send_with_retry()
         .await
         .context("Failed to send message")?;
 
     if !response.status().is_success() {
+        // The cached devices may be out of date; look them up next time.
+        database::expire_cached_contact(Some(recipient_username))?;
         let error_text = response.text().await?;
         anyhow::bail!("Failed to send message: {}", error_text);
     }
 
-    database::save_message(
-        recipient_username,
-        &sender_username,
-        recipient_username,
-        message,
-        true,
-    )?;
+    Ok(())
+}
+
+/// Uploads an outbox envelope again exactly as it was encrypted, e.g. after
+/// a crash or a lost connection. Messages the recipient already has are
+/// ignored by them as replays.
+pub async fn retry_outgoing(id: i64) -> Result<()> {
+    let entry =
+        database::get_outbox_entry(id)?.with_context(|| format!("No outbox entry #{}", id))?;
+
+    if entry.state == database::OutboxState::Sent {
+        anyhow::bail!("Outbox entry #{} was already sent", id);
+    }
+
+    // A failed message is sent again as a whole by `dood resend`.
+    if let Some(message_id) = entry.message {
+        if database::is_failed(message_id)? {
+            anyhow::bail!(
+                "Outbox entry #{} belongs to message #{}, which failed to send. Run 'dood resend {}' instead.",
+                id,
+                message_id,
+                message_id
+            );
+        }
+    }
+
+    let body: serde_json::Value = serde_json::from_str(&entry.envelope)?;
+    let mut sender_x3dh = auth::get_current_x3dh()?;
+    post_envelope(&mut sender_x3dh, id, &entry.recipient, &body).await?;
 
     println!(
-        "{} Message sent to {}",
+        "{} Outbox entry #{} sent to {}",
         "✓".green().bold(),
-        recipient_username.bold()
+        id,
+        entry.recipient.bold()
     );
 
     Ok(())
 }
 
-async fn search_user(username: &str) -> Result<(u64, u64)> {
+/// Looks `username` up on the server, which also refreshes their profile.
+pub async fn refresh_contact(username: &str) -> Result<()> {
+    search_user(username).await.map(|_| ())
+}
+
+/// Looks up `username` and fetches their key bundles again, ignoring what
+/// is cached, and checks them against the pinned identity key.
+pub async fn refresh_keys(username: &str) -> Result<()> {
+    let (user_id, device_ids) = search_user(username).await?;
+    let bundles = fetch_device_bundles(username, user_id).await?;
+
+    println!(
+        "{} Refreshed keys for {} ({} device(s), {} with a key bundle)",
+        "✓".green().bold(),
+        username.bold(),
+        device_ids.len(),
+        bundles.len()
+    );
+
+    Ok(())
+}
+
+/// Like `fetch_device_bundles`, but answers from the bundles saved in the
+/// contacts table while they are fresh and cover every one of `device_ids`.
+/// Their one-time prekeys are left out, since they may have been used.
+async fn device_bundles(
+    username: &str,
+    user_id: u64,
+    device_ids: &[u64],
+) -> Result<Vec<(u64, X3DHKeyBundle)>> {
+    if let Some(saved) = database::get_cached_key_bundles(username, contact_cache_fresh_since()?)? {
+        let mut saved: Vec<api::DeviceKeyBundle> = serde_json::from_str(&saved)?;
+        if device_ids
+            .iter()
+            .all(|id| saved.iter().any(|device| device.id == *id))
+        {
+            for device in &mut saved {
+                device.key_bundle.one_time_pre_key = None;
+            }
+            return parse_key_bundles(&saved);
+        }
+    }
+
+    fetch_device_bundles(username, user_id).await
+}
+
+/// Fetches and checks the key bundles of all devices of `username`.
+async fn fetch_device_bundles(username: &str, user_id: u64) -> Result<Vec<(u64, X3DHKeyBundle)>> {
+    let bundles_response = server::fetch_key_bundle_by_id(user_id).await?;
+    let bundles = parse_key_bundles(&bundles_response)?;
+
+    for (_, bundle) in &bundles {
+        crypto::verify_signed_pre_key(bundle)
+            .with_context(|| format!("Refusing to start a session with '{}'", username))?;
+    }
+
+    // All devices of an account share its identity key, which is pinned once
+    // per contact; the log likewise holds one entry per account.
+    let (_, first) = &bundles[0];
+    if bundles
+        .iter()
+        .any(|(_, bundle)| bundle.identity_key != first.identity_key)
+    {
+        anyhow::bail!(
+            "The devices of '{}' have different identity keys; refusing to start a session",
+            username
+        );
+    }
+    check_transparency_log(username, first).await?;
+    check_pinned_identity(username, &first.identity_key, &bundles_response)?;
+
+    Ok(bundles)
+}
+
+/// Fetches and saves `username`'s devices and key bundles again without
+/// asking anything, so it can run unattended: a changed identity key is left
+/// for `dood refresh-keys` to confirm.
+async fn refresh_key_bundles(username: &str) -> Result<()> {
+    let (user_id, _) = search_user(username).await?;
+    let bundles_response = server::fetch_key_bundle_by_id(user_id).await?;
+    let bundles = parse_key_bundles(&bundles_response)?;
+
+    for (_, bundle) in &bundles {
+        crypto::verify_signed_pre_key(bundle)?;
+    }
+
+    let (_, first) = &bundles[0];
+    database::record_identity_key(username, &first.identity_key)?;
+    if database::get_contact_identity(username)?
+        .is_some_and(|pinned| pinned != first.identity_key)
+    {
+        anyhow::bail!(
+            "the identity key changed; run 'dood refresh-keys {}' to review it",
+            username
+        );
+    }
+
+    database::save_contact_identity(
+        username,
+        &first.identity_key,
+        &serde_json::to_string(&bundles_response)?,
+    )
+}
+
+/// How long a contact's user and device ids are reused before sending looks
+/// them up again, unless set with the `contact_cache_ttl` setting.
+const DEFAULT_CONTACT_CACHE_TTL: &str = "1h";
+
+/// Cached contact details older than this are looked up again.
+fn contact_cache_fresh_since() -> Result<DateTime<Utc>> {
+    let ttl = config::get_setting("contact_cache_ttl")?
+        .unwrap_or_else(|| DEFAULT_CONTACT_CACHE_TTL.to_string());
+    Ok(Utc::now() - config::parse_duration(&ttl)?)
+}
+
+/// Like `search_user`, but answers from the contacts cache while it is fresh.
+/// The cache belongs to the current account, so another account's contact
+/// of the same name is never used.
+async fn lookup_user(username: &str) -> Result<(u64, Vec<u64>)> {
+    match database::get_cached_contact(username, contact_cache_fresh_since()?)? {
+        Some(cached) => Ok(cached),
+        None => search_user(username).await,
+    }
+}
+
+/// Looks up `username` and returns their user id and all their device ids.
+async fn search_user(username: &str) -> Result<(u64, Vec<u64>)> {
     let server_url = auth::get_server_url()?;
-    let client = reqwest::Client::new();
+    let client = server::http_client()?;
 
     let response = client
         .get(format!("{}/account/search", server_url))
         .query(&[("username", username)])
-        .send()
+        .send_with_retry()
         .await
         .context("Failed to search for user")?;
 
@@ -131,76 +679,259 @@ async fn search_user(username: &str) -> Result<(u64, u64)> {
         anyhow::bail!("Failed to search for user: {}", error_text);
     }
 
-    let search_results: serde_json::Value = response.json().await?;
-    let users = search_results
-        .as_array()
-        .context("Expected array of users")?;
-
-    if users.is_empty() {
-        anyhow::bail!("User '{}' not found", username);
-    }
+    let users: Vec<api::User> = api::decode(response, "/account/search").await?;
 
     let user = users
-        .iter()
-        .find(|u| u["username"].as_str() == Some(username))
+        .into_iter()
+        .find(|u| u.username == username)
         .context(format!("User '{}' not found", username))?;
 
-    let user_id = user["id"].as_u64().context("Missing user id")?;
-
-    let devices = user["Devices"].as_array().context("Missing devices")?;
-    if devices.is_empty() {
+    if user.devices.is_empty() {
         anyhow::bail!("User '{}' has no devices", username);
     }
 
-    let device_id = devices[0]["id"].as_u64().context("Missing device id")?;
+    let device_ids: Vec<u64> = user.devices.iter().map(|device| device.id).collect();
 
-    store_user_device_mapping(username, user_id, device_id)?;
+    forget_revoked_devices(username, &device_ids)?;
+    database::set_contact_devices(username, user.id, &device_ids)?;
+    database::save_profile(
+        username,
+        user.display_name.as_deref(),
+        user.status.as_deref(),
+    )?;
 
-    Ok((user_id, device_id))
+    Ok((user.id, device_ids))
 }
 
-fn store_user_device_mapping(username: &str, user_id: u64, device_id: u64) -> Result<()> {
-    let conn = database::get_connection()?;
+/// Drops sessions with devices `username` no longer has, so nothing is
+/// encrypted to a revoked device again.
+fn forget_revoked_devices(username: &str, device_ids: &[u64]) -> Result<()> {
+    let current_user = auth::get_current_username()?;
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS user_devices (
-            username TEXT PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            device_id INTEGER NOT NULL,
-            last_updated TEXT NOT NULL
-        )",
-        [],
-    )?;
+    for known in database::get_contact_devices(username)? {
+        if !device_ids.contains(&known) {
+            database::delete_device_session(&current_user, username, known)?;
+            output::status(format!("Device {} of {} was removed", known, username).bright_black());
+        }
+    }
 
-    let now = chrono::Utc::now().to_rfc3339();
+    Ok(())
+}
 
-    conn.execute(
-        "INSERT OR REPLACE INTO user_devices (username, user_id, device_id, last_updated)
-         VALUES (?1, ?2, ?3, ?4)",
-        rusqlite::params![username, user_id, device_id, now],
-    )?;
+pub async fn fetch_messages() -> Result<()> {
+    output::status("📥 Fetching messages...".cyan());
+
+    let started = Utc::now();
+    let new_count = fetch_pending(None).await?;
+
+    if output::is_machine() {
+        return output::print(&output::FetchResult {
+            new: new_count,
+            messages: database::get_received_since(started)?
+                .iter()
+                .map(Into::into)
+                .collect(),
+        });
+    }
+
+    if new_count == 0 {
+        println!("{}", "No new messages.".yellow());
+    } else {
+        println!("{} {} new message(s)", "✓".green(), new_count);
+    }
 
     Ok(())
 }
 
-pub async fn fetch_messages() -> Result<()> {
-    println!("{}", "📥 Fetching messages...".cyan());
+/// How long `fetch --follow` waits between polls, and at most how much random
+/// delay is added on top, unless set with flags or the `fetch_interval` and
+/// `fetch_jitter` settings.
+const DEFAULT_FETCH_INTERVAL: &str = "30s";
+const DEFAULT_FETCH_JITTER: &str = "5s";
 
-    let mut sender_x3dh = auth::get_current_x3dh()?;
+/// Keeps fetching until interrupted. Each poll asks the server to hold the
+/// request open for up to `interval` until a message arrives, so messages
+/// show up almost immediately where WebSockets are not available.
+pub async fn follow_messages(interval: Option<&str>, jitter: Option<&str>) -> Result<()> {
+    let (interval, jitter) = poll_timing(interval, jitter)?;
+
+    println!(
+        "{}",
+        "📥 Following new messages. Press Ctrl+C to stop.".cyan()
+    );
+
+    loop {
+        let started = std::time::Instant::now();
+
+        let delay = match fetch_pending(Some(interval)).await {
+            Ok(0) => interval.saturating_sub(started.elapsed()),
+            Ok(new_count) => {
+                println!("{} {} new message(s)", "✓".green(), new_count);
+                std::time::Duration::ZERO
+            }
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e);
+                interval
+            }
+        };
+
+        tokio::time::sleep(delay + random_jitter(jitter)).await;
+    }
+}
+
+/// Poll interval and maximum jitter, from the given values or the settings.
+pub fn poll_timing(
+    interval: Option<&str>,
+    jitter: Option<&str>,
+) -> Result<(std::time::Duration, std::time::Duration)> {
+    let interval = match interval {
+        Some(interval) => interval.to_string(),
+        None => config::get_setting("fetch_interval")?
+            .unwrap_or_else(|| DEFAULT_FETCH_INTERVAL.to_string()),
+    };
+    let jitter = match jitter {
+        Some(jitter) => jitter.to_string(),
+        None => {
+            config::get_setting("fetch_jitter")?.unwrap_or_else(|| DEFAULT_FETCH_JITTER.to_string())
+        }
+    };
+
+    Ok((
+        config::parse_duration(&interval)?.to_std()?,
+        config::parse_duration(&jitter)?.to_std()?,
+    ))
+}
+
+pub fn random_jitter(max: std::time::Duration) -> std::time::Duration {
+    max.mul_f64(rand::random::<f64>())
+}
+
+/// Messages requested per page unless set with the `fetch_page_size` setting.
+const DEFAULT_FETCH_PAGE_SIZE: usize = 100;
+
+/// Downloads and processes waiting messages page by page until the server has
+/// no more, returning how many were new. With `wait`, the server may hold the
+/// first request until a message arrives.
+pub async fn fetch_pending(wait: Option<std::time::Duration>) -> Result<usize> {
     let current_username = auth::get_current_username()?;
+    let page_size = config::get_setting("fetch_page_size")?
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(DEFAULT_FETCH_PAGE_SIZE);
+
+    let mut cursor = None;
+    let mut wait = wait;
+    let mut received = 0;
+    let mut new_count = 0;
+
+    loop {
+        let page = fetch_page(page_size, cursor.as_deref(), wait.take()).await?;
+        received += page.messages.len();
+        new_count += process_messages(&current_username, &page.messages).await?;
+
+        if let Some(remaining) = page.prekeys_remaining {
+            check_prekey_pool(&current_username, remaining)?;
+        }
+
+        match page.next_cursor {
+            Some(next) if !page.messages.is_empty() => {
+                output::status(
+                    format!("  Fetched {} messages, continuing...", received).bright_black(),
+                );
+                cursor = Some(next);
+            }
+            _ => {
+                flush_read_syncs().await?;
+                return Ok(new_count);
+            }
+        }
+    }
+}
+
+struct FetchPage {
+    messages: Vec<serde_json::Value>,
+    next_cursor: Option<String>,
+    prekeys_remaining: Option<u32>,
+}
+
+/// Below this many one-time prekeys on the server, new sessions may have to
+/// be started without one.
+const PREKEY_LOW_WATERMARK: u32 = 10;
+
+/// Records how many one-time prekeys the server reports for me and warns
+/// when the pool runs low. The encryption library cannot generate further
+/// one-time prekeys for an existing identity, so the pool cannot be refilled
+/// automatically; sessions started once it is empty rely on the signed
+/// prekey alone.
+fn check_prekey_pool(username: &str, remaining: u32) -> Result<()> {
+    let key = format!("prekeys_remaining:{}", username);
+    let previous = config::get_setting(&key)?.and_then(|value| value.parse::<u32>().ok());
+    config::set_setting(&key, &remaining.to_string())?;
+
+    // Only warn when the count drops, not on every fetch.
+    if remaining < PREKEY_LOW_WATERMARK && previous.map_or(true, |previous| remaining < previous) {
+        output::notice(
+            format!(
+                "⚠️  Only {} one-time prekey(s) left on the server. New sessions will use the signed prekey alone once they run out.",
+                remaining
+            )
+            .yellow(),
+        );
+    }
+
+    Ok(())
+}
+
+/// The number of one-time prekeys the server last reported for `username`.
+pub fn prekeys_remaining(username: &str) -> Result<Option<u32>> {
+    Ok(
+        config::get_setting(&format!("prekeys_remaining:{}", username))?
+            .and_then(|value| value.parse().ok()),
+    )
+}
+
+/// Requests one page of messages. Servers without pagination answer with a
+/// plain array, which is treated as the only page.
+async fn fetch_page(
+    limit: usize,
+    cursor: Option<&str>,
+    wait: Option<std::time::Duration>,
+) -> Result<FetchPage> {
+    let mut sender_x3dh = auth::get_current_x3dh()?;
     let server_url = auth::get_server_url()?;
 
-    let client = reqwest::Client::new();
+    let client = server::http_client()?;
 
     let challenge = sender_x3dh.generate_challenge();
     let token = BASE64_STANDARD.encode(&challenge);
     let identity_pub = auth::get_identity_public_key(&sender_x3dh);
 
-    let response = client
+    // With explicit acknowledgment the server keeps messages until
+    // `process_messages` confirms they are stored, so fetching again is safe.
+    let mut request = client
         .post(format!("{}/message/fetch", server_url))
+        .query(&[
+            ("limit", limit.to_string()),
+            ("ack", "explicit".to_string()),
+        ])
         .bearer_auth(&token)
         .header("identity", BASE64_STANDARD.encode(identity_pub.to_bytes()))
-        .send()
+        .header(server::IDEMPOTENCY_KEY_HEADER, server::idempotency_key());
+
+    if let Some(cursor) = cursor {
+        request = request.query(&[("cursor", cursor)]);
+    }
+
+    if let Some(wait) = wait {
+        // The server holds the request for up to `wait` before answering.
+        let (_, request_timeout) = server::timeouts()?;
+        request = request
+            .query(&[("wait", wait.as_secs())])
+            .timeout(wait + request_timeout);
+    }
+
+    let response = request
+        .send_with_retry()
         .await
         .context("Failed to fetch messages")?;
 
@@ -209,126 +940,1285 @@ pub async fn fetch_messages() -> Result<()> {
         anyhow::bail!("Failed to fetch messages: {}", error_text);
     }
 
-    let messages: serde_json::Value = response.json().await?;
+    Ok(match api::decode(response, "/message/fetch").await? {
+        api::FetchResponse::Page {
+            messages,
+            next_cursor,
+            prekeys_remaining,
+        } => FetchPage {
+            messages,
+            next_cursor,
+            prekeys_remaining,
+        },
+        api::FetchResponse::All(messages) => FetchPage {
+            messages,
+            next_cursor: None,
+            prekeys_remaining: None,
+        },
+    })
+}
 
-    if let Some(messages_array) = messages.as_array() {
-        if messages_array.is_empty() {
-            println!("{}", "No new messages.".yellow());
-            return Ok(());
+/// Processes a batch of messages from the server, quarantining the ones that
+/// fail, and returns how many were new. Messages are acknowledged only once
+/// they are stored, so the server keeps anything lost to a crash and
+/// delivers it again.
+async fn process_messages(current_username: &str, messages: &[serde_json::Value]) -> Result<usize> {
+    let mut new_count = 0;
+    let mut stored = Vec::new();
+
+    let result = async {
+        for raw in messages {
+            match api::from_value::<api::IncomingMessage>(raw.clone(), "the message queue") {
+                Ok(msg) => match process_received_message(current_username, &msg).await {
+                    Ok(processed) => {
+                        if processed {
+                            new_count += 1;
+                        }
+                    }
+                    Err(e) => {
+                        output::notice(format!("{} Failed to process message: {}", "✗".red(), e));
+                        quarantine(&msg, &e)?;
+                    }
+                },
+                // Nothing can be done with it later either, so it is dropped.
+                Err(e) => {
+                    output::notice(format!("{} Ignoring malformed message: {}", "✗".red(), e))
+                }
+            }
+
+            if let Some(id) = raw.get("id").filter(|id| !id.is_null()) {
+                stored.push(id.clone());
+            }
         }
+        anyhow::Ok(())
+    }
+    .await;
+
+    // Acknowledge what was stored even if a later message failed.
+    if !stored.is_empty() {
+        if let Err(e) = server::acknowledge_messages(&stored).await {
+            output::notice(
+                format!(
+                    "Could not acknowledge messages; they will be delivered again: {}",
+                    e
+                )
+                .bright_black(),
+            );
+        }
+    }
 
-        let mut new_count = 0;
+    download_attachments(current_username).await?;
+    lock::obey_remote_command(current_username)?;
 
-        for msg in messages_array {
-            match process_received_message(&current_username, msg).await {
-                Ok(processed) => {
-                    if processed {
-                        new_count += 1;
+    result.map(|()| new_count)
+}
+
+/// Fetches anything that is waiting, then prints messages as the server pushes
+/// them until the stream closes.
+pub async fn listen() -> Result<()> {
+    fetch_messages().await?;
+
+    let current_username = auth::get_current_username()?;
+    let mut stream = server::open_message_stream().await?;
+
+    println!(
+        "{}",
+        "👂 Listening for messages. Press Ctrl+C to stop.".cyan()
+    );
+
+    while let Some(frame) = stream.next().await {
+        handle_stream_frame(&current_username, frame?).await?;
+    }
+
+    println!("{}", "The server closed the message stream.".yellow());
+
+    Ok(())
+}
+
+/// Processes one frame of the message stream. Each text frame holds a message
+/// or an array of messages in the same format as `/message/fetch`.
+pub async fn handle_stream_frame(current_username: &str, frame: Message) -> Result<usize> {
+    let Message::Text(text) = frame else {
+        return Ok(0);
+    };
+
+    let payload: serde_json::Value =
+        serde_json::from_str(&text).context("Invalid message from the stream")?;
+
+    match payload {
+        serde_json::Value::Array(messages) => process_messages(current_username, &messages).await,
+        message => process_messages(current_username, &[message]).await,
+    }
+}
+
+/// Keeps the raw ciphertext of a message that could not be processed so it
+/// can be retried later with `dood retry-decrypt`.
+fn quarantine(msg: &api::IncomingMessage, error: &anyhow::Error) -> Result<()> {
+    database::quarantine_message(
+        &msg.username,
+        &msg.ciphertext,
+        &msg.header,
+        &error.to_string(),
+    )?;
+    output::notice(format!(
+        "  {}",
+        "The message was quarantined. Run 'dood retry-decrypt' after resetting the session."
+            .bright_black()
+    ));
+
+    Ok(())
+}
+
+pub async fn retry_quarantined() -> Result<()> {
+    let current_username = auth::get_current_username()?;
+    let quarantined = database::get_quarantined_messages()?;
+
+    if quarantined.is_empty() {
+        println!("{}", "No quarantined messages.".green());
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        format!(
+            "🔁 Retrying {} quarantined message(s)...",
+            quarantined.len()
+        )
+        .cyan()
+    );
+
+    let mut recovered = 0;
+
+    for (id, sender, ciphertext, header) in quarantined {
+        let msg = api::IncomingMessage {
+            id: None,
+            username: sender.clone(),
+            ciphertext,
+            header,
+            sender_device_id: None,
+            sent_at: None,
+        };
+
+        match process_received_message(&current_username, &msg).await {
+            Ok(_) => {
+                database::remove_quarantined(id)?;
+                recovered += 1;
+            }
+            Err(e) => {
+                database::update_quarantine_error(id, &e.to_string())?;
+                eprintln!("{} Message from {} still failed: {}", "✗".red(), sender, e);
+            }
+        }
+    }
+
+    println!("{} Recovered {} message(s)", "✓".green().bold(), recovered);
+
+    Ok(())
+}
+
+async fn process_received_message(
+    current_username: &str,
+    msg: &api::IncomingMessage,
+) -> Result<bool> {
+    let sender = msg.username.as_str();
+    let address = sender_address(sender, msg.sender_device_id)?;
+
+    let ciphertext = BASE64_STANDARD.decode(&msg.ciphertext)?;
+    let full_header = BASE64_STANDARD.decode(&msg.header)?;
+
+    let header = &full_header[32..];
+
+    let header_json: serde_json::Value =
+        serde_json::from_slice(header).context("Failed to parse header JSON")?;
+
+    let parsed_header = DoubleRatchet::read_header(header);
+    let alice_dh_public = PublicKey::from(parsed_header.public_key);
+    let counter = parsed_header.n as i64;
+
+    if database::is_counter_seen(sender, &parsed_header.public_key, counter)? {
+        report_replay(sender, &parsed_header.public_key, counter)?;
+        return Ok(false);
+    }
+
+    // Checked before the transaction below, so a refused key stays on record.
+    if header_json.get("x3dh_init").is_some() {
+        check_sender_identity(
+            current_username,
+            sender,
+            &init_sender_identity(&header_json)?,
+        )?;
+    }
+
+    let saved = load_ratchet_state(&address)?;
+    if let Some(ratchet_state) = &saved {
+        if is_old_message(ratchet_state, &parsed_header, &alice_dh_public) {
+            return Ok(false);
+        }
+    }
+
+    // Decrypting advances the ratchet; store the new state together with
+    // what the message says, so a failure or crash can never leave the ratchet
+    // past a message that was not saved.
+    database::with_transaction(|| {
+        let (ratchet_state, decrypted) = decrypt_message(
+            sender,
+            &address,
+            saved,
+            &header_json,
+            alice_dh_public,
+            &full_header,
+            &ciphertext,
+        )?;
+
+        save_ratchet_state(&address, &ratchet_state)?;
+        // They have the session now, so my messages need not start it again.
+        set_pending_init(&address, None)?;
+        database::record_received_counter(sender, &parsed_header.public_key, counter)?;
+
+        let decrypted = decompress_payload(decrypted)?;
+
+        match parse_payload(&decrypted) {
+            Some(Payload::Poll {
+                poll_id,
+                question,
+                options,
+            }) => {
+                if database::get_poll(sender, &poll_id)?.is_some() {
+                    return Ok(false);
+                }
+
+                let content = poll_content(&question);
+                let message_id = database::save_message(
+                    sender,
+                    sender,
+                    current_username,
+                    &content,
+                    false,
+                    msg.sent_at,
+                    None,
+                )?;
+                database::save_poll(&poll_id, message_id, sender, &question, &options)?;
+
+                if !database::is_muted(sender)? {
+                    output::status(format!(
+                        "\n{} {} {}",
+                        "📊".bold(),
+                        "Poll from".cyan(),
+                        sender.bold()
+                    ));
+                    output::status(format!("  {}", question));
+                    for (i, option) in options.iter().enumerate() {
+                        output::status(format!("  {}. {}", i + 1, option));
                     }
+                    output::status(format!(
+                        "  {}",
+                        format!("Vote with '/vote {} <number>' in chat", poll_id).bright_black()
+                    ));
                 }
-                Err(e) => {
-                    eprintln!("{} Failed to process message: {}", "✗".red(), e);
+            }
+            Some(Payload::SessionReset) => {
+                output::status(format!(
+                    "\n{} {} reset the encrypted session",
+                    "🔄".bold(),
+                    sender.bold()
+                ));
+            }
+            Some(Payload::SyncSent { message_id, .. })
+                if from_own_device(current_username, sender)? =>
+            {
+                return store_complete_message(
+                    current_username,
+                    sender,
+                    &message_id,
+                    &decrypted,
+                    msg.sent_at,
+                )
+            }
+            Some(Payload::SyncRead { conversation })
+                if from_own_device(current_username, sender)? =>
+            {
+                database::mark_messages_as_read(&conversation)?;
+            }
+            Some(Payload::Remote { command }) if from_own_device(current_username, sender)? => {
+                lock::queue_remote_command(current_username, command)?;
+            }
+            Some(Payload::SyncSent { .. } | Payload::SyncRead { .. } | Payload::Remote { .. }) => {
+                anyhow::bail!("Ignoring a sync message from {}", sender);
+            }
+            Some(Payload::ServerMoved { server_url }) => {
+                database::record_security_event(sender, "server_moved", &server_url)?;
+                output::status(format!(
+                    "\n{} {} moved their account to {}",
+                    "🚚".bold(),
+                    sender.bold(),
+                    server_url.bold()
+                ));
+            }
+            Some(Payload::Vote {
+                poll_id,
+                creator,
+                option,
+            }) => {
+                record_vote(sender, &creator, &poll_id, sender, option)?;
+
+                if !database::is_muted(sender)? {
+                    output::status(format!(
+                        "\n{} {} voted in a poll",
+                        "🗳️".bold(),
+                        sender.bold()
+                    ));
                 }
             }
+            Some(Payload::Part {
+                message_id,
+                index,
+                total,
+                text,
+            }) => {
+                let complete = database::save_message_part(
+                    sender,
+                    &message_id,
+                    index,
+                    total,
+                    &text,
+                    MAX_REASSEMBLED_BYTES,
+                )?;
+
+                match complete {
+                    Some(full_text) => {
+                        return store_complete_message(
+                            current_username,
+                            sender,
+                            &message_id,
+                            &full_text,
+                            msg.sent_at,
+                        )
+                    }
+                    None => return Ok(false),
+                }
+            }
+            Some(Payload::Text { message_id, text }) => {
+                return store_complete_message(
+                    current_username,
+                    sender,
+                    &message_id,
+                    &text,
+                    msg.sent_at,
+                )
+            }
+            Some(Payload::Attachment { name, data }) => {
+                store_attachment_message(current_username, sender, None, &name, &data, msg.sent_at)?
+            }
+            Some(Payload::AttachmentPointer(pointer)) => {
+                store_attachment_pointer(current_username, sender, None, &pointer, msg.sent_at)?
+            }
+            Some(Payload::Compressed { .. }) | None => {
+                store_text_message(current_username, sender, None, &decrypted, msg.sent_at)?
+            }
+        }
+
+        Ok(true)
+    })
+}
+
+/// Stores a message that arrived whole or was reassembled from its parts,
+/// unless a copy of it is already stored. Returns whether it was new.
+fn store_complete_message(
+    current_username: &str,
+    sender: &str,
+    message_id: &str,
+    text: &str,
+    sent_at: Option<DateTime<Utc>>,
+) -> Result<bool> {
+    if database::has_message(sender, message_id)? {
+        return Ok(false);
+    }
+
+    match parse_payload(text) {
+        Some(Payload::Attachment { name, data }) => store_attachment_message(
+            current_username,
+            sender,
+            Some(message_id),
+            &name,
+            &data,
+            sent_at,
+        )?,
+        Some(Payload::AttachmentPointer(pointer)) => store_attachment_pointer(
+            current_username,
+            sender,
+            Some(message_id),
+            &pointer,
+            sent_at,
+        )?,
+        Some(Payload::SyncSent {
+            recipient, text, ..
+        }) if sender == current_username => {
+            store_sent_copy(current_username, &recipient, message_id, &text, sent_at)?
+        }
+        Some(Payload::SyncSent { .. }) => {
+            anyhow::bail!("Ignoring a sync message from {}", sender)
+        }
+        _ => store_text_message(current_username, sender, Some(message_id), text, sent_at)?,
+    }
+
+    Ok(true)
+}
+
+/// Stores what I sent to `recipient` from another of my devices: a text, a
+/// file, a poll or a vote.
+fn store_sent_copy(
+    current_username: &str,
+    recipient: &str,
+    message_id: &str,
+    payload: &str,
+    sent_at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let save = |content: &str| {
+        database::save_message(
+            recipient,
+            current_username,
+            recipient,
+            content,
+            true,
+            sent_at,
+            Some(message_id),
+        )
+    };
+
+    match parse_payload(payload) {
+        Some(Payload::Attachment { name, data }) => {
+            let content = decode_attachment(&data)?;
+            let id = save(&attachment_content(&name))?;
+            attachments::store(Some(id), &name, &content)?;
+        }
+        Some(Payload::AttachmentPointer(pointer)) => {
+            check_attachment_pointer(&pointer)?;
+            let id = save(&attachment_content(&pointer.name))?;
+            database::save_attachment_download(
+                id,
+                current_username,
+                &pointer.name,
+                &pointer.id,
+                &pointer.key,
+                &pointer.digest,
+            )?;
+        }
+        Some(Payload::Poll {
+            poll_id,
+            question,
+            options,
+        }) => {
+            let id = save(&poll_content(&question))?;
+            database::save_poll(&poll_id, id, current_username, &question, &options)?;
+        }
+        Some(Payload::Vote {
+            poll_id,
+            creator,
+            option,
+        }) => record_vote(recipient, &creator, &poll_id, current_username, option)?,
+        _ => {
+            save(payload)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Records `voter`'s vote for the poll `creator` made as `poll_id`, which
+/// must belong to the conversation with `conversation` and have the option.
+fn record_vote(
+    conversation: &str,
+    creator: &str,
+    poll_id: &str,
+    voter: &str,
+    option: usize,
+) -> Result<()> {
+    let poll = database::get_poll(creator, poll_id)?
+        .filter(|poll| poll.conversation == conversation)
+        .with_context(|| format!("Ignoring a vote by {} for unknown poll '{}'", voter, poll_id))?;
+
+    if option >= poll.options.len() {
+        anyhow::bail!(
+            "Ignoring a vote by {} for option {} of poll '{}', which has {}",
+            voter,
+            option + 1,
+            poll_id,
+            poll.options.len()
+        );
+    }
+
+    database::record_vote(creator, poll_id, voter, option)
+}
+
+fn store_text_message(
+    current_username: &str,
+    sender: &str,
+    message_id: Option<&str>,
+    text: &str,
+    sent_at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let message_id = database::save_message(
+        sender,
+        sender,
+        current_username,
+        text,
+        false,
+        sent_at,
+        message_id,
+    )?;
+
+    if mentions_user(text, current_username) {
+        database::mark_mentioned(message_id)?;
+    }
+
+    if !database::is_muted(sender)? {
+        output::status(format!(
+            "\n{} {} {}",
+            "📨".bold(),
+            "From".cyan(),
+            theme::current().sender(sender)
+        ));
+        output::status(format!("  {}", theme::current().text(text, false)));
+    }
+
+    Ok(())
+}
+
+/// Keeps a received file in the attachment store, with a message about it
+/// in the conversation.
+fn store_attachment_message(
+    current_username: &str,
+    sender: &str,
+    message_id: Option<&str>,
+    name: &str,
+    data: &str,
+    sent_at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let content = decode_attachment(data)?;
+    let message_id = database::save_message(
+        sender,
+        sender,
+        current_username,
+        &attachment_content(name),
+        false,
+        sent_at,
+        message_id,
+    )?;
+    let (attachment_id, quarantined) =
+        attachments::store_received(Some(message_id), sender, name, &content)?;
+
+    announce_attachment(sender, name, attachment_id, quarantined)
+}
+
+/// Tells the user about a file that is now in the attachment store.
+fn announce_attachment(
+    sender: &str,
+    name: &str,
+    attachment_id: i64,
+    quarantined: Option<String>,
+) -> Result<()> {
+    if let Some(reason) = quarantined {
+        output::notice(
+            format!(
+                "⚠️  Quarantined {} from {}: {}. Run 'dood attachments approve {}' to open it.",
+                name, sender, reason, attachment_id
+            )
+            .yellow(),
+        );
+    } else if !database::is_muted(sender)? {
+        output::status(format!(
+            "\n{} {} {}",
+            "📎".bold(),
+            "File from".cyan(),
+            sender.bold()
+        ));
+        output::status(format!(
+            "  {} {}",
+            name,
+            format!("(open with 'dood attachments open {}')", attachment_id).bright_black()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Records a file announced by a pointer message, with a message about it in
+/// the conversation. It is downloaded once the fetch is done.
+fn store_attachment_pointer(
+    current_username: &str,
+    sender: &str,
+    message_id: Option<&str>,
+    pointer: &AttachmentPointer,
+    sent_at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    check_attachment_pointer(pointer)?;
+
+    let message_id = database::save_message(
+        sender,
+        sender,
+        current_username,
+        &attachment_content(&pointer.name),
+        false,
+        sent_at,
+        message_id,
+    )?;
+    database::save_attachment_download(
+        message_id,
+        sender,
+        &pointer.name,
+        &pointer.id,
+        &pointer.key,
+        &pointer.digest,
+    )?;
+
+    Ok(())
+}
+
+fn check_attachment_pointer(pointer: &AttachmentPointer) -> Result<()> {
+    if pointer.size > MAX_ATTACHMENT_BYTES as u64 {
+        anyhow::bail!(
+            "Refusing a received attachment larger than {}",
+            ui::format_size(MAX_ATTACHMENT_BYTES as u64)
+        );
+    }
+
+    Ok(())
+}
+
+/// Downloads the files announced by pointer messages, checking each blob
+/// against its digest before decrypting it into the attachment store. A
+/// failed download is tried again after the next fetch.
+async fn download_attachments(current_username: &str) -> Result<()> {
+    for download in database::get_attachment_downloads()? {
+        match download_attachment(current_username, &download).await {
+            Ok(()) => database::delete_attachment_download(download.id)?,
+            Err(e) => output::notice(format!(
+                "{} Could not download {} from {}: {}",
+                "✗".red(),
+                download.name,
+                download.sender,
+                e
+            )),
+        }
+    }
+
+    Ok(())
+}
+
+async fn download_attachment(
+    current_username: &str,
+    download: &database::AttachmentDownload,
+) -> Result<()> {
+    let max_bytes = MAX_ATTACHMENT_BYTES + attachments::SEAL_OVERHEAD;
+    let Some(blob) = server::download_attachment(&download.remote_id, max_bytes).await? else {
+        output::notice(
+            format!(
+                "{} from {} is no longer on the server.",
+                download.name, download.sender
+            )
+            .yellow(),
+        );
+        return Ok(());
+    };
+
+    let key = Zeroizing::new(
+        BASE64_STANDARD
+            .decode(download.key.as_str())
+            .context("Invalid attachment key")?,
+    );
+    let content = attachments::unseal(&blob, &key, &download.digest)?;
+
+    // A copy of a file I sent from another device.
+    if download.sender == current_username {
+        attachments::store(Some(download.message_id), &download.name, &content)?;
+        return Ok(());
+    }
+
+    let (attachment_id, quarantined) = attachments::store_received(
+        Some(download.message_id),
+        &download.sender,
+        &download.name,
+        &content,
+    )?;
+
+    announce_attachment(&download.sender, &download.name, attachment_id, quarantined)
+}
+
+/// Decodes a received file, refusing anything larger than a file that can
+/// be sent before decoding it.
+fn decode_attachment(data: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if data.len() > MAX_ATTACHMENT_BYTES.div_ceil(3) * 4 {
+        anyhow::bail!(
+            "Refusing a received attachment larger than {}",
+            ui::format_size(MAX_ATTACHMENT_BYTES as u64)
+        );
+    }
+
+    Ok(Zeroizing::new(
+        BASE64_STANDARD
+            .decode(data)
+            .context("Received a corrupted attachment")?,
+    ))
+}
+
+/// Structured message bodies. Plain text messages are sent as-is; these are
+/// sent as a JSON object tagged with `dood_type` inside the encrypted payload.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "dood_type", rename_all = "snake_case")]
+enum Payload {
+    Poll {
+        poll_id: String,
+        question: String,
+        options: Vec<String>,
+    },
+    /// A vote for a poll, which is identified by its creator and id.
+    Vote {
+        poll_id: String,
+        creator: String,
+        option: usize,
+    },
+    /// Tells the recipient that the sender discarded the old session and
+    /// started a new one.
+    SessionReset,
+    /// Tells the recipient that the sender's account now lives on another
+    /// server, with the same identity key.
+    ServerMoved {
+        server_url: String,
+    },
+    /// Copy of a message I sent from another of my devices.
+    SyncSent {
+        recipient: String,
+        message_id: String,
+        text: String,
+    },
+    /// I read a conversation on another of my devices.
+    SyncRead {
+        conversation: String,
+    },
+    /// A command for my other devices, which they obey if they accept it.
+    Remote {
+        command: RemoteCommand,
+    },
+    /// A zstd-compressed, base64-encoded payload (plain text or another
+    /// structured payload).
+    Compressed {
+        data: String,
+    },
+    /// A file, base64-encoded. Usually sent in parts.
+    Attachment {
+        name: String,
+        data: String,
+    },
+    /// A file in the server's blob storage, encrypted with a key of its own.
+    AttachmentPointer(AttachmentPointer),
+    /// A text message, or a file too small to need parts, with the id its
+    /// sender gave it.
+    Text {
+        message_id: String,
+        text: String,
+    },
+    /// One numbered piece of a message too large to send in one payload.
+    Part {
+        message_id: String,
+        index: usize,
+        total: usize,
+        text: String,
+    },
+}
+
+/// What one of my devices can tell the others to do, from least to most
+/// drastic: accepting a command also accepts those before it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteCommand {
+    /// Forget the cached unlock, so the PIN is asked for again.
+    Lock,
+    /// Delete the account's local data.
+    Wipe,
+}
+
+/// Where to fetch a file and how to open it: the id of its encrypted blob,
+/// the blob's key (base64) and SHA-256 digest (hex).
+#[derive(Serialize, Deserialize)]
+struct AttachmentPointer {
+    name: String,
+    id: String,
+    size: u64,
+    key: String,
+    digest: String,
+}
+
+/// Payloads shorter than this are not worth compressing.
+const COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Wraps `plaintext` in a compressed payload when it is large enough and the
+/// result is actually smaller.
+fn compress_payload(plaintext: &str) -> Result<String> {
+    if plaintext.len() < COMPRESSION_THRESHOLD_BYTES {
+        return Ok(plaintext.to_string());
+    }
+
+    let compressed = zstd::encode_all(plaintext.as_bytes(), COMPRESSION_LEVEL)?;
+    let payload = serde_json::to_string(&Payload::Compressed {
+        data: BASE64_STANDARD.encode(compressed),
+    })?;
+
+    if payload.len() < plaintext.len() {
+        Ok(payload)
+    } else {
+        Ok(plaintext.to_string())
+    }
+}
+
+/// How many times `max_message_bytes` a compressed payload may expand to.
+/// Anything larger is refused rather than decompressed into memory.
+const MAX_DECOMPRESSION_RATIO: usize = 64;
+
+fn decompress_payload(plaintext: String) -> Result<String> {
+    match parse_payload(&plaintext) {
+        Some(Payload::Compressed { data }) => {
+            expand_payload(&data, max_message_bytes()? * MAX_DECOMPRESSION_RATIO)
+        }
+        _ => Ok(plaintext),
+    }
+}
+
+/// Decodes the data of a compressed payload, refusing to expand it past
+/// `limit` bytes.
+fn expand_payload(data: &str, limit: usize) -> Result<String> {
+    let compressed = BASE64_STANDARD.decode(data)?;
+    let decompressed = decompress(&compressed, limit)?;
+    String::from_utf8(decompressed).context("Decompressed message is not valid UTF-8")
+}
+
+/// Decompresses `compressed`, failing once the output grows past `limit`.
+fn decompress(compressed: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    zstd::Decoder::new(compressed)?
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .context("Failed to decompress message")?;
+
+    if decompressed.len() > limit {
+        anyhow::bail!(
+            "Compressed message expands to more than {} bytes; refusing it",
+            limit
+        );
+    }
+
+    Ok(decompressed)
+}
+
+/// Largest plaintext sent in a single payload when neither the
+/// `max_message_bytes` setting nor the server says otherwise.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 4096;
+
+/// Smallest usable payload size: room for a part's JSON wrapper and some of
+/// the text.
+const MIN_MESSAGE_BYTES: usize = 256;
+
+/// What encryption adds to a payload on top of base64, for servers whose
+/// limit applies to the encrypted message.
+const ENCRYPTION_OVERHEAD_BYTES: usize = 64;
+
+/// Largest plaintext sent in a single payload: the `max_message_bytes`
+/// setting, or what fits in the largest message the server accepts.
+fn max_message_bytes() -> Result<usize> {
+    let configured = config::get_setting("max_message_bytes")?
+        .and_then(|value| value.parse::<usize>().ok());
+    let advertised = config::get_setting(&server_limit_key()?)?
+        .and_then(|value| value.parse::<usize>().ok())
+        .map(|limit| (limit / 4 * 3).saturating_sub(ENCRYPTION_OVERHEAD_BYTES));
+
+    Ok(configured
+        .or(advertised)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES)
+        .max(MIN_MESSAGE_BYTES))
+}
+
+fn server_limit_key() -> Result<String> {
+    Ok(format!("server_max_message_bytes:{}", auth::get_server_url()?))
+}
+
+/// Asks the server once for the largest message it accepts. Servers that do
+/// not say are remembered as such and not asked again.
+async fn learn_server_limit() -> Result<()> {
+    let key = server_limit_key()?;
+    if config::get_setting(&key)?.is_some() {
+        return Ok(());
+    }
+
+    let limit = server::fetch_max_message_bytes(&auth::get_server_url()?).await?;
+    config::set_setting(&key, &limit.map_or("none".to_string(), |limit| limit.to_string()))
+}
+
+/// Splits `text` on character boundaries into pieces that take at most
+/// `max_bytes` once escaped as a JSON string.
+fn split_message(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+
+    for (index, c) in text.char_indices() {
+        let escaped = json_escaped_len(c);
+        if size + escaped > max_bytes && index > start {
+            parts.push(&text[start..index]);
+            start = index;
+            size = 0;
         }
+        size += escaped;
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Bytes `c` takes inside a JSON string as written by `serde_json`.
+fn json_escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        c if c < ' ' => 6,
+        c => c.len_utf8(),
+    }
+}
+
+fn parse_payload(plaintext: &str) -> Option<Payload> {
+    if !plaintext.starts_with('{') {
+        return None;
+    }
+    serde_json::from_str(plaintext).ok()
+}
+
+fn poll_content(question: &str) -> String {
+    format!("📊 {}", question)
+}
+
+fn attachment_content(name: &str) -> String {
+    format!("📎 {}", name)
+}
+
+/// Largest file that can be sent or received. Files go out in parts of at
+/// most `max_message_bytes`, each a request of its own.
+const MAX_ATTACHMENT_BYTES: usize = 1024 * 1024;
+
+/// Largest message reassembled from parts: the copy of the largest file
+/// synced to my other devices, base64-encoded, with room for its name and the
+/// JSON around it.
+const MAX_REASSEMBLED_BYTES: usize = MAX_ATTACHMENT_BYTES.div_ceil(3) * 4 + 64 * 1024;
+
+/// Sends a file and keeps a copy in the attachment store.
+pub async fn send_attachment(recipient_username: &str, path: &Path) -> Result<()> {
+    let content = Zeroizing::new(
+        std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?,
+    );
+    if content.len() > MAX_ATTACHMENT_BYTES {
+        anyhow::bail!(
+            "{} is too large to send (at most {})",
+            path.display(),
+            ui::format_size(MAX_ATTACHMENT_BYTES as u64)
+        );
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .with_context(|| format!("{} is not a file", path.display()))?;
+    let sender_username = auth::get_current_username()?;
+
+    let sealed = attachments::seal(&content)?;
+    let payload = match server::upload_attachment(sealed.blob).await? {
+        Some(id) => Payload::AttachmentPointer(AttachmentPointer {
+            name: name.clone(),
+            id,
+            size: content.len() as u64,
+            key: BASE64_STANDARD.encode(sealed.key.as_slice()),
+            digest: sealed.digest,
+        }),
+        // Without blob storage on the server the file goes inside the
+        // message itself.
+        None => Payload::Attachment {
+            name: name.clone(),
+            data: BASE64_STANDARD.encode(content.as_slice()),
+        },
+    };
+    let payload = Zeroizing::new(serde_json::to_string(&payload)?);
+    let uid = new_message_id();
+    deliver_text(recipient_username, &uid, &payload).await?;
+
+    let message_id = database::save_message(
+        recipient_username,
+        &sender_username,
+        recipient_username,
+        &attachment_content(&name),
+        true,
+        None,
+        Some(&uid),
+    )?;
+    attachments::store(Some(message_id), &name, &content)?;
+
+    output::status(format!(
+        "{} Sent {} to {}",
+        "✓".green().bold(),
+        name.bold(),
+        recipient_username.bold()
+    ));
+
+    sync_sent(recipient_username, &uid, &payload).await;
+
+    Ok(())
+}
+
+pub async fn send_poll(recipient_username: &str, question: &str, options: &[String]) -> Result<()> {
+    if options.len() < 2 {
+        anyhow::bail!("A poll needs at least two options");
+    }
+
+    let sender_username = auth::get_current_username()?;
+    let poll_id = format!("{:08x}", rand::random::<u32>());
+
+    let payload = serde_json::to_string(&Payload::Poll {
+        poll_id: poll_id.clone(),
+        question: question.to_string(),
+        options: options.to_vec(),
+    })?;
+    deliver(recipient_username, &payload).await?;
+
+    let uid = new_message_id();
+    let message_id = database::save_message(
+        recipient_username,
+        &sender_username,
+        recipient_username,
+        &poll_content(question),
+        true,
+        None,
+        Some(&uid),
+    )?;
+    database::save_poll(&poll_id, message_id, &sender_username, question, options)?;
+
+    output::status(format!(
+        "{} Poll {} sent to {}",
+        "✓".green().bold(),
+        poll_id.bold(),
+        recipient_username.bold()
+    ));
 
-        if new_count == 0 {
-            println!("{}", "No new messages.".yellow());
-        } else {
-            println!("{} {} new message(s)", "✓".green(), new_count);
-        }
-    }
+    sync_sent(recipient_username, &uid, &payload).await;
 
     Ok(())
 }
 
-async fn process_received_message(current_username: &str, msg: &serde_json::Value) -> Result<bool> {
-    let ciphertext_b64 = msg["ciphertext"].as_str().context("Missing ciphertext")?;
-    let header_b64 = msg["header"].as_str().context("Missing header")?;
-    let sender = msg["username"].as_str().unwrap_or("unknown");
+/// Casts a vote for the 1-based `option` of a poll and shares it with the
+/// other participant.
+pub async fn send_vote(recipient_username: &str, poll_id: &str, option: usize) -> Result<()> {
+    let poll = database::find_poll(recipient_username, poll_id)?
+        .with_context(|| format!("Poll '{}' not found", poll_id))?;
+
+    if option == 0 || option > poll.options.len() {
+        anyhow::bail!(
+            "Invalid option {}. Choose between 1 and {}",
+            option,
+            poll.options.len()
+        );
+    }
 
-    let ciphertext = BASE64_STANDARD.decode(ciphertext_b64)?;
-    let full_header = BASE64_STANDARD.decode(header_b64)?;
+    let sender_username = auth::get_current_username()?;
+    let payload = serde_json::to_string(&Payload::Vote {
+        poll_id: poll_id.to_string(),
+        creator: poll.creator.clone(),
+        option: option - 1,
+    })?;
+    deliver(recipient_username, &payload).await?;
 
-    let associated_data = &full_header[0..32];
-    let header = &full_header[32..];
+    database::record_vote(&poll.creator, poll_id, &sender_username, option - 1)?;
 
-    let header_json: serde_json::Value =
-        serde_json::from_slice(header).context("Failed to parse header JSON")?;
+    output::status(format!(
+        "{} Voted for '{}'",
+        "✓".green().bold(),
+        poll.options[option - 1].bold()
+    ));
 
-    let parsed_header = DoubleRatchet::read_header(header);
-    let alice_dh_public = PublicKey::from(parsed_header.public_key);
+    sync_sent(recipient_username, &new_message_id(), &payload).await;
 
-    if let Ok(ratchet_state) = load_ratchet_state(sender) {
-        if is_old_message(&ratchet_state, &parsed_header, &alice_dh_public) {
-            return Ok(false);
+    Ok(())
+}
+
+/// Byte ranges of `@username` mentions in `text`, including the `@`.
+pub fn mention_spans(text: &str) -> Vec<(usize, usize)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+    let mut spans = Vec::new();
+    let mut prev: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c == '@' && !prev.is_some_and(is_name_char) {
+            let mut end = start + 1;
+            while let Some(&(i, next)) = chars.peek() {
+                if !is_name_char(next) {
+                    break;
+                }
+                end = i + next.len_utf8();
+                chars.next();
+            }
+
+            // Trailing dots are punctuation, not part of the name.
+            while end > start + 1 && text[..end].ends_with('.') {
+                end -= 1;
+            }
+
+            if end > start + 1 {
+                spans.push((start, end));
+            }
+            prev = text[..end].chars().last();
+            continue;
         }
+        prev = Some(c);
     }
 
-    let mut ratchet_state =
-        get_or_initialize_receiver_ratchet(sender, &header_json, alice_dh_public).await?;
+    spans
+}
 
-    let decrypted = ratchet_state.ratchet_decrypt(header, &ciphertext, associated_data);
+fn mentions_user(text: &str, username: &str) -> bool {
+    mention_spans(text)
+        .iter()
+        .any(|&(start, end)| text[start + 1..end].eq_ignore_ascii_case(username))
+}
 
-    save_ratchet_state(sender, &ratchet_state)?;
+/// Ratchet peer a received message belongs to. Servers that do not report
+/// the sending device are assumed to relay the sender's primary device.
+fn sender_address(sender: &str, sender_device_id: Option<u64>) -> Result<String> {
+    database::check_peer_username(sender)?;
 
-    database::save_message(sender, sender, current_username, &decrypted, false)?;
+    let device_id = match sender_device_id {
+        Some(device_id) => Some(device_id),
+        None => database::get_primary_device(sender)?,
+    };
 
-    println!("\n{} {} {}", "📨".bold(), "From".cyan(), sender.bold());
-    println!("  {}", decrypted);
+    Ok(match device_id {
+        Some(device_id) => database::device_address(sender, device_id),
+        None => sender.to_string(),
+    })
+}
 
-    Ok(true)
+/// A ciphertext whose chain key and counter were already decrypted is being
+/// delivered again: either a server glitch or someone replaying traffic.
+fn report_replay(sender: &str, ratchet_key: &[u8], counter: i64) -> Result<()> {
+    let details = format!(
+        "message #{} of chain {} delivered again",
+        counter,
+        &BASE64_STANDARD.encode(ratchet_key)[..8]
+    );
+    database::record_security_event(sender, "replay_rejected", &details)?;
+
+    output::notice(format!(
+        "{} Rejected a replayed message from {} ({})",
+        "⚠️".yellow(),
+        sender.bold(),
+        details
+    ));
+    output::notice(
+        format!(
+            "Run 'dood key-history {}' to review security events.",
+            sender
+        )
+        .bright_black(),
+    );
+
+    Ok(())
 }
 
+/// A message is old (already processed) when it is behind the receiving
+/// chain. Messages whose key was skipped earlier arrived out of order and can
+/// still be decrypted with the skipped key stored in the ratchet state.
 fn is_old_message(
     ratchet_state: &DoubleRatchet,
     header: &dood_encryption::double_ratchet::ParsedHeader,
     header_dh_public: &PublicKey,
 ) -> bool {
-    if ratchet_state.dh_public_r.to_bytes() == header_dh_public.to_bytes() {
-        if header.n < ratchet_state.nr {
-            return true;
-        }
-    }
+    let has_skipped_key = ratchet_state
+        .mk_skipped
+        .iter()
+        .any(|skipped| skipped.public_key == header.public_key && skipped.n == header.n);
 
-    for skipped in &ratchet_state.mk_skipped {
-        if skipped.public_key == header.public_key && skipped.n == header.n {
-            return true;
-        }
+    if has_skipped_key {
+        return false;
     }
 
-    false
+    ratchet_state.dh_public_r.to_bytes() == header_dh_public.to_bytes()
+        && header.n < ratchet_state.nr
 }
 
-async fn get_or_initialize_receiver_ratchet(
+/// Decrypts a message with the saved session with `address`. A message
+/// starting a new session (e.g. after the sender reset it) only replaces the
+/// saved session when that cannot decrypt it, so a replayed or forged first
+/// message cannot reset a working session; the replacement is recorded as a
+/// security event.
+fn decrypt_message(
     sender: &str,
+    address: &str,
+    saved: Option<DoubleRatchet>,
     header_json: &serde_json::Value,
     alice_dh_public: PublicKey,
-) -> Result<DoubleRatchet> {
-    if let Ok(state) = load_ratchet_state(sender) {
-        return Ok(state);
+    full_header: &[u8],
+    ciphertext: &[u8],
+) -> Result<(DoubleRatchet, String)> {
+    let starts_session = header_json.get("x3dh_init").is_some();
+
+    if let Some(mut ratchet_state) = saved {
+        match try_decrypt(&mut ratchet_state, full_header, ciphertext) {
+            Some(decrypted) => return Ok((ratchet_state, decrypted)),
+            None if !starts_session => {
+                anyhow::bail!("Failed to decrypt message from {}", sender)
+            }
+            None => database::record_security_event(sender, "session_replaced", address)?,
+        }
     }
 
-    println!(
-        "{}",
-        "🔑 Initializing new encrypted session as receiver...".cyan()
-    );
+    let mut ratchet_state = initialize_receiver_ratchet(header_json, alice_dh_public)?;
+    let decrypted = try_decrypt(&mut ratchet_state, full_header, ciphertext)
+        .with_context(|| format!("Failed to decrypt message from {}", sender))?;
 
-    let mut receiver_x3dh = auth::get_current_x3dh()?;
+    Ok((ratchet_state, decrypted))
+}
 
-    let x3dh_init = header_json["x3dh_init"]
-        .as_object()
-        .context("Missing x3dh_init in first message header")?;
+/// The ratchet panics on authentication failure; treat that as the message
+/// not decrypting, so it can be quarantined instead of aborting the whole fetch.
+fn try_decrypt(
+    ratchet_state: &mut DoubleRatchet,
+    full_header: &[u8],
+    ciphertext: &[u8],
+) -> Option<String> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        ratchet_state.ratchet_decrypt(&full_header[32..], ciphertext, &full_header[0..32])
+    }))
+    .ok()
+}
 
-    let sender_identity_b64 = x3dh_init["sender_identity"]
+/// The identity key the sender of a session's first message announces.
+fn init_sender_identity(header_json: &serde_json::Value) -> Result<[u8; 32]> {
+    let sender_identity_b64 = header_json["x3dh_init"]["sender_identity"]
         .as_str()
         .context("Missing sender_identity")?;
 
-    let sender_identity_bytes = BASE64_STANDARD.decode(sender_identity_b64)?;
-    let alice_identity: [u8; 32] = sender_identity_bytes
+    BASE64_STANDARD
+        .decode(sender_identity_b64)?
         .try_into()
-        .map_err(|_| anyhow::anyhow!("Invalid sender identity length"))?;
-    let alice_identity_pub = PublicKey::from(alice_identity);
+        .map_err(|_| anyhow::anyhow!("Invalid sender identity length"))
+}
+
+fn initialize_receiver_ratchet(
+    header_json: &serde_json::Value,
+    alice_dh_public: PublicKey,
+) -> Result<DoubleRatchet> {
+    output::status("🔑 Initializing new encrypted session as receiver...".cyan());
+
+    let mut receiver_x3dh = auth::get_current_x3dh()?;
+
+    let x3dh_init = header_json["x3dh_init"]
+        .as_object()
+        .context("Missing x3dh_init in first message header")?;
+    let alice_identity_pub = PublicKey::from(init_sender_identity(header_json)?);
 
     let one_time_pre_key = x3dh_init["one_time_pre_key"]
         .as_str()
@@ -351,44 +2241,283 @@ async fn get_or_initialize_receiver_ratchet(
     Ok(ratchet)
 }
 
-fn parse_key_bundle(response: &serde_json::Value) -> Result<X3DHKeyBundle> {
-    let devices = response.as_array().context("Expected array of devices")?;
+/// Pins the identity key a contact's first message comes with, and refuses
+/// a session with any other key until the user has verified it again.
+/// Sessions from my own username are never pinned: they must come with this
+/// account's identity key.
+fn check_sender_identity(
+    current_username: &str,
+    sender: &str,
+    identity_key: &[u8; 32],
+) -> Result<()> {
+    if sender == current_username {
+        return check_own_identity(current_username, identity_key);
+    }
+
+    database::record_identity_key(sender, identity_key)?;
+
+    match database::get_contact_identity(sender)? {
+        None => database::pin_contact_identity(sender, identity_key),
+        Some(pinned) if pinned != identity_key => {
+            database::record_security_event(
+                sender,
+                "identity_mismatch",
+                &BASE64_STANDARD.encode(identity_key),
+            )?;
+            print_identity_change_warning(sender);
+            anyhow::bail!(
+                "Refusing to decrypt a message from '{}' signed with a new identity key. Verify it with 'dood verify {}', then run 'dood retry-decrypt'.",
+                sender,
+                sender
+            )
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// My other devices share my identity key. The server says who a message is
+/// from, so a session from my own username with any other key was opened by
+/// someone else, e.g. the server itself.
+fn check_own_identity(current_username: &str, identity_key: &[u8; 32]) -> Result<()> {
+    if database::get_account_identity_key(current_username)? == identity_key {
+        return Ok(());
+    }
+
+    database::record_security_event(
+        current_username,
+        "identity_mismatch",
+        &BASE64_STANDARD.encode(identity_key),
+    )?;
+    anyhow::bail!(
+        "Refusing a session from '{}' with an identity key that is not this account's",
+        current_username
+    )
+}
+
+/// Whether `sender` is another device of mine, the only source of sync
+/// messages and remote commands. Sessions from before `check_own_identity` may have pinned
+/// another key for my username; those are not trusted either.
+fn from_own_device(current_username: &str, sender: &str) -> Result<bool> {
+    if sender != current_username {
+        return Ok(false);
+    }
+
+    let own_key = database::get_account_identity_key(current_username)?;
+    Ok(database::get_contact_identity(sender)?.map_or(true, |pinned| pinned == own_key))
+}
+
+/// Fetches `username`'s current identity key from the server and records it
+/// in the contacts table.
+pub async fn fetch_identity_key(username: &str) -> Result<[u8; 32]> {
+    let (user_id, _) = search_user(username).await?;
+    let bundles_response = server::fetch_key_bundle_by_id(user_id).await?;
+    let (_, bundle) = parse_key_bundles(&bundles_response)?
+        .into_iter()
+        .next()
+        .context("No devices found for user")?;
+
+    check_transparency_log(username, &bundle).await?;
+    check_pinned_identity(username, &bundle.identity_key, &bundles_response)?;
+
+    Ok(bundle.identity_key)
+}
+
+/// Compares a fetched bundle with what the server's key transparency log
+/// says was published for `username`. Servers without a log are skipped; a
+/// mismatch is only warned about and recorded, since the log may lag behind.
+async fn check_transparency_log(username: &str, bundle: &X3DHKeyBundle) -> Result<()> {
+    let Ok(Some(log_url)) = server::fetch_transparency_log_url().await else {
+        return Ok(());
+    };
+
+    let entry = match server::fetch_transparency_entry(&log_url, username).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            warn_transparency(username, "the transparency log has no entry for this user")?;
+            return Ok(());
+        }
+        Err(e) => {
+            output::notice(format!(
+                "{} Could not check the key transparency log: {}",
+                "⚠️".yellow(),
+                e
+            ));
+            return Ok(());
+        }
+    };
+
+    let published_identity = entry["identity_key"].as_str();
+    let published_pre_key = entry["signed_pre_key"].as_str();
+
+    if published_identity != Some(BASE64_STANDARD.encode(bundle.identity_key).as_str()) {
+        warn_transparency(username, "identity key differs from the transparency log")?;
+    } else if published_pre_key
+        != Some(
+            BASE64_STANDARD
+                .encode(bundle.signed_pre_key.to_bytes())
+                .as_str(),
+        )
+    {
+        warn_transparency(username, "signed prekey differs from the transparency log")?;
+    }
+
+    Ok(())
+}
+
+fn warn_transparency(username: &str, details: &str) -> Result<()> {
+    database::record_security_event(username, "transparency_mismatch", details)?;
+
+    output::notice(
+        format!(
+            "⚠️  WARNING: the key bundle served for {} does not match the transparency log: {}",
+            username, details
+        )
+        .red()
+        .bold(),
+    );
+    output::notice(
+        format!(
+            "The server may be handing out keys it did not publish. Compare safety numbers with 'dood verify {}'.",
+            username
+        )
+        .red(),
+    );
+
+    Ok(())
+}
+
+/// Trust on first use: the first identity key seen for a contact is pinned,
+/// and a different key later on is only accepted after explicit confirmation.
+fn check_pinned_identity(
+    username: &str,
+    identity_key: &[u8; 32],
+    bundles: &[api::DeviceKeyBundle],
+) -> Result<()> {
+    // My own devices must have my identity key; there is nothing to trust.
+    if username == database::current_account()? {
+        check_own_identity(username, identity_key)?;
+        return database::save_contact_identity(
+            username,
+            identity_key,
+            &serde_json::to_string(bundles)?,
+        );
+    }
+
+    database::record_identity_key(username, identity_key)?;
+
+    if let Some(pinned) = database::get_contact_identity(username)? {
+        if pinned != identity_key {
+            // Nobody can answer a prompt while a view owns the terminal; the
+            // view asks itself and accepts the key if the user agrees.
+            if output::is_captured() {
+                return Err(IdentityChanged {
+                    username: username.to_string(),
+                    identity_key: *identity_key,
+                    bundles: serde_json::to_string(bundles)?,
+                    message: None,
+                }
+                .into());
+            }
+
+            print_identity_change_warning(username);
+
+            let accepted = Confirm::new()
+                .with_prompt(format!("Trust the new identity key for '{}'?", username))
+                .default(false)
+                .interact()
+                .unwrap_or(false);
+
+            if !accepted {
+                anyhow::bail!(
+                    "Refusing to use the new identity key for '{}'. Verify it with 'dood verify {}'.",
+                    username,
+                    username
+                );
+            }
+        }
+    }
+
+    database::save_contact_identity(username, identity_key, &serde_json::to_string(bundles)?)
+}
+
+/// A contact's identity key changed while output was captured, so the user
+/// has not been asked yet whether to trust the new one.
+#[derive(Debug, Clone)]
+pub struct IdentityChanged {
+    pub username: String,
+    identity_key: [u8; 32],
+    bundles: String,
+    /// The message that could not be sent because of it, saved as failed.
+    pub message: Option<i64>,
+}
+
+impl IdentityChanged {
+    /// Trusts the new key, as answering yes to the prompt would.
+    pub fn accept(&self) -> Result<()> {
+        database::save_contact_identity(&self.username, &self.identity_key, &self.bundles)
+    }
+}
+
+impl std::fmt::Display for IdentityChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}'s identity key has changed. This can happen if they reinstalled or switched devices, but it can also mean someone is intercepting your conversation.",
+            self.username
+        )
+    }
+}
+
+impl std::error::Error for IdentityChanged {}
+
+fn print_identity_change_warning(username: &str) {
+    output::notice("");
+    output::notice(
+        format!("⚠️  WARNING: {}'s identity key has changed!", username)
+            .red()
+            .bold(),
+    );
+    output::notice(
+        "This can happen if they reinstalled or switched devices, but it can also mean\n\
+         someone is intercepting your conversation. Compare safety numbers before\n\
+         trusting the new key."
+            .red(),
+    );
+    output::notice("");
+}
 
+/// Parses the key bundle response into one bundle per device.
+fn parse_key_bundles(devices: &[api::DeviceKeyBundle]) -> Result<Vec<(u64, X3DHKeyBundle)>> {
     if devices.is_empty() {
         anyhow::bail!("No devices found for user");
     }
 
-    let first_device = &devices[0];
-    let bundle_json = &first_device["key_bundle"];
-
-    let identity_key_b64 = bundle_json["identity_key"]
-        .as_str()
-        .context("Missing identity_key")?;
-    let signed_pre_key_b64 = bundle_json["signed_pre_key"]
-        .as_str()
-        .context("Missing signed_pre_key")?;
-    let signature_b64 = bundle_json["signed_pre_key_signature"]
-        .as_str()
-        .context("Missing signature")?;
+    devices
+        .iter()
+        .map(|device| Ok((device.id, parse_key_bundle(&device.key_bundle)?)))
+        .collect()
+}
 
-    let identity_key_bytes = BASE64_STANDARD.decode(identity_key_b64)?;
+fn parse_key_bundle(bundle: &api::KeyBundle) -> Result<X3DHKeyBundle> {
+    let identity_key_bytes = BASE64_STANDARD.decode(&bundle.identity_key)?;
     let identity_key: [u8; 32] = identity_key_bytes
         .try_into()
         .map_err(|_| anyhow::anyhow!("Invalid identity key length"))?;
 
-    let signed_pre_key_bytes = BASE64_STANDARD.decode(signed_pre_key_b64)?;
+    let signed_pre_key_bytes = BASE64_STANDARD.decode(&bundle.signed_pre_key)?;
     let signed_pre_key_array: [u8; 32] = signed_pre_key_bytes
         .try_into()
         .map_err(|_| anyhow::anyhow!("Invalid signed pre key length"))?;
     let signed_pre_key = PublicKey::from(signed_pre_key_array);
 
-    let signature_bytes = BASE64_STANDARD.decode(signature_b64)?;
+    let signature_bytes = BASE64_STANDARD.decode(&bundle.signed_pre_key_signature)?;
     let signature: [u8; 64] = signature_bytes
         .try_into()
         .map_err(|_| anyhow::anyhow!("Invalid signature length"))?;
 
-    let one_time_pre_key = bundle_json["one_time_pre_key"]
-        .as_str()
+    let one_time_pre_key = bundle
+        .one_time_pre_key
+        .as_deref()
         .and_then(|s| BASE64_STANDARD.decode(s).ok())
         .and_then(|bytes| {
             let arr: [u8; 32] = bytes.try_into().ok()?;
@@ -403,39 +2532,385 @@ fn parse_key_bundle(response: &serde_json::Value) -> Result<X3DHKeyBundle> {
     })
 }
 
-fn save_ratchet_state(username: &str, state: &DoubleRatchet) -> Result<()> {
+/// Tells every contact with a session that the current account moved to
+/// `server_url`. Failures are reported but do not stop the move.
+pub async fn announce_server_move(server_url: &str) -> Result<()> {
+    let current_user = auth::get_current_username()?;
+    let payload = serde_json::to_string(&Payload::ServerMoved {
+        server_url: server_url.to_string(),
+    })?;
+
+    let mut contacts: Vec<String> = database::get_ratchet_sessions(&current_user)?
+        .into_iter()
+        .map(|(peer, _, _)| database::address_username(&peer).map(str::to_string))
+        .collect::<Result<_>>()?;
+    contacts.sort();
+    contacts.dedup();
+
+    for contact in contacts {
+        if let Err(e) = deliver(&contact, &payload).await {
+            eprintln!("{} Could not notify {}: {}", "✗".red(), contact, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sessions not used for this long are reported as idle during warm-up.
+const IDLE_SESSION_DAYS: i64 = 30;
+
+/// Loads every ratchet session of the current user to check it can be used,
+/// while the key bundles of contacts whose cached ones are stale are fetched
+/// again in the background, and reports sessions that need attention.
+pub async fn warm_up_sessions() -> Result<()> {
+    let current_user = auth::get_current_username()?;
+    let sessions = database::get_ratchet_sessions(&current_user)?;
+
+    if sessions.is_empty() {
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        format!("🔥 Warming up {} session(s)...", sessions.len()).cyan()
+    );
+
+    let mut attention = Vec::new();
+    let fresh_since = contact_cache_fresh_since()?;
+
+    // Sessions with several devices of one contact share a refresh.
+    let mut contacts: Vec<String> = Vec::new();
+    for (peer, _, _) in &sessions {
+        match database::address_username(peer) {
+            Ok(username) if !contacts.iter().any(|contact| contact == username) => {
+                contacts.push(username.to_string())
+            }
+            Ok(_) => {}
+            Err(e) => attention.push((peer.clone(), e.to_string())),
+        }
+    }
+
+    let mut refreshes = Vec::new();
+    for username in contacts {
+        if database::get_cached_key_bundles(&username, fresh_since)?.is_some() {
+            continue;
+        }
+        refreshes.push((
+            username.clone(),
+            tokio::spawn(async move { refresh_key_bundles(&username).await }),
+        ));
+    }
+
+    let idle_cutoff = chrono::Utc::now() - chrono::Duration::days(IDLE_SESSION_DAYS);
+    for (peer, _, last_updated) in sessions {
+        if let Err(e) = load_ratchet_state(&peer) {
+            attention.push((peer, e.to_string()));
+            continue;
+        }
+
+        if last_updated < idle_cutoff {
+            attention.push((
+                peer,
+                format!("idle for more than {} days", IDLE_SESSION_DAYS),
+            ));
+        }
+    }
+
+    for (peer, refresh) in refreshes {
+        match refresh.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => attention.push((peer, format!("could not refresh keys: {}", e))),
+            Err(e) => attention.push((peer, format!("refresh task failed: {}", e))),
+        }
+    }
+
+    if attention.is_empty() {
+        println!("{} All sessions ready", "✓".green().bold());
+    } else {
+        println!("{}", "⚠️  Sessions needing attention:".yellow());
+        for (peer, reason) in attention {
+            println!("  {} {}", peer.bold(), reason.bright_black());
+        }
+    }
+
+    Ok(())
+}
+
+/// Version of the serialized ratchet state written by `save_ratchet_state`.
+/// Bump it when `dood_encryption` changes its export format, and teach
+/// `upgrade_ratchet_state` to convert the previous one.
+const RATCHET_STATE_VERSION: i64 = 1;
+
+fn save_ratchet_state(peer: &str, state: &DoubleRatchet) -> Result<()> {
     let conn = database::get_connection()?;
     let current_user = auth::get_current_username()?;
     let now = chrono::Utc::now().to_rfc3339();
 
-    let state_json = state.export();
-    let state_str = serde_json::to_string(&state_json)?;
+    let state_json = crypto::SecretJson(state.export());
+    let state_str = Zeroizing::new(serde_json::to_string(&state_json.0)?);
 
-    let key = format!("{}:{}", current_user, username);
+    let key = database::conversation_key(&current_user, peer);
 
     conn.execute(
-        "INSERT OR REPLACE INTO ratchet_states (username, state_data, last_updated)
-         VALUES (?1, ?2, ?3)",
-        rusqlite::params![key, state_str, now],
+        "INSERT INTO ratchet_states (username, state_data, last_updated, format_version)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(username) DO UPDATE SET
+             state_data = excluded.state_data,
+             last_updated = excluded.last_updated,
+             format_version = excluded.format_version",
+        rusqlite::params![key, state_str.as_str(), now, RATCHET_STATE_VERSION],
     )?;
 
     Ok(())
 }
 
-fn load_ratchet_state(username: &str) -> Result<DoubleRatchet> {
+/// The `x3dh_init` header of a session I started with `peer` that they have
+/// not answered on yet. Every message repeats it until they do, so a message
+/// sent after the first one was lost (e.g. with `dood resend`) can still
+/// start the session on their side.
+fn load_pending_init(peer: &str) -> Result<Option<serde_json::Value>> {
     let conn = database::get_connection()?;
-    let current_user = auth::get_current_username()?;
+    let key = database::conversation_key(&auth::get_current_username()?, peer);
+
+    let pending: Option<String> = conn
+        .query_row(
+            "SELECT pending_init FROM ratchet_states WHERE username = ?1",
+            rusqlite::params![key],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+
+    pending
+        .map(|pending| serde_json::from_str(&pending).context("Invalid pending session header"))
+        .transpose()
+}
 
-    let key = format!("{}:{}", current_user, username);
+fn set_pending_init(peer: &str, x3dh_init: Option<&serde_json::Value>) -> Result<()> {
+    let conn = database::get_connection()?;
+    let key = database::conversation_key(&auth::get_current_username()?, peer);
 
-    let state_str: String = conn.query_row(
-        "SELECT state_data FROM ratchet_states WHERE username = ?1",
-        rusqlite::params![key],
-        |row| row.get(0),
+    conn.execute(
+        "UPDATE ratchet_states SET pending_init = ?2 WHERE username = ?1",
+        rusqlite::params![key, x3dh_init.map(|init| init.to_string())],
     )?;
 
+    Ok(())
+}
+
+pub fn display_session_info(username: &str) -> Result<()> {
+    let current_user = auth::get_current_username()?;
+    let sessions: Vec<_> = database::get_ratchet_sessions(&current_user)?
+        .into_iter()
+        .filter(|(peer, _, _)| database::address_username(peer).is_ok_and(|peer| peer == username))
+        .collect();
+
+    if sessions.is_empty() {
+        anyhow::bail!("No encrypted session with '{}'", username);
+    }
+
+    println!("\n{} {}", "🔐 Session with".bold().cyan(), username.bold());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for (peer, _, last_updated) in &sessions {
+        let Some(state) = load_ratchet_state(peer)? else {
+            continue;
+        };
+
+        if let (_, Some(device_id)) = database::split_address(peer)? {
+            println!("{} {}", "Device:".bold(), device_id);
+        }
+        println!(
+            "{} {}",
+            "Their ratchet key:".bold(),
+            BASE64_STANDARD
+                .encode(state.dh_public_r.to_bytes())
+                .bright_black()
+        );
+        println!("{} {}", "Messages received on chain:".bold(), state.nr);
+        println!(
+            "{} {}",
+            "Skipped message keys:".bold(),
+            state.mk_skipped.len()
+        );
+        println!(
+            "{} {}",
+            "Last updated:".bold(),
+            last_updated.to_rfc3339().bright_black()
+        );
+        println!();
+    }
+
+    println!(
+        "{} {}",
+        "Verified:".bold(),
+        if database::is_verified(username)? {
+            "yes".green()
+        } else {
+            "no".yellow()
+        }
+    );
+
+    Ok(())
+}
+
+/// Discards the ratchet with `username` and starts a new session from their
+/// current key bundle, notifying them with a session reset message.
+pub async fn reset_session(username: &str) -> Result<()> {
+    let current_user = auth::get_current_username()?;
+    database::delete_peer_sessions(&current_user, username)?;
+
+    deliver(username, &serde_json::to_string(&Payload::SessionReset)?).await?;
+
+    println!(
+        "{} Session with {} has been reset",
+        "✓".green().bold(),
+        username.bold()
+    );
+
+    Ok(())
+}
+
+/// The saved ratchet with `peer`, or `None` if there is no session yet. A
+/// session that exists but cannot be read is an error, never `None`, so it
+/// is not silently replaced by a new one.
+fn load_ratchet_state(peer: &str) -> Result<Option<DoubleRatchet>> {
+    let conn = database::get_connection()?;
+    let current_user = auth::get_current_username()?;
+
+    let key = database::conversation_key(&current_user, peer);
+
+    let Some((state_str, version)) = conn
+        .query_row(
+            "SELECT state_data, format_version FROM ratchet_states WHERE username = ?1",
+            rusqlite::params![key],
+            |row| Ok((Zeroizing::new(row.get::<_, String>(0)?), row.get::<_, i64>(1)?)),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    if version > RATCHET_STATE_VERSION {
+        anyhow::bail!(
+            "The session with {} was saved by a newer version of dood-cli. Please upgrade dood-cli.",
+            peer
+        );
+    }
+
     let state_json: serde_json::Value = serde_json::from_str(&state_str)?;
-    let state = DoubleRatchet::from(state_json);
+    let state_json = upgrade_ratchet_state(version, state_json)?;
+
+    // A state the library cannot read must not abort the whole command.
+    let state =
+        panic::catch_unwind(AssertUnwindSafe(|| DoubleRatchet::from(state_json))).map_err(|_| {
+            anyhow::anyhow!(
+                "The session with {} could not be loaded (format version {}). Run 'dood reset-session {}' to start a new one.",
+                peer,
+                version,
+                database::address_username(peer).unwrap_or(peer)
+            )
+        })?;
+
+    Ok(Some(state))
+}
+
+/// Converts a saved ratchet state from `version` to the current format.
+fn upgrade_ratchet_state(version: i64, state: serde_json::Value) -> Result<serde_json::Value> {
+    match version {
+        RATCHET_STATE_VERSION => Ok(state),
+        _ => anyhow::bail!("Unknown ratchet state format version {}", version),
+    }
+}
 
-    Ok(state)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_parts_fit_once_escaped() {
+        let text = "quote \" backslash \\ newline \n bell \u{7} ünïcödé 🦀 ".repeat(50);
+
+        let parts = split_message(&text, 100);
+
+        assert_eq!(parts.concat(), text);
+        for part in &parts {
+            assert!(serde_json::to_string(part).unwrap().len() - 2 <= 100);
+        }
+    }
+
+    #[test]
+    fn split_keeps_short_text_whole() {
+        assert_eq!(split_message("hello", 100), vec!["hello"]);
+        assert_eq!(split_message("", 100), vec![""]);
+    }
+
+    #[test]
+    fn decompress_round_trips_within_limit() {
+        let text = "hello ".repeat(1000);
+        let compressed = zstd::encode_all(text.as_bytes(), COMPRESSION_LEVEL).unwrap();
+
+        let decompressed = decompress(&compressed, text.len()).unwrap();
+
+        assert_eq!(decompressed, text.as_bytes());
+    }
+
+    #[test]
+    fn decompress_refuses_output_past_limit() {
+        let bomb = zstd::encode_all(vec![0u8; 16 * 1024 * 1024].as_slice(), 19).unwrap();
+
+        let error = decompress(&bomb, 64 * 1024).unwrap_err();
+
+        assert!(error.to_string().contains("more than 65536 bytes"));
+    }
+
+    fn compressed_data(plaintext: &str) -> String {
+        match parse_payload(&compress_payload(plaintext).unwrap()) {
+            Some(Payload::Compressed { data }) => data,
+            _ => panic!("payload was not compressed"),
+        }
+    }
+
+    #[test]
+    fn compressed_payloads_past_limit_are_refused() {
+        let text = "a".repeat(1024 * 1024);
+        let limit = DEFAULT_MAX_MESSAGE_BYTES * MAX_DECOMPRESSION_RATIO;
+
+        let error = expand_payload(&compressed_data(&text), limit).unwrap_err();
+
+        assert!(error.to_string().contains("refusing it"));
+    }
+
+    #[test]
+    fn sessions_from_myself_need_my_identity_key() {
+        database::test_database();
+        let forged = [2; 32];
+
+        assert!(check_sender_identity("alice", "alice", &forged).is_err());
+        assert!(database::get_contact_identity("alice").unwrap().is_none());
+        check_sender_identity("alice", "alice", &database::TEST_IDENTITY_KEY).unwrap();
+        assert!(database::get_contact_identity("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn remote_commands_from_a_forged_own_session_are_refused() {
+        database::test_database();
+        database::add_test_account("erin", &[3; 32]);
+        // Pinned on first use by a session the server opened in erin's name.
+        database::pin_contact_identity("erin", &[4; 32]).unwrap();
+
+        assert!(!from_own_device("erin", "erin").unwrap());
+        assert!(!from_own_device("alice", "erin").unwrap());
+        assert!(from_own_device("alice", "alice").unwrap());
+    }
+
+    #[test]
+    fn my_own_identity_key_cannot_be_replaced() {
+        database::test_database();
+
+        let error = check_pinned_identity("alice", &[5; 32], &[]).unwrap_err();
+
+        assert!(error.downcast_ref::<IdentityChanged>().is_none());
+        assert!(database::get_contact_identity("alice").unwrap().is_none());
+    }
 }
@@ -0,0 +1,499 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Display;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::database;
+
+/// Version tag carried by every machine-readable document. Bump it whenever a
+/// field is removed or changes meaning; adding fields is backwards compatible.
+pub const SCHEMA_VERSION: &str = "dood/v1";
+
+/// A payload that can be printed in `--json` or `--porcelain` mode.
+pub trait Output: Serialize {
+    /// Identifies the payload type so consumers can dispatch on it.
+    const KIND: &'static str;
+
+    /// The payload as tab-separated lines. Like the JSON fields, columns are
+    /// only ever added at the end of a line.
+    fn porcelain(&self) -> Vec<String>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Human,
+    Json,
+    Porcelain,
+}
+
+/// Set once from the global `--json` and `--porcelain` flags.
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Chooses how results are printed. Colors are turned off for the machine
+/// modes, so nothing printed to stderr carries escape codes either.
+pub fn set_mode(mode: Mode) {
+    MODE.set(mode).ok();
+    if mode != Mode::Human {
+        colored::control::set_override(false);
+    }
+}
+
+fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Human)
+}
+
+/// Whether results are printed for scripts rather than people.
+pub fn is_machine() -> bool {
+    mode() != Mode::Human
+}
+
+/// Prints a progress or status line meant for people. Left out in the
+/// machine modes, where stdout holds nothing but the result.
+pub fn status(line: impl Display) {
+    if !is_machine() {
+        if let Some(line) = dispatch(Event::Status(line.to_string())) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Prints a warning or error meant for people to stderr, in every mode.
+pub fn notice(line: impl Display) {
+    if let Some(line) = dispatch(Event::Notice(line.to_string())) {
+        eprintln!("{}", line);
+    }
+}
+
+/// A line meant for people, handed to the printer installed with `capture`
+/// instead of being written to the terminal.
+pub enum Event {
+    /// Progress and results, which `status` otherwise prints to stdout.
+    Status(String),
+    /// Warnings and errors, which `notice` otherwise prints to stderr.
+    Notice(String),
+}
+
+type Printer = Box<dyn FnMut(Event) + Send>;
+
+/// Set while a view that owns the terminal is open.
+static PRINTER: Mutex<Option<Printer>> = Mutex::new(None);
+
+/// Hands everything `status` and `notice` print to `printer` instead, until
+/// the returned guard is dropped. The TUI and chat's line editor own the
+/// terminal while they run, so they show these lines themselves.
+pub fn capture(printer: impl FnMut(Event) + Send + 'static) -> Capture {
+    *PRINTER.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(printer));
+    Capture
+}
+
+/// Sends output to the terminal again when dropped.
+pub struct Capture;
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        *PRINTER.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Whether output is captured by a view, so nothing can be asked on the
+/// terminal directly.
+pub fn is_captured() -> bool {
+    PRINTER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_some()
+}
+
+/// Hands `event` to the printer, or gives its line back if there is none.
+fn dispatch(event: Event) -> Option<String> {
+    let mut printer = PRINTER.lock().unwrap_or_else(PoisonError::into_inner);
+    match printer.as_mut() {
+        Some(printer) => {
+            printer(event);
+            None
+        }
+        None => match event {
+            Event::Status(line) | Event::Notice(line) => Some(line),
+        },
+    }
+}
+
+/// Prints a result in the selected machine mode.
+pub fn print<T: Output>(data: &T) -> Result<()> {
+    if mode() == Mode::Porcelain {
+        for line in data.porcelain() {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    print_json(data)
+}
+
+/// Joins fields with tabs. Backslashes, tabs and line breaks inside a field
+/// are escaped so every record stays on one line.
+fn line(fields: &[&dyn Display]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            field
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+        })
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// An optional field, empty when missing.
+fn optional<T: Display>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+}
+
+fn flag(value: bool) -> u8 {
+    value as u8
+}
+
+fn timestamp(value: &DateTime<Utc>) -> String {
+    value.to_rfc3339()
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    schema: &'static str,
+    kind: &'static str,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+fn print_json<T: Output>(data: &T) -> Result<()> {
+    let envelope = Envelope {
+        schema: SCHEMA_VERSION,
+        kind: T::KIND,
+        data,
+    };
+    println!("{}", serde_json::to_string_pretty(&envelope)?);
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct UnreadConversation {
+    pub username: String,
+    pub unread: i32,
+}
+
+#[derive(Serialize)]
+pub struct UnreadSummary {
+    pub conversations: Vec<UnreadConversation>,
+    pub total: i32,
+}
+
+impl Output for UnreadSummary {
+    const KIND: &'static str = "unread";
+
+    fn porcelain(&self) -> Vec<String> {
+        self.conversations
+            .iter()
+            .map(|conversation| line(&[&conversation.username, &conversation.unread]))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct LastMessage {
+    pub timestamp: DateTime<Utc>,
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct Chat {
+    pub username: String,
+    /// My nickname for the contact, or the name from their profile.
+    pub name: Option<String>,
+    pub verified: bool,
+    pub archived: bool,
+    pub muted: bool,
+    /// Disappearing message timer in seconds.
+    pub expire_timer: Option<u64>,
+    pub unread: i32,
+    pub last_message: Option<LastMessage>,
+}
+
+#[derive(Serialize)]
+pub struct ChatList {
+    pub conversations: Vec<Chat>,
+}
+
+impl Output for ChatList {
+    const KIND: &'static str = "chats";
+
+    /// username, unread, verified, archived, muted, timer, last time, last message
+    fn porcelain(&self) -> Vec<String> {
+        self.conversations
+            .iter()
+            .map(|chat| {
+                let (time, content) = match &chat.last_message {
+                    Some(last) => (timestamp(&last.timestamp), last.content.clone()),
+                    None => (String::new(), String::new()),
+                };
+                line(&[
+                    &chat.username,
+                    &chat.unread,
+                    &flag(chat.verified),
+                    &flag(chat.archived),
+                    &flag(chat.muted),
+                    &optional(&chat.expire_timer),
+                    &time,
+                    &content,
+                ])
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct MessageEntry {
+    pub id: i64,
+    pub conversation: String,
+    pub sender: String,
+    pub recipient: String,
+    pub outgoing: bool,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    pub received_at: Option<DateTime<Utc>>,
+    pub read: bool,
+}
+
+impl From<&database::Message> for MessageEntry {
+    fn from(msg: &database::Message) -> Self {
+        MessageEntry {
+            id: msg.id,
+            conversation: msg.conversation_with.clone(),
+            sender: msg.sender.clone(),
+            recipient: msg.recipient.clone(),
+            outgoing: msg.is_outgoing,
+            content: msg.content.clone(),
+            timestamp: msg.timestamp,
+            received_at: msg.received_at,
+            read: msg.is_read,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub message: MessageEntry,
+    /// Outgoing messages that could not be sent yet.
+    pub failed: bool,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    pub username: String,
+    pub name: Option<String>,
+    pub verified: bool,
+    pub status: Option<String>,
+    /// Oldest first.
+    pub messages: Vec<HistoryEntry>,
+}
+
+impl Output for History {
+    const KIND: &'static str = "history";
+
+    /// id, time, sender, outgoing, failed, content
+    fn porcelain(&self) -> Vec<String> {
+        self.messages
+            .iter()
+            .map(|entry| {
+                let msg = &entry.message;
+                line(&[
+                    &msg.id,
+                    &timestamp(&msg.timestamp),
+                    &msg.sender,
+                    &flag(msg.outgoing),
+                    &flag(entry.failed),
+                    &msg.content,
+                ])
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct FetchResult {
+    pub new: usize,
+    pub messages: Vec<MessageEntry>,
+}
+
+impl Output for FetchResult {
+    const KIND: &'static str = "fetch";
+
+    /// id, time, sender, content; one line per new message
+    fn porcelain(&self) -> Vec<String> {
+        self.messages
+            .iter()
+            .map(|msg| {
+                line(&[
+                    &msg.id,
+                    &timestamp(&msg.timestamp),
+                    &msg.sender,
+                    &msg.content,
+                ])
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SendStatus {
+    Sent,
+    /// Rate limited; saved to be sent with `dood resend`.
+    Queued,
+}
+
+#[derive(Serialize)]
+pub struct SendResult {
+    pub recipient: String,
+    pub message_id: i64,
+    pub status: SendStatus,
+}
+
+impl Output for SendResult {
+    const KIND: &'static str = "send";
+
+    /// status, message id, recipient
+    fn porcelain(&self) -> Vec<String> {
+        let status = match self.status {
+            SendStatus::Sent => "sent",
+            SendStatus::Queued => "queued",
+        };
+        vec![line(&[&status, &self.message_id, &self.recipient])]
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountInfo {
+    pub username: String,
+    pub server: String,
+    pub device_id: Option<i64>,
+    pub identity_fingerprint: String,
+    pub one_time_pre_keys: usize,
+    pub prekeys_left_on_server: Option<u32>,
+    pub conversations: usize,
+    pub total_messages: i32,
+}
+
+impl Output for AccountInfo {
+    const KIND: &'static str = "info";
+
+    /// One `name<TAB>value` line per field, named as in the JSON document.
+    fn porcelain(&self) -> Vec<String> {
+        vec![
+            line(&[&"username", &self.username]),
+            line(&[&"server", &self.server]),
+            line(&[&"device_id", &optional(&self.device_id)]),
+            line(&[&"identity_fingerprint", &self.identity_fingerprint]),
+            line(&[&"one_time_pre_keys", &self.one_time_pre_keys]),
+            line(&[
+                &"prekeys_left_on_server",
+                &optional(&self.prekeys_left_on_server),
+            ]),
+            line(&[&"conversations", &self.conversations]),
+            line(&[&"total_messages", &self.total_messages]),
+        ]
+    }
+}
+
+/// Describes every document kind emitted in `--json` mode.
+pub fn schema() -> serde_json::Value {
+    json!({
+        "schema": SCHEMA_VERSION,
+        "envelope": {
+            "schema": "string, always the schema version",
+            "kind": "string, one of the keys of 'kinds'"
+        },
+        "kinds": {
+            (ChatList::KIND): {
+                "conversations": [{
+                    "username": "string",
+                    "name": "string or null",
+                    "verified": "boolean",
+                    "archived": "boolean",
+                    "muted": "boolean",
+                    "expire_timer": "integer (seconds) or null",
+                    "unread": "integer",
+                    "last_message": {
+                        "timestamp": "string (RFC 3339)",
+                        "content": "string"
+                    }
+                }]
+            },
+            (History::KIND): {
+                "username": "string",
+                "name": "string or null",
+                "verified": "boolean",
+                "status": "string or null",
+                "messages": [message_schema(json!({ "failed": "boolean" }))]
+            },
+            (FetchResult::KIND): {
+                "new": "integer",
+                "messages": [message_schema(json!({}))]
+            },
+            (SendResult::KIND): {
+                "recipient": "string",
+                "message_id": "integer",
+                "status": "'sent' or 'queued'"
+            },
+            (AccountInfo::KIND): {
+                "username": "string",
+                "server": "string",
+                "device_id": "integer or null",
+                "identity_fingerprint": "string (hex)",
+                "one_time_pre_keys": "integer",
+                "prekeys_left_on_server": "integer or null",
+                "conversations": "integer",
+                "total_messages": "integer"
+            },
+            (UnreadSummary::KIND): {
+                "conversations": [{
+                    "username": "string",
+                    "unread": "integer"
+                }],
+                "total": "integer"
+            }
+        }
+    })
+}
+
+/// A message as described in the schema, with `extra` fields added.
+fn message_schema(extra: serde_json::Value) -> serde_json::Value {
+    let mut message = json!({
+        "id": "integer",
+        "conversation": "string",
+        "sender": "string",
+        "recipient": "string",
+        "outgoing": "boolean",
+        "content": "string",
+        "timestamp": "string (RFC 3339)",
+        "received_at": "string (RFC 3339) or null",
+        "read": "boolean"
+    });
+    if let (Some(message), serde_json::Value::Object(extra)) = (message.as_object_mut(), extra) {
+        message.extend(extra);
+    }
+    message
+}
+
+pub fn dump_schema() -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&schema())?);
+    Ok(())
+}
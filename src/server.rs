@@ -1,18 +1,775 @@
 use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use colored::*;
 use reqwest;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
 
-use crate::auth;
+use crate::{api, auth, config, output, tls};
 
-pub async fn fetch_key_bundle_by_id(user_id: u64) -> Result<serde_json::Value> {
+/// A connection to the server, either direct or through a SOCKS proxy.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub type MessageStream = WebSocketStream<MaybeTlsStream<Box<dyn Connection>>>;
+
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Identifies the client and platform to the server, e.g.
+/// `dood-cli/0.1.0 (linux; x86_64)`.
+fn user_agent() -> String {
+    format!(
+        "dood-cli/{} ({}; {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+/// How long idle connections are kept for reuse, and how often idle
+/// connections are probed, unless set with the `pool_idle_timeout` and
+/// `keep_alive` settings (`0s` disables either).
+const DEFAULT_POOL_IDLE_TIMEOUT: &str = "90s";
+const DEFAULT_KEEP_ALIVE: &str = "60s";
+
+/// Reads a duration setting; zero means "off".
+fn duration_setting(key: &str, default: &str) -> Result<Option<Duration>> {
+    let value = config::get_setting(key)?.unwrap_or_else(|| default.to_string());
+    let duration = config::parse_duration(&value)?.to_std()?;
+    Ok(Some(duration).filter(|duration| !duration.is_zero()))
+}
+
+/// HTTP client for talking to servers, going through the configured proxy.
+/// It is built on first use and shared, so connections and TLS sessions are
+/// reused across requests.
+pub fn http_client() -> Result<&'static reqwest::Client> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+
+    let (connect_timeout, request_timeout) = timeouts()?;
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::ACCEPT,
+        reqwest::header::HeaderValue::from_static("application/json"),
+    );
+    headers.insert(PROTOCOL_HEADER, PROTOCOL_VERSION.into());
+    headers.insert(
+        reqwest::header::USER_AGENT,
+        reqwest::header::HeaderValue::from_str(&user_agent())?,
+    );
+    for (name, value) in config::get_extra_headers()? {
+        headers.insert(
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+            reqwest::header::HeaderValue::from_str(&value)?,
+        );
+    }
+
+    let keep_alive = duration_setting("keep_alive", DEFAULT_KEEP_ALIVE)?;
+    // HTTP/2 is negotiated over TLS unless the `http2` setting is `off`.
+    let http2 = config::get_setting("http2")?.as_deref() != Some("off");
+
+    let mut tls = tls::client_config()?;
+    if http2 {
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+
+    // Large fetch batches are sent compressed by servers that support it.
+    let mut builder = reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .default_headers(headers)
+        .gzip(true)
+        .brotli(true)
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .pool_idle_timeout(duration_setting(
+            "pool_idle_timeout",
+            DEFAULT_POOL_IDLE_TIMEOUT,
+        )?)
+        .tcp_keepalive(keep_alive);
+
+    if http2 {
+        builder = builder
+            .http2_keep_alive_interval(keep_alive)
+            .http2_keep_alive_while_idle(true);
+    } else {
+        builder = builder.http1_only();
+    }
+
+    if let Some(proxy) = config::get_proxy()? {
+        builder = builder.proxy(reqwest::Proxy::all(&proxy).context("Invalid proxy URL")?);
+    }
+
+    let client = builder.build()?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+/// Connect and whole-request timeouts unless set with the `connect_timeout`
+/// and `request_timeout` settings.
+const DEFAULT_CONNECT_TIMEOUT: &str = "10s";
+const DEFAULT_REQUEST_TIMEOUT: &str = "60s";
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Overrides the request timeout for this invocation (`--timeout`).
+pub fn set_timeout(timeout: &str) -> Result<()> {
+    let timeout = config::parse_duration(timeout)?.to_std()?;
+    if timeout.is_zero() {
+        anyhow::bail!("Timeout must be greater than zero");
+    }
+    let _ = TIMEOUT.set(timeout);
+    Ok(())
+}
+
+/// Connect timeout and request timeout.
+pub fn timeouts() -> Result<(Duration, Duration)> {
+    let connect = config::get_setting("connect_timeout")?
+        .unwrap_or_else(|| DEFAULT_CONNECT_TIMEOUT.to_string());
+    let connect = config::parse_duration(&connect)?.to_std()?;
+
+    let request = match TIMEOUT.get() {
+        Some(timeout) => *timeout,
+        None => {
+            let request = config::get_setting("request_timeout")?
+                .unwrap_or_else(|| DEFAULT_REQUEST_TIMEOUT.to_string());
+            config::parse_duration(&request)?.to_std()?
+        }
+    };
+
+    // A request cannot finish before its connection is made.
+    Ok((connect.min(request), request))
+}
+
+pub const DEFAULT_RETRIES: u32 = 3;
+
+static RETRIES: OnceLock<u32> = OnceLock::new();
+
+/// Sets how many times requests that fail temporarily are retried.
+pub fn set_retries(retries: u32) {
+    let _ = RETRIES.set(retries);
+}
+
+/// Delay before the first retry; doubled for each further one.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest `Retry-After` that is waited out automatically; longer limits are
+/// reported as `RateLimited` instead.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// The server answered 429 Too Many Requests and retrying now would not help.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(delay) => write!(
+                f,
+                "Rate limited by the server, try again in {}s",
+                delay.as_secs()
+            ),
+            None => write!(f, "Rate limited by the server, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// The server could not be connected to at all, most likely because this
+/// machine is offline. Requests fail with this right away instead of being
+/// retried.
+#[derive(Debug)]
+pub struct Offline {
+    pub server_url: String,
+}
+
+impl std::fmt::Display for Offline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cannot reach {}; you appear to be offline. Local commands such as 'chats' and 'history' still work.",
+            self.server_url
+        )
+    }
+}
+
+impl std::error::Error for Offline {}
+
+/// Lets the server recognise a repeated request, so a POST that carries it
+/// can be retried without being acted on twice.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// A new value for `IDEMPOTENCY_KEY_HEADER`, shared by all tries of one
+/// request.
+pub fn idempotency_key() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+pub trait RetryExt {
+    /// Sends the request, retrying timeouts, dropped connections and 5xx
+    /// responses with exponential backoff and jitter, and waiting out short
+    /// rate limits. The last response or error is returned once the retries
+    /// are used up; a response that is still rate limited becomes a
+    /// `RateLimited` error. Requests that are not idempotent and carry no
+    /// `IDEMPOTENCY_KEY_HEADER` may already have been acted on when they
+    /// fail, so they are only retried when rate limited.
+    fn send_with_retry(self) -> impl Future<Output = Result<reqwest::Response>>;
+}
+
+impl RetryExt for reqwest::RequestBuilder {
+    fn send_with_retry(self) -> impl Future<Output = Result<reqwest::Response>> {
+        async {
+            let response = send_with_retry(self).await?;
+            check_protocol(&response)?;
+            Ok(response)
+        }
+    }
+}
+
+/// Version of the server API this client speaks, sent with every request.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest server protocol this client still works with.
+const MIN_SERVER_PROTOCOL: u32 = 1;
+
+/// Carries the protocol version in both directions. The server may also send
+/// the oldest client version it accepts in `PROTOCOL_MIN_HEADER`.
+pub const PROTOCOL_HEADER: &str = "x-dood-protocol";
+const PROTOCOL_MIN_HEADER: &str = "x-dood-protocol-min";
+
+static NEWER_PROTOCOL_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The protocol version a server advertised in `response`, if any.
+pub fn server_protocol(response: &reqwest::Response) -> Option<u32> {
+    protocol_header(response, PROTOCOL_HEADER)
+}
+
+fn protocol_header(response: &reqwest::Response, name: &str) -> Option<u32> {
+    response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Refuses to talk to servers whose protocol is incompatible, and warns once
+/// about servers newer than this client. Servers that do not advertise a
+/// version are assumed to be compatible.
+fn check_protocol(response: &reqwest::Response) -> Result<()> {
+    if response.status() == reqwest::StatusCode::UPGRADE_REQUIRED {
+        anyhow::bail!(
+            "The server no longer supports this version of dood (protocol {}). Please update dood-cli.",
+            PROTOCOL_VERSION
+        );
+    }
+
+    if let Some(min_client) = protocol_header(response, PROTOCOL_MIN_HEADER) {
+        if min_client > PROTOCOL_VERSION {
+            anyhow::bail!(
+                "The server requires protocol {} or newer, but this dood speaks {}. Please update dood-cli.",
+                min_client,
+                PROTOCOL_VERSION
+            );
+        }
+    }
+
+    let Some(server) = server_protocol(response) else {
+        return Ok(());
+    };
+
+    if server < MIN_SERVER_PROTOCOL {
+        anyhow::bail!(
+            "The server speaks protocol {}, which this dood no longer supports (needs {} or newer). Ask its operator to upgrade it.",
+            server,
+            MIN_SERVER_PROTOCOL
+        );
+    }
+
+    if server > PROTOCOL_VERSION && !NEWER_PROTOCOL_WARNED.swap(true, Ordering::Relaxed) {
+        output::notice(
+            format!(
+                "⚠️  The server speaks protocol {}, newer than this dood ({}). Consider updating dood-cli.",
+                server, PROTOCOL_VERSION
+            )
+            .yellow(),
+        );
+    }
+
+    Ok(())
+}
+
+async fn send_with_retry(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let retries = *RETRIES.get().unwrap_or(&DEFAULT_RETRIES);
+    let mut attempt = 0;
+
+    let repeatable = request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .is_some_and(|request| {
+            request.method().is_idempotent()
+                || request.headers().contains_key(IDEMPOTENCY_KEY_HEADER)
+        });
+
+    loop {
+        // The original request is kept for the last attempt.
+        let this_try = request.try_clone().filter(|_| attempt < retries);
+        let last_attempt = this_try.is_none();
+
+        let result = match this_try {
+            Some(this_try) => this_try.send().await,
+            None => {
+                return match request.send().await {
+                    Ok(response) => check_rate_limit(response),
+                    Err(e) if e.is_connect() => Err(connect_error(e)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        };
+
+        let delay = (RETRY_BASE_DELAY * 2u32.saturating_pow(attempt)).min(RETRY_MAX_DELAY);
+        let delay = delay + delay.mul_f64(rand::random::<f64>() / 2.0);
+
+        let (failure, delay) = match result {
+            Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                match retry_after(&response) {
+                    Some(wait) if wait > MAX_RATE_LIMIT_WAIT || last_attempt => {
+                        return check_rate_limit(response)
+                    }
+                    Some(wait) => ("Rate limited".to_string(), wait),
+                    None => ("Rate limited".to_string(), delay),
+                }
+            }
+            Ok(response) if response.status().is_server_error() && repeatable => (
+                format!("Request failed (server error {})", response.status()),
+                delay,
+            ),
+            Err(e) if e.is_connect() => return Err(connect_error(e)),
+            Err(e) if (e.is_timeout() || e.is_request()) && repeatable => {
+                (format!("Request failed ({})", e), delay)
+            }
+            result => return Ok(result?),
+        };
+
+        output::notice(
+            format!("{}, retrying in {:.1}s...", failure, delay.as_secs_f64()).bright_black(),
+        );
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Reports a failed connection: a TLS failure as a certificate problem, since
+/// retrying will not help, and anything else as `Offline`.
+fn connect_error(error: reqwest::Error) -> anyhow::Error {
+    let Some(tls_error) = tls_error(&error).map(ToString::to_string) else {
+        return offline(error);
+    };
+    let server_url = error
+        .url()
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_else(|| "the server".to_string());
+
+    anyhow::Error::new(error).context(format!(
+        "The TLS connection to {} failed ({}). Check the server's certificate and the --ca-cert, --pin and --client-cert options of 'dood set-server'.",
+        server_url, tls_error
+    ))
+}
+
+/// The rustls error behind `error`, if the handshake is what failed.
+fn tls_error(error: &reqwest::Error) -> Option<&rustls::Error> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(current) = source {
+        if let Some(tls_error) = current.downcast_ref::<rustls::Error>() {
+            return Some(tls_error);
+        }
+        // hyper-rustls reports handshake failures as I/O errors.
+        if let Some(tls_error) = current
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref())
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        {
+            return Some(tls_error);
+        }
+        source = current.source();
+    }
+    None
+}
+
+/// Turns a failure to connect into `Offline`, keeping the cause.
+fn offline(error: reqwest::Error) -> anyhow::Error {
+    let server_url = error
+        .url()
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_else(|| "the server".to_string());
+
+    anyhow::Error::new(error).context(Offline { server_url })
+}
+
+fn check_rate_limit(response: reqwest::Response) -> Result<reqwest::Response> {
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(RateLimited {
+            retry_after: retry_after(&response),
+        }
+        .into());
+    }
+    Ok(response)
+}
+
+/// The `Retry-After` header, given either in seconds or as an HTTP date.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+        .or(Some(Duration::ZERO))
+}
+
+/// Opens a TCP connection to `host:port`, through the configured SOCKS proxy
+/// if there is one. `socks5h` proxies resolve the host themselves, which
+/// `.onion` addresses require.
+async fn connect(host: &str, port: u16) -> Result<Box<dyn Connection>> {
+    let Some(proxy) = config::get_proxy()? else {
+        return Ok(Box::new(TcpStream::connect((host, port)).await?));
+    };
+
+    let proxy = reqwest::Url::parse(&proxy).context("Invalid proxy URL")?;
+    let proxy_addr = (
+        proxy
+            .host_str()
+            .context("Proxy URL has no host")?
+            .to_string(),
+        proxy.port().unwrap_or(1080),
+    );
+
+    let stream = match proxy.scheme() {
+        "socks5h" => connect_socks(proxy_addr, &proxy, (host, port)).await?,
+        "socks5" => {
+            let target = tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .with_context(|| format!("Could not resolve {}", host))?;
+            connect_socks(proxy_addr, &proxy, target).await?
+        }
+        scheme => anyhow::bail!(
+            "The message stream only supports SOCKS proxies, not {}",
+            scheme
+        ),
+    };
+
+    Ok(Box::new(stream))
+}
+
+async fn connect_socks<'t, T: tokio_socks::IntoTargetAddr<'t>>(
+    proxy_addr: (String, u16),
+    proxy: &reqwest::Url,
+    target: T,
+) -> Result<Socks5Stream<TcpStream>> {
+    let proxy_addr = (proxy_addr.0.as_str(), proxy_addr.1);
+
+    let stream = if proxy.username().is_empty() {
+        Socks5Stream::connect(proxy_addr, target).await
+    } else {
+        Socks5Stream::connect_with_password(
+            proxy_addr,
+            target,
+            proxy.username(),
+            proxy.password().unwrap_or_default(),
+        )
+        .await
+    };
+
+    stream.context("Failed to connect through the proxy")
+}
+
+/// Challenge token and identity header that authenticate the current user.
+fn credentials() -> Result<(String, String)> {
+    let mut x3dh = auth::get_current_x3dh()?;
+    let token = BASE64_STANDARD.encode(x3dh.generate_challenge());
+    let identity = BASE64_STANDARD.encode(auth::get_identity_public_key(&x3dh).to_bytes());
+    Ok((token, identity))
+}
+
+/// Devices registered for the current account.
+pub async fn list_devices() -> Result<serde_json::Value> {
     let server_url = auth::get_server_url()?;
-    let client = reqwest::Client::new();
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .get(format!("{}/account/devices", server_url))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .send_with_retry()
+        .await
+        .context("Failed to list devices")?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to list devices: {}", error_text);
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Publishes the current account's display name and status.
+pub async fn publish_profile(display_name: Option<&str>, status: Option<&str>) -> Result<()> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .put(format!("{}/account/profile", server_url))
+        .json(&serde_json::json!({
+            "display_name": display_name,
+            "status": status,
+        }))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .send_with_retry()
+        .await
+        .context("Failed to publish profile")?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to publish profile: {}", error_text);
+    }
+
+    Ok(())
+}
+
+/// Stores an encrypted attachment in the server's blob storage and returns
+/// its id, or `None` if the server has no blob storage.
+pub async fn upload_attachment(blob: Vec<u8>) -> Result<Option<String>> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .post(format!("{}/attachment", server_url))
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .body(blob)
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .header(IDEMPOTENCY_KEY_HEADER, idempotency_key())
+        .send_with_retry()
+        .await
+        .context("Failed to upload attachment")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to upload attachment: {}", error_text);
+    }
+
+    let info: serde_json::Value = response.json().await?;
+    let id = info["id"]
+        .as_str()
+        .filter(|id| is_attachment_id(id))
+        .context("The server returned no valid attachment id")?;
+
+    Ok(Some(id.to_string()))
+}
+
+/// Fetches an encrypted attachment from the server's blob storage, refusing
+/// anything larger than `max_bytes`. `None` if the server no longer has it.
+pub async fn download_attachment(id: &str, max_bytes: usize) -> Result<Option<Vec<u8>>> {
+    // The id comes from the sender, so it must not be able to name any
+    // other endpoint.
+    if !is_attachment_id(id) {
+        anyhow::bail!("Invalid attachment id '{}'", id);
+    }
+
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let mut response = http_client()?
+        .get(format!("{}/attachment/{}", server_url, id))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .send_with_retry()
+        .await
+        .context("Failed to download attachment")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to download attachment: {}", error_text);
+    }
+
+    let mut blob = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if blob.len() + chunk.len() > max_bytes {
+            anyhow::bail!(
+                "Refusing an attachment of more than {} bytes from the server",
+                max_bytes
+            );
+        }
+        blob.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(blob))
+}
+
+fn is_attachment_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Result of asking a server's health endpoint.
+pub struct Health {
+    pub round_trip: Duration,
+    pub status: reqwest::StatusCode,
+    /// The protocol version the server advertised.
+    pub protocol: Option<u32>,
+    /// The JSON body, which may include `version`, if the server sent one.
+    pub info: Option<serde_json::Value>,
+}
+
+/// Calls `GET /health` on `server_url` once, without retries, and measures
+/// the round trip. Fails only if the server could not be reached at all.
+pub async fn check_health(server_url: &str) -> Result<Health> {
+    let started = std::time::Instant::now();
+
+    let response = http_client()?
+        .get(format!("{}/health", server_url))
+        .send()
+        .await
+        .map_err(|e| match e.is_connect() {
+            true => connect_error(e),
+            false => anyhow::Error::new(e).context(format!("Could not reach {}", server_url)),
+        })?;
+
+    let round_trip = started.elapsed();
+    let status = response.status();
+    let protocol = server_protocol(&response);
+    check_protocol(&response)?;
+    let info = response.json().await.ok();
+
+    Ok(Health {
+        round_trip,
+        status,
+        protocol,
+        info,
+    })
+}
+
+/// The largest encrypted message `server_url` accepts, if its health
+/// endpoint advertises one as `max_message_bytes`.
+pub async fn fetch_max_message_bytes(server_url: &str) -> Result<Option<usize>> {
+    let health = check_health(server_url).await?;
+
+    Ok(health
+        .info
+        .filter(|_| health.status.is_success())
+        .and_then(|info| info["max_message_bytes"].as_u64())
+        .map(|limit| limit as usize))
+}
+
+/// Tells the server that these messages are stored locally and can be
+/// deleted.
+pub async fn acknowledge_messages(ids: &[serde_json::Value]) -> Result<()> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .post(format!("{}/message/ack", server_url))
+        .json(&serde_json::json!({ "ids": ids }))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .header(IDEMPOTENCY_KEY_HEADER, idempotency_key())
+        .send_with_retry()
+        .await
+        .context("Failed to acknowledge messages")?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to acknowledge messages: {}", error_text);
+    }
+
+    Ok(())
+}
+
+/// Removes the current account and all its devices from the server.
+pub async fn deregister() -> Result<()> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .delete(format!("{}/account", server_url))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .send_with_retry()
+        .await
+        .context("Failed to delete account")?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to delete account: {}", error_text);
+    }
+
+    Ok(())
+}
+
+pub async fn revoke_device(device_id: u64) -> Result<()> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let response = http_client()?
+        .delete(format!("{}/account/device/{}", server_url, device_id))
+        .bearer_auth(&token)
+        .header("identity", identity)
+        .send_with_retry()
+        .await
+        .context("Failed to revoke device")?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to revoke device: {}", error_text);
+    }
+
+    Ok(())
+}
+
+pub async fn fetch_key_bundle_by_id(user_id: u64) -> Result<Vec<api::DeviceKeyBundle>> {
+    let server_url = auth::get_server_url()?;
+    let client = http_client()?;
 
     let response = client
         .get(format!(
             "{}/account/key-bundle?user_id={}",
             server_url, user_id
         ))
-        .send()
+        .send_with_retry()
         .await
         .context("Failed to fetch key bundle")?;
 
@@ -21,6 +778,117 @@ pub async fn fetch_key_bundle_by_id(user_id: u64) -> Result<serde_json::Value> {
         anyhow::bail!("Failed to fetch key bundle: {}", error_text);
     }
 
-    let bundle = response.json().await?;
-    Ok(bundle)
+    api::decode(response, "/account/key-bundle").await
+}
+
+/// Opens the server's WebSocket stream, which pushes messages for the current
+/// user as they arrive.
+pub async fn open_message_stream() -> Result<MessageStream> {
+    let server_url = auth::get_server_url()?;
+    let (token, identity) = credentials()?;
+
+    let stream_url = server_url
+        .replacen("https://", "wss://", 1)
+        .replacen("http://", "ws://", 1);
+
+    let mut request = format!("{}/message/stream", stream_url).into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "authorization",
+        HeaderValue::from_str(&format!("Bearer {}", token))?,
+    );
+    headers.insert("identity", HeaderValue::from_str(&identity)?);
+    headers.insert(PROTOCOL_HEADER, PROTOCOL_VERSION.into());
+    headers.insert("user-agent", HeaderValue::from_str(&user_agent())?);
+    for (name, value) in config::get_extra_headers()? {
+        headers.insert(
+            tokio_tungstenite::tungstenite::http::HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(&value)?,
+        );
+    }
+
+    let host = request
+        .uri()
+        .host()
+        .context("Server URL has no host")?
+        .to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if request.uri().scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        });
+
+    let (connect_timeout, _) = timeouts()?;
+    let connector = Connector::Rustls(Arc::new(tls::client_config()?));
+
+    let handshake = async {
+        let connection = connect(&host, port).await?;
+        let (stream, _) = tokio_tungstenite::client_async_tls_with_config(
+            request,
+            connection,
+            None,
+            Some(connector),
+        )
+        .await?;
+        anyhow::Ok(stream)
+    };
+
+    tokio::time::timeout(connect_timeout, handshake)
+        .await
+        .context("Timed out opening the message stream")?
+        .context("Failed to open the message stream")
+}
+
+/// URL of the key transparency log the server publishes key bundles to, if it
+/// advertises one.
+pub async fn fetch_transparency_log_url() -> Result<Option<String>> {
+    let server_url = auth::get_server_url()?;
+    let client = http_client()?;
+
+    let response = client
+        .get(format!("{}/transparency", server_url))
+        .send_with_retry()
+        .await
+        .context("Failed to query key transparency support")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to query key transparency support: {}", error_text);
+    }
+
+    let info: serde_json::Value = response.json().await?;
+    Ok(info["log_url"]
+        .as_str()
+        .map(|url| url.trim_end_matches('/').to_string()))
+}
+
+/// The latest key bundle entry the transparency log holds for `username`.
+pub async fn fetch_transparency_entry(
+    log_url: &str,
+    username: &str,
+) -> Result<Option<serde_json::Value>> {
+    let client = http_client()?;
+
+    let response = client
+        .get(format!("{}/entries", log_url))
+        .query(&[("username", username)])
+        .send_with_retry()
+        .await
+        .context("Failed to query the key transparency log")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        let error_text = response.text().await?;
+        anyhow::bail!("Failed to query the key transparency log: {}", error_text);
+    }
+
+    Ok(Some(response.json().await?))
 }
@@ -0,0 +1,135 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Columns `text` takes up in a terminal. CJK characters and most emoji take
+/// two, combining marks none.
+pub fn width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+pub fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+/// Shortens `text` to at most `max_width` columns, ending in "..." if
+/// anything was cut. Never splits a grapheme cluster, such as an emoji with
+/// a skin tone or a letter with its accents.
+pub fn truncate(text: &str, max_width: usize) -> String {
+    if width(text) <= max_width {
+        return text.to_string();
+    }
+
+    let limit = max_width.saturating_sub(3);
+    let mut truncated = String::new();
+    let mut used = 0;
+
+    for grapheme in text.graphemes(true) {
+        let grapheme_width = width(grapheme);
+        if used + grapheme_width > limit {
+            break;
+        }
+        truncated.push_str(grapheme);
+        used += grapheme_width;
+    }
+
+    truncated.push_str("...");
+    truncated
+}
+
+/// Pads `text` with spaces to `min_width` columns; `{:<N}` counts characters
+/// instead, which misaligns wide ones.
+pub fn pad(text: &str, min_width: usize) -> String {
+    format!(
+        "{}{}",
+        text,
+        " ".repeat(min_width.saturating_sub(width(text)))
+    )
+}
+
+/// Splits `text` into lines at most `max_width` columns wide, between grapheme
+/// clusters; the first line leaves room for a prefix of `first_indent`
+/// columns.
+pub fn wrap(text: &str, max_width: usize, first_indent: usize) -> Vec<String> {
+    let max_width = max_width.max(1);
+    let mut lines = Vec::new();
+
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        let mut limit = if lines.is_empty() {
+            max_width.saturating_sub(first_indent).max(1)
+        } else {
+            max_width
+        };
+
+        for grapheme in paragraph.graphemes(true) {
+            let grapheme_width = width(grapheme);
+            // A character wider than the whole line still gets one of its own.
+            if line_width + grapheme_width > limit && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+                limit = max_width;
+            }
+            line.push_str(grapheme);
+            line_width += grapheme_width;
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_keeps_text_that_fits() {
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("", 0), "");
+    }
+
+    #[test]
+    fn truncate_counts_columns_not_characters() {
+        assert_eq!(truncate("hello world", 8), "hello...");
+        // Each of these takes two columns.
+        assert_eq!(truncate("日本語のテキスト", 9), "日本語...");
+        assert!(width(&truncate("日本語のテキスト", 8)) <= 8);
+    }
+
+    #[test]
+    fn truncate_never_splits_graphemes() {
+        let text = "👍🏽👍🏽👍🏽👍🏽👍🏽👍🏽";
+        let truncated = truncate(text, 9);
+
+        assert!(truncated.ends_with("..."));
+        assert!(width(&truncated) <= 9);
+        assert!(truncated
+            .trim_end_matches("...")
+            .graphemes(true)
+            .all(|grapheme| grapheme == "👍🏽"));
+    }
+
+    #[test]
+    fn wrap_fits_lines_to_width() {
+        let lines = wrap("the quick brown fox", 10, 0);
+
+        assert_eq!(lines.concat(), "the quick brown fox");
+        assert!(lines.iter().all(|line| width(line) <= 10));
+    }
+
+    #[test]
+    fn wrap_leaves_room_for_first_indent() {
+        let lines = wrap("abcdefghij", 6, 2);
+
+        assert_eq!(lines, vec!["abcd", "efghij"]);
+    }
+
+    #[test]
+    fn wrap_keeps_paragraphs_and_wide_characters() {
+        assert_eq!(wrap("ab\n\ncd", 10, 0), vec!["ab", "", "cd"]);
+        assert_eq!(wrap("日本語", 3, 0), vec!["日", "本", "語"]);
+        // Wider than the line, but still placed rather than dropped.
+        assert_eq!(wrap("日", 1, 0), vec!["日"]);
+    }
+}
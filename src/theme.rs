@@ -0,0 +1,197 @@
+use anyhow::Result;
+use colored::*;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::config;
+
+/// The parts of a message whose color can be chosen. Each is stored in the
+/// `theme_<name>` setting.
+pub const ELEMENTS: [(&str, &str); 5] = [
+    ("incoming", "text of messages you received"),
+    ("outgoing", "text of messages you sent"),
+    ("sender", "names of your contacts"),
+    ("you", "your own name, 'You'"),
+    ("timestamp", "times of messages"),
+];
+
+pub struct Theme {
+    incoming: Option<Color>,
+    outgoing: Option<Color>,
+    sender: Option<Color>,
+    you: Option<Color>,
+    timestamp: Option<Color>,
+}
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Turns colors off with `--no-color`, when `NO_COLOR` is set, or when
+/// stdout is not a terminal, e.g. when it is piped into another program.
+/// `CLICOLOR_FORCE` keeps them on in the last two cases.
+pub fn init_colors(no_color: bool) {
+    let set = |name: &str| env::var_os(name).is_some_and(|value| !value.is_empty() && value != "0");
+
+    if no_color {
+        colored::control::set_override(false);
+    } else if set("CLICOLOR_FORCE") {
+        colored::control::set_override(true);
+    } else if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+        || !io::stdout().is_terminal()
+    {
+        colored::control::set_override(false);
+    }
+}
+
+/// The theme from the settings, read once per run. Falls back to the default
+/// colors if a setting cannot be read, so output never fails over a color.
+pub fn current() -> &'static Theme {
+    THEME.get_or_init(|| {
+        let color = |element: &str, default: Option<Color>| {
+            config::get_setting(&setting_key(element))
+                .ok()
+                .flatten()
+                .and_then(|value| parse_color(&value).ok())
+                .unwrap_or(default)
+        };
+
+        Theme {
+            incoming: color("incoming", None),
+            outgoing: color("outgoing", None),
+            sender: color("sender", Some(Color::Green)),
+            you: color("you", Some(Color::Blue)),
+            timestamp: color("timestamp", Some(Color::BrightBlack)),
+        }
+    })
+}
+
+impl Theme {
+    fn color(&self, element: &str) -> Option<Color> {
+        match element {
+            "incoming" => self.incoming,
+            "outgoing" => self.outgoing,
+            "sender" => self.sender,
+            "you" => self.you,
+            "timestamp" => self.timestamp,
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, text: &str, outgoing: bool) -> ColoredString {
+        paint(
+            text,
+            if outgoing {
+                self.outgoing
+            } else {
+                self.incoming
+            },
+        )
+    }
+
+    pub fn sender(&self, name: &str) -> ColoredString {
+        paint(name, self.sender).bold()
+    }
+
+    pub fn you(&self, text: &str) -> ColoredString {
+        paint(text, self.you).bold()
+    }
+
+    pub fn timestamp(&self, time: &str) -> ColoredString {
+        paint(time, self.timestamp)
+    }
+}
+
+fn paint(text: &str, color: Option<Color>) -> ColoredString {
+    match color {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    }
+}
+
+fn setting_key(element: &str) -> String {
+    format!("theme_{}", element)
+}
+
+/// A color name such as `red` or `bright black`, a hex color such as
+/// `#ff8800`, or `default` for the terminal's own color.
+fn parse_color(value: &str) -> Result<Option<Color>> {
+    let value = value.trim();
+
+    if value.eq_ignore_ascii_case("default") {
+        return Ok(None);
+    }
+
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |range: std::ops::Range<usize>| {
+            hex.get(range)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        };
+        if let (6, Some(r), Some(g), Some(b)) =
+            (hex.len(), channel(0..2), channel(2..4), channel(4..6))
+        {
+            return Ok(Some(Color::TrueColor { r, g, b }));
+        }
+        anyhow::bail!("'{}' is not a color; use #rrggbb for hex colors", value);
+    }
+
+    Color::from_str(&value.replace(['_', '-'], " "))
+        .map(Some)
+        .map_err(|_| anyhow::anyhow!("'{}' is not a color", value))
+}
+
+fn check_element(element: &str) -> Result<()> {
+    if !ELEMENTS.iter().any(|(name, _)| *name == element) {
+        let names: Vec<&str> = ELEMENTS.iter().map(|(name, _)| *name).collect();
+        anyhow::bail!(
+            "Unknown theme element '{}'. Choose one of: {}",
+            element,
+            names.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub fn set(element: &str, color: &str) -> Result<()> {
+    check_element(element)?;
+    parse_color(color)?;
+    config::set_setting(&setting_key(element), color.trim())?;
+
+    println!(
+        "{} The {} color is now {}",
+        "✓".green().bold(),
+        element.bold(),
+        color.trim()
+    );
+    Ok(())
+}
+
+pub fn reset() -> Result<()> {
+    for (element, _) in ELEMENTS {
+        config::delete_setting(&setting_key(element))?;
+    }
+
+    println!("{} Restored the default colors", "✓".green().bold());
+    Ok(())
+}
+
+pub fn display() -> Result<()> {
+    let theme = current();
+
+    println!("\n{}", "🎨 Theme".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for (element, about) in ELEMENTS {
+        let sample = paint(&format!("{:<10}", element), theme.color(element));
+
+        println!(
+            "  {} {} {}",
+            sample,
+            config::get_setting(&setting_key(element))?.unwrap_or_else(|| "default".to_string()),
+            format!("({})", about).bright_black()
+        );
+    }
+
+    println!();
+    Ok(())
+}
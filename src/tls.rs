@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use colored::*;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{config, database};
+
+/// TLS settings shared by the HTTP client and the message stream: the system
+/// roots and the configured private CA, the client certificate for mutual
+/// TLS, plus the pinned certificate if one is configured.
+pub fn client_config() -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()
+        .context("Failed to load the system certificates")?
+    {
+        // Skip certificates rustls cannot use instead of failing outright.
+        let _ = roots.add(&Certificate(cert.0));
+    }
+
+    if let Some(path) = config::get_setting(CA_CERT_SETTING)? {
+        for cert in load_certs(Path::new(&path))? {
+            roots
+                .add(&cert)
+                .with_context(|| format!("Invalid CA certificate in {}", path))?;
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+
+    let cert_path = config::get_setting(CLIENT_CERT_SETTING)?;
+    let key_path = config::get_setting(CLIENT_KEY_SETTING)?;
+    let mut tls = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(
+                load_certs(Path::new(&cert_path))?,
+                load_private_key(Path::new(&key_path))?,
+            )
+            .context("The client certificate does not match its key")?,
+        _ => builder.with_no_client_auth(),
+    };
+
+    if let Some(pin) = config::get_setting(PIN_SETTING)? {
+        tls.dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                pin,
+            }));
+    }
+
+    Ok(tls)
+}
+
+/// Setting holding the pinned SHA-256 hash, as lowercase hex.
+const PIN_SETTING: &str = "tls_pin";
+
+/// Setting holding the path of an extra CA certificate (PEM) to trust.
+const CA_CERT_SETTING: &str = "ca_cert";
+
+/// Trusts the CA certificates in the PEM file at `path` in addition to the
+/// system ones, for servers with internal or self-signed certificates. `None`
+/// stops trusting it.
+pub fn set_ca_cert(path: Option<&str>) -> Result<()> {
+    let Some(path) = path else {
+        let conn = database::get_connection()?;
+        conn.execute("DELETE FROM config WHERE key = ?1", [CA_CERT_SETTING])?;
+        println!("{} Custom CA certificate removed", "✓".green().bold());
+        return Ok(());
+    };
+
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("Cannot find CA certificate {}", path))?;
+    let certs = load_certs(&path)?;
+
+    config::set_setting(CA_CERT_SETTING, &path.to_string_lossy())?;
+    println!(
+        "{} Trusting {} certificate(s) from {}",
+        "✓".green().bold(),
+        certs.len(),
+        path.display().to_string().bold()
+    );
+
+    Ok(())
+}
+
+/// Settings holding the paths of the client certificate chain and its
+/// private key (PEM) for mutual TLS.
+const CLIENT_CERT_SETTING: &str = "client_cert";
+const CLIENT_KEY_SETTING: &str = "client_key";
+
+/// Presents this certificate and key to the server, for deployments behind
+/// a proxy that requires mutual TLS. `None` stops presenting one.
+pub fn set_client_cert(paths: Option<(&str, &str)>) -> Result<()> {
+    let Some((cert, key)) = paths else {
+        let conn = database::get_connection()?;
+        conn.execute(
+            "DELETE FROM config WHERE key IN (?1, ?2)",
+            [CLIENT_CERT_SETTING, CLIENT_KEY_SETTING],
+        )?;
+        println!("{} Client certificate removed", "✓".green().bold());
+        return Ok(());
+    };
+
+    let cert = std::fs::canonicalize(cert)
+        .with_context(|| format!("Cannot find client certificate {}", cert))?;
+    let key =
+        std::fs::canonicalize(key).with_context(|| format!("Cannot find client key {}", key))?;
+
+    // Check that the pair is usable now rather than on the next request.
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_client_auth_cert(load_certs(&cert)?, load_private_key(&key)?)
+        .context("The client certificate does not match its key")?;
+
+    config::set_setting(CLIENT_CERT_SETTING, &cert.to_string_lossy())?;
+    config::set_setting(CLIENT_KEY_SETTING, &key.to_string_lossy())?;
+    println!(
+        "{} Using client certificate {}",
+        "✓".green().bold(),
+        cert.display().to_string().bold()
+    );
+
+    Ok(())
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open private key {}", path.display()))?;
+
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key {}", path.display()))?
+    {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    anyhow::bail!("No private key found in {}", path.display())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("No PEM certificates found in {}", path.display());
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Pins the server's certificate: connections are only made when the
+/// certificate, or its public key (SPKI), has this SHA-256 hash. `None`
+/// removes the pin.
+pub fn set_pin(pin: Option<&str>) -> Result<()> {
+    let Some(pin) = pin else {
+        let conn = database::get_connection()?;
+        conn.execute("DELETE FROM config WHERE key = ?1", [PIN_SETTING])?;
+        println!("{} Certificate pin removed", "✓".green().bold());
+        return Ok(());
+    };
+
+    let pin = pin.replace(':', "").to_lowercase();
+    if pin.len() != 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid pin. Expected a SHA-256 hash as 64 hex digits.");
+    }
+
+    config::set_setting(PIN_SETTING, &pin)?;
+    println!(
+        "{} Server certificate pinned to {}",
+        "✓".green().bold(),
+        pin.bold()
+    );
+
+    Ok(())
+}
+
+/// Validates the chain as usual, then requires the leaf certificate to match
+/// the pin.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pin: String,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let cert_hash = hex_sha256(&end_entity.0);
+        let spki_hash = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map(|(_, cert)| hex_sha256(cert.tbs_certificate.subject_pki.raw))
+            .map_err(|_| rustls::Error::General("Invalid server certificate".to_string()))?;
+
+        if self.pin == cert_hash || self.pin == spki_hash {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate does not match the pin (certificate {}, public key {})",
+                cert_hash, spki_hash
+            )))
+        }
+    }
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
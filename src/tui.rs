@@ -0,0 +1,702 @@
+use anyhow::Result;
+use chrono::{Datelike, Local, NaiveDate};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures_util::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::HashMap;
+use std::io::{self, Stdout};
+use std::time::Duration;
+
+use crate::{auth, daemon, database, messages, output, server, text, ui};
+
+/// How often the screen is reloaded from the database, which also picks up
+/// messages stored by the daemon.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Messages loaded for the open conversation.
+const HISTORY_LIMIT: usize = 500;
+
+const SIDEBAR_WIDTH: u16 = 28;
+
+/// Most weeks of activity the stats view shows, as many as fit.
+const STATS_WEEKS: i64 = 26;
+
+const HELP: &str = "Tab/Shift+Tab switch chat · PgUp/PgDn scroll · ↑/↓ input history · /open <user> · /stats · /fetch · Esc quit";
+
+type Backend = CrosstermBackend<Stdout>;
+
+/// What the event loop has to do after a key press.
+enum Action {
+    None,
+    Send(String),
+    Fetch,
+    /// Trust the changed identity key the user was asked about.
+    Trust,
+    Quit,
+}
+
+struct App {
+    conversations: Vec<database::Conversation>,
+    /// The open conversation, which may not have messages yet.
+    current: Option<String>,
+    messages: Vec<database::Message>,
+    /// How many lines the message pane is scrolled up from the newest.
+    scroll: usize,
+    input: String,
+    /// Cursor position in `input`, in characters.
+    cursor: usize,
+    /// Lines entered this session, oldest first.
+    history: Vec<String>,
+    /// Position in `history` while browsing it with the arrow keys.
+    history_index: Option<usize>,
+    status: String,
+    /// Activity in the open conversation, shown instead of its messages
+    /// while set.
+    stats: Option<Stats>,
+    /// A changed identity key the user is being asked to trust.
+    identity_change: Option<messages::IdentityChanged>,
+}
+
+/// Message counts per day and per hour of the day, as `dood stats` shows
+/// them.
+struct Stats {
+    today: NaiveDate,
+    /// Monday of the first week that can be shown.
+    start: NaiveDate,
+    daily: HashMap<NaiveDate, i64>,
+    hourly: [i64; 24],
+}
+
+impl Stats {
+    fn load(username: &str) -> Result<Self> {
+        let today = Local::now().date_naive();
+        let days_back = (STATS_WEEKS - 1) * 7 + today.weekday().num_days_from_monday() as i64;
+
+        Ok(Stats {
+            today,
+            start: today - chrono::Duration::days(days_back),
+            daily: database::get_daily_message_counts(username, days_back)?
+                .into_iter()
+                .collect(),
+            hourly: database::get_hourly_message_counts(username)?,
+        })
+    }
+}
+
+impl App {
+    fn new() -> Result<Self> {
+        let mut app = App {
+            conversations: Vec::new(),
+            current: None,
+            messages: Vec::new(),
+            scroll: 0,
+            input: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_index: None,
+            status: HELP.to_string(),
+            stats: None,
+            identity_change: None,
+        };
+        app.reload()?;
+        Ok(app)
+    }
+
+    /// Reads conversations and the open conversation's messages again, and
+    /// marks what is on screen as read.
+    fn reload(&mut self) -> Result<()> {
+        self.conversations = database::get_conversations(false)?;
+
+        if self.current.is_none() {
+            self.current = self
+                .conversations
+                .first()
+                .map(|conversation| conversation.username.clone());
+        }
+
+        let Some(username) = self.current.clone() else {
+            self.messages.clear();
+            self.stats = None;
+            return Ok(());
+        };
+
+        if self.stats.is_some() {
+            self.stats = Some(Stats::load(&username)?);
+        }
+
+        if let Some(conversation) = self
+            .conversations
+            .iter_mut()
+            .find(|conversation| conversation.username == username && conversation.unread > 0)
+        {
+            messages::mark_read(&username)?;
+            conversation.unread = 0;
+        }
+
+        self.messages = database::get_messages(&username, HISTORY_LIMIT)?;
+        self.messages.reverse();
+
+        Ok(())
+    }
+
+    fn open(&mut self, username: &str) -> Result<()> {
+        self.current = Some(username.to_string());
+        self.scroll = 0;
+        self.reload()
+    }
+
+    /// Opens the conversation `offset` places down the sidebar, wrapping
+    /// around at either end.
+    fn select(&mut self, offset: isize) -> Result<()> {
+        if self.conversations.is_empty() {
+            return Ok(());
+        }
+
+        let len = self.conversations.len() as isize;
+        let position = self
+            .current_index()
+            .map(|index| index as isize + offset)
+            .unwrap_or(0)
+            .rem_euclid(len);
+
+        let username = self.conversations[position as usize].username.clone();
+        self.open(&username)
+    }
+
+    fn current_index(&self) -> Option<usize> {
+        let current = self.current.as_ref()?;
+        self.conversations
+            .iter()
+            .position(|conversation| &conversation.username == current)
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Result<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        // Any key but Y answers no.
+        if let Some(change) = self.identity_change.take() {
+            if let KeyCode::Char('y') | KeyCode::Char('Y') = key.code {
+                self.identity_change = Some(change);
+                return Ok(Action::Trust);
+            }
+            self.status = format!(
+                "Not trusted. Verify the new key with 'dood verify {}'.",
+                change.username
+            );
+            return Ok(Action::None);
+        }
+
+        match key.code {
+            KeyCode::Esc if self.stats.is_some() => self.stats = None,
+            KeyCode::Esc => return Ok(Action::Quit),
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return Ok(Action::Quit),
+            KeyCode::Tab => self.select(1)?,
+            KeyCode::BackTab => self.select(-1)?,
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Up => self.browse_history(true),
+            KeyCode::Down => self.browse_history(false),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    let at = self.byte_index(self.cursor);
+                    self.input.remove(at);
+                }
+            }
+            KeyCode::Delete => {
+                if self.cursor < self.input.chars().count() {
+                    let at = self.byte_index(self.cursor);
+                    self.input.remove(at);
+                }
+            }
+            KeyCode::Char(c) if !ctrl => {
+                let at = self.byte_index(self.cursor);
+                self.input.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Enter => return self.submit(),
+            _ => {}
+        }
+
+        Ok(Action::None)
+    }
+
+    /// Shows `error`, or asks whether to trust the new key if a contact's
+    /// identity key changed.
+    fn show_error(&mut self, error: anyhow::Error) {
+        match error.downcast_ref::<messages::IdentityChanged>() {
+            Some(change) => {
+                self.status = format!("⚠ {} Trust the new key? (y/N)", change);
+                self.identity_change = Some(change.clone());
+            }
+            None => self.status = format!("✗ Error: {}", error),
+        }
+    }
+
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.input
+            .char_indices()
+            .nth(cursor)
+            .map(|(index, _)| index)
+            .unwrap_or(self.input.len())
+    }
+
+    fn browse_history(&mut self, back: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        self.history_index = match (self.history_index, back) {
+            (None, true) => Some(self.history.len() - 1),
+            (None, false) => None,
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (Some(index), false) if index + 1 < self.history.len() => Some(index + 1),
+            (Some(_), false) => None,
+        };
+
+        self.input = self
+            .history_index
+            .map(|index| self.history[index].clone())
+            .unwrap_or_default();
+        self.cursor = self.input.chars().count();
+    }
+
+    fn submit(&mut self) -> Result<Action> {
+        let input = self.input.trim().to_string();
+        self.input.clear();
+        self.cursor = 0;
+        self.history_index = None;
+
+        if input.is_empty() {
+            return Ok(Action::None);
+        }
+        self.history.push(input.clone());
+
+        if input == "/quit" || input == "/exit" {
+            return Ok(Action::Quit);
+        }
+        if input == "/fetch" {
+            return Ok(Action::Fetch);
+        }
+        if let Some(username) = input.strip_prefix("/open") {
+            match username.trim() {
+                "" => self.status = "Usage: /open <username>".to_string(),
+                username => {
+                    self.open(username)?;
+                    self.status = HELP.to_string();
+                }
+            }
+            return Ok(Action::None);
+        }
+
+        if self.current.is_none() {
+            self.status = "Open a conversation first with /open <username>".to_string();
+            return Ok(Action::None);
+        }
+
+        if input == "/stats" {
+            match self.stats {
+                Some(_) => self.stats = None,
+                None => {
+                    self.stats = Some(Stats::load(self.current.as_deref().unwrap_or_default())?);
+                    self.status = "Esc or /stats goes back to the messages".to_string();
+                }
+            }
+            return Ok(Action::None);
+        }
+
+        Ok(Action::Send(input))
+    }
+}
+
+/// Puts the terminal back the way it was, also when leaving with an error.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        colored::control::unset_override();
+    }
+}
+
+pub async fn run() -> Result<()> {
+    let current_username = auth::get_current_username()?;
+    let mut app = App::new()?;
+
+    let mut stream = if daemon::is_running(&current_username) {
+        app.status =
+            "The daemon is receiving messages; they appear here as it stores them.".to_string();
+        None
+    } else {
+        match server::open_message_stream().await {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                app.status = format!("Live updates unavailable ({}). Use /fetch instead.", e);
+                None
+            }
+        }
+    };
+
+    // Anything sending and receiving would print shows up in the status bar
+    // instead, without escape codes, which ratatui would draw as text.
+    let (notice_sender, mut notices) = tokio::sync::mpsc::unbounded_channel();
+    let _capture = output::capture(move |event| {
+        if let output::Event::Notice(line) = event {
+            notice_sender.send(line).ok();
+        }
+    });
+
+    enable_raw_mode()?;
+    let _guard = TerminalGuard;
+    colored::control::set_override(false);
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+
+    loop {
+        terminal.draw(|frame| draw(frame, &app))?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    match app.handle_key(key)? {
+                        Action::None => {}
+                        Action::Quit => break,
+                        Action::Send(text) => send(&mut terminal, &mut app, &text).await?,
+                        Action::Fetch => fetch(&mut app).await?,
+                        Action::Trust => trust(&mut terminal, &mut app).await?,
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            frame = ui::next_frame(&mut stream) => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        if let Err(e) = messages::handle_stream_frame(&current_username, frame).await {
+                            app.show_error(e);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        app.status = format!("Live updates stopped: {}", e);
+                        stream = None;
+                    }
+                    None => {
+                        app.status = "Live updates stopped.".to_string();
+                        stream = None;
+                    }
+                }
+                app.reload()?;
+            }
+            Some(line) = notices.recv() => {
+                // The status bar has room for one line.
+                if let Some(line) = line.lines().map(str::trim).find(|line| !line.is_empty()) {
+                    if app.identity_change.is_none() {
+                        app.status = line.to_string();
+                    }
+                }
+            }
+            _ = refresh.tick() => app.reload()?,
+        }
+    }
+
+    Ok(())
+}
+
+async fn send(terminal: &mut Terminal<Backend>, app: &mut App, text: &str) -> Result<()> {
+    let Some(username) = app.current.clone() else {
+        return Ok(());
+    };
+
+    app.status = format!("Sending to {}...", username);
+    terminal.draw(|frame| draw(frame, app))?;
+
+    match messages::send_message(&username, text).await {
+        Ok(()) => app.status = "✓ Sent".to_string(),
+        Err(e) => app.show_error(e),
+    }
+    app.scroll = 0;
+
+    app.reload()
+}
+
+async fn fetch(app: &mut App) -> Result<()> {
+    let result = match daemon::ensure_not_running() {
+        Ok(()) => messages::fetch_pending(None).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(0) => app.status = "No new messages".to_string(),
+        Ok(count) => app.status = format!("{} new message(s)", count),
+        Err(e) => app.show_error(e),
+    }
+
+    app.reload()
+}
+
+/// Trusts the changed identity key the user agreed to, then sends the
+/// message it held back, if any.
+async fn trust(terminal: &mut Terminal<Backend>, app: &mut App) -> Result<()> {
+    let Some(change) = app.identity_change.take() else {
+        return Ok(());
+    };
+    change.accept()?;
+    app.status = format!("Trusted the new identity key for {}", change.username);
+
+    if let Some(message_id) = change.message {
+        app.status = format!("Sending to {}...", change.username);
+        terminal.draw(|frame| draw(frame, app))?;
+
+        match messages::resend_message(message_id).await {
+            Ok(()) => app.status = "✓ Sent".to_string(),
+            Err(e) => app.show_error(e),
+        }
+    }
+
+    app.reload()
+}
+
+fn draw(frame: &mut Frame, app: &App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .split(frame.size());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(1)])
+        .split(rows[0]);
+    let chat = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(3)])
+        .split(columns[1]);
+
+    draw_sidebar(frame, app, columns[0]);
+    match &app.stats {
+        Some(stats) => draw_stats(frame, app, stats, chat[0]),
+        None => draw_messages(frame, app, chat[0]),
+    }
+    draw_input(frame, app, chat[1]);
+
+    frame.render_widget(
+        Paragraph::new(app.status.as_str()).style(Style::default().fg(Color::DarkGray)),
+        rows[1],
+    );
+}
+
+fn draw_sidebar(frame: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .conversations
+        .iter()
+        .map(|conversation| {
+            let mut spans = vec![Span::raw(conversation.username.clone())];
+            if conversation.unread > 0 {
+                let style = if conversation.muted {
+                    Style::default().fg(Color::DarkGray)
+                } else {
+                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+                };
+                spans.push(Span::styled(format!(" [{}]", conversation.unread), style));
+            }
+            if conversation.muted {
+                spans.push(Span::raw(" 🔕"));
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let mut state = ListState::default().with_selected(app.current_index());
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(" Chats "))
+        .highlight_style(
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
+    let title = match &app.current {
+        Some(username) => format!(" {} ", username),
+        None => " No conversation ".to_string(),
+    };
+    let width = area.width.saturating_sub(2) as usize;
+    let height = area.height.saturating_sub(2) as usize;
+
+    let mut lines = Vec::new();
+    for msg in &app.messages {
+        let (name, style) = if msg.is_outgoing {
+            ("You", Style::default().fg(Color::Blue))
+        } else {
+            (msg.sender.as_str(), Style::default().fg(Color::Green))
+        };
+        let prefix = format!("{} {}: ", ui::format_timestamp(&msg.timestamp), name);
+
+        for (index, line) in text::wrap(&msg.content, width.saturating_sub(2), text::width(&prefix))
+            .into_iter()
+            .enumerate()
+        {
+            if index == 0 {
+                lines.push(Line::from(vec![
+                    Span::styled(prefix.clone(), style.add_modifier(Modifier::BOLD)),
+                    Span::raw(line),
+                ]));
+            } else {
+                lines.push(Line::from(format!("  {}", line)));
+            }
+        }
+    }
+
+    // Keep the newest line at the bottom unless scrolled up.
+    let scroll = app.scroll.min(lines.len().saturating_sub(height));
+    let end = lines.len() - scroll;
+    let start = end.saturating_sub(height);
+    let visible = lines[start..end].to_vec();
+
+    let title = if scroll > 0 {
+        format!("{}(scrolled up {} lines) ", title, scroll)
+    } else {
+        title
+    };
+
+    frame.render_widget(
+        Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+}
+
+/// An activity heatmap of the weeks that fit, like GitHub's, and a bar per
+/// hour of the day.
+fn draw_stats(frame: &mut Frame, app: &App, stats: &Stats, area: Rect) {
+    let title = format!(" Activity with {} ", app.current.as_deref().unwrap_or_default());
+    let width = area.width.saturating_sub(2) as usize;
+
+    // Each week is a column two characters wide, after the day labels.
+    let weeks = (width.saturating_sub(4) / 2).clamp(1, STATS_WEEKS as usize) as i64;
+    let first = stats.start + chrono::Duration::weeks(STATS_WEEKS - weeks);
+    let max_daily = stats.daily.values().copied().max().unwrap_or(0);
+    let label_style = Style::default().fg(Color::DarkGray);
+
+    let mut lines = Vec::new();
+    for (weekday, label) in ["Mon", "", "Wed", "", "Fri", "", "Sun"].iter().enumerate() {
+        let mut spans = vec![Span::styled(format!("{:<4}", label), label_style)];
+        for week in 0..weeks {
+            let day = first + chrono::Duration::days(week * 7 + weekday as i64);
+            if day > stats.today {
+                break;
+            }
+            let count = stats.daily.get(&day).copied().unwrap_or(0);
+            spans.push(heat_cell(count, max_daily));
+        }
+        lines.push(Line::from(spans));
+    }
+
+    let mut legend = vec![Span::styled("    Less ", label_style)];
+    legend.extend([0, 1, 2, 4].map(|count| heat_cell(count, 4)));
+    legend.push(Span::styled("More", label_style));
+    lines.push(Line::from(legend));
+    lines.push(Line::from(""));
+
+    lines.push(Line::from(Span::styled(
+        "Messages by hour",
+        Style::default().add_modifier(Modifier::BOLD),
+    )));
+    let max_hourly = stats.hourly.iter().copied().max().unwrap_or(0).max(1);
+    let bar_width = width.saturating_sub(10) as i64;
+    for (hour, &count) in stats.hourly.iter().enumerate() {
+        let mut spans = vec![
+            Span::styled(format!("{:02} ", hour), label_style),
+            Span::styled(
+                "█".repeat((count * bar_width / max_hourly) as usize),
+                Style::default().fg(Color::Green),
+            ),
+        ];
+        if count > 0 {
+            spans.push(Span::styled(format!(" {}", count), label_style));
+        }
+        lines.push(Line::from(spans));
+    }
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+}
+
+/// One day of the heatmap, shaded like `ui::display_stats` does.
+fn heat_cell(count: i64, max: i64) -> Span<'static> {
+    if count == 0 || max == 0 {
+        return Span::styled("· ", Style::default().fg(Color::DarkGray));
+    }
+
+    let (symbol, style) = match count * 4 / (max + 1) {
+        0 => ("▪ ", Style::default().fg(Color::Green)),
+        1 => ("■ ", Style::default().fg(Color::Green)),
+        2 => ("■ ", Style::default().fg(Color::LightGreen)),
+        _ => (
+            "■ ",
+            Style::default()
+                .fg(Color::LightGreen)
+                .add_modifier(Modifier::BOLD),
+        ),
+    };
+
+    Span::styled(symbol, style)
+}
+
+fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
+    let width = area.width.saturating_sub(2) as usize;
+
+    // Scroll the input sideways so the cursor stays visible. Positions are
+    // in characters, the screen in columns, and some characters take two.
+    let chars: Vec<char> = app.input.chars().collect();
+    let mut offset = 0;
+    let mut cursor_column: usize = chars[..app.cursor]
+        .iter()
+        .map(|&c| text::char_width(c))
+        .sum();
+    while cursor_column >= width && offset < app.cursor {
+        cursor_column -= text::char_width(chars[offset]);
+        offset += 1;
+    }
+
+    let mut visible = String::new();
+    let mut used = 0;
+    for &c in &chars[offset..] {
+        used += text::char_width(c);
+        if used > width {
+            break;
+        }
+        visible.push(c);
+    }
+
+    frame.render_widget(
+        Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title(" Message ")),
+        area,
+    );
+    frame.set_cursor(area.x + 1 + cursor_column as u16, area.y + 1);
+}
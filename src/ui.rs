@@ -1,108 +1,1145 @@
 use anyhow::Result;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use chrono::{DateTime, Local, Utc};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Utc};
 use colored::*;
-use std::io::{self, Write};
+use dialoguer::Confirm;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{auth, database, messages};
+use crate::line_editor::LineEditor;
+use crate::{
+    attachments, auth, config, crypto, daemon, database, messages, output, server, text, theme,
+};
 
-pub fn display_chats() -> Result<()> {
-    let conversations = database::get_conversations()?;
+pub fn display_chats(show_archived: bool) -> Result<()> {
+    let conversations = database::get_conversations(show_archived)?;
+
+    if output::is_machine() {
+        let mut chats = Vec::new();
+        for conversation in conversations {
+            chats.push(output::Chat {
+                name: contact_name(&conversation.username)?,
+                username: conversation.username,
+                verified: conversation.verified,
+                archived: conversation.archived,
+                muted: conversation.muted,
+                expire_timer: conversation.expire_timer,
+                unread: conversation.unread,
+                last_message: conversation
+                    .last_message
+                    .map(|(timestamp, content)| output::LastMessage { timestamp, content }),
+            });
+        }
+        return output::print(&output::ChatList {
+            conversations: chats,
+        });
+    }
 
     if conversations.is_empty() {
         println!("{}", "No conversations yet.".yellow());
         return Ok(());
     }
 
-    println!("\n{}", "📱 Your Conversations".bold().cyan());
+    println!("\n{}", "📱 Your Conversations".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for conversation in conversations {
+        let (time_str, preview) = match &conversation.last_message {
+            Some((last_time, last_msg)) => (
+                format_timestamp(last_time),
+                // A preview is a single line, whatever the message looks like.
+                text::truncate(&last_msg.replace('\n', " "), 40),
+            ),
+            None => (String::new(), "No messages".to_string()),
+        };
+        let unread = conversation.unread;
+
+        let archived_badge = if conversation.archived {
+            format!(" {}", "(archived)".bright_black())
+        } else {
+            String::new()
+        };
+
+        let muted = conversation.muted;
+
+        let unread_badge = if unread > 0 && muted {
+            format!(" {}", format!("[{}]", unread).bright_black())
+        } else if unread > 0 {
+            format!(" {}", format!("[{}]", unread).bright_red().bold())
+        } else {
+            String::new()
+        };
+
+        let verified_badge = if conversation.verified {
+            format!(" {}", "✔".green())
+        } else {
+            String::new()
+        };
+
+        let muted_badge = if muted {
+            " 🔕".to_string()
+        } else {
+            String::new()
+        };
+
+        let timer_badge = match conversation.expire_timer {
+            Some(seconds) => format!(" ⏱️ {}", format_timer(seconds)),
+            None => String::new(),
+        };
+
+        println!(
+            "{} {}{} {}{}{}{}{}",
+            "👤".bold(),
+            theme::current().sender(&display_name(&conversation.username)?),
+            verified_badge,
+            theme::current().timestamp(&time_str),
+            unread_badge,
+            muted_badge,
+            timer_badge.bright_black(),
+            archived_badge
+        );
+        println!("   {}", preview.bright_black());
+        println!();
+    }
+
+    Ok(())
+}
+
+pub fn set_archived(username: &str, archived: bool) -> Result<()> {
+    database::set_archived(username, archived)?;
+
+    if archived {
+        println!(
+            "{} Archived conversation with {}",
+            "✓".green().bold(),
+            username.bold()
+        );
+        println!(
+            "{}",
+            "Use 'dood chats --all' to see archived conversations.".bright_black()
+        );
+    } else {
+        println!(
+            "{} Unarchived conversation with {}",
+            "✓".green().bold(),
+            username.bold()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn mute(username: &str, duration: Option<&str>) -> Result<()> {
+    let until = match duration {
+        Some(duration) => Some(Utc::now() + config::parse_duration(duration)?),
+        None => None,
+    };
+
+    database::set_muted(username, until)?;
+
+    match until {
+        Some(until) => println!(
+            "{} Muted {} until {}",
+            "🔕".bold(),
+            username.bold(),
+            until
+                .with_timezone(&Local)
+                .format("%b %d %H:%M")
+                .to_string()
+                .bright_black()
+        ),
+        None => println!("{} Muted {}", "🔕".bold(), username.bold()),
+    }
+
+    Ok(())
+}
+
+pub fn unmute(username: &str) -> Result<()> {
+    database::clear_muted(username)?;
+    println!("{} Unmuted {}", "✓".green().bold(), username.bold());
+    Ok(())
+}
+
+/// Prints unread counts per conversation and returns the total.
+pub fn display_unread() -> Result<i32> {
+    let counts = database::get_unread_counts()?;
+    let total: i32 = counts.iter().map(|(_, count)| count).sum();
+
+    if output::is_machine() {
+        output::print(&output::UnreadSummary {
+            conversations: counts
+                .iter()
+                .map(|(username, unread)| output::UnreadConversation {
+                    username: username.clone(),
+                    unread: *unread,
+                })
+                .collect(),
+            total,
+        })?;
+        return Ok(total);
+    }
+
+    if counts.is_empty() {
+        println!("{}", "No unread messages.".yellow());
+        return Ok(total);
+    }
+
+    println!("\n{}", "📬 Unread Messages".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for (username, count) in &counts {
+        println!(
+            "{} {} {}",
+            "👤".bold(),
+            theme::current().sender(username),
+            format!("[{}]", count).bright_red().bold()
+        );
+    }
+
+    println!("{}", "─".repeat(60).bright_black());
+    println!("{} {}", "Total:".bold(), total);
+
+    Ok(total)
+}
+
+/// Shows a conversation in the order messages were sent, or with `received`
+/// in the order (and at the times) they arrived on this machine.
+pub fn display_history(username: &str, limit: usize, received: bool) -> Result<()> {
+    let mut messages = database::get_messages(username, limit)?;
+    if received {
+        messages.sort_by_key(|msg| std::cmp::Reverse(msg.received_at.unwrap_or(msg.timestamp)));
+    }
+
+    if output::is_machine() {
+        let mut entries = Vec::new();
+        for msg in messages.iter().rev() {
+            entries.push(output::HistoryEntry {
+                message: msg.into(),
+                failed: msg.is_outgoing && database::is_failed(msg.id)?,
+            });
+        }
+        output::print(&output::History {
+            username: username.to_string(),
+            name: contact_name(username)?,
+            verified: database::is_verified(username)?,
+            status: database::get_profile(username)?.1,
+            messages: entries,
+        })?;
+        return messages::mark_read(username);
+    }
+
+    if messages.is_empty() {
+        println!("{}", format!("No messages with {}", username).yellow());
+        return Ok(());
+    }
+
+    let verified_badge = if database::is_verified(username)? {
+        format!(" {}", "✔ verified".green())
+    } else {
+        format!(" {}", "(unverified)".bright_black())
+    };
+
+    println!(
+        "\n{} {}{}",
+        "💬 Conversation with".bold().cyan(),
+        display_name(username)?.bold(),
+        verified_badge
+    );
+    if let (_, Some(status)) = database::get_profile(username)? {
+        println!("{}", status.italic().bright_black());
+    }
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    let sender_name = display_name(username)?;
+
+    for msg in messages.iter().rev() {
+        let time_str = if received {
+            format_timestamp(&msg.received_at.unwrap_or(msg.timestamp))
+        } else {
+            format_timestamp(&msg.timestamp)
+        };
+        let id_str = format!("#{}", msg.id);
+
+        if msg.is_outgoing {
+            let failed_badge = if database::is_failed(msg.id)? {
+                format!(" {}", "✗ not sent".red())
+            } else {
+                String::new()
+            };
+
+            println!(
+                "{} {} {} {}{}",
+                theme::current().you("You"),
+                "→".bright_black(),
+                theme::current().timestamp(&time_str),
+                id_str.bright_black(),
+                failed_badge
+            );
+            println!("  {}", highlight_mentions(msg));
+        } else {
+            println!(
+                "{} {} {} {}",
+                theme::current().sender(&sender_name),
+                "→".bright_black(),
+                theme::current().timestamp(&time_str),
+                id_str.bright_black()
+            );
+            println!("  {}", highlight_mentions(msg));
+        }
+
+        if let Some(poll) = database::get_poll_for_message(msg.id)? {
+            display_poll_summary(&poll);
+        }
+        println!();
+    }
+
+    messages::mark_read(username)?;
+
+    Ok(())
+}
+
+pub fn display_inbox(limit: usize) -> Result<()> {
+    let messages = database::get_recent_messages(limit)?;
+
+    if messages.is_empty() {
+        println!("{}", "No messages yet.".yellow());
+        return Ok(());
+    }
+
+    println!("\n{}", "📥 Inbox".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for msg in messages.iter().rev() {
+        let time_str = format_timestamp(&msg.timestamp);
+        let unread_marker = if !msg.is_outgoing && !msg.is_read {
+            format!("{} ", "●".bright_red().bold())
+        } else {
+            "  ".to_string()
+        };
+
+        let prefix = if msg.is_outgoing {
+            format!(
+                "{} {} {}",
+                theme::current().you("You"),
+                "→".bright_black(),
+                theme::current().sender(&msg.conversation_with)
+            )
+        } else {
+            theme::current().sender(&msg.sender).to_string()
+        };
+
+        println!(
+            "{}{} {}",
+            unread_marker,
+            prefix,
+            theme::current().timestamp(&time_str)
+        );
+        println!("    {}", highlight_mentions(msg));
+    }
+
+    println!();
+
+    let shown: Vec<i64> = messages.iter().map(|msg| msg.id).collect();
+    database::mark_listed_messages_as_read(&shown)?;
+
+    Ok(())
+}
+
+pub fn star(message_id: i64) -> Result<()> {
+    database::star_message(message_id)?;
+    println!("{} Starred message #{}", "⭐".bold(), message_id);
+    Ok(())
+}
+
+pub fn unstar(message_id: i64) -> Result<()> {
+    database::unstar_message(message_id)?;
+    println!("{} Unstarred message #{}", "✓".green().bold(), message_id);
+    Ok(())
+}
+
+pub fn remove_message(message_id: i64) -> Result<()> {
+    if !database::trash_message(message_id)? {
+        anyhow::bail!("Message #{} not found", message_id);
+    }
+
+    println!("{} Moved message #{} to the trash", "🗑️".bold(), message_id);
+    let hint = match config::get_trash_period()? {
+        Some(period) => format!(
+            "Restore it with 'dood trash restore {}' within {}.",
+            message_id,
+            format_timer(period.num_seconds() as u64)
+        ),
+        None => format!("Restore it with 'dood trash restore {}'.", message_id),
+    };
+    println!("{}", hint.bright_black());
+    Ok(())
+}
+
+pub fn display_trash() -> Result<()> {
+    let messages = database::get_trashed_messages()?;
+
+    if messages.is_empty() {
+        println!("{}", "The trash is empty.".yellow());
+        return Ok(());
+    }
+
+    println!("\n{}", "🗑️  Trash".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for (msg, deleted_at) in &messages {
+        let from = if msg.is_outgoing {
+            format!(
+                "{} {} {}",
+                theme::current().you("You"),
+                "→".bright_black(),
+                theme::current().sender(&msg.conversation_with)
+            )
+        } else {
+            theme::current().sender(&msg.sender).to_string()
+        };
+
+        println!(
+            "{} {} {}",
+            from,
+            format!("deleted {}", format_timestamp(deleted_at)).bright_black(),
+            format!("#{}", msg.id).bright_black()
+        );
+        println!("  {}", text::truncate(&msg.content.replace('\n', " "), 60));
+        println!();
+    }
+
+    Ok(())
+}
+
+pub fn restore_message(message_id: i64) -> Result<()> {
+    if !database::restore_message(message_id)? {
+        anyhow::bail!("Message #{} is not in the trash", message_id);
+    }
+
+    println!("{} Restored message #{}", "✓".green().bold(), message_id);
+    Ok(())
+}
+
+pub fn empty_trash(yes: bool) -> Result<()> {
+    let count = database::get_trashed_messages()?.len();
+    if count == 0 {
+        println!("{}", "The trash is empty.".yellow());
+        return Ok(());
+    }
+
+    if !yes {
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Permanently delete {} message(s)?", count))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("{}", "Emptying the trash cancelled.".yellow());
+            return Ok(());
+        }
+    }
+
+    let deleted = database::empty_trash()?;
+    attachments::collect_garbage()?;
+    println!(
+        "{} Permanently deleted {} message(s)",
+        "✓".green().bold(),
+        deleted
+    );
+    Ok(())
+}
+
+pub fn trash_period(duration: Option<&str>) -> Result<()> {
+    if let Some(duration) = duration {
+        config::set_trash_period(duration)?;
+    }
+
+    match config::get_trash_period()? {
+        Some(period) => println!(
+            "{} Messages are deleted for good {} after being moved to the trash",
+            "🗑️".bold(),
+            format_timer(period.num_seconds() as u64).bold()
+        ),
+        None => println!(
+            "{} Messages stay in the trash until it is emptied",
+            "🗑️".bold()
+        ),
+    }
+    Ok(())
+}
+
+pub fn display_starred() -> Result<()> {
+    let messages = database::get_starred_messages()?;
+
+    if messages.is_empty() {
+        println!("{}", "No starred messages.".yellow());
+        return Ok(());
+    }
+
+    println!("\n{}", "⭐ Starred Messages".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for msg in &messages {
+        let time_str = format_timestamp(&msg.timestamp);
+
+        let from = if msg.is_outgoing {
+            format!(
+                "{} {} {}",
+                theme::current().you("You"),
+                "→".bright_black(),
+                theme::current().sender(&msg.conversation_with)
+            )
+        } else {
+            theme::current().sender(&msg.sender).to_string()
+        };
+
+        println!(
+            "{} {} {}",
+            from,
+            theme::current().timestamp(&time_str),
+            format!("#{}", msg.id).bright_black()
+        );
+        println!("  {}", highlight_mentions(msg));
+        println!();
+    }
+
+    Ok(())
+}
+
+pub fn display_failed() -> Result<()> {
+    let messages = database::get_failed_messages()?;
+
+    if messages.is_empty() {
+        println!("{}", "No failed messages.".green());
+        return Ok(());
+    }
+
+    println!("\n{}", "✗ Failed Messages".bold().red());
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for (msg, error) in &messages {
+        println!(
+            "{} {} {} {}",
+            format!("#{}", msg.id).bold(),
+            "→".bright_black(),
+            theme::current().sender(&msg.conversation_with),
+            theme::current().timestamp(&format_timestamp(&msg.timestamp))
+        );
+        println!("  {}", text::truncate(&msg.content.replace('\n', " "), 60));
+        println!("  {}", error.red());
+        println!();
+    }
+
+    println!(
+        "{}",
+        "Run 'dood resend <id>' to send a message again.".bright_black()
+    );
+
+    Ok(())
+}
+
+pub fn display_outbox() -> Result<()> {
+    let entries = database::get_outbox()?;
+
+    if entries.is_empty() {
+        println!("{}", "The outbox is empty.".green());
+        return Ok(());
+    }
+
+    println!("\n{}", "📤 Outbox".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for entry in &entries {
+        let devices = serde_json::from_str::<serde_json::Value>(&entry.envelope)
+            .ok()
+            .and_then(|envelope| envelope["messages"].as_array().map(Vec::len))
+            .unwrap_or(0);
+
+        let state = match entry.state {
+            database::OutboxState::Sent => entry.state.as_str().green(),
+            database::OutboxState::Failed => entry.state.as_str().red(),
+            database::OutboxState::Queued | database::OutboxState::Sending => {
+                entry.state.as_str().yellow()
+            }
+        };
+
+        println!(
+            "{} {} {} {} {}",
+            format!("#{}", entry.id).bold(),
+            "→".bright_black(),
+            theme::current().sender(&entry.recipient),
+            state,
+            format_timestamp(&entry.created_at).bright_black()
+        );
+        println!(
+            "  {}",
+            format!(
+                "{} device(s), {} attempt(s), last change {}",
+                devices,
+                entry.attempts,
+                format_timestamp(&entry.updated_at)
+            )
+            .bright_black()
+        );
+        if let Some(message_id) = entry.message {
+            println!("  {}", format!("message #{}", message_id).bright_black());
+        }
+        if let Some(error) = &entry.error {
+            println!("  {}", error.red());
+        }
+        println!();
+    }
+
+    if entries
+        .iter()
+        .any(|entry| entry.state != database::OutboxState::Sent)
+    {
+        println!(
+            "{}",
+            "Run 'dood resend <message>' for a failed message, or 'dood outbox retry <id>' to upload another unsent entry again."
+                .bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn display_key_history(username: &str) -> Result<()> {
+    let history = database::get_key_history(username)?;
+    let events = database::get_security_events(username)?;
+
+    if history.is_empty() && events.is_empty() {
+        println!(
+            "{}",
+            format!("No identity keys seen for '{}' yet.", username).yellow()
+        );
+        return Ok(());
+    }
+
+    let current = database::get_contact_identity(username)?;
+    let verified = database::is_verified(username)?;
+
+    println!(
+        "\n{}",
+        format!("🔑 Identity keys of {}", username).bold().cyan()
+    );
+    println!("{}", "─".repeat(60).bright_black());
+    println!();
+
+    for (key, first_seen, last_seen) in &history {
+        let mut badges = String::new();
+        if current.as_deref() == Some(key.as_slice()) {
+            badges.push_str(&" current".green().bold().to_string());
+            if verified {
+                badges.push_str(&" ✔ verified".green().to_string());
+            }
+        }
+
+        for line in crypto::format_fingerprint(key) {
+            println!("  {}", line.bold());
+        }
+        println!(
+            "  {} {}  {} {}{}",
+            "first seen".bright_black(),
+            first_seen.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            "last seen".bright_black(),
+            last_seen.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            badges
+        );
+        println!();
+    }
+
+    if !events.is_empty() {
+        println!("{}", "Security events".bold());
+        for (event, details, occurred_at) in &events {
+            println!(
+                "  {} {} {}",
+                occurred_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+                    .bright_black(),
+                event.red(),
+                details
+            );
+        }
+        println!();
+    }
+
+    if history.len() > 1 {
+        println!(
+            "{}",
+            format!(
+                "{}'s identity key has changed {} time(s). Run 'dood verify {}' to compare safety numbers.",
+                username,
+                history.len() - 1,
+                username
+            )
+            .yellow()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn purge(username: &str, before: Option<&str>, yes: bool) -> Result<()> {
+    let before = before
+        .map(|date| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+                .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+                .map(|midnight| midnight.with_timezone(&Utc))
+                .ok_or_else(|| anyhow::anyhow!("Invalid date '{}'. Use YYYY-MM-DD", date))
+        })
+        .transpose()?;
+
+    let scope = match before {
+        Some(before) => format!(
+            "messages with '{}' from before {}",
+            username,
+            before.with_timezone(&Local).format("%Y-%m-%d")
+        ),
+        None => format!("all messages with '{}'", username),
+    };
+
+    if !yes {
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Permanently delete {}?", scope))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("{}", "Purge cancelled.".yellow());
+            return Ok(());
+        }
+    }
+
+    let deleted = database::purge_messages(username, before)?;
+    attachments::collect_garbage()?;
+
+    println!(
+        "{} Purged {} message(s) with '{}'",
+        "✓".green().bold(),
+        deleted,
+        username.bold()
+    );
+
+    Ok(())
+}
+
+/// Shows the retention policy, or changes the default (without `username`)
+/// or a conversation's own retention period. 0 days keeps messages forever.
+pub fn retention(username: Option<&str>, days: Option<u32>, clear: bool) -> Result<()> {
+    match (username, days, clear) {
+        (Some(username), _, true) => {
+            database::set_retention(username, None)?;
+            println!(
+                "{} Messages with {} follow the default retention period",
+                "✓".green().bold(),
+                username.bold()
+            );
+        }
+        (Some(username), Some(days), false) => {
+            database::set_retention(username, Some(days))?;
+            println!(
+                "{} Messages with {} are {}",
+                "✓".green().bold(),
+                username.bold(),
+                describe_retention(Some(days))
+            );
+        }
+        (_, None, false) => {
+            println!(
+                "{} Messages are {}",
+                "Default:".bold(),
+                describe_retention(config::get_retention_days()?)
+            );
+            for (username, days) in database::get_retention_overrides()? {
+                println!(
+                    "{} Messages with {} are {}",
+                    "•".bright_black(),
+                    username.bold(),
+                    describe_retention(Some(days))
+                );
+            }
+            return Ok(());
+        }
+        (None, days, _) => {
+            config::set_retention_days(days)?;
+            println!(
+                "{} Messages are {}",
+                "✓".green().bold(),
+                describe_retention(days)
+            );
+        }
+    }
+
+    enforce_retention(true)
+}
+
+fn describe_retention(days: Option<u32>) -> String {
+    match days {
+        Some(days) if days > 0 => format!("deleted after {} day(s)", days),
+        _ => "kept forever".to_string(),
+    }
+}
+
+/// How often `enforce_retention` looks for expired messages.
+const RETENTION_CHECK_INTERVAL: Duration = Duration::hours(1);
+
+/// Deletes messages whose disappearing timer ran out, and those past their
+/// retention period or long enough in the trash at most once per
+/// `RETENTION_CHECK_INTERVAL` unless `force` is set, reporting how many.
+pub fn enforce_retention(force: bool) -> Result<()> {
+    // Timers can be much shorter than the check interval, so they always run.
+    let expired = database::expire_messages()?;
+    if expired > 0 {
+        eprintln!(
+            "{}",
+            format!("⏱️  Deleted {} disappearing message(s)", expired).bright_black()
+        );
+    }
+
+    let checked_at = config::get_setting("retention_checked_at")?
+        .and_then(|checked_at| DateTime::parse_from_rfc3339(&checked_at).ok());
+    if !force
+        && checked_at.is_some_and(|checked_at| {
+            Utc::now().signed_duration_since(checked_at) < RETENTION_CHECK_INTERVAL
+        })
+    {
+        return Ok(());
+    }
+
+    let deleted = database::apply_retention(config::get_retention_days()?)?;
+    let emptied = match config::get_trash_period()? {
+        Some(period) => database::expire_trash(Utc::now() - period)?,
+        None => 0,
+    };
+    config::set_setting("retention_checked_at", &Utc::now().to_rfc3339())?;
+    if deleted + emptied > 0 {
+        attachments::collect_garbage()?;
+    }
+
+    if deleted > 0 {
+        eprintln!(
+            "{}",
+            format!(
+                "🗑️  Deleted {} message(s) past their retention period",
+                deleted
+            )
+            .bright_black()
+        );
+    }
+    if emptied > 0 {
+        eprintln!(
+            "{}",
+            format!("🗑️  Emptied {} message(s) from the trash", emptied).bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn backup_database(output: &str) -> Result<()> {
+    database::backup_to(Path::new(output))?;
+
+    println!(
+        "{} Database copied to {}",
+        "✓".green().bold(),
+        output.bold()
+    );
+    println!(
+        "{}",
+        "⚠️  The copy holds your messages and keys. Keep it safe, or use 'dood backup' for a passphrase-protected file."
+            .yellow()
+    );
+
+    Ok(())
+}
+
+pub fn restore_database(input: &str, yes: bool) -> Result<()> {
+    let path = Path::new(input);
+    if !path.exists() {
+        anyhow::bail!("File not found: {}", input);
+    }
+
+    if let Ok(username) = auth::get_current_username() {
+        if daemon::is_running(&username) {
+            anyhow::bail!("Stop the daemon for '{}' before restoring.", username);
+        }
+    }
+
+    let accounts = database::inspect_backup(path)?;
+
+    if !yes {
+        let confirmed = Confirm::new()
+            .with_prompt(format!(
+                "Replace all local data with this copy (accounts: {})?",
+                if accounts.is_empty() {
+                    "none".to_string()
+                } else {
+                    accounts.join(", ")
+                }
+            ))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("{}", "Restore cancelled.".yellow());
+            return Ok(());
+        }
+    }
+
+    // Keep the current data in case the copy was not the one wanted.
+    let previous = config::get_db_path().with_extension("db.before-restore");
+    if previous.exists() {
+        std::fs::remove_file(&previous)?;
+    }
+    database::backup_to(&previous)?;
+
+    database::replace_with(&std::fs::read(path)?)?;
+    database::init()?;
+
+    println!(
+        "{} Database restored from {}",
+        "✓".green().bold(),
+        input.bold()
+    );
+    println!(
+        "{}",
+        format!("The previous database was kept at {}", previous.display()).bright_black()
+    );
+
+    Ok(())
+}
+
+pub fn compact_database() -> Result<()> {
+    let before = database::database_size();
+    println!("{}", "🧹 Compacting database...".cyan());
+
+    let pruned = database::compact()?;
+    let blobs = attachments::collect_garbage()?;
+    let after = database::database_size();
+
+    println!(
+        "{} Database compacted: {} → {}",
+        "✓".green().bold(),
+        format_size(before),
+        format_size(after).bold()
+    );
+    if pruned > 0 {
+        println!(
+            "{}",
+            format!("Removed {} orphaned row(s).", pruned).bright_black()
+        );
+    }
+    if blobs > 0 {
+        println!(
+            "{}",
+            format!("Removed {} unused attachment file(s).", blobs).bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn check_database() -> Result<()> {
+    let problems = database::check_integrity()?;
+
+    if problems.is_empty() {
+        println!("{} Database integrity check passed", "✓".green().bold());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{} {}", "✗".red().bold(), problem);
+    }
+    anyhow::bail!(
+        "Database integrity check found {} problem(s). Restore from a backup with 'dood restore'.",
+        problems.len()
+    )
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+pub fn display_mentions(limit: usize) -> Result<()> {
+    let messages = database::get_mentions(limit)?;
+
+    if messages.is_empty() {
+        println!("{}", "No mentions yet.".yellow());
+        return Ok(());
+    }
+
+    println!("\n{}", "📣 Mentions".bold().cyan());
     println!("{}", "─".repeat(60).bright_black());
+    println!();
 
-    for (username, last_time, last_msg, unread) in conversations {
-        let time_str = format_timestamp(&last_time);
-        let preview = truncate(&last_msg, 40);
-
-        let unread_badge = if unread > 0 {
-            format!(" {}", format!("[{}]", unread).bright_red().bold())
-        } else {
-            String::new()
-        };
+    for msg in &messages {
+        let time_str = format_timestamp(&msg.timestamp);
 
         println!(
-            "{} {} {}{}",
-            "👤".bold(),
-            username.bold().green(),
-            time_str.bright_black(),
-            unread_badge
+            "{} {} {}",
+            theme::current().sender(&msg.sender),
+            theme::current().timestamp(&time_str),
+            format!("#{}", msg.id).bright_black()
         );
-        println!("   {}", preview.bright_black());
+        println!("  {}", highlight_mentions(msg));
         println!();
     }
 
     Ok(())
 }
 
-pub fn display_history(username: &str, limit: usize) -> Result<()> {
-    let messages = database::get_messages(username, limit)?;
+pub fn display_stats(username: &str, weeks: i64) -> Result<()> {
+    let weeks = weeks.max(1);
+    let today = Local::now().date_naive();
+    let days_back = (weeks - 1) * 7 + today.weekday().num_days_from_monday() as i64;
+    let start = today - Duration::days(days_back);
 
-    if messages.is_empty() {
+    let daily: HashMap<NaiveDate, i64> = database::get_daily_message_counts(username, days_back)?
+        .into_iter()
+        .collect();
+    let hourly = database::get_hourly_message_counts(username)?;
+
+    if daily.is_empty() && hourly.iter().all(|&count| count == 0) {
         println!("{}", format!("No messages with {}", username).yellow());
         return Ok(());
     }
 
-    println!(
-        "\n{} {}",
-        "💬 Conversation with".bold().cyan(),
-        username.bold()
-    );
+    println!("\n{} {}", "📊 Activity with".bold().cyan(), username.bold());
     println!("{}", "─".repeat(60).bright_black());
     println!();
 
-    for msg in messages.iter().rev() {
-        let time_str = format_timestamp(&msg.timestamp);
+    let max_daily = daily.values().copied().max().unwrap_or(0);
 
-        if msg.is_outgoing {
-            println!(
-                "{} {} {}",
-                "You".bold().blue(),
-                "→".bright_black(),
-                time_str.bright_black()
-            );
-            println!("  {}", msg.content.white());
-        } else {
-            println!(
-                "{} {} {}",
-                username.bold().green(),
-                "→".bright_black(),
-                time_str.bright_black()
-            );
-            println!("  {}", msg.content.white());
+    for (weekday, label) in ["Mon", "   ", "Wed", "   ", "Fri", "   ", "Sun"]
+        .iter()
+        .enumerate()
+    {
+        let mut row = format!("{} ", label.bright_black());
+        for week in 0..weeks {
+            let day = start + Duration::days(week * 7 + weekday as i64);
+            if day > today {
+                break;
+            }
+            let count = daily.get(&day).copied().unwrap_or(0);
+            row.push_str(&heat_cell(count, max_daily));
         }
-        println!();
+        println!("{}", row);
     }
 
-    database::mark_messages_as_read(username)?;
+    println!(
+        "    {} {} {} {} {} {}",
+        "Less".bright_black(),
+        heat_cell(0, 4),
+        heat_cell(1, 4),
+        heat_cell(2, 4),
+        heat_cell(4, 4),
+        "More".bright_black()
+    );
+    println!();
+
+    println!("{}", "Messages by hour".bold());
+    let max_hourly = hourly.iter().copied().max().unwrap_or(0).max(1);
+    for (hour, &count) in hourly.iter().enumerate() {
+        let width = (count * 40 / max_hourly) as usize;
+        println!(
+            "{} {} {}",
+            format!("{:02}", hour).bright_black(),
+            "█".repeat(width).green(),
+            if count > 0 {
+                count.to_string().bright_black()
+            } else {
+                "".normal()
+            }
+        );
+    }
 
     Ok(())
 }
 
+fn display_poll_summary(poll: &database::Poll) {
+    let total: i64 = poll.tally.iter().sum();
+    let max = poll.tally.iter().copied().max().unwrap_or(0).max(1);
+
+    for (i, (option, &votes)) in poll.options.iter().zip(&poll.tally).enumerate() {
+        let width = (votes * 20 / max) as usize;
+        println!(
+            "  {}. {} {}{} {}",
+            i + 1,
+            text::pad(&text::truncate(option, 20), 20),
+            "█".repeat(width).cyan(),
+            "░".repeat(20 - width).bright_black(),
+            votes
+        );
+    }
+
+    println!(
+        "  {}",
+        format!(
+            "{} vote(s) · poll {} · /vote {} <number>",
+            total, poll.poll_id, poll.poll_id
+        )
+        .bright_black()
+    );
+}
+
+fn heat_cell(count: i64, max: i64) -> String {
+    if count == 0 || max == 0 {
+        return "·".bright_black().to_string();
+    }
+
+    match count * 4 / (max + 1) {
+        0 => "▪".green().to_string(),
+        1 => "■".green().to_string(),
+        2 => "■".bright_green().to_string(),
+        _ => "■".bright_green().bold().to_string(),
+    }
+}
+
 pub async fn interactive_chat(username: &str) -> Result<()> {
     println!("\n{} {}", "💬 Chat with".bold().cyan(), username.bold());
     println!("{}", "─".repeat(60).bright_black());
     println!(
         "{}",
-        "Type your message and press Enter. Type '/quit' to exit.".bright_black()
+        "Type your message and press Enter. Type '/help' for commands, '/quit' to exit."
+            .bright_black()
     );
     println!();
 
     let messages = database::get_messages(username, 10)?;
     for msg in messages.iter().rev() {
         if msg.is_outgoing {
-            println!("{} {}", "You:".bold().blue(), msg.content);
+            println!(
+                "{} {}",
+                theme::current().you("You:"),
+                highlight_mentions(msg)
+            );
         } else {
             println!(
                 "{} {}",
-                format!("{}:", username).bold().green(),
-                msg.content
+                theme::current().sender(&format!("{}:", username)),
+                highlight_mentions(msg)
             );
         }
     }
@@ -111,53 +1148,525 @@ pub async fn interactive_chat(username: &str) -> Result<()> {
         println!("{}", "─".repeat(60).bright_black());
     }
 
-    database::mark_messages_as_read(username)?;
+    messages::mark_read(username)?;
 
-    loop {
-        print!("{} ", ">".bright_blue().bold());
-        io::stdout().flush()?;
+    let current_username = auth::get_current_username()?;
+    let daemon_running = daemon::is_running(&current_username);
+    let mut stream = if daemon_running {
+        println!(
+            "{}",
+            "The daemon is receiving messages; they appear here once it has stored them."
+                .bright_black()
+        );
+        None
+    } else {
+        match server::open_message_stream().await {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Live updates unavailable ({}). Use '/fetch' instead.", e)
+                        .bright_black()
+                );
+                None
+            }
+        }
+    };
+    let contacts = database::get_conversations(true)?
+        .into_iter()
+        .map(|conversation| conversation.username)
+        .collect();
+    let commands = CHAT_COMMANDS
+        .iter()
+        .flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()))
+        .map(String::from)
+        .collect();
+    let mut editor =
+        LineEditor::start(format!("{} ", ">".bright_blue().bold()), commands, contacts)?;
+
+    // Without live updates, new messages are polled for while waiting for input.
+    let poll_interval = chat_poll_interval()?;
+    let poll = tokio::time::sleep(poll_interval);
+    tokio::pin!(poll);
+    let mut last_seen = messages.iter().map(|msg| msg.id).max().unwrap_or(0);
+    let mut poll_failing = false;
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    loop {
+        let input = tokio::select! {
+            line = editor.next_line() => match line {
+                Some(line) => line,
+                None => break,
+            },
+            () = &mut poll, if stream.is_none() => {
+                let result = if daemon_running {
+                    show_stored_messages(username, &mut last_seen)
+                } else {
+                    messages::fetch_pending(None).await
+                };
+                match result {
+                    Ok(0) => poll_failing = false,
+                    Ok(_) => {
+                        poll_failing = false;
+                        messages::mark_read(username)?;
+                    }
+                    // Reported once, not on every poll while offline.
+                    Err(e) if !poll_failing => {
+                        poll_failing = true;
+                        output::notice(
+                            format!("Could not check for messages: {}", e).bright_black(),
+                        );
+                    }
+                    Err(_) => {}
+                }
+                poll.as_mut().reset(tokio::time::Instant::now() + poll_interval);
+                continue;
+            }
+            frame = next_frame(&mut stream) => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        match messages::handle_stream_frame(&current_username, frame).await {
+                            Ok(0) => {}
+                            Ok(_) => messages::mark_read(username)?,
+                            Err(e) => report_chat_error(&mut editor, e).await?,
+                        }
+                    }
+                    Some(Err(e)) => {
+                        output::notice(format!("Live updates stopped: {}", e).bright_black());
+                        stream = None;
+                    }
+                    None => {
+                        output::notice("Live updates stopped.".bright_black());
+                        stream = None;
+                    }
+                }
+                continue;
+            }
+        };
         let input = input.trim();
 
         if input.is_empty() {
             continue;
         }
 
-        if input == "/quit" || input == "/exit" {
-            break;
+        // A doubled slash sends a message that starts with one.
+        let input = match input.strip_prefix('/') {
+            Some(escaped) if escaped.starts_with('/') => escaped,
+            Some(_) => {
+                match run_chat_command(username, input).await {
+                    Ok(ChatFlow::Continue) => {}
+                    Ok(ChatFlow::Quit) => break,
+                    Err(e) => report_chat_error(&mut editor, e).await?,
+                }
+                continue;
+            }
+            None => input,
+        };
+
+        match messages::send_message(username, input).await {
+            Ok(_) => output::status("  ✓ Sent".green()),
+            Err(e) => report_chat_error(&mut editor, e).await?,
         }
+    }
 
-        if input == "/fetch" {
-            if let Err(e) = messages::fetch_messages().await {
-                eprintln!("{} {}", "Error:".red(), e);
-            }
-            continue;
+    println!("{}", "\nChat ended.".bright_black());
+
+    Ok(())
+}
+
+/// Prints a failure in chat. A changed identity key is asked about on the
+/// prompt instead, and once the user trusts it, the message it held back is
+/// sent again.
+async fn report_chat_error(editor: &mut LineEditor, error: anyhow::Error) -> Result<()> {
+    let Some(change) = error.downcast_ref::<messages::IdentityChanged>() else {
+        output::notice(format!("{} {}", "  ✗ Error:".red(), error));
+        return Ok(());
+    };
+
+    output::notice(format!("⚠️  WARNING: {}", change).red().bold());
+    output::notice(
+        "Compare safety numbers with '/verify' before trusting the new key. Type 'yes' to trust it now, anything else to refuse."
+            .red(),
+    );
+
+    let answer = editor.next_line().await.unwrap_or_default();
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        output::notice("  Not trusted.".yellow());
+        return Ok(());
+    }
+
+    change.accept()?;
+    output::status(format!("  {} Trusted the new key for {}", "✓".green(), change.username));
+
+    if let Some(message_id) = change.message {
+        if let Err(e) = messages::resend_message(message_id).await {
+            output::notice(format!("{} {}", "  ✗ Error:".red(), e));
         }
+    }
 
-        match messages::send_message(username, input).await {
-            Ok(_) => {
-                println!("{}", "  ✓ Sent".green());
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum ChatAction {
+    Help,
+    Quit,
+    Fetch,
+    History,
+    Info,
+    Verify,
+    Mute,
+    Unmute,
+    Attach,
+    Vote,
+}
+
+/// A slash-command of interactive chat. They all act on the conversation
+/// the chat is with.
+struct ChatCommand {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    args: &'static str,
+    about: &'static str,
+    action: ChatAction,
+}
+
+const CHAT_COMMANDS: &[ChatCommand] = &[
+    ChatCommand {
+        name: "/help",
+        aliases: &[],
+        args: "",
+        about: "Show these commands",
+        action: ChatAction::Help,
+    },
+    ChatCommand {
+        name: "/history",
+        aliases: &[],
+        args: "[N]",
+        about: "Show the last N messages (default 20)",
+        action: ChatAction::History,
+    },
+    ChatCommand {
+        name: "/fetch",
+        aliases: &[],
+        args: "",
+        about: "Check for new messages",
+        action: ChatAction::Fetch,
+    },
+    ChatCommand {
+        name: "/info",
+        aliases: &[],
+        args: "",
+        about: "Show the contact's profile",
+        action: ChatAction::Info,
+    },
+    ChatCommand {
+        name: "/verify",
+        aliases: &[],
+        args: "",
+        about: "Compare safety numbers with the contact",
+        action: ChatAction::Verify,
+    },
+    ChatCommand {
+        name: "/mute",
+        aliases: &[],
+        args: "[DURATION]",
+        about: "Mute the conversation, e.g. for 8h",
+        action: ChatAction::Mute,
+    },
+    ChatCommand {
+        name: "/unmute",
+        aliases: &[],
+        args: "",
+        about: "Unmute the conversation",
+        action: ChatAction::Unmute,
+    },
+    ChatCommand {
+        name: "/attach",
+        aliases: &[],
+        args: "<PATH>",
+        about: "Send a file",
+        action: ChatAction::Attach,
+    },
+    ChatCommand {
+        name: "/vote",
+        aliases: &[],
+        args: "<POLL-ID> <NUMBER>",
+        about: "Vote in a poll",
+        action: ChatAction::Vote,
+    },
+    ChatCommand {
+        name: "/quit",
+        aliases: &["/exit"],
+        args: "",
+        about: "Leave the chat",
+        action: ChatAction::Quit,
+    },
+];
+
+enum ChatFlow {
+    Continue,
+    Quit,
+}
+
+async fn run_chat_command(username: &str, input: &str) -> Result<ChatFlow> {
+    let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+    let args = args.trim();
+
+    let command = CHAT_COMMANDS
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown command '{}'. Type /help to list commands, or start with '//' to send a message beginning with '/'.",
+                name
+            )
+        })?;
+    let usage = || anyhow::anyhow!("Usage: {} {}", command.name, command.args);
+
+    match command.action {
+        ChatAction::Help => display_chat_help(),
+        ChatAction::Quit => return Ok(ChatFlow::Quit),
+        ChatAction::Fetch => {
+            daemon::ensure_not_running()?;
+            messages::fetch_messages().await?;
+        }
+        ChatAction::History => {
+            let limit = match args {
+                "" => 20,
+                limit => limit.parse().map_err(|_| usage())?,
+            };
+            display_history(username, limit, false)?;
+        }
+        ChatAction::Info => display_profile(Some(username)).await?,
+        ChatAction::Verify => crypto::verify_contact(username, false, None).await?,
+        ChatAction::Mute => mute(username, (!args.is_empty()).then_some(args))?,
+        ChatAction::Unmute => unmute(username)?,
+        ChatAction::Attach => {
+            if args.is_empty() {
+                return Err(usage());
             }
-            Err(e) => {
-                eprintln!("{} {}", "  ✗ Error:".red(), e);
+            messages::send_attachment(username, Path::new(args)).await?;
+        }
+        ChatAction::Vote => {
+            let mut parts = args.split_whitespace();
+            match (parts.next(), parts.next().map(str::parse::<usize>)) {
+                (Some(poll_id), Some(Ok(option))) => {
+                    messages::send_vote(username, poll_id, option).await?
+                }
+                _ => return Err(usage()),
             }
         }
     }
 
-    println!("{}", "\nChat ended.".bright_black());
+    Ok(ChatFlow::Continue)
+}
+
+fn display_chat_help() {
+    for command in CHAT_COMMANDS {
+        let mut names = vec![command.name];
+        names.extend(command.aliases);
+        let synopsis = format!("{} {}", names.join(", "), command.args);
+        println!("  {:<28} {}", synopsis.bold(), command.about.bright_black());
+    }
+}
+
+/// How often chat checks for new messages when live updates are unavailable,
+/// unless set with the `chat_poll_interval` setting.
+const DEFAULT_CHAT_POLL_INTERVAL: &str = "5s";
+
+fn chat_poll_interval() -> Result<std::time::Duration> {
+    let interval = config::get_setting("chat_poll_interval")?
+        .unwrap_or_else(|| DEFAULT_CHAT_POLL_INTERVAL.to_string());
+    Ok(config::parse_duration(&interval)?.to_std()?)
+}
+
+/// Prints messages from `username` that were stored after `last_seen`, e.g.
+/// by the daemon, and returns how many there were.
+fn show_stored_messages(username: &str, last_seen: &mut i64) -> Result<usize> {
+    let mut new_messages = database::get_messages(username, 50)?;
+    new_messages.retain(|msg| msg.id > *last_seen);
+
+    for msg in new_messages.iter().rev() {
+        *last_seen = (*last_seen).max(msg.id);
+        if !msg.is_outgoing {
+            output::status(format!(
+                "{} {}",
+                theme::current().sender(&format!("{}:", username)),
+                highlight_mentions(msg)
+            ));
+        }
+    }
+
+    Ok(new_messages.iter().filter(|msg| !msg.is_outgoing).count())
+}
+
+/// Next frame of the message stream, or never if there is none.
+pub async fn next_frame(
+    stream: &mut Option<server::MessageStream>,
+) -> Option<Result<Message, tokio_tungstenite::tungstenite::Error>> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn ping(url: Option<&str>) -> Result<()> {
+    let server_url = match url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => config::get_server_url()?,
+    };
+
+    let health = server::check_health(&server_url).await?;
+    let latency = format!("{} ms", health.round_trip.as_millis());
+
+    if health.status.is_success() {
+        println!(
+            "{} {} is up ({})",
+            "✓".green().bold(),
+            server_url.bold(),
+            latency
+        );
+    } else if health.status == reqwest::StatusCode::NOT_FOUND {
+        println!(
+            "{} {} is reachable but has no health endpoint ({})",
+            "✓".green().bold(),
+            server_url.bold(),
+            latency
+        );
+    } else {
+        anyhow::bail!(
+            "{} is reachable but unhealthy: {} ({})",
+            server_url,
+            health.status,
+            latency
+        );
+    }
+
+    if let Some(version) = health
+        .info
+        .as_ref()
+        .and_then(|info| info["version"].as_str())
+    {
+        println!("  {} {}", "Version:".bold(), version);
+    }
+
+    match health.protocol {
+        Some(protocol) => println!(
+            "  {} {} (this client: {})",
+            "Protocol:".bold(),
+            protocol,
+            server::PROTOCOL_VERSION
+        ),
+        None => println!("  {} not advertised", "Protocol:".bold()),
+    }
+
+    Ok(())
+}
+
+pub fn display_accounts() -> Result<()> {
+    let accounts = auth::list_accounts()?;
+
+    if accounts.is_empty() {
+        println!(
+            "{}",
+            "No accounts yet. Run 'dood register' to create one.".yellow()
+        );
+        return Ok(());
+    }
+
+    let current = auth::get_current_username().ok();
+
+    for account in &accounts {
+        if current.as_deref() == Some(account.as_str()) {
+            println!("{} {}", "●".green(), account.bold().green());
+        } else {
+            println!("  {}", account);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn display_devices() -> Result<()> {
+    let devices = server::list_devices().await?;
+    let devices = devices.as_array().map(Vec::as_slice).unwrap_or_default();
+    let (current_device, _) = database::get_account_details(&auth::get_current_username()?)?;
+
+    println!("\n{}", "💻 Devices".bold().cyan());
+    println!("{}", "─".repeat(60).bright_black());
+
+    for device in devices {
+        let id = device["id"].as_i64();
+        let this_device = if id.is_some() && id == current_device {
+            " (this device)".green().to_string()
+        } else {
+            String::new()
+        };
+
+        println!(
+            "{} {}{}",
+            format!("#{}", id.map(|id| id.to_string()).unwrap_or_default()).bold(),
+            device["created_at"].as_str().unwrap_or("").bright_black(),
+            this_device
+        );
+    }
 
+    println!();
+    println!(
+        "{}",
+        "Run 'dood devices revoke <id>' to remove a device.".bright_black()
+    );
+
+    Ok(())
+}
+
+pub async fn revoke_device(device_id: u64) -> Result<()> {
+    let (current_device, _) = database::get_account_details(&auth::get_current_username()?)?;
+    if current_device == Some(device_id as i64) {
+        anyhow::bail!("Cannot revoke this device. Run the command from another device.");
+    }
+
+    server::revoke_device(device_id).await?;
+
+    println!("{} Device #{} revoked", "✓".green().bold(), device_id);
     Ok(())
 }
 
-pub fn display_account_info() -> Result<()> {
+pub fn display_account_info(show_qr: bool) -> Result<()> {
     let username = auth::get_current_username()?;
     let x3dh = auth::get_current_x3dh()?;
     let server_url = auth::get_server_url()?;
 
     let identity_pub = auth::get_identity_public_key(&x3dh);
-    let identity_pub_b64 = BASE64_STANDARD.encode(identity_pub.to_bytes());
+    let bundle = x3dh.export();
+    let (device_id, created_at) = database::get_account_details(&username)?;
+
+    let one_time_pre_keys = match &bundle["one_time_pre_keys"] {
+        serde_json::Value::Array(keys) => keys.len(),
+        _ if bundle["one_time_pre_key"].is_string() => 1,
+        _ => 0,
+    };
+    let conversations = database::get_conversations(true)?;
+
+    let conn = database::get_connection()?;
+    let total_messages: i32 = conn.query_row(
+        "SELECT COUNT(*) FROM messages WHERE owner = ?1 AND deleted_at IS NULL",
+        [&username],
+        |row| row.get(0),
+    )?;
+
+    if output::is_machine() {
+        return output::print(&output::AccountInfo {
+            identity_fingerprint: crypto::format_fingerprint(&identity_pub.to_bytes()).join(" "),
+            prekeys_left_on_server: messages::prekeys_remaining(&username)?,
+            username,
+            server: server_url,
+            device_id,
+            one_time_pre_keys,
+            conversations: conversations.len(),
+            total_messages,
+        });
+    }
 
     println!("\n{}", "👤 Account Information".bold().cyan());
     println!("{}", "─".repeat(60).bright_black());
@@ -165,23 +1674,178 @@ pub fn display_account_info() -> Result<()> {
     println!("{} {}", "Server:".bold(), server_url);
     println!(
         "{} {}",
-        "Identity Key:".bold(),
-        truncate(&identity_pub_b64, 50).bright_black()
+        "Device ID:".bold(),
+        device_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| "not assigned".to_string())
     );
     println!();
 
-    let conversations = database::get_conversations()?;
-    println!("{} {}", "Conversations:".bold(), conversations.len());
+    println!("{}", "Identity Fingerprint:".bold());
+    for line in crypto::format_fingerprint(&identity_pub.to_bytes()) {
+        println!("  {}", line.bright_black());
+    }
 
-    let conn = database::get_connection()?;
-    let total_messages: i32 =
-        conn.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+    if let Some(signed_pre_key) = bundle["signed_pre_key"]
+        .as_str()
+        .and_then(|key| BASE64_STANDARD.decode(key).ok())
+    {
+        let age_days = (Utc::now() - created_at).num_days();
+        println!(
+            "{} {}",
+            "Signed Prekey Fingerprint:".bold(),
+            format!("(created {} day(s) ago)", age_days).bright_black()
+        );
+        for line in crypto::format_fingerprint(&signed_pre_key) {
+            println!("  {}", line.bright_black());
+        }
+    }
+
+    println!("{} {}", "One-time Prekeys:".bold(), one_time_pre_keys);
+    if let Some(remaining) = messages::prekeys_remaining(&username)? {
+        println!("{} {}", "Prekeys Left on Server:".bold(), remaining);
+    }
+    println!();
+
+    if show_qr {
+        let payload = crypto::fingerprint_payload(&username, &identity_pub.to_bytes());
+        println!("{}", crypto::render_qr(&payload)?);
+        println!(
+            "{}",
+            "Contacts can scan this code with 'dood verify <you> --scan <code>'.".bright_black()
+        );
+        println!();
+    }
+
+    println!("{} {}", "Conversations:".bold(), conversations.len());
     println!("{} {}", "Total Messages:".bold(), total_messages);
 
     Ok(())
 }
 
-fn format_timestamp(dt: &DateTime<Utc>) -> String {
+/// `Name (username)` when I gave the contact a nickname or they published a
+/// profile; my nickname wins.
+fn display_name(username: &str) -> Result<String> {
+    Ok(match contact_name(username)? {
+        Some(name) => format!("{} ({})", name, username),
+        None => username.to_string(),
+    })
+}
+
+/// My nickname for the contact, or else the name from their profile.
+fn contact_name(username: &str) -> Result<Option<String>> {
+    if let Some(nickname) = database::get_nickname(username)? {
+        return Ok(Some(nickname));
+    }
+
+    Ok(database::get_profile(username)?
+        .0
+        .filter(|name| !name.is_empty()))
+}
+
+pub fn set_nickname(username: &str, nickname: Option<&str>) -> Result<()> {
+    let nickname = nickname
+        .map(str::trim)
+        .filter(|nickname| !nickname.is_empty());
+    database::set_nickname(username, nickname)?;
+
+    match nickname {
+        Some(nickname) => println!(
+            "{} {} will be shown as {}",
+            "✓".green().bold(),
+            username.bold(),
+            nickname.bold()
+        ),
+        None => println!(
+            "{} Removed the nickname of {}",
+            "✓".green().bold(),
+            username.bold()
+        ),
+    }
+
+    Ok(())
+}
+
+pub fn set_expire_timer(username: &str, duration: Option<&str>) -> Result<()> {
+    let seconds = match duration {
+        Some(duration) => {
+            let seconds = config::parse_duration(duration)?.num_seconds();
+            (seconds > 0).then_some(seconds as u64)
+        }
+        None => None,
+    };
+    database::set_expire_timer(username, seconds)?;
+
+    match seconds {
+        Some(seconds) => println!(
+            "{} Messages with {} will disappear {} after they are sent",
+            "✓".green().bold(),
+            username.bold(),
+            format_timer(seconds).bold()
+        ),
+        None => println!(
+            "{} Messages with {} will no longer disappear",
+            "✓".green().bold(),
+            username.bold()
+        ),
+    }
+    println!(
+        "{}",
+        "The timer only applies to this device's copy of the conversation.".bright_black()
+    );
+
+    Ok(())
+}
+
+/// Largest whole unit of a timer, e.g. `30m` or `2d`.
+fn format_timer(seconds: u64) -> String {
+    for (unit, size) in [("w", 604_800), ("d", 86_400), ("h", 3_600), ("m", 60)] {
+        if seconds >= size && seconds % size == 0 {
+            return format!("{}{}", seconds / size, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
+
+pub async fn set_profile(name: Option<&str>, status: Option<&str>) -> Result<()> {
+    if name.is_none() && status.is_none() {
+        anyhow::bail!("Nothing to change. Pass --name and/or --status.");
+    }
+
+    let username = auth::get_current_username()?;
+    let (current_name, current_status) = database::get_profile(&username)?;
+    let name = name.map(str::to_string).or(current_name);
+    let status = status.map(str::to_string).or(current_status);
+
+    server::publish_profile(name.as_deref(), status.as_deref()).await?;
+    database::save_profile(&username, name.as_deref(), status.as_deref())?;
+
+    println!("{} Profile updated", "✓".green().bold());
+    Ok(())
+}
+
+pub async fn display_profile(username: Option<&str>) -> Result<()> {
+    let username = match username {
+        Some(username) => {
+            messages::refresh_contact(username).await?;
+            username.to_string()
+        }
+        None => auth::get_current_username()?,
+    };
+    let (name, status) = database::get_profile(&username)?;
+
+    println!("{} {}", "Username:".bold(), username.green());
+    println!(
+        "{} {}",
+        "Display name:".bold(),
+        name.as_deref().unwrap_or("-")
+    );
+    println!("{} {}", "Status:".bold(), status.as_deref().unwrap_or("-"));
+
+    Ok(())
+}
+
+pub fn format_timestamp(dt: &DateTime<Utc>) -> String {
     let local: DateTime<Local> = dt.with_timezone(&Local::now().timezone());
     let now = Local::now();
 
@@ -194,10 +1858,19 @@ fn format_timestamp(dt: &DateTime<Utc>) -> String {
     }
 }
 
-fn truncate(s: &str, max_len: usize) -> String {
-    if s.len() <= max_len {
-        s.to_string()
-    } else {
-        format!("{}...", &s[..max_len - 3])
+/// The text of a message in the theme's colors, with mentions highlighted.
+fn highlight_mentions(msg: &database::Message) -> String {
+    let text = &msg.content;
+    let theme = theme::current();
+    let mut highlighted = String::new();
+    let mut last = 0;
+
+    for (start, end) in messages::mention_spans(text) {
+        highlighted.push_str(&theme.text(&text[last..start], msg.is_outgoing).to_string());
+        highlighted.push_str(&text[start..end].bold().yellow().to_string());
+        last = end;
     }
+
+    highlighted.push_str(&theme.text(&text[last..], msg.is_outgoing).to_string());
+    highlighted
 }